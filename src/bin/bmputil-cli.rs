@@ -5,11 +5,50 @@
 // SPDX-FileContributor: Modified by P-Storm <pauldeman@gmail.com>
 
 use std::ffi::OsStr;
-use std::io::stdout;
+use std::fs::File;
+use std::io::{Read, Write, stdin, stdout};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use bmputil::bmp::{BmpDevice, BmpMatcher, FirmwareType};
+use bmputil::firmware_file::FirmwareFile;
 use bmputil::metadata::download_metadata;
+use bmputil::serial::bmd_rsp::BmdRspInterface;
+use bmputil::serial::gdb_flash;
+use bmputil::serial::gdb_rsp::{GdbRspInterface, ScanProtocol};
+use bmputil::serial::remote::BmdRemoteProtocol;
+use bmputil::serial::remote::riscv_debug::riscv_jtag_part;
+use bmputil::serial::uart::LineTimestamper;
+use bmputil::server::gdb_host::GdbHostServer;
+use bmputil::server::gdb_proxy::GdbProxy;
+use bmputil::server::remote::RemoteBridge;
+use bmputil::target::Target;
+use bmputil::target::breakpoints::{Dwt, Fpb, HaltReason, WatchKind};
+use bmputil::target::coredump::CoreDump;
+use bmputil::target::coresight::discover_components;
+use bmputil::target::cortexm::{
+	CORTEXM_CORE_REGISTER_NAMES, CORTEXM_REG_MSP, CORTEXM_REG_PC, CORTEXM_REG_SP, CORTEXM_REG_XPSR, CORTEXM_SRAM_REGION,
+	CORTEXM_VTOR, CORTEXM_XPSR_THUMB, CortexM,
+};
+use bmputil::target::defmt::{DefmtDecoder, DefmtTable};
+use bmputil::target::fault::FaultReport;
+use bmputil::target::flash::{detect_flash, program_image};
+use bmputil::target::itm::{ItmDecoder, ItmPacket};
+use bmputil::target::mass_erase::{MassEraseVendor, mass_erase};
+use bmputil::target::profile::Profile;
+use bmputil::target::riscv::{RISCV_CSR_DPC, RISCV_GPR_NAMES, RiscvJtagTarget};
+use bmputil::target::rtt::Rtt;
+use bmputil::target::semihosting::{Semihosting, TargetStop};
+use bmputil::target::svd::Svd;
+use bmputil::target::swo::{SwoCapture, SwoConfig, SwoMode, configure_swo};
+use bmputil::target::variables::Variable;
+use bmputil::target::verify::verify_image;
+use bmputil::watch_viewer::WatchViewer;
 #[cfg(windows)]
 use bmputil::windows;
 use bmputil::{AllowDangerous, BmpParams, FlashParams};
@@ -18,7 +57,7 @@ use clap::builder::styling::Styles;
 use clap::{Arg, ArgAction, Args, Command, CommandFactory, Parser, Subcommand, crate_description, crate_version};
 use clap_complete::{Shell, generate};
 use color_eyre::config::HookBuilder;
-use color_eyre::eyre::{Context, EyreHandler, InstallError, OptionExt, Result};
+use color_eyre::eyre::{Context, EyreHandler, InstallError, OptionExt, Result, eyre};
 use directories::ProjectDirs;
 use log::{debug, error, info, warn};
 use owo_colors::OwoColorize;
@@ -42,6 +81,9 @@ struct CliArguments
 	#[arg(global = true, short = 'p', long = "port")]
 	/// Use the device on the given USB port
 	port: Option<String>,
+	#[arg(global = true, long = "remote")]
+	/// Use the probe reached over the network at the given URL (eg, tcp://host:port) rather than a local one
+	remote: Option<String>,
 
 	#[cfg(windows)]
 	#[arg(global = true, long = "windows-wdi-install-mode", value_parser = u32::from_str, hide = true)]
@@ -61,9 +103,11 @@ enum ToplevelCommmands
 	#[command(subcommand)]
 	Target(TargetCommmands),
 	/// Actions that run the tool as a debug/tracing server
-	Server,
+	#[command(subcommand)]
+	Server(ServerCommands),
 	/// Actions that run debugging commands against a target connected to a probe
-	Debug,
+	#[command(subcommand)]
+	Debug(DebugCommands),
 	/// Generate completions data for the shell
 	Complete(CompletionArguments),
 }
@@ -86,6 +130,68 @@ enum TargetCommmands
 {
 	/// Print information about the target power control state
 	Power,
+	/// Erase the target's Flash
+	Erase(EraseArguments),
+	/// Read the target's Flash back and check it matches a firmware file
+	Verify(VerifyArguments),
+	/// Halt the target and capture its registers and RAM to an ELF core file
+	Coredump(CoredumpArguments),
+	/// Halt the target and explain why it faulted
+	Fault(FaultArguments),
+	/// Set, list or clear hardware breakpoints
+	Break(BreakArguments),
+	/// Set, list or clear hardware watchpoints
+	Watch(WatchArguments),
+	/// Stream a SEGGER RTT channel to and from the terminal
+	Rtt(RttArguments),
+	/// Let the target run, servicing its semihosting calls until it exits
+	Semihost(SemihostArguments),
+	/// Load and run firmware on the target, streaming its output and exiting with its exit status
+	Run(RunArguments),
+	/// Capture and decode ITM/DWT trace from the target's SWO pin
+	Swo(SwoArguments),
+	/// Profile the running target by sampling its program counter
+	Profile(ProfileArguments),
+	/// Watch the values of variables in the running target's memory
+	WatchVar(WatchVarArguments),
+	/// Read or write peripheral registers by name, decoded using a CMSIS-SVD file
+	Peripheral(PeripheralArguments),
+	/// Halt the target's core
+	Halt(CoreArguments),
+	/// Let the target's core run again
+	Resume(CoreArguments),
+	/// Print the target core's registers, halting it briefly if it's running
+	Regs(CoreArguments),
+	/// Read the target's memory, printing it as a hex dump or writing it to a file
+	Read(ReadArguments),
+	/// Scan the JTAG chain, listing the devices on it and any RISC-V debug transport modules found
+	JtagScan,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+enum ServerCommands
+{
+	/// Let GDB clients connect over the network to the probe's GDB server
+	Gdb(GdbServerArguments),
+	/// Let BMDA or bmputil on other machines use probes over the network via the BMD remote protocol
+	Remote(RemoteServerArguments),
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+enum DebugCommands
+{
+	/// Run a monitor command on the probe's GDB server, printing its output
+	Monitor(MonitorArguments),
+	/// Have the probe's GDB server scan for targets, listing the ones found
+	Scan(ScanArguments),
+	/// Scan for targets and attach to one of them, reporting why it stopped
+	Attach(AttachArguments),
+	/// Program firmware into the target using the probe's own Flash drivers, then verify it
+	Load(LoadArguments),
+	/// Describe the target's registers and memory map, as the probe's GDB server reports them
+	Describe(DescribeArguments),
 }
 
 #[derive(Subcommand)]
@@ -100,11 +206,348 @@ enum ProbeCommmands
 	Switch(SwitchArguments),
 	/// Reboot a Black Magic Probe (potentially into its bootloader)
 	Reboot(RebootArguments),
+	/// Talk to the target's serial port via the probe's auxiliary UART
+	Uart(UartArguments),
 	#[cfg(windows)]
 	/// Install USB drivers for BMP devices, and quit
 	InstallDrivers(DriversArguments),
 }
 
+#[derive(Args)]
+struct EraseArguments
+{
+	#[arg(long = "mass", default_value_t = false)]
+	/// Mass erase the whole device, recovering it from read-out protection where the vendor allows
+	mass: bool,
+	#[arg(long = "vendor", value_enum)]
+	/// Use the given vendor's mass erase sequence instead of detecting which the target needs
+	vendor: Option<MassEraseVendor>,
+}
+
+#[derive(Args)]
+struct VerifyArguments
+{
+	/// Firmware file (ELF, Intel Hex or raw binary) to compare the target's Flash against
+	firmware: PathBuf,
+	#[arg(long = "address", value_parser = parse_number)]
+	/// Address the firmware is expected to be at (required for raw binaries)
+	address: Option<u64>,
+	#[arg(long = "crc", default_value_t = false)]
+	/// Compare the firmware a sector at a time by CRC32, computed by the probe's GDB server, rather than reading
+	/// it all back to compare byte-for-byte
+	crc: bool,
+	#[arg(long = "sector-size", value_parser = parse_number, default_value = "1024", requires = "crc")]
+	/// Size of the sectors to compare by CRC32
+	sector_size: u64,
+	#[arg(long = "target", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), requires = "crc")]
+	/// Number of the target to verify when comparing by CRC32, as listed by `debug scan`
+	target: u32,
+	#[command(flatten)]
+	scan: ScanArguments,
+}
+
+#[derive(Args)]
+struct CoredumpArguments
+{
+	#[arg(short = 'o', long = "output")]
+	/// File to write the ELF core dump to
+	output: PathBuf,
+	#[arg(long = "elf")]
+	/// Firmware ELF for the target, the writable segments of which are captured if no ranges are given
+	elf: Option<PathBuf>,
+	#[arg(long = "range", value_parser = parse_range)]
+	/// Memory range to capture, as start:length (may be given multiple times)
+	ranges: Vec<Range<u32>>,
+}
+
+#[derive(Args)]
+struct FaultArguments
+{
+	#[arg(long = "elf")]
+	/// Firmware ELF for the target, used to symbolise the stacked PC and LR
+	elf: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct HaltWaitArguments
+{
+	#[arg(long = "wait", default_value_t = false)]
+	/// Let the core run and wait for it to halt, then report why it did
+	wait: bool,
+	#[arg(long = "timeout", requires = "wait")]
+	/// Give up waiting for the core to halt after this many seconds
+	timeout: Option<u64>,
+	#[arg(long = "elf")]
+	/// Firmware ELF for the target, used to look up symbols
+	elf: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct BreakArguments
+{
+	/// Address or symbol name (requires --elf) to break on
+	#[arg(required_unless_present_any = ["list", "clear"])]
+	location: Option<String>,
+	#[arg(long = "list", default_value_t = false, conflicts_with = "clear")]
+	/// List the active breakpoints
+	list: bool,
+	#[arg(long = "clear", default_value_t = false)]
+	/// Remove all breakpoints
+	clear: bool,
+	#[command(flatten)]
+	wait: HaltWaitArguments,
+}
+
+#[derive(Args)]
+struct WatchArguments
+{
+	/// Address or symbol name (requires --elf) to watch
+	#[arg(required_unless_present_any = ["list", "clear"])]
+	location: Option<String>,
+	#[arg(long = "read", default_value_t = false)]
+	/// Halt on reads from the location
+	read: bool,
+	#[arg(long = "write", default_value_t = false)]
+	/// Halt on writes to the location
+	write: bool,
+	#[arg(long = "size", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=4))]
+	/// Number of bytes to watch (1, 2 or 4)
+	size: u32,
+	#[arg(long = "list", default_value_t = false, conflicts_with = "clear")]
+	/// List the active watchpoints
+	list: bool,
+	#[arg(long = "clear", default_value_t = false)]
+	/// Remove all watchpoints
+	clear: bool,
+	#[command(flatten)]
+	wait: HaltWaitArguments,
+}
+
+#[derive(Args)]
+struct RttArguments
+{
+	#[arg(long = "channel", default_value_t = 0)]
+	/// RTT channel to display and send terminal input to
+	channel: usize,
+	#[arg(long = "elf")]
+	/// Firmware ELF for the target, used to find the RTT control block by its symbol
+	elf: Option<PathBuf>,
+	#[arg(long = "address", value_parser = parse_number, conflicts_with = "scan")]
+	/// Address of the RTT control block
+	address: Option<u64>,
+	#[arg(long = "scan", value_parser = parse_range)]
+	/// RAM range to scan for the RTT control block, as start:length
+	scan: Option<Range<u32>>,
+	#[arg(long = "defmt", conflicts_with = "elf")]
+	/// Decode the channel as defmt log frames using the format strings in this firmware ELF
+	defmt: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct SemihostArguments
+{
+	#[arg(long = "root", default_value = ".")]
+	/// Directory the target is allowed to open files in
+	root: PathBuf,
+	#[arg(long = "timeout")]
+	/// How long to let the target run for before giving up, in seconds
+	timeout: Option<u64>,
+}
+
+#[derive(Args)]
+struct RunArguments
+{
+	/// Firmware ELF to run - images linked for RAM are loaded, ones linked for Flash are programmed
+	firmware: PathBuf,
+	#[arg(long = "root", default_value = ".")]
+	/// Directory the target is allowed to open files in via semihosting
+	root: PathBuf,
+	#[arg(long = "timeout")]
+	/// How long to let the target run for before giving up, in seconds
+	timeout: Option<u64>,
+}
+
+#[derive(Args)]
+struct SwoArguments
+{
+	#[arg(long = "input", conflicts_with_all = ["clock", "output"])]
+	/// Decode a previously captured trace file rather than capturing from the probe
+	input: Option<PathBuf>,
+	#[arg(long = "output", short = 'o')]
+	/// Also save the raw captured trace data to this file
+	output: Option<PathBuf>,
+	#[arg(long = "clock", value_parser = parse_number)]
+	/// Target trace clock frequency in Hz - if given, the target is configured to emit trace
+	/// (otherwise its firmware is assumed to have done so)
+	clock: Option<u64>,
+	#[arg(long = "baud", value_parser = parse_number, default_value = "2250000")]
+	/// SWO bit rate the target should use, which the probe's capture is set to match in UART mode
+	baud: u64,
+	#[arg(long = "mode", value_enum, default_value_t = SwoMode::Manchester)]
+	/// How the target should encode the SWO output
+	mode: SwoMode,
+	#[arg(long = "ports", value_parser = parse_number, default_value = "0xffffffff")]
+	/// Bitmask of ITM stimulus ports to enable
+	ports: u64,
+	#[arg(long = "pc-sampling", default_value_t = false)]
+	/// Have the DWT periodically sample the PC
+	pc_sampling: bool,
+	#[arg(long = "exceptions", default_value_t = false)]
+	/// Trace exception entry, exit and return
+	exceptions: bool,
+	#[arg(long = "counters", default_value_t = false)]
+	/// Report DWT event counter wraps
+	counters: bool,
+	#[arg(long = "timestamps", default_value_t = false)]
+	/// Have the ITM emit local timestamps
+	timestamps: bool,
+}
+
+#[derive(Args)]
+struct ProfileArguments
+{
+	#[arg(long = "duration", value_parser = parse_duration, default_value = "10s")]
+	/// How long to sample for (eg, 10s, 500ms or 2m)
+	duration: Duration,
+	#[arg(long = "elf")]
+	/// Firmware ELF for the target, used to attribute samples to functions
+	elf: Option<PathBuf>,
+	#[arg(long = "folded")]
+	/// Write the samples to this file in the folded stacks format used by flamegraph tools
+	folded: Option<PathBuf>,
+	#[arg(long = "top", default_value_t = 20)]
+	/// How many of the most sampled functions to display
+	top: usize,
+}
+
+#[derive(Args)]
+struct WatchVarArguments
+{
+	/// Firmware ELF for the target, used to find the variables
+	elf: PathBuf,
+	#[arg(value_delimiter = ',', required = true)]
+	/// Names of the variables to watch, separated by commas
+	symbols: Vec<String>,
+	#[arg(long = "interval", value_parser = parse_duration, default_value = "100ms")]
+	/// How often to read the variables (eg, 100ms or 1s)
+	interval: Duration,
+	#[arg(long = "csv")]
+	/// Also log every sample of the variables to this CSV file
+	csv: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct PeripheralArguments
+{
+	/// Peripheral (eg, GPIOA) or register (eg, GPIOA.ODR) to display
+	path: String,
+	#[arg(long = "svd", required = true)]
+	/// CMSIS-SVD file describing the target's peripherals
+	svd: PathBuf,
+	#[arg(long = "set", value_parser = parse_assignment)]
+	/// Change a field of the register, as FIELD=VALUE (where VALUE is a number or an enumerated value name)
+	set: Vec<(String, String)>,
+}
+
+#[derive(Args)]
+struct CoreArguments
+{
+	#[arg(long = "riscv", default_value_t = false)]
+	/// Talk to a RISC-V target over JTAG rather than an ARM Cortex-M target over SWD
+	riscv: bool,
+	#[arg(long = "hart", default_value_t = 0, requires = "riscv")]
+	/// Which of the RISC-V target's harts to use
+	hart: u32,
+}
+
+#[derive(Args)]
+struct ReadArguments
+{
+	/// Address to start reading from
+	#[arg(value_parser = parse_number)]
+	address: u64,
+	/// Number of bytes to read
+	#[arg(value_parser = parse_number)]
+	length: u64,
+	#[arg(long = "output", short = 'o')]
+	/// Write the memory read to this file rather than printing it
+	output: Option<PathBuf>,
+	#[command(flatten)]
+	core: CoreArguments,
+}
+
+#[derive(Args)]
+struct GdbServerArguments
+{
+	#[arg(long = "listen", default_value = "127.0.0.1:2000")]
+	/// Address and port to listen for GDB clients on (eg, 0.0.0.0:2000 to allow connections from other machines)
+	listen: SocketAddr,
+	#[arg(long = "host-side", default_value_t = false)]
+	/// Run the GDB server in bmputil, driving the target over the probe's remote protocol, rather than using the
+	/// one in the probe firmware (such as for targets the firmware does not support)
+	host_side: bool,
+}
+
+#[derive(Args)]
+struct RemoteServerArguments
+{
+	#[arg(long = "listen", required = true, value_parser = parse_remote_listen)]
+	/// Address and port to listen for clients on, optionally as SERIAL=ADDRESS to pick the probe served there
+	/// by its serial number (may be given multiple times to serve multiple probes, one per port)
+	listen: Vec<(Option<String>, SocketAddr)>,
+}
+
+#[derive(Args)]
+struct MonitorArguments
+{
+	/// Monitor command to run, along with its arguments (eg, swdp_scan or tpwr enable)
+	#[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+	command: Vec<String>,
+}
+
+#[derive(Args)]
+struct ScanArguments
+{
+	#[arg(long = "jtag", default_value_t = false)]
+	/// Scan using JTAG rather than SWD
+	jtag: bool,
+}
+
+#[derive(Args)]
+struct AttachArguments
+{
+	/// Number of the target to attach to, as listed by `debug scan`
+	#[arg(value_parser = clap::value_parser!(u32).range(1..))]
+	target: u32,
+	#[command(flatten)]
+	scan: ScanArguments,
+}
+
+#[derive(Args)]
+struct LoadArguments
+{
+	/// Firmware file (ELF, Intel Hex or raw binary) to load onto the target
+	firmware: PathBuf,
+	#[arg(long = "address", value_parser = parse_number)]
+	/// Address to load the firmware at (required for raw binaries)
+	address: Option<u64>,
+	#[arg(long = "target", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+	/// Number of the target to load the firmware onto, as listed by `debug scan`
+	target: u32,
+	#[command(flatten)]
+	scan: ScanArguments,
+}
+
+#[derive(Args)]
+struct DescribeArguments
+{
+	#[arg(long = "target", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+	/// Number of the target to describe, as listed by `debug scan`
+	target: u32,
+	#[command(flatten)]
+	scan: ScanArguments,
+}
+
 #[derive(Args)]
 struct InfoArguments
 {
@@ -166,6 +609,23 @@ struct RebootArguments
 	repeat: bool,
 }
 
+#[derive(Args)]
+struct UartArguments
+{
+	#[arg(long = "baud", default_value_t = 115200)]
+	/// Baud rate to run the UART at
+	baud: u32,
+	#[arg(long = "log")]
+	/// Also write everything received from the target to the given file
+	log: Option<PathBuf>,
+	#[arg(long = "timestamps", default_value_t = false, conflicts_with = "hex")]
+	/// Prefix each line received with the time since the terminal was started
+	timestamps: bool,
+	#[arg(long = "hex", default_value_t = false)]
+	/// Display what is received from the target as a hex dump, for binary protocols
+	hex: bool,
+}
+
 #[cfg(windows)]
 #[derive(Args)]
 struct DriversArguments
@@ -233,8 +693,74 @@ impl TypedValueParser for ConfirmedBoolParser
 	}
 }
 
+/// Parse a number given on the command line, accepting hex with a 0x prefix as well as plain decimal
+fn parse_number(value: &str) -> Result<u64, String>
+{
+	let result = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => value.parse(),
+	};
+	result.map_err(|error| format!("'{value}' is not a valid number: {error}"))
+}
+
+/// Parse a duration given on the command line as a number with an optional ms, s or m suffix (seconds by default)
+fn parse_duration(value: &str) -> Result<Duration, String>
+{
+	let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+		(number, 0.001)
+	} else if let Some(number) = value.strip_suffix('s') {
+		(number, 1.0)
+	} else if let Some(number) = value.strip_suffix('m') {
+		(number, 60.0)
+	} else {
+		(value, 1.0)
+	};
+	let number: f64 = number
+		.parse()
+		.map_err(|error| format!("'{value}' is not a valid duration: {error}"))?;
+	Duration::try_from_secs_f64(number * scale).map_err(|error| format!("'{value}' is not a valid duration: {error}"))
+}
+
+/// Parse an assignment given on the command line as NAME=VALUE
+fn parse_assignment(value: &str) -> Result<(String, String), String>
+{
+	value
+		.split_once('=')
+		.map(|(name, value)| (name.trim().into(), value.trim().into()))
+		.ok_or_else(|| format!("'{value}' is not a valid assignment, expected NAME=VALUE"))
+}
+
+/// Parse a remote server listen address given on the command line as either ADDRESS:PORT or SERIAL=ADDRESS:PORT,
+/// the latter picking which probe (by serial number) to serve on that address
+fn parse_remote_listen(value: &str) -> Result<(Option<String>, SocketAddr), String>
+{
+	let (serial, address) = match value.split_once('=') {
+		Some((serial, address)) => (Some(serial.to_string()), address),
+		None => (None, value),
+	};
+	let address = address
+		.parse()
+		.map_err(|error| format!("'{}' is not a valid address and port: {}", address, error))?;
+	Ok((serial, address))
+}
+
+/// Parse a memory range given on the command line as start:length
+fn parse_range(value: &str) -> Result<Range<u32>, String>
+{
+	let (start, length) = value
+		.split_once(':')
+		.ok_or_else(|| format!("'{value}' is not a valid range, expected start:length"))?;
+	let start = u32::try_from(parse_number(start)?).map_err(|error| error.to_string())?;
+	let length = u32::try_from(parse_number(length)?).map_err(|error| error.to_string())?;
+	let end = start
+		.checked_add(length)
+		.ok_or_else(|| format!("'{value}' extends past the end of the address space"))?;
+	Ok(start..end)
+}
+
 fn reboot_command(cli_args: &CliArguments, reboot_args: &RebootArguments) -> Result<()>
 {
+	require_local_probe(cli_args, "reboot")?;
 	let matcher = BmpMatcher::from_params(cli_args);
 	let mut results = matcher.find_matching_probes();
 	let mut dev = results.pop_single("detach").map_err(|kind| kind.error())?;
@@ -271,10 +797,63 @@ fn reboot_command(cli_args: &CliArguments, reboot_args: &RebootArguments) -> Res
 	dev.detach_and_destroy().wrap_err("detaching device")
 }
 
+fn uart_command(cli_args: &CliArguments, uart_args: &UartArguments) -> Result<()>
+{
+	require_local_probe(cli_args, "uart")?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single("uart").map_err(|kind| kind.error())?;
+
+	let mut uart = probe.uart_serial_interface(uart_args.baud)?;
+	let mut log = uart_args
+		.log
+		.as_ref()
+		.map(|path| File::create(path).wrap_err_with(|| eyre!("Failed to create log file {}", path.display())))
+		.transpose()?;
+	info!("Connected to the target UART at {} baud, press Ctrl+C to exit", uart_args.baud);
+
+	// Forward terminal input on its own thread so it doesn't hold up the target's output
+	let mut input = uart.try_clone()?;
+	thread::spawn(move || {
+		let mut buffer = [0u8; 1024];
+		while let Ok(length @ 1..) = stdin().read(&mut buffer) {
+			if input.write_all(&buffer[..length]).is_err() {
+				break;
+			}
+		}
+	});
+
+	let mut timestamper = uart_args.timestamps.then(LineTimestamper::new);
+	let mut offset = 0;
+	let mut buffer = [0u8; 1024];
+	loop {
+		let length = uart.read(&mut buffer)?;
+		if length == 0 {
+			continue;
+		}
+		let data = &buffer[..length];
+		if let Some(log) = &mut log {
+			log.write_all(data)?;
+		}
+
+		if uart_args.hex {
+			// Dump each piece of data as it arrives, addressed by where it falls in the stream
+			print_hex_dump(offset, data);
+			offset += length as u64;
+		} else if let Some(timestamper) = &mut timestamper {
+			stdout().write_all(&timestamper.process(data))?;
+		} else {
+			stdout().write_all(data)?;
+		}
+		stdout().flush()?;
+	}
+}
+
 fn update_probe(cli_args: &CliArguments, flash_args: &UpdateArguments, paths: &ProjectDirs) -> Result<()>
 {
 	use bmputil::switcher::{download_firmware, pick_firmware};
 
+	require_local_probe(cli_args, "flash")?;
 	// Try to find the Black Magic Probe device based on the filter arguments.
 	let matcher = BmpMatcher::from_params(cli_args);
 	let mut results = matcher.find_matching_probes();
@@ -429,15 +1008,35 @@ fn list_targets(probe: BmpDevice) -> Result<()>
 	Ok(())
 }
 
-fn power_command(cli_args: &CliArguments) -> Result<()>
+/// Start a remote protocol session with the probe to use for an operation - the one given by --remote
+/// if any, otherwise the locally connected probe matching the other options
+fn open_remote(cli_args: &CliArguments, operation: &str) -> Result<Box<dyn BmdRemoteProtocol>>
 {
-	// Try and identify all the probes on the system that are allowed by the invocation
+	if let Some(remote) = &cli_args.remote {
+		return BmdRspInterface::from_url(remote)?.remote();
+	}
 	let matcher = BmpMatcher::from_params(cli_args);
 	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single(operation).map_err(|kind| kind.error())?;
+	probe.bmd_serial_interface()?.remote()
+}
 
-	// Otherwise, turn the result set into a list and go through them displaying them
-	let device = results.pop_single("power").map_err(|kind| kind.error())?;
-	let remote = device.bmd_serial_interface()?.remote()?;
+/// Refuse --remote for operations that need more of the probe than its remote protocol interface (such as
+/// its GDB server or other USB interfaces), rather than quietly using a local probe instead
+fn require_local_probe(cli_args: &CliArguments, operation: &str) -> Result<()>
+{
+	if cli_args.remote.is_some() {
+		return Err(eyre!(
+			"The {} operation needs a locally connected probe, so cannot be used with --remote",
+			operation
+		));
+	}
+	Ok(())
+}
+
+fn power_command(cli_args: &CliArguments) -> Result<()>
+{
+	let remote = open_remote(cli_args, "power")?;
 
 	let power = remote.get_target_power_state()?;
 
@@ -446,8 +1045,1078 @@ fn power_command(cli_args: &CliArguments) -> Result<()>
 	Ok(())
 }
 
+fn erase_command(cli_args: &CliArguments, erase_args: &EraseArguments) -> Result<()>
+{
+	// Mass erase is the only kind of erase there is so far, so make sure that's what was asked for
+	if !erase_args.mass {
+		return Err(eyre!("Only mass erase is supported, please specify --mass"));
+	}
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "erase")?;
+
+	// Attach to the target and run the mass erase
+	let target = Target::attach_remote(remote)?;
+	mass_erase(&target, erase_args.vendor)
+}
+
+fn verify_command(cli_args: &CliArguments, verify_args: &VerifyArguments) -> Result<()>
+{
+	// Load the firmware to check against, and figure out where it should be
+	let firmware = FirmwareFile::from_path(&verify_args.firmware)?;
+	let load_address = verify_args
+		.address
+		.or_else(|| firmware.load_address().map(u64::from))
+		.ok_or_eyre("Firmware file does not specify a load address, please provide one with --address")?;
+
+	info!(
+		"Verifying {} bytes at 0x{:08x} against {}",
+		firmware.len(),
+		load_address,
+		verify_args.firmware.display()
+	);
+	let mismatches = if verify_args.crc {
+		let sector_size = usize::try_from(verify_args.sector_size)?;
+		if sector_size == 0 {
+			return Err(eyre!("Sector size must be non-zero"));
+		}
+		// Have the probe's GDB server compute the CRCs so the firmware does not have to be read back
+		let mut gdb = gdb_attach(cli_args, "verify", &verify_args.scan, verify_args.target)?;
+		let mismatches = gdb_flash::verify_image_crc(&mut gdb, firmware.data(), load_address, sector_size)?;
+		gdb.detach()?;
+		for mismatch in &mismatches {
+			println!("{mismatch}");
+		}
+		mismatches.len()
+	} else {
+		// Start talking to the probe to use for the operation
+		let remote = open_remote(cli_args, "verify")?;
+
+		// Attach to the target and find the AP to read its memory back through
+		let target = Target::attach_remote(remote)?;
+		let ap = target
+			.memory_ap()
+			.ok_or_eyre("Could not find a memory access port on the target")?;
+
+		let mismatches = verify_image(ap, firmware.data(), load_address)?;
+		for mismatch in &mismatches {
+			println!("{mismatch}");
+		}
+		mismatches.len()
+	};
+
+	if mismatches == 0 {
+		info!("Target Flash matches {}", verify_args.firmware.display());
+		Ok(())
+	} else {
+		Err(eyre!("Verification failed, found {} mismatching regions", mismatches))
+	}
+}
+
+fn coredump_command(cli_args: &CliArguments, coredump_args: &CoredumpArguments) -> Result<()>
+{
+	// Figure out which memory ranges to capture - if none were given, use the firmware's RAM
+	let ranges = if !coredump_args.ranges.is_empty() {
+		coredump_args.ranges.clone()
+	} else if let Some(elf) = &coredump_args.elf {
+		let firmware = FirmwareFile::from_path(elf)?;
+		firmware.writable_regions().to_vec()
+	} else {
+		return Err(eyre!("No memory ranges to capture, please provide either --elf or one or more --range"));
+	};
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "coredump")?;
+
+	// Attach to the target, and capture its state
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	let dump = CoreDump::capture(&core, &ranges)?;
+	dump.write_to(&coredump_args.output)?;
+	info!("Core dump written to {}", coredump_args.output.display());
+	Ok(())
+}
+
+fn fault_command(cli_args: &CliArguments, fault_args: &FaultArguments) -> Result<()>
+{
+	let firmware = fault_args
+		.elf
+		.as_deref()
+		.map(FirmwareFile::from_path)
+		.transpose()?;
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "fault")?;
+
+	// Attach to the target, and pull out its fault state
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	let mut report = FaultReport::capture(&core)?;
+	if let Some(firmware) = &firmware {
+		report.symbolise(firmware);
+	}
+	print!("{report}");
+	Ok(())
+}
+
+/// Turn a location given on the command line into an address, looking it up as a symbol if it's not a number
+fn resolve_location(location: &str, firmware: Option<&FirmwareFile>) -> Result<u32>
+{
+	if let Ok(address) = parse_number(location) {
+		return Ok(u32::try_from(address)?);
+	}
+	let firmware = firmware.ok_or_else(|| eyre!("'{location}' is not an address, and no --elf given to look it up in"))?;
+	firmware
+		.symbol_by_name(location)
+		.map(|symbol| symbol.address)
+		.ok_or_else(|| eyre!("Could not find symbol '{location}' in the firmware"))
+}
+
+/// Let the core run until it halts, and then report why and where it did
+fn wait_for_halt(core: &CortexM, wait_args: &HaltWaitArguments, firmware: Option<&FirmwareFile>) -> Result<()>
+{
+	// Clear any stale halt reasons before letting the core go
+	HaltReason::read(core.ap())?;
+	core.resume()?;
+	info!("Waiting for the core to halt");
+	core.wait_for_halt(wait_args.timeout.map_or(Duration::MAX, Duration::from_secs))?;
+
+	let reason = HaltReason::read(core.ap())?;
+	let pc = core.read_register(15)?;
+	let symbol = firmware
+		.and_then(|firmware| firmware.symbolise(pc))
+		.map(|(symbol, offset)| format!(" <{}+0x{:x}>", symbol.name, offset))
+		.unwrap_or_default();
+	println!("Core halted due to {reason} at 0x{pc:08x}{symbol}");
+	Ok(())
+}
+
+fn break_command(cli_args: &CliArguments, break_args: &BreakArguments) -> Result<()>
+{
+	let firmware = break_args
+		.wait
+		.elf
+		.as_deref()
+		.map(FirmwareFile::from_path)
+		.transpose()?;
+	let address = break_args
+		.location
+		.as_deref()
+		.map(|location| resolve_location(location, firmware.as_ref()))
+		.transpose()?;
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "break")?;
+
+	// Attach to the target and find its breakpoint unit
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	let components = discover_components(core.ap())?;
+	let fpb = Fpb::new(core.ap(), &components)?;
+
+	if break_args.clear {
+		fpb.clear()?;
+		info!("Cleared all breakpoints");
+	} else if break_args.list {
+		let breakpoints = fpb.breakpoints()?;
+		println!("{} of {} breakpoints in use", breakpoints.len(), fpb.comparators());
+		for (comparator, address) in breakpoints {
+			println!("{comparator}: breakpoint at 0x{address:08x}");
+		}
+	} else if let Some(address) = address {
+		// Breakpoints only halt the core if halting debug is on
+		core.enable_debug()?;
+		let comparator = fpb.set_breakpoint(address)?;
+		info!("Breakpoint set at 0x{address:08x} using comparator {comparator}");
+	}
+
+	if break_args.wait.wait {
+		wait_for_halt(&core, &break_args.wait, firmware.as_ref())?;
+	}
+	Ok(())
+}
+
+fn watch_command(cli_args: &CliArguments, watch_args: &WatchArguments) -> Result<()>
+{
+	let firmware = watch_args
+		.wait
+		.elf
+		.as_deref()
+		.map(FirmwareFile::from_path)
+		.transpose()?;
+	let address = watch_args
+		.location
+		.as_deref()
+		.map(|location| resolve_location(location, firmware.as_ref()))
+		.transpose()?;
+	let kind = match (watch_args.read, watch_args.write) {
+		(true, false) => WatchKind::Read,
+		(false, true) => WatchKind::Write,
+		_ => WatchKind::Access,
+	};
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "watch")?;
+
+	// Attach to the target and find its watchpoint unit
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	let components = discover_components(core.ap())?;
+	let dwt = Dwt::new(core.ap(), &components)?;
+
+	if watch_args.clear {
+		dwt.clear()?;
+		info!("Cleared all watchpoints");
+	} else if watch_args.list {
+		let watchpoints = dwt.watchpoints()?;
+		println!("{} of {} watchpoints in use", watchpoints.len(), dwt.comparators());
+		for watchpoint in watchpoints {
+			println!("{watchpoint}");
+		}
+	} else if let Some(address) = address {
+		// Watchpoints only halt the core if halting debug is on
+		core.enable_debug()?;
+		let comparator = dwt.set_watchpoint(address, watch_args.size, kind)?;
+		info!("{kind} watchpoint set at 0x{address:08x} using comparator {comparator}");
+	}
+
+	if watch_args.wait.wait {
+		wait_for_halt(&core, &watch_args.wait, firmware.as_ref())?;
+	}
+	Ok(())
+}
+
+/// Default RAM range to scan for the RTT control block when we have nothing better to go on
+const RTT_DEFAULT_SCAN_RANGE: Range<u32> = 0x20000000..0x20010000;
+
+fn rtt_command(cli_args: &CliArguments, rtt_args: &RttArguments) -> Result<()>
+{
+	let firmware = rtt_args
+		.elf
+		.as_deref()
+		.or(rtt_args.defmt.as_deref())
+		.map(FirmwareFile::from_path)
+		.transpose()?;
+	// If we're decoding defmt, load the format string table up front so we fail early if it's missing
+	let mut defmt = match (&rtt_args.defmt, &firmware) {
+		(Some(_), Some(firmware)) => Some(DefmtDecoder::new(DefmtTable::from_firmware(firmware)?)),
+		_ => None,
+	};
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "rtt")?;
+
+	// Attach to the target and find its memory AP
+	let target = Target::attach_remote(remote)?;
+	let ap = target
+		.memory_ap()
+		.ok_or_eyre("Could not find a memory access port on the target")?;
+
+	// Work out where the control block is - either we were told, it's in the symbol table, or we have to hunt
+	let symbol = firmware
+		.as_ref()
+		.and_then(|firmware| firmware.symbol_by_name("_SEGGER_RTT"));
+	let address = if let Some(address) = rtt_args.address {
+		u32::try_from(address)?
+	} else if let (None, Some(symbol)) = (&rtt_args.scan, symbol) {
+		symbol.address
+	} else {
+		let ranges = match (&rtt_args.scan, &firmware) {
+			(Some(range), _) => vec![range.clone()],
+			(None, Some(firmware)) if !firmware.writable_regions().is_empty() => firmware.writable_regions().to_vec(),
+			_ => vec![RTT_DEFAULT_SCAN_RANGE],
+		};
+		let mut found = None;
+		for range in ranges {
+			found = Rtt::scan(ap, range)?;
+			if found.is_some() {
+				break;
+			}
+		}
+		found.ok_or_eyre("Could not find the RTT control block")?
+	};
+
+	let rtt = Rtt::attach(ap, address)?;
+	let channel = rtt
+		.up_channels()
+		.get(rtt_args.channel)
+		.ok_or_else(|| eyre!("RTT channel {} does not exist", rtt_args.channel))?;
+	info!(
+		"Streaming RTT channel {} ({}) from control block at 0x{:08x}",
+		channel.index,
+		channel.name.as_deref().unwrap_or("unnamed"),
+		rtt.address()
+	);
+	let has_down_channel = rtt.down_channels().len() > rtt_args.channel;
+	if !has_down_channel {
+		warn!("RTT channel {} has no down buffer, terminal input will be ignored", rtt_args.channel);
+	}
+
+	// Read stdin on its own thread so we don't block polling the target
+	let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+	thread::spawn(move || {
+		let mut buffer = [0u8; 256];
+		while let Ok(length @ 1..) = stdin().read(&mut buffer) {
+			if sender.send(buffer[..length].to_vec()).is_err() {
+				break;
+			}
+		}
+	});
+
+	let mut output = Vec::new();
+	let mut input: Vec<u8> = Vec::new();
+	loop {
+		output.clear();
+		let received = rtt.read(rtt_args.channel, &mut output)?;
+		write_rtt_output(&output, defmt.as_mut())?;
+
+		// Forward whatever terminal input we have to the target
+		while let Ok(data) = receiver.try_recv() {
+			if has_down_channel {
+				input.extend(data);
+			}
+		}
+		if !input.is_empty() {
+			let written = rtt.write(rtt_args.channel, &input)?;
+			input.drain(..written);
+		}
+
+		if received == 0 {
+			thread::sleep(Duration::from_millis(10));
+		}
+	}
+}
+
+/// Display data read from an RTT channel, decoding it as defmt frames if we have a decoder for them
+fn write_rtt_output(data: &[u8], defmt: Option<&mut DefmtDecoder>) -> Result<()>
+{
+	if data.is_empty() {
+		return Ok(());
+	}
+	let mut stdout = stdout().lock();
+	if let Some(defmt) = defmt {
+		for frame in defmt.feed(data) {
+			match frame {
+				Ok(frame) => writeln!(stdout, "{frame}")?,
+				Err(error) => warn!("Failed to decode defmt frame: {error}"),
+			}
+		}
+	} else {
+		stdout.write_all(data)?;
+	}
+	stdout.flush()?;
+	Ok(())
+}
+
+fn semihost_command(cli_args: &CliArguments, semihost_args: &SemihostArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "semihost")?;
+
+	// Attach to the target, clear any stale halt reason and let it go
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	HaltReason::read(core.ap())?;
+	core.resume()?;
+
+	let mut semihosting = Semihosting::new(&semihost_args.root);
+	let start = Instant::now();
+	let status = loop {
+		match semihosting.poll(&core)? {
+			Some(TargetStop::Exit(status)) => break status,
+			Some(TargetStop::Halt {
+				reason,
+				pc,
+			}) => return Err(eyre!("Core halted due to {} at 0x{:08x}", reason, pc)),
+			None => {},
+		}
+		if let Some(timeout) = semihost_args.timeout &&
+			start.elapsed() > Duration::from_secs(timeout)
+		{
+			core.halt()?;
+			return Err(eyre!("Target did not exit within {} seconds", timeout));
+		}
+		thread::sleep(Duration::from_millis(1));
+	};
+
+	info!("Target exited with status {}", status);
+	std::process::exit(status);
+}
+
+/// Put the firmware image onto the target with the core held at reset. Images linked for RAM are loaded
+/// and the core pointed at their vector table, while ones linked for Flash are programmed in (if they're
+/// not there already) and the core reset to start from them.
+fn load_image(core: &CortexM, firmware: &FirmwareFile, load_address: u32) -> Result<()>
+{
+	let ap = core.ap();
+	if !CORTEXM_SRAM_REGION.contains(&load_address) {
+		let flash = detect_flash(ap)?.ok_or_else(|| {
+			eyre!("Programming this target's Flash is not supported, please program the firmware onto it first")
+		})?;
+		info!("Programming {} bytes into Flash at 0x{:08x}", firmware.len(), load_address);
+		program_image(flash.as_ref(), ap, firmware.data(), load_address as u64)?;
+		if !verify_image(ap, firmware.data(), load_address as u64)?.is_empty() {
+			return Err(eyre!("Target Flash does not match the firmware after programming it"));
+		}
+		// Reset again so the core starts from the reset vector of the new image
+		return core.reset_halt();
+	}
+
+	info!("Loading {} bytes into RAM at 0x{:08x}", firmware.len(), load_address);
+	let data = firmware.data();
+	if data.len() < 8 {
+		return Err(eyre!("Firmware is too small to contain a vector table"));
+	}
+	ap.mem_write(load_address as u64, data)?;
+	// The image starts with its vector table, giving the initial stack pointer and the reset vector
+	let stack_pointer = u32::from_le_bytes(data[0..4].try_into()?);
+	let reset_vector = u32::from_le_bytes(data[4..8].try_into()?);
+	ap.mem_write_u32(CORTEXM_VTOR, load_address)?;
+	core.write_register(CORTEXM_REG_MSP, stack_pointer)?;
+	core.write_register(CORTEXM_REG_SP, stack_pointer)?;
+	core.write_register(CORTEXM_REG_PC, reset_vector & !1)?;
+	core.write_register(CORTEXM_REG_XPSR, CORTEXM_XPSR_THUMB)
+}
+
+fn run_command(cli_args: &CliArguments, run_args: &RunArguments) -> Result<()>
+{
+	let firmware = FirmwareFile::from_path(&run_args.firmware)?;
+	let load_address = firmware
+		.load_address()
+		.ok_or_eyre("Firmware file does not specify a load address, please use an ELF file")?;
+	let mut defmt = if firmware.defmt_symbols().is_empty() {
+		None
+	} else {
+		Some(DefmtDecoder::new(DefmtTable::from_firmware(&firmware)?))
+	};
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "run")?;
+
+	// Attach to the target, reset it and get the firmware in place
+	let target = Target::attach_remote(remote)?;
+	let core = target.cortexm()?;
+	let ap = core.ap();
+	core.reset_halt()?;
+	load_image(&core, &firmware, load_address)?;
+	core.catch_hard_faults()?;
+
+	// Wipe any stale RTT control block so we only pick it up once the firmware has set it up afresh
+	let rtt_address = firmware.symbol_by_name("_SEGGER_RTT").map(|symbol| symbol.address);
+	if let Some(address) = rtt_address {
+		ap.mem_write(address as u64, &[0u8; 16])?;
+	}
+
+	info!("Running {}", run_args.firmware.display());
+	HaltReason::read(ap)?;
+	core.resume()?;
+
+	let mut semihosting = Semihosting::new(&run_args.root);
+	let mut rtt = None;
+	let mut output = Vec::new();
+	let start = Instant::now();
+	let status = loop {
+		if let (None, Some(address)) = (&rtt, rtt_address) {
+			rtt = Rtt::attach(ap, address).ok();
+		}
+		output.clear();
+		if let Some(rtt) = &rtt {
+			rtt.read(0, &mut output)?;
+		}
+		write_rtt_output(&output, defmt.as_mut())?;
+
+		match semihosting.poll(&core)? {
+			Some(TargetStop::Exit(status)) => break status,
+			// By convention, a plain breakpoint means the firmware finished successfully
+			Some(TargetStop::Halt {
+				reason,
+				pc,
+			}) if reason.is_breakpoint() => {
+				info!("Target stopped on a breakpoint at 0x{:08x}", pc);
+				break 0;
+			},
+			Some(TargetStop::Halt {
+				reason,
+				pc,
+			}) => {
+				error!("Target halted due to {} at 0x{:08x}", reason, pc);
+				let mut report = FaultReport::capture(&core)?;
+				report.symbolise(&firmware);
+				print!("{report}");
+				break 1;
+			},
+			None => {},
+		}
+
+		if let Some(timeout) = run_args.timeout &&
+			start.elapsed() > Duration::from_secs(timeout)
+		{
+			core.halt()?;
+			return Err(eyre!("Target did not exit within {} seconds", timeout));
+		}
+		if output.is_empty() {
+			thread::sleep(Duration::from_millis(10));
+		}
+	};
+
+	// Make sure we've shown everything the target logged before it stopped
+	if let Some(rtt) = &rtt {
+		output.clear();
+		rtt.read(0, &mut output)?;
+		write_rtt_output(&output, defmt.as_mut())?;
+	}
+	info!("Target exited with status {}", status);
+	std::process::exit(status);
+}
+
+/// Display decoded trace packets - stimulus port data goes out as-is, everything else gets a line of its own
+fn write_itm_packets(packets: Vec<ItmPacket>) -> Result<()>
+{
+	let mut stdout = stdout().lock();
+	for packet in packets {
+		match packet {
+			ItmPacket::Sync => {},
+			ItmPacket::Instrumentation {
+				data,
+				..
+			} => stdout.write_all(&data)?,
+			packet => writeln!(stdout, "[{packet}]")?,
+		}
+	}
+	stdout.flush()?;
+	Ok(())
+}
+
+fn swo_command(cli_args: &CliArguments, swo_args: &SwoArguments) -> Result<()>
+{
+	let mut decoder = ItmDecoder::new();
+	if let Some(input) = &swo_args.input {
+		let data = std::fs::read(input)?;
+		return write_itm_packets(decoder.feed(&data));
+	}
+
+	// Find the probe to use for the operation
+	require_local_probe(cli_args, "swo")?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let device = results.pop_single("swo").map_err(|kind| kind.error())?;
+
+	// Have the probe start capturing trace, in the same mode the target is to send it in. The GDB session
+	// shares its interface with the remote protocol, so it has to be closed again before attaching below
+	let baud_rate = u32::try_from(swo_args.baud)?;
+	{
+		let mut gdb = device.gdb_serial_interface()?;
+		gdb.negotiate()?;
+		gdb.start_traceswo(match swo_args.mode {
+			SwoMode::Manchester => None,
+			SwoMode::Uart => Some(baud_rate),
+		})?;
+	}
+
+	// If we've been told the trace clock, set the target up to generate trace
+	if let Some(clock) = swo_args.clock {
+		let target = Target::attach(&device)?;
+		let ap = target
+			.memory_ap()
+			.ok_or_eyre("Could not find a memory access port on the target")?;
+		let config = SwoConfig {
+			mode: swo_args.mode,
+			baud_rate,
+			trace_clock: u32::try_from(clock)?,
+			stimulus_ports: u32::try_from(swo_args.ports)?,
+			pc_sampling: swo_args.pc_sampling,
+			exception_trace: swo_args.exceptions,
+			event_counters: swo_args.counters,
+			timestamps: swo_args.timestamps,
+		};
+		configure_swo(ap, &config)?;
+	}
+
+	let mut output = swo_args.output.as_deref().map(File::create).transpose()?;
+	let mut capture = SwoCapture::open(&device)?;
+	loop {
+		let data = capture.read()?;
+		if let Some(output) = &mut output {
+			output.write_all(&data)?;
+		}
+		write_itm_packets(decoder.feed(&data))?;
+	}
+}
+
+fn profile_command(cli_args: &CliArguments, profile_args: &ProfileArguments) -> Result<()>
+{
+	let firmware = profile_args
+		.elf
+		.as_deref()
+		.map(FirmwareFile::from_path)
+		.transpose()?;
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "profile")?;
+
+	// Attach to the target and find its DWT to sample the PC through
+	let target = Target::attach_remote(remote)?;
+	let ap = target
+		.memory_ap()
+		.ok_or_eyre("Could not find a memory access port on the target")?;
+	let components = discover_components(ap)?;
+	let dwt = Dwt::new(ap, &components)?;
+	let profile = Profile::capture(&dwt, profile_args.duration)?;
+
+	if let Some(folded) = &profile_args.folded {
+		let mut file = File::create(folded)?;
+		profile.write_folded(firmware.as_ref(), &mut file)?;
+		info!("Folded stacks written to {}", folded.display());
+	}
+
+	println!("{profile}");
+	println!("{:>10} {:>7}  function", "samples", "%");
+	let total = profile.total() as f64;
+	for function in profile.by_function(firmware.as_ref()).iter().take(profile_args.top) {
+		println!(
+			"{:>10} {:>6.2}%  {}",
+			function.samples,
+			function.samples as f64 * 100.0 / total,
+			function.name
+		);
+	}
+	Ok(())
+}
+
+fn watch_var_command(cli_args: &CliArguments, watch_args: &WatchVarArguments) -> Result<()>
+{
+	// Look all the variables up before we go near the probe
+	let firmware = FirmwareFile::from_path(&watch_args.elf)?;
+	let variables = watch_args
+		.symbols
+		.iter()
+		.map(|symbol| Variable::from_symbol(&firmware, symbol))
+		.collect::<Result<Vec<_>>>()?;
+	let log = watch_args.csv.as_deref().map(File::create).transpose()?;
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "watch-var")?;
+
+	// Attach to the target and read the variables through its memory AP while it runs
+	let target = Target::attach_remote(remote)?;
+	let ap = target
+		.memory_ap()
+		.ok_or_eyre("Could not find a memory access port on the target")?;
+	WatchViewer::display(ap, variables, watch_args.interval, log)
+}
+
+fn peripheral_command(cli_args: &CliArguments, peripheral_args: &PeripheralArguments) -> Result<()>
+{
+	let svd = Svd::from_path(&peripheral_args.svd)?;
+	// Work out what we're looking at before going near the probe
+	let (peripheral, register) = match peripheral_args.path.split_once('.') {
+		Some(_) => {
+			let (peripheral, register) = svd.register(&peripheral_args.path)?;
+			(peripheral, Some(register))
+		},
+		None => (svd.peripheral(&peripheral_args.path)?, None),
+	};
+	if register.is_none() && !peripheral_args.set.is_empty() {
+		return Err(eyre!("Fields can only be set on a register, not a whole peripheral"));
+	}
+
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "peripheral")?;
+
+	// Attach to the target and find its memory AP
+	let target = Target::attach_remote(remote)?;
+	let ap = target
+		.memory_ap()
+		.ok_or_eyre("Could not find a memory access port on the target")?;
+
+	let Some(register) = register else {
+		// Display all the registers of the peripheral that can be read, leaving out those where reading has
+		// side effects - those have to be asked for by name
+		for register in peripheral.registers.iter().filter(|register| register.access.is_readable()) {
+			if register.read_action {
+				info!(
+					"Skipping {}.{} as reading it has side effects, name it explicitly to read it",
+					peripheral.name, register.name
+				);
+				continue;
+			}
+			print!("{}", peripheral.decode(ap, register)?);
+		}
+		return Ok(());
+	};
+
+	if !peripheral_args.set.is_empty() {
+		// Read-modify-write the register, starting from its reset value for write-only registers
+		let value = if register.access.is_readable() {
+			peripheral.read(ap, register)?
+		} else {
+			register.reset_value
+		};
+		let value = register.apply_fields(value, &peripheral_args.set)?;
+		peripheral.write(ap, register, value)?;
+		info!("Wrote 0x{:x} to {}.{}", value, peripheral.name, register.name);
+	}
+	if register.access.is_readable() {
+		print!("{}", peripheral.decode(ap, register)?);
+	}
+	Ok(())
+}
+
+fn halt_command(cli_args: &CliArguments, core_args: &CoreArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "halt")?;
+
+	let pc = if core_args.riscv {
+		let target = RiscvJtagTarget::attach_remote(remote)?;
+		let debug_module = target.debug_module()?;
+		debug_module.halt(core_args.hart)?;
+		debug_module.read_csr(core_args.hart, RISCV_CSR_DPC)?
+	} else {
+		let target = Target::attach_remote(remote)?;
+		let core = target.cortexm()?;
+		core.halt()?;
+		core.read_register(CORTEXM_REG_PC)? as u64
+	};
+	info!("Core halted at 0x{:08x}", pc);
+	Ok(())
+}
+
+fn resume_command(cli_args: &CliArguments, core_args: &CoreArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "resume")?;
+
+	if core_args.riscv {
+		let target = RiscvJtagTarget::attach_remote(remote)?;
+		target.debug_module()?.resume(core_args.hart)?;
+	} else {
+		let target = Target::attach_remote(remote)?;
+		target.cortexm()?.resume()?;
+	}
+	info!("Core resumed");
+	Ok(())
+}
+
+fn regs_command(cli_args: &CliArguments, core_args: &CoreArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "regs")?;
+
+	// Registers can only be read while the core is halted, so halt it for the duration if needed
+	if core_args.riscv {
+		let target = RiscvJtagTarget::attach_remote(remote)?;
+		let debug_module = target.debug_module()?;
+		let was_running = !debug_module.is_halted(core_args.hart)?;
+		if was_running {
+			debug_module.halt(core_args.hart)?;
+		}
+		let registers = debug_module.read_registers(core_args.hart)?;
+		if was_running {
+			debug_module.resume(core_args.hart)?;
+		}
+		for (name, value) in RISCV_GPR_NAMES.iter().chain(&["pc"]).zip(registers) {
+			println!("{:>7}: 0x{:08x}", name, value);
+		}
+	} else {
+		let target = Target::attach_remote(remote)?;
+		let core = target.cortexm()?;
+		let was_running = !core.is_halted()?;
+		if was_running {
+			core.halt()?;
+		}
+		let registers = core.read_core_registers()?;
+		if was_running {
+			core.resume()?;
+		}
+		for (name, value) in CORTEXM_CORE_REGISTER_NAMES.iter().zip(registers) {
+			println!("{:>7}: 0x{:08x}", name, value);
+		}
+	}
+	Ok(())
+}
+
+/// How much target memory to read at a time, so large reads are streamed out rather than held in memory
+const MEMORY_READ_CHUNK_SIZE: usize = 4096;
+
+fn read_command(cli_args: &CliArguments, read_args: &ReadArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "read")?;
+
+	if read_args.core.riscv {
+		let target = RiscvJtagTarget::attach_remote(remote)?;
+		let debug_module = target.debug_module()?;
+		read_memory_chunked(read_args, |data, address| {
+			debug_module.read_memory(read_args.core.hart, data, address)
+		})
+	} else {
+		let target = Target::attach_remote(remote)?;
+		let ap = target
+			.memory_ap()
+			.ok_or_eyre("Could not find a memory access port on the target")?;
+		read_memory_chunked(read_args, |data, address| ap.mem_read(data, address))
+	}
+}
+
+/// Read the memory asked for a chunk at a time using `read`, writing each chunk out to the output file or
+/// hex dump as it arrives
+fn read_memory_chunked<F>(read_args: &ReadArguments, mut read: F) -> Result<()>
+where
+	F: FnMut(&mut [u8], u64) -> Result<()>,
+{
+	let end = read_args
+		.address
+		.checked_add(read_args.length)
+		.ok_or_eyre("Read extends past the end of the address space")?;
+	let mut output = read_args.output.as_deref().map(File::create).transpose()?;
+	let mut buffer = vec![0u8; MEMORY_READ_CHUNK_SIZE];
+	for address in (read_args.address..end).step_by(MEMORY_READ_CHUNK_SIZE) {
+		let data = &mut buffer[..(end - address).min(MEMORY_READ_CHUNK_SIZE as u64) as usize];
+		read(data, address)?;
+		match &mut output {
+			Some(output) => output.write_all(data)?,
+			None => print_hex_dump(address, data),
+		}
+	}
+	if let Some(output) = &read_args.output {
+		info!("Wrote {} bytes to {}", read_args.length, output.display());
+	}
+	Ok(())
+}
+
+/// Print a block of memory read from the target as a classic 16 bytes per line hex dump
+fn print_hex_dump(address: u64, data: &[u8])
+{
+	for (index, line) in data.chunks(16).enumerate() {
+		let hex: Vec<_> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+		let text: String = line
+			.iter()
+			.map(|&byte| {
+				if byte.is_ascii_graphic() || byte == b' ' {
+					byte as char
+				} else {
+					'.'
+				}
+			})
+			.collect();
+		println!("{:08x}: {:<47}  |{}|", address + index as u64 * 16, hex.join(" "), text);
+	}
+}
+
+fn jtag_scan_command(cli_args: &CliArguments) -> Result<()>
+{
+	// Start talking to the probe to use for the operation
+	let remote = open_remote(cli_args, "JTAG scan")?;
+
+	let target = RiscvJtagTarget::attach_remote(remote)?;
+	println!("Found {} devices on the JTAG chain:", target.devices().len());
+	for (index, device) in target.devices().iter().enumerate() {
+		let part = riscv_jtag_part(device.idcode()).unwrap_or("");
+		println!(
+			"{:3}: IDCODE 0x{:08x}, IR length {:2} {}",
+			index,
+			device.idcode(),
+			device.ir_len(),
+			part
+		);
+	}
+	for dmi in target.dmis() {
+		println!(
+			"RISC-V DTM on device {}: debug spec {}, {} DMI address bits, {} idle cycles",
+			dmi.dev_index(),
+			dmi.version(),
+			dmi.address_width(),
+			dmi.idle_cycles()
+		);
+	}
+	Ok(())
+}
+
+fn gdb_server_command(cli_args: &CliArguments, server_args: &GdbServerArguments) -> Result<()>
+{
+	if server_args.host_side {
+		// Attach to the target ourselves and serve GDB clients from here
+		let target = Target::attach_remote(open_remote(cli_args, "GDB server")?)?;
+		let listener = TcpListener::bind(server_args.listen)
+			.wrap_err_with(|| eyre!("Failed to listen for GDB clients on {}", server_args.listen))?;
+		info!("Listening for GDB clients on {}", listener.local_addr()?);
+		return GdbHostServer::new(target).serve(listener);
+	}
+
+	require_local_probe(cli_args, "GDB server")?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single("GDB server").map_err(|kind| kind.error())?;
+
+	let gdb = probe.gdb_serial_interface()?;
+	let listener = TcpListener::bind(server_args.listen)
+		.wrap_err_with(|| eyre!("Failed to listen for GDB clients on {}", server_args.listen))?;
+	info!("Listening for GDB clients on {}", listener.local_addr()?);
+	GdbProxy::new(gdb.into_inner()).serve(listener)
+}
+
+fn remote_server_command(cli_args: &CliArguments, server_args: &RemoteServerArguments) -> Result<()>
+{
+	require_local_probe(cli_args, "remote server")?;
+	// Set up a bridge for each probe, so problems finding any of them are reported before serving starts
+	let mut bridges = Vec::with_capacity(server_args.listen.len());
+	for (serial, address) in &server_args.listen {
+		let matcher = match serial {
+			Some(serial) => BmpMatcher::new().serial(serial.as_str()),
+			None => BmpMatcher::from_params(cli_args),
+		};
+		let mut results = matcher.find_matching_probes();
+		let probe = results.pop_single("remote server").map_err(|kind| kind.error())?;
+		let name = probe.serial_number()?.to_string();
+
+		let interface = probe.bmd_serial_interface()?;
+		let listener = TcpListener::bind(address)
+			.wrap_err_with(|| eyre!("Failed to listen for clients on {}", address))?;
+		info!("Serving probe {} on {}", name, listener.local_addr()?);
+		bridges.push((RemoteBridge::new(name, interface.into_transport()), listener));
+	}
+
+	let servers: Vec<_> = bridges
+		.into_iter()
+		.map(|(bridge, listener)| thread::spawn(move || bridge.serve(listener)))
+		.collect();
+	for server in servers {
+		server
+			.join()
+			.map_err(|_| eyre!("Remote protocol server thread panicked"))??;
+	}
+	Ok(())
+}
+
+fn monitor_command(cli_args: &CliArguments, monitor_args: &MonitorArguments) -> Result<()>
+{
+	require_local_probe(cli_args, "monitor")?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single("monitor").map_err(|kind| kind.error())?;
+
+	let mut gdb = probe.gdb_serial_interface()?;
+	gdb.negotiate()?;
+	// Stream the command's output as the probe produces it, as some commands (such as scans) take a while
+	let mut stdout = stdout().lock();
+	gdb.monitor(&monitor_args.command.join(" "), |output| {
+		let _ = stdout.write_all(output.as_bytes());
+		let _ = stdout.flush();
+	})
+}
+
+fn scan_protocol(scan_args: &ScanArguments) -> ScanProtocol
+{
+	if scan_args.jtag {
+		ScanProtocol::Jtag
+	} else {
+		ScanProtocol::Swd
+	}
+}
+
+fn debug_scan_command(cli_args: &CliArguments, scan_args: &ScanArguments) -> Result<()>
+{
+	require_local_probe(cli_args, "scan")?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single("scan").map_err(|kind| kind.error())?;
+
+	let mut gdb = probe.gdb_serial_interface()?;
+	gdb.negotiate()?;
+	let targets = gdb.scan_targets(scan_protocol(scan_args))?;
+	println!("No. Att Driver");
+	for target in targets {
+		let attached = if target.attached {
+			'*'
+		} else {
+			' '
+		};
+		println!("{:3}  {}  {}", target.index, attached, target.driver);
+	}
+	Ok(())
+}
+
+/// Connect to the probe's GDB server, scan for targets and attach to the requested one
+fn gdb_attach(
+	cli_args: &CliArguments,
+	operation: &str,
+	scan_args: &ScanArguments,
+	target_index: u32,
+) -> Result<GdbRspInterface>
+{
+	require_local_probe(cli_args, operation)?;
+	let matcher = BmpMatcher::from_params(cli_args);
+	let mut results = matcher.find_matching_probes();
+	let probe = results.pop_single(operation).map_err(|kind| kind.error())?;
+
+	let mut gdb = probe.gdb_serial_interface()?;
+	gdb.negotiate()?;
+	// Scan first so the firmware's target list is fresh and the requested target is known to exist
+	let targets = gdb.scan_targets(scan_protocol(scan_args))?;
+	let target = targets
+		.iter()
+		.find(|target| target.index == target_index as usize)
+		.ok_or_else(|| eyre!("No target {} found, the scan found {} targets", target_index, targets.len()))?;
+
+	let stop_reply = gdb.attach(target.index)?;
+	info!("Attached to target {} ({}), {}", target.index, target.driver, stop_reply);
+	Ok(gdb)
+}
+
+fn debug_attach_command(cli_args: &CliArguments, attach_args: &AttachArguments) -> Result<()>
+{
+	gdb_attach(cli_args, "attach", &attach_args.scan, attach_args.target)?;
+	Ok(())
+}
+
+fn debug_load_command(cli_args: &CliArguments, load_args: &LoadArguments) -> Result<()>
+{
+	let firmware = FirmwareFile::from_path(&load_args.firmware)?;
+	let load_address = load_args
+		.address
+		.or_else(|| firmware.load_address().map(u64::from))
+		.ok_or_eyre("Firmware file does not specify a load address, please provide one with --address")?;
+
+	let mut gdb = gdb_attach(cli_args, "load", &load_args.scan, load_args.target)?;
+	gdb_flash::load_image(&mut gdb, firmware.data(), load_address)?;
+	info!("Loaded {} onto the target", load_args.firmware.display());
+	// Let the target go so it can run the new firmware
+	gdb.detach()
+}
+
+fn debug_describe_command(cli_args: &CliArguments, describe_args: &DescribeArguments) -> Result<()>
+{
+	let mut gdb = gdb_attach(cli_args, "describe", &describe_args.scan, describe_args.target)?;
+	let description = gdb.target_description()?;
+	let memory_map = gdb.memory_map()?;
+	gdb.detach()?;
+
+	if let Some(architecture) = &description.architecture {
+		println!("Architecture: {}", architecture);
+	}
+	println!("Registers:");
+	for register in &description.registers {
+		println!(
+			"{:4}: {:12} {:3} bits  {:12} {:10} {}",
+			register.number,
+			register.name,
+			register.bitsize,
+			register.kind,
+			register.group.as_deref().unwrap_or("-"),
+			register.feature
+		);
+	}
+	println!("Memory map:");
+	for region in &memory_map.regions {
+		println!("  {}", region);
+	}
+	Ok(())
+}
+
 fn info_command(cli_args: &CliArguments, info_args: &InfoArguments) -> Result<()>
 {
+	require_local_probe(cli_args, "info")?;
 	// Try and identify all the probes on the system that are allowed by the invocation
 	let matcher = BmpMatcher::from_params(cli_args);
 	let mut results = matcher.find_matching_probes();
@@ -666,6 +2335,7 @@ fn main() -> Result<()>
 			},
 			ProbeCommmands::Switch(_) => bmputil::switcher::switch_firmware(&cli_args, &paths),
 			ProbeCommmands::Reboot(reboot_args) => reboot_command(&cli_args, reboot_args),
+			ProbeCommmands::Uart(uart_args) => uart_command(&cli_args, uart_args),
 			#[cfg(windows)]
 			ProbeCommmands::InstallDrivers(driver_args) => {
 				windows::ensure_access(
@@ -678,14 +2348,35 @@ fn main() -> Result<()>
 		},
 		ToplevelCommmands::Target(command) => match command {
 			TargetCommmands::Power => power_command(&cli_args),
+			TargetCommmands::Erase(erase_args) => erase_command(&cli_args, erase_args),
+			TargetCommmands::Verify(verify_args) => verify_command(&cli_args, verify_args),
+			TargetCommmands::Coredump(coredump_args) => coredump_command(&cli_args, coredump_args),
+			TargetCommmands::Fault(fault_args) => fault_command(&cli_args, fault_args),
+			TargetCommmands::Break(break_args) => break_command(&cli_args, break_args),
+			TargetCommmands::Watch(watch_args) => watch_command(&cli_args, watch_args),
+			TargetCommmands::Rtt(rtt_args) => rtt_command(&cli_args, rtt_args),
+			TargetCommmands::Semihost(semihost_args) => semihost_command(&cli_args, semihost_args),
+			TargetCommmands::Run(run_args) => run_command(&cli_args, run_args),
+			TargetCommmands::Swo(swo_args) => swo_command(&cli_args, swo_args),
+			TargetCommmands::Profile(profile_args) => profile_command(&cli_args, profile_args),
+			TargetCommmands::WatchVar(watch_args) => watch_var_command(&cli_args, watch_args),
+			TargetCommmands::Peripheral(peripheral_args) => peripheral_command(&cli_args, peripheral_args),
+			TargetCommmands::Halt(core_args) => halt_command(&cli_args, core_args),
+			TargetCommmands::Resume(core_args) => resume_command(&cli_args, core_args),
+			TargetCommmands::Regs(core_args) => regs_command(&cli_args, core_args),
+			TargetCommmands::Read(read_args) => read_command(&cli_args, read_args),
+			TargetCommmands::JtagScan => jtag_scan_command(&cli_args),
 		},
-		ToplevelCommmands::Server => {
-			warn!("Command space reserved for future tool version");
-			Ok(())
+		ToplevelCommmands::Server(command) => match command {
+			ServerCommands::Gdb(server_args) => gdb_server_command(&cli_args, server_args),
+			ServerCommands::Remote(server_args) => remote_server_command(&cli_args, server_args),
 		},
-		ToplevelCommmands::Debug => {
-			warn!("Command space reserved for future tool version");
-			Ok(())
+		ToplevelCommmands::Debug(command) => match command {
+			DebugCommands::Monitor(monitor_args) => monitor_command(&cli_args, monitor_args),
+			DebugCommands::Scan(scan_args) => debug_scan_command(&cli_args, scan_args),
+			DebugCommands::Attach(attach_args) => debug_attach_command(&cli_args, attach_args),
+			DebugCommands::Load(load_args) => debug_load_command(&cli_args, load_args),
+			DebugCommands::Describe(describe_args) => debug_describe_command(&cli_args, describe_args),
 		},
 		ToplevelCommmands::Complete(comp_args) => {
 			let mut cmd = CliArguments::command();
@@ -694,3 +2385,22 @@ fn main() -> Result<()>
 		},
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn remote_listen_parsing()
+	{
+		let address: SocketAddr = "0.0.0.0:2000".parse().unwrap();
+		assert_eq!(parse_remote_listen("0.0.0.0:2000").unwrap(), (None, address));
+		assert_eq!(
+			parse_remote_listen("7BB180B4=0.0.0.0:2000").unwrap(),
+			(Some("7BB180B4".into()), address)
+		);
+		assert!(parse_remote_listen("7BB180B4=0.0.0.0").is_err());
+		assert!(parse_remote_listen("2000").is_err());
+	}
+}
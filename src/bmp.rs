@@ -26,6 +26,7 @@ use crate::probe_identity::ProbeIdentity;
 use crate::serial::bmd_rsp::BmdRspInterface;
 use crate::serial::gdb_rsp::GdbRspInterface;
 use crate::serial::interface::ProbeInterface;
+use crate::serial::uart::UartInterface;
 use crate::usb::{
 	DfuFunctionalDescriptor, DfuOperatingMode, DfuRequest, GenericDescriptorRef, InterfaceClass, InterfaceSubClass,
 	Pid, PortId, Vid,
@@ -593,6 +594,13 @@ impl BmpDevice
 		let serial_interface = ProbeInterface::from_device(self)?;
 		serial_interface.bmd_interface()
 	}
+
+	/// Locate and return the auxiliary UART interface of the probe, set up to run at the given baud rate
+	pub fn uart_serial_interface(&self, baud_rate: u32) -> Result<UartInterface>
+	{
+		let serial_interface = ProbeInterface::from_device(self)?;
+		serial_interface.uart_interface(baud_rate)
+	}
 }
 
 impl Debug for BmpDevice
@@ -12,15 +12,19 @@ use color_eyre::eyre::{Report, Result, eyre};
 use goblin::container::Endian;
 use goblin::elf::Elf;
 use goblin::elf::header::{EI_CLASS, ELFCLASS32, EM_ARM, ET_EXEC};
-use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::program_header::{PF_W, PT_LOAD};
+use goblin::elf::sym::{STT_FUNC, STT_OBJECT};
 use log::debug;
 
-use super::FirmwareStorage;
+use super::{FirmwareStorage, Symbol};
 
 pub struct ELFFirmwareFile
 {
 	contents: Box<[u8]>,
 	segments: BTreeMap<u32, Range<usize>>,
+	writable_regions: Vec<Range<u32>>,
+	symbols: Vec<Symbol>,
+	defmt_symbols: Vec<Symbol>,
 	firmware_image: Box<[u8]>,
 }
 
@@ -61,10 +65,70 @@ impl TryFrom<File> for ELFFirmwareFile
 			.collect::<BTreeMap<_, _>>();
 		debug!("Consuming {} segments from file", segments.len());
 
+		// Extract where all the writable (RAM) segments live at runtime
+		let writable_regions = elf
+			.program_headers
+			.iter()
+			.flat_map(|header| {
+				(header.p_type == PT_LOAD && header.p_flags & PF_W != 0 && header.p_memsz != 0)
+					.then_some((header.p_vaddr as u32)..((header.p_vaddr + header.p_memsz) as u32))
+			})
+			.collect();
+
+		// Extract the function and data symbols so addresses can be looked up later
+		let mut symbols = elf
+			.syms
+			.iter()
+			.flat_map(|symbol| {
+				let kind = symbol.st_type();
+				if (kind != STT_FUNC && kind != STT_OBJECT) || symbol.st_value == 0 {
+					return None;
+				}
+				let name = elf.strtab.get_at(symbol.st_name)?;
+				// Function symbols have the Thumb bit set, which is not part of their address
+				let address = if kind == STT_FUNC {
+					symbol.st_value & !1
+				} else {
+					symbol.st_value
+				};
+				Some(Symbol {
+					name: name.to_string(),
+					address: address as u32,
+					size: symbol.st_size as u32,
+				})
+			})
+			.collect::<Vec<_>>();
+		symbols.sort_by_key(|symbol| symbol.address);
+		debug!("Found {} symbols in file", symbols.len());
+
+		// Extract the defmt format string table, which lives as symbols in the .defmt sections
+		let defmt_symbols = elf
+			.syms
+			.iter()
+			.flat_map(|symbol| {
+				let section = elf.section_headers.get(symbol.st_shndx)?;
+				let section_name = elf.shdr_strtab.get_at(section.sh_name)?;
+				if !section_name.starts_with(".defmt") {
+					return None;
+				}
+				Some(Symbol {
+					name: elf.strtab.get_at(symbol.st_name)?.to_string(),
+					address: symbol.st_value as u32,
+					size: symbol.st_size as u32,
+				})
+			})
+			.collect::<Vec<_>>();
+		if !defmt_symbols.is_empty() {
+			debug!("Found {} defmt symbols in file", defmt_symbols.len());
+		}
+
 		// Make one of ourself
 		let mut result = Self {
 			contents,
 			segments,
+			writable_regions,
+			symbols,
+			defmt_symbols,
 			firmware_image: Box::default(),
 		};
 		// Use the data to make the firmware image
@@ -135,4 +199,19 @@ impl FirmwareStorage for ELFFirmwareFile
 	{
 		&self.firmware_image
 	}
+
+	fn writable_regions(&self) -> &[Range<u32>]
+	{
+		&self.writable_regions
+	}
+
+	fn symbols(&self) -> &[Symbol]
+	{
+		&self.symbols
+	}
+
+	fn defmt_symbols(&self) -> &[Symbol]
+	{
+		&self.defmt_symbols
+	}
 }
@@ -4,6 +4,7 @@
 
 use std::fs::File;
 use std::io::{Read, Seek};
+use std::ops::Range;
 use std::path::Path;
 
 use color_eyre::eyre::{Context, Result, eyre};
@@ -21,6 +22,33 @@ trait FirmwareStorage
 {
 	fn load_address(&self) -> Option<u32>;
 	fn firmware_data(&self) -> &[u8];
+
+	/// Regions of memory the firmware expects to be able to write to at runtime, if known
+	fn writable_regions(&self) -> &[Range<u32>]
+	{
+		&[]
+	}
+
+	/// Function and data symbols the file defines, sorted by address, if known
+	fn symbols(&self) -> &[Symbol]
+	{
+		&[]
+	}
+
+	/// Symbols from the defmt format string table, if the file has one
+	fn defmt_symbols(&self) -> &[Symbol]
+	{
+		&[]
+	}
+}
+
+/// A function or data symbol from a firmware file
+#[derive(Clone, Debug)]
+pub struct Symbol
+{
+	pub name: String,
+	pub address: u32,
+	pub size: u32,
 }
 
 pub struct FirmwareFile
@@ -72,4 +100,49 @@ impl FirmwareFile
 	{
 		self.inner.firmware_data().len() as u32
 	}
+
+	/// Provides the regions of memory (RAM) the firmware writes to at runtime, where the file format
+	/// records them (only ELF files do)
+	pub fn writable_regions(&self) -> &[Range<u32>]
+	{
+		self.inner.writable_regions()
+	}
+
+	/// Provides the function and data symbols the file defines (only ELF files have any), sorted by address
+	pub fn symbols(&self) -> &[Symbol]
+	{
+		self.inner.symbols()
+	}
+
+	/// Provides the symbols making up the defmt format string table (only ELF files built with defmt have any),
+	/// where each symbol's name is the JSON description of a format string and its address is its index
+	pub fn defmt_symbols(&self) -> &[Symbol]
+	{
+		self.inner.defmt_symbols()
+	}
+
+	/// Look up a symbol by name
+	pub fn symbol_by_name(&self, name: &str) -> Option<&Symbol>
+	{
+		self.symbols().iter().find(|symbol| symbol.name == name)
+	}
+
+	/// Find the symbol covering an address, returning it and how far into it the address is
+	pub fn symbolise(&self, address: u32) -> Option<(&Symbol, u32)>
+	{
+		// Find the last symbol starting at or before the address
+		let symbols = self.symbols();
+		let index = symbols.partition_point(|symbol| symbol.address <= address);
+		let symbol = symbols[..index]
+			.iter()
+			.rev()
+			.find(|symbol| address < symbol.address + symbol.size.max(1))?;
+		Some((symbol, address - symbol.address))
+	}
+
+	/// Whether the firmware image this file holds is empty
+	pub fn is_empty(&self) -> bool
+	{
+		self.inner.firmware_data().is_empty()
+	}
 }
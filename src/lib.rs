@@ -13,15 +13,18 @@ pub mod bmp;
 mod bmp_matcher;
 pub mod docs_viewer;
 pub mod error;
-mod firmware_file;
+pub mod firmware_file;
 pub mod firmware_selector;
 pub mod firmware_type;
 pub mod flasher;
 pub mod metadata;
 pub mod probe_identity;
 pub mod serial;
+pub mod server;
 pub mod switcher;
+pub mod target;
 pub mod usb;
+pub mod watch_viewer;
 #[cfg(windows)]
 pub mod windows;
 
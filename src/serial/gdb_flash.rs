@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Loading firmware onto a target through a GDB server, the same way GDB's `load` command does it
+
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, info};
+
+use crate::serial::gdb_rsp::GdbRspInterface;
+use crate::serial::gdb_target::{MemoryKind, MemoryMap};
+use crate::target::verify::{SectorMismatch, crc32, progress_bar, verify_sectors};
+
+/// A piece of a firmware image that falls entirely within one region of the target's memory map
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LoadSegment
+{
+	/// Where the segment gets written to on the target
+	address: u64,
+	/// Which part of the image the segment consists of
+	image_range: Range<usize>,
+	/// The range of Flash that must be erased (a whole number of blocks) before writing the segment,
+	/// or None if the segment is going into RAM
+	erase: Option<Range<u64>>,
+}
+
+/// Work out how to split up an image that is to be loaded at `load_address`, so that each piece
+/// lands in a single memory region and Flash pieces get erased in whole blocks
+fn plan_load(memory_map: &MemoryMap, load_address: u64, length: usize) -> Result<Vec<LoadSegment>>
+{
+	let end = load_address + length as u64;
+	let mut segments = Vec::new();
+	let mut address = load_address;
+	while address < end {
+		let region = memory_map
+			.region_containing(address)
+			.ok_or_else(|| eyre!("Image address 0x{:08x} is not in the target's memory map", address))?;
+		let segment_end = end.min(region.end());
+		let erase = match region.kind {
+			MemoryKind::Ram => None,
+			MemoryKind::Rom => return Err(eyre!("Image address 0x{:08x} is in ROM and cannot be written", address)),
+			MemoryKind::Flash {
+				block_size,
+			} => {
+				// Round out to the enclosing blocks, measuring them from the start of the region
+				let erase_start = region.start + (address - region.start) / block_size * block_size;
+				let erase_end = region.start + (segment_end - region.start).div_ceil(block_size) * block_size;
+				Some(erase_start..erase_end.min(region.end()))
+			},
+		};
+		let offset = (address - load_address) as usize;
+		segments.push(LoadSegment {
+			address,
+			image_range: offset..offset + (segment_end - address) as usize,
+			erase,
+		});
+		address = segment_end;
+	}
+	Ok(segments)
+}
+
+/// Load an image onto the target at `load_address`, erasing and programming Flash as needed via the
+/// GDB server's vFlash commands, then check it was written correctly with qCRC
+pub fn load_image<T: Read + Write>(gdb: &mut GdbRspInterface<T>, image: &[u8], load_address: u64) -> Result<()>
+{
+	let memory_map = gdb.memory_map()?;
+	let segments = plan_load(&memory_map, load_address, image.len())?;
+
+	// Erase all the Flash blocks the image covers up front, as the remote expects
+	for erase in segments.iter().filter_map(|segment| segment.erase.as_ref()) {
+		debug!("Erasing Flash 0x{:08x}-0x{:08x}", erase.start, erase.end);
+		gdb.flash_erase(erase.start, erase.end - erase.start)?;
+	}
+
+	info!("Writing {} bytes at 0x{:08x}", image.len(), load_address);
+	let progress = progress_bar(image.len() as u64);
+	for segment in &segments {
+		let data = &image[segment.image_range.clone()];
+		// Write the segment a packet at a time so progress can be shown
+		let chunk_size = gdb.binary_chunk_size();
+		for (index, chunk) in data.chunks(chunk_size).enumerate() {
+			let address = segment.address + (index * chunk_size) as u64;
+			if segment.erase.is_some() {
+				gdb.flash_write(address, chunk)?;
+			} else {
+				gdb.write_memory_binary(address, chunk)?;
+			}
+			progress.inc(chunk.len() as u64);
+		}
+	}
+	if segments.iter().any(|segment| segment.erase.is_some()) {
+		gdb.flash_done()?;
+	}
+	progress.finish();
+
+	info!("Verifying image");
+	for segment in &segments {
+		let data = &image[segment.image_range.clone()];
+		let expected = crc32(data);
+		let actual = gdb.crc(segment.address, data.len() as u64)?;
+		if actual != expected {
+			return Err(eyre!(
+				"Verification failed for 0x{:08x}-0x{:08x}, expected CRC32 0x{:08x} but got 0x{:08x}",
+				segment.address,
+				segment.address + data.len() as u64,
+				expected,
+				actual
+			));
+		}
+	}
+	Ok(())
+}
+
+/// Compare the target memory covered by `image` (which is expected to be at `load_address`) a sector at a time,
+/// having the GDB server compute the CRC32 of each sector with qCRC so none of it has to be read back, and
+/// returning the runs of adjacent sectors found not to match
+pub fn verify_image_crc<T: Read + Write>(
+	gdb: &mut GdbRspInterface<T>,
+	image: &[u8],
+	load_address: u64,
+	sector_size: usize,
+) -> Result<Vec<SectorMismatch>>
+{
+	verify_sectors(image, load_address, sector_size, |address, length| {
+		gdb.crc(address, length as u64)
+	})
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::serial::gdb_target::MemoryRegion;
+
+	#[test]
+	fn load_planning()
+	{
+		let memory_map = MemoryMap {
+			regions: vec![
+				MemoryRegion {
+					kind: MemoryKind::Flash {
+						block_size: 0x400,
+					},
+					start: 0x08000000,
+					length: 0x1000,
+				},
+				MemoryRegion {
+					kind: MemoryKind::Ram,
+					start: 0x08001000,
+					length: 0x1000,
+				},
+			],
+		};
+		let segments = plan_load(&memory_map, 0x08000f00, 0x200).unwrap();
+		assert_eq!(
+			segments,
+			[
+				LoadSegment {
+					address: 0x08000f00,
+					image_range: 0..0x100,
+					erase: Some(0x08000c00..0x08001000),
+				},
+				LoadSegment {
+					address: 0x08001000,
+					image_range: 0x100..0x200,
+					erase: None,
+				},
+			]
+		);
+		assert!(plan_load(&memory_map, 0x08001f00, 0x200).is_err());
+	}
+}
@@ -2,23 +2,937 @@
 // SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
 // SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
 
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
 use std::fs::File;
+use std::io::{Read, Write};
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, trace};
 
-pub struct GdbRspInterface
+use crate::serial::gdb_target::{MemoryMap, TargetDescription};
+use crate::serial::port;
+use crate::serial::remote::{hexify, unhexify};
+
+/// Start of packet marker
+pub const GDB_PACKET_START: u8 = b'$';
+/// End of packet marker, followed by the two checksum digits
+pub const GDB_PACKET_END: u8 = b'#';
+/// Start of notification packet marker
+pub const GDB_NOTIFICATION_START: u8 = b'%';
+/// Escape character, the following character is XOR'd with 0x20
+pub const GDB_ESCAPE: u8 = b'}';
+/// Run-length encoding marker, the following character encodes a repeat count
+pub const GDB_RUN_LENGTH: u8 = b'*';
+/// Packet acknowledgement
+pub const GDB_ACK: u8 = b'+';
+/// Negative packet acknowledgement, requesting retransmission
+pub const GDB_NACK: u8 = b'-';
+/// Out-of-band interrupt request (Ctrl-C)
+pub const GDB_INTERRUPT: u8 = 0x03;
+
+/// Packet size to assume until the remote tells us otherwise
+const GDB_DEFAULT_PACKET_SIZE: usize = 1024;
+/// The smallest packet size we work with, leaving room for request headers alongside some data - remotes
+/// claiming less than this are assumed to be mistaken
+const GDB_MIN_PACKET_SIZE: usize = 64;
+/// How many times to retransmit a packet the remote NACKs before giving up
+const GDB_MAX_RETRANSMITS: usize = 3;
+/// How long to wait for the remote to send anything before giving up
+const GDB_DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait on operations that involve the target's Flash, such as erasing it
+const GDB_FLASH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Features we tell the remote we support when negotiating
+const GDB_CLIENT_FEATURES: &str = "qSupported:multiprocess-;swbreak+;hwbreak+;qRelocInsn-;vContSupported+";
+
+/// A GDB Remote Serial Protocol client, for talking to the GDB server built into the probe firmware
+pub struct GdbRspInterface<T: Read + Write = File>
+{
+	handle: T,
+	/// Whether packets are acknowledged (the default until no-ack mode is negotiated)
+	ack_mode: bool,
+	/// The features the remote reported supporting in reply to qSupported
+	features: BTreeMap<String, String>,
+	packet_size: usize,
+	timeout: Duration,
+
+	read_buffer: [u8; GDB_DEFAULT_PACKET_SIZE],
+	read_buffer_fullness: usize,
+	read_buffer_offset: usize,
+}
+
+/// Why the target stopped, as reported in a stop reply packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReply
+{
+	/// The target stopped with the given signal, along with any extra information the remote gave
+	Signal
+	{
+		signal: u8,
+		fields: Vec<(String, String)>,
+	},
+	/// The process exited with the given exit status
+	Exited(u8),
+	/// The process was terminated by the given signal
+	Terminated(u8),
+}
+
+/// Which debug protocol the probe should use to scan for targets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanProtocol
+{
+	Swd,
+	Jtag,
+}
+
+/// A target found by the probe's scan, as listed by the firmware
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedTarget
 {
-	#[allow(unused)]
-	interface: File,
+	/// The number the firmware gave the target, for use with vAttach
+	pub index: usize,
+	/// Whether GDB is currently attached to the target
+	pub attached: bool,
+	/// The name of the firmware driver handling the target (and its core, if known)
+	pub driver: String,
 }
 
 impl GdbRspInterface
 {
 	pub fn from_path(serial_port: &Path) -> Result<Self>
 	{
-		Ok(Self {
-			interface: File::options().read(true).write(true).open(serial_port)?,
+		debug!("Opening probe GDB interface at {:?}", serial_port);
+		let handle = File::options().read(true).write(true).open(serial_port)?;
+		port::configure(&handle)?;
+		Ok(Self::new(handle))
+	}
+}
+
+impl<T: Read + Write> GdbRspInterface<T>
+{
+	/// Wrap a connection to a GDB server. No traffic is exchanged until [`Self::negotiate`] or
+	/// a request is made.
+	pub fn new(handle: T) -> Self
+	{
+		Self {
+			handle,
+			ack_mode: true,
+			features: BTreeMap::new(),
+			packet_size: GDB_DEFAULT_PACKET_SIZE,
+			timeout: GDB_DEFAULT_TIMEOUT,
+
+			read_buffer: [0; GDB_DEFAULT_PACKET_SIZE],
+			read_buffer_fullness: 0,
+			read_buffer_offset: 0,
+		}
+	}
+
+	/// Give up the connection to the GDB server, such as to relay traffic to it directly
+	pub fn into_inner(self) -> T
+	{
+		self.handle
+	}
+
+	/// Set how long to wait on the remote to send anything before giving up on a request
+	pub fn set_timeout(&mut self, timeout: Duration)
+	{
+		self.timeout = timeout;
+	}
+
+	/// Find out what the remote supports with qSupported, and switch to no-ack mode if we can
+	pub fn negotiate(&mut self) -> Result<()>
+	{
+		let reply = self.request_string(GDB_CLIENT_FEATURES)?;
+		self.features = parse_features(&reply);
+		if let Some(size) = self.features.get("PacketSize") {
+			let packet_size =
+				usize::from_str_radix(size, 16).map_err(|_| eyre!("Invalid packet size {:?} from remote", size))?;
+			if packet_size < GDB_MIN_PACKET_SIZE {
+				debug!(
+					"GDB server packet size {} is implausibly small, using {}",
+					packet_size, GDB_MIN_PACKET_SIZE
+				);
+			}
+			self.packet_size = packet_size.max(GDB_MIN_PACKET_SIZE);
+		}
+		debug!("GDB server supports {:?}", self.features);
+
+		if self.supports("QStartNoAckMode") {
+			self.request_ok("QStartNoAckMode")?;
+			self.ack_mode = false;
+			trace!("Switched to no-ack mode");
+		}
+		Ok(())
+	}
+
+	/// Whether the remote reported supporting a feature in reply to qSupported
+	pub fn supports(&self, feature: &str) -> bool
+	{
+		self.features.get(feature).is_some_and(|value| value != "-")
+	}
+
+	/// The largest packet the remote will accept
+	pub fn packet_size(&self) -> usize
+	{
+		self.packet_size
+	}
+
+	/// The most binary data that's sure to fit in a single packet alongside a request header
+	pub fn binary_chunk_size(&self) -> usize
+	{
+		// Escaping can double the size of the data in the worst case
+		self.packet_size.saturating_sub(32) / 2
+	}
+
+	/// Send a packet to the remote, waiting for it to be acknowledged if in ack mode
+	pub fn send_packet(&mut self, payload: &[u8]) -> Result<()>
+	{
+		let packet = frame_packet(payload);
+		trace!("GDB RSP write: {}", String::from_utf8_lossy(&packet));
+		for _ in 0..=GDB_MAX_RETRANSMITS {
+			self.handle.write_all(&packet)?;
+			self.handle.flush()?;
+			if !self.ack_mode {
+				return Ok(());
+			}
+			// Wait for the remote to acknowledge the packet
+			loop {
+				match self.read_byte()? {
+					GDB_ACK => return Ok(()),
+					GDB_NACK => break,
+					_ => (),
+				}
+			}
+			debug!("GDB server requested retransmission");
+		}
+		Err(eyre!("GDB server failed to accept packet after {} attempts", GDB_MAX_RETRANSMITS + 1))
+	}
+
+	/// Receive a packet from the remote, returning its decoded payload
+	pub fn read_packet(&mut self) -> Result<Vec<u8>>
+	{
+		loop {
+			// Skip anything (such as stray acks) until the start of a packet
+			while self.read_byte()? != GDB_PACKET_START {}
+			let mut payload = Vec::new();
+			loop {
+				match self.read_byte()? {
+					GDB_PACKET_END => break,
+					byte => payload.push(byte),
+				}
+			}
+			let checksum = [self.read_byte()?, self.read_byte()?];
+			let valid = std::str::from_utf8(&checksum)
+				.ok()
+				.and_then(|checksum| u8::from_str_radix(checksum, 16).ok()) ==
+				Some(compute_checksum(&payload));
+			trace!("GDB RSP read: {}", String::from_utf8_lossy(&payload));
+
+			if self.ack_mode {
+				if valid {
+					self.handle.write_all(&[GDB_ACK])?;
+				} else {
+					self.handle.write_all(&[GDB_NACK])?;
+					debug!("Checksum mismatch on packet from GDB server, requesting retransmission");
+					continue;
+				}
+			} else if !valid {
+				return Err(eyre!("Checksum mismatch on packet from GDB server"));
+			}
+			return decode_payload(&payload);
+		}
+	}
+
+	/// Send a request and return the reply, turning error replies into errors
+	pub fn request(&mut self, payload: &[u8]) -> Result<Vec<u8>>
+	{
+		self.send_packet(payload)?;
+		let reply = self.read_packet()?;
+		check_error(&reply, payload)?;
+		Ok(reply)
+	}
+
+	/// Send a request and return the reply as a string
+	pub fn request_string(&mut self, payload: &str) -> Result<String>
+	{
+		let reply = self.request(payload.as_bytes())?;
+		Ok(String::from_utf8_lossy(&reply).into_owned())
+	}
+
+	/// Send a request that should be answered with "OK"
+	pub fn request_ok(&mut self, payload: &str) -> Result<()>
+	{
+		match self.request(payload.as_bytes())?.as_slice() {
+			b"OK" => Ok(()),
+			b"" => Err(eyre!("GDB server does not support {:?}", payload)),
+			reply => Err(eyre!("Unexpected reply {:?} to {:?}", String::from_utf8_lossy(reply), payload)),
+		}
+	}
+
+	/// Send an out-of-band interrupt to stop the running target
+	pub fn interrupt(&mut self) -> Result<()>
+	{
+		self.handle.write_all(&[GDB_INTERRUPT])?;
+		Ok(self.handle.flush()?)
+	}
+
+	/// Read target memory with `m` packets, split up so each reply fits in a packet
+	pub fn read_memory(&mut self, address: u64, length: usize) -> Result<Vec<u8>>
+	{
+		// Each byte is two hex digits in the reply, and leave room for the packet framing
+		let chunk_size = self.packet_size.saturating_sub(4) / 2;
+		let mut data = Vec::with_capacity(length);
+		while data.len() < length {
+			let offset = data.len();
+			let chunk = chunk_size.min(length - offset);
+			let reply = self.request_string(&format!("m{:x},{:x}", address + offset as u64, chunk))?;
+			if reply.is_empty() {
+				return Err(eyre!("Empty reply reading memory at 0x{:08x}", address + offset as u64));
+			}
+			let mut bytes = vec![0; (reply.len() / 2).min(chunk)];
+			unhexify(&reply, &mut bytes)?;
+			data.extend(bytes);
+		}
+		Ok(data)
+	}
+
+	/// Write target memory with `M` packets, split up to fit in the remote's packet size
+	pub fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<()>
+	{
+		// Leave room for the framing and header, with each byte taking two hex digits
+		let chunk_size = self.packet_size.saturating_sub(32) / 2;
+		for (index, chunk) in data.chunks(chunk_size).enumerate() {
+			let chunk_address = address + (index * chunk_size) as u64;
+			self.request_ok(&format!("M{:x},{:x}:{}", chunk_address, chunk.len(), hexify(chunk)))?;
+		}
+		Ok(())
+	}
+
+	/// Write target memory with binary `X` packets, split up to fit in the remote's packet size
+	pub fn write_memory_binary(&mut self, address: u64, data: &[u8]) -> Result<()>
+	{
+		let chunk_size = self.binary_chunk_size();
+		for (index, chunk) in data.chunks(chunk_size).enumerate() {
+			let chunk_address = address + (index * chunk_size) as u64;
+			let mut packet = format!("X{:x},{:x}:", chunk_address, chunk.len()).into_bytes();
+			packet.extend(escape(chunk));
+			match self.request(&packet)?.as_slice() {
+				b"OK" => (),
+				reply => {
+					return Err(eyre!(
+						"Unexpected reply {:?} writing memory at 0x{:08x}",
+						String::from_utf8_lossy(reply),
+						chunk_address
+					));
+				},
+			}
+		}
+		Ok(())
+	}
+
+	/// Read all the general registers with `g`, returning them in target byte order
+	pub fn read_registers(&mut self) -> Result<Vec<u8>>
+	{
+		let reply = self.request_string("g")?;
+		let mut registers = vec![0; reply.len() / 2];
+		unhexify(&reply, &mut registers)?;
+		Ok(registers)
+	}
+
+	/// Read a single register by number with `p`, returning it in target byte order
+	pub fn read_register(&mut self, register: usize) -> Result<Vec<u8>>
+	{
+		let reply = self.request_string(&format!("p{:x}", register))?;
+		let mut value = vec![0; reply.len() / 2];
+		unhexify(&reply, &mut value)?;
+		Ok(value)
+	}
+
+	/// Attach to a target (numbered from 1 in the probe's scan results), returning why it stopped
+	pub fn attach(&mut self, target: usize) -> Result<StopReply>
+	{
+		let reply = self.request_string(&format!("vAttach;{:x}", target))?;
+		if reply.is_empty() {
+			return Err(eyre!("GDB server does not support vAttach"));
+		}
+		parse_stop_reply(&reply)
+	}
+
+	/// Run a monitor command with `qRcmd`, passing each piece of console output the command produces
+	/// to `output` as it arrives
+	pub fn monitor<F>(&mut self, command: &str, mut output: F) -> Result<()>
+	where
+		F: FnMut(&str),
+	{
+		self.send_packet(format!("qRcmd,{}", hexify(command.as_bytes())).as_bytes())?;
+		loop {
+			let reply = self.read_packet()?;
+			match reply.as_slice() {
+				b"OK" => return Ok(()),
+				b"" => return Err(eyre!("GDB server does not support monitor commands")),
+				[b'O', hex @ ..] if hex != b"K" => {
+					let mut text = vec![0; hex.len() / 2];
+					unhexify(&String::from_utf8_lossy(hex), &mut text)?;
+					output(&String::from_utf8_lossy(&text));
+				},
+				_ => {
+					check_error(&reply, b"qRcmd")?;
+					// Some servers reply with the output directly, hex encoded, rather than with O packets
+					let mut text = vec![0; reply.len() / 2];
+					unhexify(&String::from_utf8_lossy(&reply), &mut text)?;
+					output(&String::from_utf8_lossy(&text));
+					return Ok(());
+				},
+			}
+		}
+	}
+
+	/// Run a monitor command, collecting all of its output
+	pub fn monitor_output(&mut self, command: &str) -> Result<String>
+	{
+		let mut result = String::new();
+		self.monitor(command, |output| result.push_str(output))?;
+		Ok(result)
+	}
+
+	/// Have the probe start capturing SWO trace - at `baud_rate` for async (NRZ) trace, or in Manchester mode
+	/// if no rate is given
+	pub fn start_traceswo(&mut self, baud_rate: Option<u32>) -> Result<()>
+	{
+		let command = match baud_rate {
+			Some(baud_rate) => format!("traceswo enable {}", baud_rate),
+			None => "traceswo enable".into(),
+		};
+		let output = self.monitor_output(&command)?;
+		debug!("Probe trace capture started: {}", output.trim());
+		Ok(())
+	}
+
+	/// Have the probe scan for targets, returning the targets it found
+	pub fn scan_targets(&mut self, protocol: ScanProtocol) -> Result<Vec<ScannedTarget>>
+	{
+		let mut output = String::new();
+		let result = self.monitor(protocol.monitor_command(), |text| output.push_str(text));
+		// The firmware explains why a scan failed in its output, so use that if there is any
+		if let Err(error) = result {
+			return match output.trim() {
+				"" => Err(error),
+				reason => Err(eyre!("Target scan failed: {}", reason)),
+			};
+		}
+		let targets = parse_scan_output(&output);
+		if targets.is_empty() {
+			return Err(eyre!("No targets found: {}", output.trim()));
+		}
+		Ok(targets)
+	}
+
+	/// Fetch and parse the target's memory map
+	pub fn memory_map(&mut self) -> Result<MemoryMap>
+	{
+		let memory_map = self.qxfer_read("memory-map", "")?;
+		MemoryMap::parse(&String::from_utf8_lossy(&memory_map))
+	}
+
+	/// Fetch and parse the target's description, along with any documents it includes
+	pub fn target_description(&mut self) -> Result<TargetDescription>
+	{
+		let description = self.qxfer_read("features", "target.xml")?;
+		TargetDescription::parse(&String::from_utf8_lossy(&description), |annex| {
+			let document = self.qxfer_read("features", annex)?;
+			Ok(String::from_utf8_lossy(&document).into_owned())
+		})
+	}
+
+	/// Erase a block-aligned range of the target's Flash
+	pub fn flash_erase(&mut self, address: u64, length: u64) -> Result<()>
+	{
+		self.with_timeout(GDB_FLASH_TIMEOUT, |gdb| gdb.request_ok(&format!("vFlashErase:{:x},{:x}", address, length)))
+	}
+
+	/// Write data to previously erased target Flash, split up to fit in the remote's packet size. The
+	/// remote may buffer the data until [`Self::flash_done`] is called.
+	pub fn flash_write(&mut self, address: u64, data: &[u8]) -> Result<()>
+	{
+		let chunk_size = self.binary_chunk_size();
+		for (index, chunk) in data.chunks(chunk_size).enumerate() {
+			let chunk_address = address + (index * chunk_size) as u64;
+			let mut packet = format!("vFlashWrite:{:x}:", chunk_address).into_bytes();
+			packet.extend(escape(chunk));
+			match self.with_timeout(GDB_FLASH_TIMEOUT, |gdb| gdb.request(&packet))?.as_slice() {
+				b"OK" => (),
+				reply => {
+					return Err(eyre!(
+						"Unexpected reply {:?} writing Flash at 0x{:08x}",
+						String::from_utf8_lossy(reply),
+						chunk_address
+					));
+				},
+			}
+		}
+		Ok(())
+	}
+
+	/// Tell the remote all Flash writes are done, so it can finish programming
+	pub fn flash_done(&mut self) -> Result<()>
+	{
+		self.with_timeout(GDB_FLASH_TIMEOUT, |gdb| gdb.request_ok("vFlashDone"))
+	}
+
+	/// Have the remote compute the CRC32 of a range of target memory with `qCRC`, which is calculated
+	/// the same way as [`crate::target::verify::crc32`]
+	pub fn crc(&mut self, address: u64, length: u64) -> Result<u32>
+	{
+		let request = format!("qCRC:{:x},{:x}", address, length);
+		let reply = self.with_timeout(GDB_FLASH_TIMEOUT, |gdb| gdb.request_string(&request))?;
+		reply
+			.strip_prefix('C')
+			.and_then(|crc| u32::from_str_radix(crc, 16).ok())
+			.ok_or_else(|| eyre!("Invalid reply {:?} to CRC request", reply))
+	}
+
+	/// Detach from the target, letting it run again
+	pub fn detach(&mut self) -> Result<()>
+	{
+		self.request_ok("D")
+	}
+
+	/// Read a whole object from the remote with `qXfer`, in as many pieces as it takes
+	pub fn qxfer_read(&mut self, object: &str, annex: &str) -> Result<Vec<u8>>
+	{
+		let chunk_size = self.packet_size.saturating_sub(4);
+		let mut data = Vec::new();
+		loop {
+			let request = format!("qXfer:{}:read:{}:{:x},{:x}", object, annex, data.len(), chunk_size);
+			let reply = self.request(request.as_bytes())?;
+			match reply.split_first() {
+				// 'm' means there's more to come, 'l' that this is the last of it
+				Some((b'm', chunk)) => data.extend_from_slice(chunk),
+				Some((b'l', chunk)) => {
+					data.extend_from_slice(chunk);
+					return Ok(data);
+				},
+				_ => return Err(eyre!("GDB server does not support reading {} with qXfer", object)),
+			}
+		}
+	}
+
+	/// Run a request that can take the remote a while to complete, with a longer timeout than usual
+	fn with_timeout<R>(&mut self, timeout: Duration, request: impl FnOnce(&mut Self) -> Result<R>) -> Result<R>
+	{
+		let previous_timeout = self.timeout;
+		self.timeout = previous_timeout.max(timeout);
+		let result = request(self);
+		self.timeout = previous_timeout;
+		result
+	}
+
+	/// Get the next byte from the remote, waiting up to the timeout for one to arrive
+	fn read_byte(&mut self) -> Result<u8>
+	{
+		if self.read_buffer_offset == self.read_buffer_fullness {
+			self.read_more_data()?;
+		}
+		let byte = self.read_buffer[self.read_buffer_offset];
+		self.read_buffer_offset += 1;
+		Ok(byte)
+	}
+
+	fn read_more_data(&mut self) -> Result<()>
+	{
+		let start = Instant::now();
+		loop {
+			let bytes_received = self.handle.read(&mut self.read_buffer)?;
+			if bytes_received != 0 {
+				self.read_buffer_fullness = bytes_received;
+				self.read_buffer_offset = 0;
+				return Ok(());
+			}
+			if start.elapsed() > self.timeout {
+				return Err(eyre!("Timeout while waiting for GDB server response"));
+			}
+			// The port may be set up to return immediately when there's no data, so don't spin hard
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+}
+
+/// Calculate the checksum of a packet's payload - the modulo 256 sum of its bytes
+pub fn compute_checksum(payload: &[u8]) -> u8
+{
+	payload.iter().fold(0u8, |checksum, &byte| checksum.wrapping_add(byte))
+}
+
+/// Wrap a payload up as a packet ready to send, as `$payload#xx`
+pub fn frame_packet(payload: &[u8]) -> Vec<u8>
+{
+	let mut packet = Vec::with_capacity(payload.len() + 4);
+	packet.push(GDB_PACKET_START);
+	packet.extend_from_slice(payload);
+	packet.push(GDB_PACKET_END);
+	packet.extend(format!("{:02x}", compute_checksum(payload)).into_bytes());
+	packet
+}
+
+/// Escape binary data so it can be sent in a packet
+pub fn escape(data: &[u8]) -> Vec<u8>
+{
+	let mut result = Vec::with_capacity(data.len());
+	for &byte in data {
+		match byte {
+			GDB_PACKET_START | GDB_PACKET_END | GDB_ESCAPE | GDB_RUN_LENGTH => {
+				result.push(GDB_ESCAPE);
+				result.push(byte ^ 0x20);
+			},
+			_ => result.push(byte),
+		}
+	}
+	result
+}
+
+/// Undo the escaping of binary data in a packet
+pub fn unescape(data: &[u8]) -> Vec<u8>
+{
+	let mut result = Vec::with_capacity(data.len());
+	let mut bytes = data.iter();
+	while let Some(&byte) = bytes.next() {
+		if byte == GDB_ESCAPE {
+			if let Some(&escaped) = bytes.next() {
+				result.push(escaped ^ 0x20);
+			}
+		} else {
+			result.push(byte);
+		}
+	}
+	result
+}
+
+/// Undo the escaping and run-length encoding (`x*n` means x repeated a further n - 29 times) of a reply. Both
+/// have to be undone in the same pass, as an escaped `*` is data rather than the start of a run.
+pub fn decode_payload(data: &[u8]) -> Result<Vec<u8>>
+{
+	let mut result = Vec::with_capacity(data.len());
+	let mut bytes = data.iter();
+	while let Some(&byte) = bytes.next() {
+		match byte {
+			GDB_ESCAPE => {
+				let escaped = bytes
+					.next()
+					.ok_or_else(|| eyre!("Incomplete escape sequence in packet"))?;
+				result.push(escaped ^ 0x20);
+			},
+			GDB_RUN_LENGTH => {
+				let (Some(&count), Some(&last)) = (bytes.next(), result.last()) else {
+					return Err(eyre!("Invalid run-length encoding in packet"));
+				};
+				let count = count
+					.checked_sub(29)
+					.ok_or_else(|| eyre!("Invalid run-length count in packet"))?;
+				result.extend(std::iter::repeat_n(last, count as usize));
+			},
+			_ => result.push(byte),
+		}
+	}
+	Ok(result)
+}
+
+/// Turn an `Exx` (or `E.message`) error reply into an error
+fn check_error(reply: &[u8], request: &[u8]) -> Result<()>
+{
+	let request = String::from_utf8_lossy(request);
+	let request = request.split([':', ',', ';']).next().unwrap_or_default();
+	match reply {
+		[b'E', b'.', message @ ..] => Err(eyre!(
+			"GDB server returned error for {}: {}",
+			request,
+			String::from_utf8_lossy(message)
+		)),
+		[b'E', high, low] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => Err(eyre!(
+			"GDB server returned error {} for {}",
+			String::from_utf8_lossy(&[*high, *low]),
+			request
+		)),
+		_ => Ok(()),
+	}
+}
+
+/// Parse a qSupported reply into a map of feature name to value ("+", "-", "?" or the value given)
+fn parse_features(reply: &str) -> BTreeMap<String, String>
+{
+	reply
+		.split(';')
+		.filter(|feature| !feature.is_empty())
+		.map(|feature| {
+			if let Some((name, value)) = feature.split_once('=') {
+				(name.into(), value.into())
+			} else {
+				let (name, support) = feature.split_at(feature.len() - 1);
+				(name.into(), support.into())
+			}
 		})
+		.collect()
+}
+
+/// Parse the numbered target list the firmware prints after a scan, which looks like:
+///
+/// ```text
+/// Available Targets:
+/// No. Att Driver
+///  1   *  STM32F1 L/M density M3
+/// ```
+pub fn parse_scan_output(output: &str) -> Vec<ScannedTarget>
+{
+	output
+		.lines()
+		.filter_map(|line| {
+			let (index, rest) = line.trim_start().split_once(char::is_whitespace)?;
+			let index = index.parse().ok()?;
+			let rest = rest.trim_start();
+			let (attached, driver) = match rest.strip_prefix('*') {
+				Some(driver) => (true, driver.trim()),
+				None => (false, rest.trim()),
+			};
+			Some(ScannedTarget {
+				index,
+				attached,
+				driver: driver.into(),
+			})
+		})
+		.collect()
+}
+
+/// Parse a stop reply packet (`S`, `T`, `W` or `X`)
+pub fn parse_stop_reply(reply: &str) -> Result<StopReply>
+{
+	let parse_code = |code: &str| {
+		u8::from_str_radix(code.get(..2).unwrap_or(code), 16).map_err(|_| eyre!("Invalid stop reply {:?}", reply))
+	};
+	let (kind, rest) = reply.split_at_checked(1).ok_or_else(|| eyre!("Empty stop reply"))?;
+	match kind {
+		"S" => Ok(StopReply::Signal {
+			signal: parse_code(rest)?,
+			fields: Vec::new(),
+		}),
+		"T" => Ok(StopReply::Signal {
+			signal: parse_code(rest)?,
+			fields: rest
+				.get(2..)
+				.unwrap_or_default()
+				.split(';')
+				.filter_map(|field| field.split_once(':'))
+				.map(|(name, value)| (name.into(), value.into()))
+				.collect(),
+		}),
+		"W" => Ok(StopReply::Exited(parse_code(rest)?)),
+		"X" => Ok(StopReply::Terminated(parse_code(rest)?)),
+		_ => Err(eyre!("Unexpected stop reply {:?}", reply)),
+	}
+}
+
+impl ScanProtocol
+{
+	fn monitor_command(&self) -> &'static str
+	{
+		match self {
+			Self::Swd => "swdp_scan",
+			Self::Jtag => "jtag_scan",
+		}
+	}
+}
+
+impl Display for StopReply
+{
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+	{
+		match self {
+			Self::Signal {
+				signal,
+				..
+			} => match signal_name(*signal) {
+				Some(name) => write!(f, "stopped by signal {} ({})", signal, name),
+				None => write!(f, "stopped by signal {}", signal),
+			},
+			Self::Exited(status) => write!(f, "exited with status {}", status),
+			Self::Terminated(signal) => write!(f, "terminated by signal {}", signal),
+		}
+	}
+}
+
+/// Names of the signals the probe firmware reports targets stopping with
+fn signal_name(signal: u8) -> Option<&'static str>
+{
+	match signal {
+		2 => Some("SIGINT"),
+		5 => Some("SIGTRAP"),
+		11 => Some("SIGSEGV"),
+		15 => Some("SIGTERM"),
+		29 => Some("SIGLOST"),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::collections::VecDeque;
+
+	use super::*;
+
+	/// A fake GDB server connection, replaying canned responses and recording what was sent
+	struct Loopback
+	{
+		input: VecDeque<u8>,
+		output: Vec<u8>,
+	}
+
+	impl Read for Loopback
+	{
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+		{
+			self.input.read(buf)
+		}
+	}
+
+	impl Write for Loopback
+	{
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+		{
+			self.output.write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()>
+		{
+			Ok(())
+		}
+	}
+
+	fn interface(responses: &[&[u8]]) -> GdbRspInterface<Loopback>
+	{
+		let mut interface = GdbRspInterface::new(Loopback {
+			input: responses.concat().into(),
+			output: Vec::new(),
+		});
+		interface.set_timeout(Duration::ZERO);
+		interface
+	}
+
+	#[test]
+	fn framing()
+	{
+		assert_eq!(frame_packet(b"qSupported"), b"$qSupported#37");
+		assert_eq!(unescape(&escape(b"a$b#c}d*e")), b"a$b#c}d*e");
+		assert_eq!(escape(b"}"), b"}]");
+		assert_eq!(decode_payload(b"0* ").unwrap(), b"0000");
+		assert!(decode_payload(b"*").is_err());
+		assert!(decode_payload(b"0}").is_err());
+	}
+
+	#[test]
+	fn binary_replies()
+	{
+		// An escaped '*' (0x2a) is data, which can itself be repeated by a run
+		assert_eq!(decode_payload(b"}\x0a}]").unwrap(), [0x2a, 0x7d]);
+		assert_eq!(decode_payload(b"}\x0a* ").unwrap(), [0x2a; 4]);
+		let data = [0x2a, 0x00, 0x2a, 0x2a, 0x23, 0x24, 0x7d];
+		assert_eq!(decode_payload(&escape(&data)).unwrap(), data);
+
+		let mut interface = interface(&[&frame_packet(&[b"l".as_slice(), &escape(&data)].concat())]);
+		interface.ack_mode = false;
+		assert_eq!(interface.qxfer_read("memory-map", "").unwrap(), data);
+	}
+
+	#[test]
+	fn negotiation()
+	{
+		let mut interface = interface(&[
+			b"+",
+			&frame_packet(b"PacketSize=400;qXfer:memory-map:read+;QStartNoAckMode+"),
+			b"+",
+			&frame_packet(b"OK"),
+		]);
+		interface.negotiate().unwrap();
+		assert_eq!(interface.packet_size(), 0x400);
+		assert!(interface.supports("qXfer:memory-map:read"));
+		assert!(!interface.supports("qXfer:features:read"));
+		assert!(!interface.ack_mode);
+		// The reply to QStartNoAckMode still gets acknowledged
+		assert!(interface.handle.output.ends_with(&[frame_packet(b"QStartNoAckMode"), b"+".to_vec()].concat()));
+	}
+
+	#[test]
+	fn tiny_packet_size()
+	{
+		// A packet size too small to carry any data gets raised to the minimum rather than underflowing
+		let mut interface = interface(&[b"+", &frame_packet(b"PacketSize=10")]);
+		interface.negotiate().unwrap();
+		assert_eq!(interface.packet_size(), GDB_MIN_PACKET_SIZE);
+		assert!(interface.binary_chunk_size() > 0);
+	}
+
+	#[test]
+	fn requests()
+	{
+		let mut interface = interface(&[
+			&frame_packet(b"O48690a"),
+			&frame_packet(b"OK"),
+			&frame_packet(b"ldata"),
+			&frame_packet(b"E01"),
+		]);
+		interface.ack_mode = false;
+		assert_eq!(interface.monitor_output("version").unwrap(), "Hi\n");
+		assert_eq!(interface.qxfer_read("memory-map", "").unwrap(), b"data");
+		assert!(interface.read_memory(0x20000000, 4).is_err());
+		assert_eq!(
+			parse_stop_reply("T05thread:01;").unwrap(),
+			StopReply::Signal {
+				signal: 5,
+				fields: vec![("thread".into(), "01".into())],
+			}
+		);
+	}
+
+	#[test]
+	fn traceswo()
+	{
+		let mut interface = interface(&[&frame_packet(b"OK"), &frame_packet(b"OK")]);
+		interface.ack_mode = false;
+		interface.start_traceswo(Some(115200)).unwrap();
+		interface.start_traceswo(None).unwrap();
+		let expected = [
+			frame_packet(format!("qRcmd,{}", hexify(b"traceswo enable 115200")).as_bytes()),
+			frame_packet(format!("qRcmd,{}", hexify(b"traceswo enable")).as_bytes()),
+		]
+		.concat();
+		assert_eq!(interface.handle.output, expected);
+	}
+
+	#[test]
+	fn scan_output()
+	{
+		let output = concat!(
+			"Target voltage: 3.3V\n",
+			"Available Targets:\n",
+			"No. Att Driver\n",
+			" 1   *  STM32F1 L/M density M3\n",
+			" 2      RP2040 M0+\n",
+		);
+		assert_eq!(
+			parse_scan_output(output),
+			[
+				ScannedTarget {
+					index: 1,
+					attached: true,
+					driver: "STM32F1 L/M density M3".into(),
+				},
+				ScannedTarget {
+					index: 2,
+					attached: false,
+					driver: "RP2040 M0+".into(),
+				},
+			]
+		);
 	}
 }
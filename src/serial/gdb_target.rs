@@ -0,0 +1,389 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Descriptions of the attached target that a GDB server provides as XML documents
+
+use std::fmt::Display;
+
+use color_eyre::eyre::{Context, OptionExt, Result, eyre};
+use roxmltree::{Document, Node, ParsingOptions};
+
+/// How deeply nested `<xi:include>`s may be before we decide they're recursive
+const GDB_MAX_INCLUDE_DEPTH: usize = 8;
+
+/// What kind of memory a region of the target's address space is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryKind
+{
+	Ram,
+	Rom,
+	/// Flash, which must be erased in blocks of the given size before being written
+	Flash
+	{
+		block_size: u64,
+	},
+}
+
+/// A region of the target's address space, as described by the GDB server's memory map
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion
+{
+	pub kind: MemoryKind,
+	pub start: u64,
+	pub length: u64,
+}
+
+/// The target's memory map, as fetched with `qXfer:memory-map:read`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryMap
+{
+	pub regions: Vec<MemoryRegion>,
+}
+
+/// A register of the target, as described by the GDB server's target description
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register
+{
+	pub name: String,
+	/// The number GDB uses to refer to the register in `p` and `P` packets
+	pub number: usize,
+	/// Size of the register in bits
+	pub bitsize: u32,
+	/// GDB's type for the register (eg, int, data_ptr or ieee_single)
+	pub kind: String,
+	/// The register group it belongs in (eg, general, float or system), if the description gives one
+	pub group: Option<String>,
+	/// Name of the target description feature the register is part of
+	pub feature: String,
+}
+
+/// The target's description, as fetched with `qXfer:features:read:target.xml`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetDescription
+{
+	/// The architecture of the target (eg, arm or riscv:rv32), if the description gives it
+	pub architecture: Option<String>,
+	pub registers: Vec<Register>,
+}
+
+/// Parse a number as written in a GDB XML document - decimal, or hex with a 0x prefix
+fn parse_xml_number(value: &str) -> Result<u64>
+{
+	let value = value.trim();
+	let result = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+		u64::from_str_radix(hex, 16)
+	} else {
+		value.parse()
+	};
+	result.wrap_err_with(|| eyre!("'{}' is not a valid number", value))
+}
+
+fn required_attribute<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str>
+{
+	node.attribute(name)
+		.ok_or_else(|| eyre!("<{}> element is missing its {} attribute", node.tag_name().name(), name))
+}
+
+/// Parse an XML document from a GDB server, which usually names the DTD it conforms to
+fn parse_document(text: &str) -> Result<Document<'_>>
+{
+	let options = ParsingOptions {
+		allow_dtd: true,
+		..Default::default()
+	};
+	Ok(Document::parse_with_options(text, options)?)
+}
+
+impl MemoryRegion
+{
+	/// The first address after the end of the region
+	pub fn end(&self) -> u64
+	{
+		self.start + self.length
+	}
+
+	pub fn contains(&self, address: u64) -> bool
+	{
+		address >= self.start && address < self.end()
+	}
+
+	fn from_node(node: Node) -> Result<Self>
+	{
+		let start = parse_xml_number(required_attribute(node, "start")?)?;
+		let length = parse_xml_number(required_attribute(node, "length")?)?;
+		let kind = match required_attribute(node, "type")? {
+			"ram" => MemoryKind::Ram,
+			"rom" => MemoryKind::Rom,
+			"flash" => {
+				let block_size = node
+					.children()
+					.find(|child| child.has_tag_name("property") && child.attribute("name") == Some("blocksize"))
+					.and_then(|property| property.text())
+					.ok_or_else(|| eyre!("Flash region at 0x{:08x} has no block size", start))?;
+				let block_size = parse_xml_number(block_size)?;
+				if block_size == 0 {
+					return Err(eyre!("Flash region at 0x{:08x} has a block size of 0", start));
+				}
+				MemoryKind::Flash {
+					block_size,
+				}
+			},
+			kind => return Err(eyre!("Unknown memory type '{}' at 0x{:08x}", kind, start)),
+		};
+		Ok(Self {
+			kind,
+			start,
+			length,
+		})
+	}
+}
+
+impl Display for MemoryRegion
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(f, "0x{:08x}-0x{:08x} ", self.start, self.end())?;
+		match self.kind {
+			MemoryKind::Ram => write!(f, "RAM"),
+			MemoryKind::Rom => write!(f, "ROM"),
+			MemoryKind::Flash {
+				block_size,
+			} => write!(f, "Flash ({} byte blocks)", block_size),
+		}
+	}
+}
+
+impl MemoryMap
+{
+	pub fn parse(text: &str) -> Result<Self>
+	{
+		let document = parse_document(text)?;
+		let root = document.root_element();
+		if !root.has_tag_name("memory-map") {
+			return Err(eyre!("Not a memory map, root element is <{}>", root.tag_name().name()));
+		}
+		let regions = root
+			.children()
+			.filter(|child| child.has_tag_name("memory"))
+			.map(MemoryRegion::from_node)
+			.collect::<Result<_>>()?;
+		Ok(Self {
+			regions,
+		})
+	}
+
+	/// Render the memory map as the XML document a GDB server sends for `qXfer:memory-map:read`
+	pub fn to_xml(&self) -> String
+	{
+		let mut xml = String::from(concat!(
+			"<?xml version=\"1.0\"?>",
+			"<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" ",
+			"\"http://sourceware.org/gdb/gdb-memory-map.dtd\">",
+			"<memory-map>",
+		));
+		for region in &self.regions {
+			let kind = match region.kind {
+				MemoryKind::Ram => "ram",
+				MemoryKind::Rom => "rom",
+				MemoryKind::Flash {
+					..
+				} => "flash",
+			};
+			xml.push_str(&format!(
+				"<memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\">",
+				kind, region.start, region.length
+			));
+			if let MemoryKind::Flash {
+				block_size,
+			} = region.kind
+			{
+				xml.push_str(&format!("<property name=\"blocksize\">0x{:x}</property>", block_size));
+			}
+			xml.push_str("</memory>");
+		}
+		xml.push_str("</memory-map>");
+		xml
+	}
+
+	/// Find the region an address falls in, if any
+	pub fn region_containing(&self, address: u64) -> Option<&MemoryRegion>
+	{
+		self.regions.iter().find(|region| region.contains(address))
+	}
+}
+
+/// Skip past the XML declaration, DOCTYPE and any comments at the start of a document
+fn strip_prolog(text: &str) -> Result<&str>
+{
+	let mut text = text.trim_start();
+	loop {
+		let terminator = if text.starts_with("<?") {
+			"?>"
+		} else if text.starts_with("<!--") {
+			"-->"
+		} else if text.starts_with("<!DOCTYPE") {
+			">"
+		} else {
+			return Ok(text);
+		};
+		let end = text
+			.find(terminator)
+			.ok_or_else(|| eyre!("Unterminated XML prolog, expected {}", terminator))?;
+		text = text[end + terminator.len()..].trim_start();
+	}
+}
+
+/// Replace each `<xi:include href="..."/>` in a target description with the contents of the document
+/// it names, as fetched by `resolve`. This is done textually (as GDB does) because target descriptions
+/// don't reliably declare the XInclude namespace.
+fn expand_includes(text: &str, resolve: &mut dyn FnMut(&str) -> Result<String>, depth: usize) -> Result<String>
+{
+	let mut result = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find("<xi:include") {
+		result.push_str(&rest[..start]);
+		let end = rest[start..]
+			.find("/>")
+			.map(|end| start + end + 2)
+			.ok_or_eyre("Unterminated <xi:include> element")?;
+		let element = &rest[start..end];
+		let href = element
+			.split_once("href=")
+			.and_then(|(_, value)| {
+				let quote = value.chars().next()?;
+				value[quote.len_utf8()..].split(quote).next()
+			})
+			.ok_or_else(|| eyre!("<xi:include> element has no href: {}", element))?;
+		if depth == GDB_MAX_INCLUDE_DEPTH {
+			return Err(eyre!("Target description includes are nested too deeply at {}", href));
+		}
+
+		let included = resolve(href).wrap_err_with(|| eyre!("Failed to fetch included document {}", href))?;
+		result.push_str(&expand_includes(strip_prolog(&included)?, resolve, depth + 1)?);
+		rest = &rest[end..];
+	}
+	result.push_str(rest);
+	Ok(result)
+}
+
+impl TargetDescription
+{
+	/// Parse a target description, using `resolve` to fetch the contents of any documents it includes
+	pub fn parse(text: &str, mut resolve: impl FnMut(&str) -> Result<String>) -> Result<Self>
+	{
+		let text = expand_includes(text, &mut resolve, 0)?;
+		let document = parse_document(&text)?;
+		let root = document.root_element();
+		if !root.has_tag_name("target") {
+			return Err(eyre!("Not a target description, root element is <{}>", root.tag_name().name()));
+		}
+		let architecture = root
+			.children()
+			.find(|child| child.has_tag_name("architecture"))
+			.and_then(|node| node.text())
+			.map(|architecture| architecture.trim().into());
+
+		// Registers without an explicit number follow on from the one before, across features
+		let mut registers: Vec<Register> = Vec::new();
+		for feature in root.children().filter(|child| child.has_tag_name("feature")) {
+			let feature_name = required_attribute(feature, "name")?;
+			for node in feature.children().filter(|child| child.has_tag_name("reg")) {
+				let number = match node.attribute("regnum") {
+					Some(number) => parse_xml_number(number)? as usize,
+					None => registers.last().map_or(0, |register| register.number + 1),
+				};
+				registers.push(Register {
+					name: required_attribute(node, "name")?.into(),
+					number,
+					bitsize: parse_xml_number(required_attribute(node, "bitsize")?)? as u32,
+					kind: node.attribute("type").unwrap_or("int").into(),
+					group: node.attribute("group").map(Into::into),
+					feature: feature_name.into(),
+				});
+			}
+		}
+		Ok(Self {
+			architecture,
+			registers,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn memory_map()
+	{
+		let map = MemoryMap::parse(concat!(
+			"<?xml version=\"1.0\"?>",
+			"<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" ",
+			"\"http://sourceware.org/gdb/gdb-memory-map.dtd\">",
+			"<memory-map>",
+			"<memory type=\"flash\" start=\"0x8000000\" length=\"0x20000\">",
+			"<property name=\"blocksize\">0x400</property>",
+			"</memory>",
+			"<memory type=\"ram\" start=\"0x20000000\" length=\"0x5000\"/>",
+			"</memory-map>",
+		))
+		.unwrap();
+		assert_eq!(
+			map.regions,
+			[
+				MemoryRegion {
+					kind: MemoryKind::Flash {
+						block_size: 0x400,
+					},
+					start: 0x08000000,
+					length: 0x20000,
+				},
+				MemoryRegion {
+					kind: MemoryKind::Ram,
+					start: 0x20000000,
+					length: 0x5000,
+				},
+			]
+		);
+		assert_eq!(map.region_containing(0x20004fff), Some(&map.regions[1]));
+		assert_eq!(map.region_containing(0x20005000), None);
+		assert_eq!(MemoryMap::parse(&map.to_xml()).unwrap(), map);
+	}
+
+	#[test]
+	fn target_description()
+	{
+		let target = concat!(
+			"<?xml version=\"1.0\"?>",
+			"<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+			"<target version=\"1.0\">",
+			"<architecture>arm</architecture>",
+			"<xi:include href=\"core.xml\"/>",
+			"<feature name=\"org.gnu.gdb.arm.m-system\">",
+			"<reg name=\"msp\" bitsize=\"32\" regnum=\"25\" type=\"data_ptr\" group=\"system\"/>",
+			"</feature>",
+			"</target>",
+		);
+		let core = concat!(
+			"<?xml version=\"1.0\"?>",
+			"<feature name=\"org.gnu.gdb.arm.m-profile\">",
+			"<reg name=\"r0\" bitsize=\"32\"/>",
+			"<reg name=\"r1\" bitsize=\"32\" group=\"general\"/>",
+			"</feature>",
+		);
+		let description = TargetDescription::parse(target, |href| {
+			assert_eq!(href, "core.xml");
+			Ok(core.into())
+		})
+		.unwrap();
+		assert_eq!(description.architecture.as_deref(), Some("arm"));
+		let registers: Vec<_> = description
+			.registers
+			.iter()
+			.map(|register| (register.name.as_str(), register.number, register.group.as_deref()))
+			.collect();
+		assert_eq!(registers, [("r0", 0, None), ("r1", 1, Some("general")), ("msp", 25, Some("system"))]);
+	}
+}
@@ -9,6 +9,7 @@ use color_eyre::eyre::Result;
 use crate::bmp::BmpDevice;
 use crate::serial::bmd_rsp::BmdRspInterface;
 use crate::serial::gdb_rsp::GdbRspInterface;
+use crate::serial::uart::UartInterface;
 
 pub struct ProbeInterface
 {
@@ -17,6 +18,10 @@ pub struct ProbeInterface
 
 impl ProbeInterface
 {
+	/// USB interface numbers of the probe's CDC-ACM interfaces - the GDB server and the auxiliary UART
+	const GDB_INTERFACE: u8 = 0;
+	const UART_INTERFACE: u8 = 2;
+
 	pub fn from_device(probe: &BmpDevice) -> Result<Self>
 	{
 		Ok(Self {
@@ -26,12 +31,17 @@ impl ProbeInterface
 
 	pub fn gdb_interface(&self) -> Result<GdbRspInterface>
 	{
-		GdbRspInterface::from_path(&self.probe_interface()?)
+		GdbRspInterface::from_path(&self.probe_interface(Self::GDB_INTERFACE)?)
 	}
 
 	pub fn bmd_interface(&self) -> Result<BmdRspInterface>
 	{
-		BmdRspInterface::from_path(&self.probe_interface()?)
+		BmdRspInterface::from_path(&self.probe_interface(Self::GDB_INTERFACE)?)
+	}
+
+	pub fn uart_interface(&self, baud_rate: u32) -> Result<UartInterface>
+	{
+		UartInterface::from_path(&self.probe_interface(Self::UART_INTERFACE)?, baud_rate)
 	}
 }
 
@@ -43,13 +53,15 @@ impl ProbeInterface
 	const BMD_IDSTRING_BLACKSHERE: &str = "usb-Black_Sphere_Technologies_Black_Magic_Probe";
 	const DEVICE_BY_ID: &str = "/dev/serial/by-id";
 
-	/// Locate the GDB serial interface associated with the probe of the given serial number
-	fn probe_interface(&self) -> Result<PathBuf>
+	/// Locate the serial interface with the given USB interface number on the probe of the given serial number
+	fn probe_interface(&self, interface: u8) -> Result<PathBuf>
 	{
 		use std::fs::read_dir;
 
 		use color_eyre::eyre::eyre;
 
+		let suffix = format!("-if{:02}", interface);
+
 		// Start by opening the by-id serial interfaces device tree
 		let dir = read_dir(Self::DEVICE_BY_ID)?;
 		// Read through all the entries and try to locate one that has a serial number match
@@ -65,11 +77,11 @@ impl ProbeInterface
 			};
 
 			// Check to see if this entry represents a BMD based probe
-			if !Self::device_is_bmd_gdb_port(file_name) {
+			if !Self::device_is_bmd_port(file_name, &suffix) {
 				continue;
 			}
 			// It does! Horray, now check if we have an entry with a matching serial number
-			if self.serial_matches(file_name) {
+			if self.serial_matches(file_name, &suffix) {
 				// We have a match! Convert the entry into a path and return then
 				return Ok(entry.path());
 			}
@@ -78,17 +90,17 @@ impl ProbeInterface
 		Err(eyre!("Failed to locate a device matching serial number {}", self.serial_number))
 	}
 
-	fn device_is_bmd_gdb_port(file_name: &str) -> bool
+	fn device_is_bmd_port(file_name: &str, suffix: &str) -> bool
 	{
 		// Check if the device file name fragment starts with one of the known
 		// by-id prefixes and ends with the right interface suffix
 		(file_name.starts_with(Self::BMD_IDSTRING_BLACKSHERE) ||
 			file_name.starts_with(Self::BMD_IDSTRING_BLACKMAGIC) ||
 			file_name.starts_with(Self::BMD_IDSTRING_1BITSQUARED)) &&
-			file_name.ends_with("-if00")
+			file_name.ends_with(suffix)
 	}
 
-	fn serial_matches(&self, file_name: &str) -> bool
+	fn serial_matches(&self, file_name: &str, suffix: &str) -> bool
 	{
 		// Start by trying to find the last _ just before the serial string
 		let last_underscore = if let Some(pos) = file_name.rfind('_') {
@@ -98,8 +110,8 @@ impl ProbeInterface
 		};
 		// Having done that, extract the slice representing the serial number for this device
 		let begin = last_underscore + 1;
-		// This represents one past the last byte of the serial number string, chopping off `-if00`
-		let end = file_name.len() - 5;
+		// This represents one past the last byte of the serial number string, chopping off the interface suffix
+		let end = file_name.len() - suffix.len();
 		// Create the slice and compare to the stored serial number
 		file_name[begin..end] == self.serial_number
 	}
@@ -111,15 +123,15 @@ impl ProbeInterface
 	const PRODUCT_ID_BMP: u16 = 0x6018;
 	const VENDOR_ID_BMP: u16 = 0x1d50;
 
-	/// Locate the GDB serial interface associated with the probe of the given serial number
-	fn probe_interface(&self) -> Result<PathBuf>
+	/// Locate the serial interface with the given USB interface number on the probe of the given serial number
+	fn probe_interface(&self, interface: u8) -> Result<PathBuf>
 	{
 		// Try to locate the probe's instance ID from the registry
 		let serial_path = format!("\\{}", self.serial_number);
 		let prefix = Self::read_key_from_path(&serial_path, "ParentIdPrefix")?;
 		// Having grabbed the instance ID, read out the device path that matches up
-		// for interface 0, giving us a `\\.\COMn` name to use with the file APIs
-		let parameter_path = format!("&MI_00\\{}&0000\\Device Parameters", prefix);
+		// for the interface, giving us a `\\.\COMn` name to use with the file APIs
+		let parameter_path = format!("&MI_{:02X}\\{}&{:04X}\\Device Parameters", interface, prefix, interface);
 		let port_name = Self::read_key_from_path(&parameter_path, "PortName")?;
 		// Return by converting the string to a PathBuf
 		if port_name.starts_with("\\\\.\\") {
@@ -153,9 +165,10 @@ impl ProbeInterface
 #[cfg(target_os = "macos")]
 impl ProbeInterface
 {
-	/// Locate the GDB serial interface associated with the probe of the given serial number
-	fn probe_interface(&self) -> Result<PathBuf>
+	/// Locate the serial interface with the given USB interface number on the probe of the given serial number
+	fn probe_interface(&self, interface: u8) -> Result<PathBuf>
 	{
-		Ok(format!("/dev/cu.usbmodem{}1", self.serial_number).into())
+		// The device is named for the probe's serial number, followed by one more than the interface number
+		Ok(format!("/dev/cu.usbmodem{}{}", self.serial_number, interface + 1).into())
 	}
 }
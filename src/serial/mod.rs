@@ -3,6 +3,11 @@
 // SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
 
 pub mod bmd_rsp;
+pub mod gdb_flash;
 pub mod gdb_rsp;
+pub mod gdb_target;
 pub mod interface;
+mod port;
 pub mod remote;
+pub mod transport;
+pub mod uart;
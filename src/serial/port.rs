@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Configuration of the serial ports (CDC-ACM interfaces) probes expose
+
+use std::fs::File;
+
+use color_eyre::eyre::Result;
+use log::trace;
+
+/// Configure a freshly opened serial port handle for talking to a probe: raw 8-bit data, no flow
+/// control, and reads that return after a short timeout rather than blocking forever
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub(crate) fn configure(handle: &File) -> Result<()>
+{
+	use std::os::fd::AsRawFd;
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	use termios::os::linux::CRTSCTS;
+	#[cfg(target_os = "macos")]
+	use termios::os::macos::CRTSCTS;
+	use termios::*;
+
+	// Extract the current termios config for the handle
+	let fd = handle.as_raw_fd();
+	let mut attrs = Termios::from_fd(fd)?;
+
+	// Reconfigure the attributes for 8-bit characters, no CTS/RTS hardware control flow,
+	// w/ no model control signalling
+	attrs.c_cflag &= !(CSIZE | CSTOPB);
+	attrs.c_cflag |= CS8 | CLOCAL | CREAD | CRTSCTS;
+	// Disable break character handling and turn off XON/XOFF based control flow
+	attrs.c_iflag &= !(IGNBRK | IXON | IXOFF | IXANY);
+	// Disable all signaling, echo, remapping and delays
+	attrs.c_lflag = 0;
+	attrs.c_oflag = 0;
+	// Make reads not block, and set 0.5s for read timeout
+	attrs.c_cc[VMIN] = 0;
+	attrs.c_cc[VTIME] = 5;
+
+	// Reconfigure the handle with the new termios config
+	tcsetattr(fd, TCSANOW, &attrs)?;
+
+	// Let the caller know that we successfully got done
+	trace!("Configured comms handle to probe remote serial interface");
+	Ok(())
+}
+
+/// Set the baud rate of a serial port, which for a probe's UART interface sets the rate its UART runs at
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub(crate) fn set_baud_rate(handle: &File, baud_rate: u32) -> Result<()>
+{
+	use std::os::fd::AsRawFd;
+
+	use termios::{TCSANOW, Termios, cfsetspeed, tcsetattr};
+
+	let fd = handle.as_raw_fd();
+	let mut attrs = Termios::from_fd(fd)?;
+	cfsetspeed(&mut attrs, baud_rate_speed(baud_rate)?)?;
+	tcsetattr(fd, TCSANOW, &attrs)?;
+	trace!("Set comms handle baud rate to {}", baud_rate);
+	Ok(())
+}
+
+/// Linux only understands a fixed set of baud rates through termios, given by constants rather than numerically
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn baud_rate_speed(baud_rate: u32) -> Result<termios::speed_t>
+{
+	use color_eyre::eyre::eyre;
+	use termios::os::linux::{
+		B1200, B2400, B4800, B9600, B19200, B38400, B57600, B115200, B230400, B460800, B500000, B576000, B921600,
+		B1000000, B1152000, B1500000, B2000000, B2500000, B3000000, B3500000, B4000000,
+	};
+
+	Ok(match baud_rate {
+		1200 => B1200,
+		2400 => B2400,
+		4800 => B4800,
+		9600 => B9600,
+		19200 => B19200,
+		38400 => B38400,
+		57600 => B57600,
+		115200 => B115200,
+		230400 => B230400,
+		460800 => B460800,
+		500000 => B500000,
+		576000 => B576000,
+		921600 => B921600,
+		1000000 => B1000000,
+		1152000 => B1152000,
+		1500000 => B1500000,
+		2000000 => B2000000,
+		2500000 => B2500000,
+		3000000 => B3000000,
+		3500000 => B3500000,
+		4000000 => B4000000,
+		_ => return Err(eyre!("Unsupported baud rate {}", baud_rate)),
+	})
+}
+
+#[cfg(target_os = "macos")]
+fn baud_rate_speed(baud_rate: u32) -> Result<termios::speed_t>
+{
+	Ok(baud_rate as termios::speed_t)
+}
+
+/// Configure a freshly opened serial port handle for talking to a probe: raw 8-bit data, no flow
+/// control, and reads that return immediately with whatever data is available
+#[cfg(target_os = "windows")]
+pub(crate) fn configure(handle: &File) -> Result<()>
+{
+	use std::mem::MaybeUninit;
+	use std::os::windows::io::AsRawHandle;
+
+	use windows::Win32::Devices::Communication::{
+		COMMTIMEOUTS, DCB, GetCommState, NOPARITY, PURGE_RXCLEAR, PurgeComm, SetCommState, SetCommTimeouts,
+	};
+	use windows::Win32::Foundation::HANDLE;
+
+	// Bits of the DCB structure's bitfield member
+	const DCB_CHECK_PARITY: u32 = 1 << 1;
+	const DCB_DSR_SENSITIVE: u32 = 1 << 6;
+	const DCB_DTR_CONTROL_ENABLE: u32 = 1 << 4;
+	const DCB_DTR_CONTROL_MASK: u32 = 3 << 4;
+	const DCB_RTS_CONTROL_DISABLE: u32 = 0 << 12;
+	const DCB_RTS_CONTROL_MASK: u32 = 3 << 12;
+	const DCB_USE_CTS: u32 = 1 << 2;
+	const DCB_USE_DSR: u32 = 1 << 3;
+	const DCB_USE_XOFF: u32 = 1 << 9;
+	const DCB_USE_XON: u32 = 1 << 8;
+
+	// Extract the current CommState for the handle
+	let handle = HANDLE(handle.as_raw_handle());
+	let mut serial_params = MaybeUninit::<DCB>::uninit();
+	let mut serial_params = unsafe {
+		GetCommState(handle, serial_params.as_mut_ptr())?;
+		serial_params.assume_init()
+	};
+
+	// Reconfigure and adjust device state to disable hardware flow control and
+	// get it into the right mode for communications to work properly
+	serial_params.ByteSize = 8;
+	serial_params.Parity = NOPARITY;
+	// The windows-rs crate exposes the bitfield parameters to us as a nebulous thing..
+	// we hold local definitions for each of the values so we can turn them on and off
+	// appropriately here. See <https://learn.microsoft.com/en-us/windows/win32/api/winbase/ns-winbase-dcb>
+	// for where these values come from. When reading this particular bitfield, assume LSb to MSb
+	// as one traverses down the structure.
+	serial_params._bitfield &= !(DCB_CHECK_PARITY |
+		DCB_USE_CTS |
+		DCB_USE_DSR |
+		DCB_DTR_CONTROL_MASK |
+		DCB_DSR_SENSITIVE |
+		DCB_USE_XOFF |
+		DCB_USE_XON |
+		DCB_RTS_CONTROL_MASK);
+	serial_params._bitfield |= DCB_DTR_CONTROL_ENABLE | DCB_RTS_CONTROL_DISABLE;
+
+	// Reconfigure the handle with the new communications state
+	unsafe { SetCommState(handle, &serial_params)? };
+
+	let timeouts = COMMTIMEOUTS {
+		// Turn off read timeouts so that ReadFile() underlying File's read calls instantly returns
+		// even if there's o data waiting (we implement our own mechanism below for that case as we
+		// only want to wait if we get no data)
+		ReadIntervalTimeout: u32::MAX,
+		ReadTotalTimeoutMultiplier: 0,
+		ReadTotalTimeoutConstant: 0,
+		// Configure an exactly 100ms write timeout - we want this triggering to be fatal as something
+		// has gone very wrong if we ever hit this.
+		WriteTotalTimeoutMultiplier: 0,
+		WriteTotalTimeoutConstant: 100,
+	};
+	unsafe {
+		SetCommTimeouts(handle, &timeouts)?;
+
+		// Having adjusted the line state, discard anything sat in the receive buffer
+		PurgeComm(handle, PURGE_RXCLEAR)?;
+	}
+
+	// Let the caller know that we successfully got done
+	trace!("Configured comms handle to probe remote serial interface");
+	Ok(())
+}
+
+/// Set the baud rate of a serial port, which for a probe's UART interface sets the rate its UART runs at
+#[cfg(target_os = "windows")]
+pub(crate) fn set_baud_rate(handle: &File, baud_rate: u32) -> Result<()>
+{
+	use std::mem::MaybeUninit;
+	use std::os::windows::io::AsRawHandle;
+
+	use windows::Win32::Devices::Communication::{DCB, GetCommState, SetCommState};
+	use windows::Win32::Foundation::HANDLE;
+
+	let handle = HANDLE(handle.as_raw_handle());
+	let mut serial_params = MaybeUninit::<DCB>::uninit();
+	let mut serial_params = unsafe {
+		GetCommState(handle, serial_params.as_mut_ptr())?;
+		serial_params.assume_init()
+	};
+	serial_params.BaudRate = baud_rate;
+	unsafe { SetCommState(handle, &serial_params)? };
+	trace!("Set comms handle baud rate to {}", baud_rate);
+	Ok(())
+}
@@ -3,8 +3,77 @@
 // SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
 
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::serial::remote::{BmdAdiV5Protocol, TargetAddr64};
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, trace};
+
+use crate::serial::remote::{Align, BmdAdiV5Protocol, TargetAddr64};
+
+/// Low-level access direction for a write to a DP or AP register
+pub const ADIV5_LOW_WRITE: u8 = 0;
+/// Low-level access direction for a read of a DP or AP register
+pub const ADIV5_LOW_READ: u8 = 1;
+/// Address flag marking a register address as being an AP register rather than a DP one
+pub const ADIV5_APNDP: u16 = 0x100;
+
+/// DP identification register (read-only)
+pub const ADIV5_DP_DPIDR: u16 = 0x0;
+/// DP abort register (write-only)
+pub const ADIV5_DP_ABORT: u16 = 0x0;
+/// DP control and status register
+pub const ADIV5_DP_CTRLSTAT: u16 = 0x4;
+/// DP AP and register bank selection register
+pub const ADIV5_DP_SELECT: u16 = 0x8;
+/// DP read buffer register
+pub const ADIV5_DP_RDBUFF: u16 = 0xc;
+
+/// Build the address of an AP register from its offset in the AP's register space
+pub const fn adiv5_ap_reg(offset: u16) -> u16
+{
+	ADIV5_APNDP | offset
+}
+
+/// AP control and status word register
+pub const ADIV5_AP_CSW: u16 = adiv5_ap_reg(0x00);
+/// AP transfer address register
+pub const ADIV5_AP_TAR: u16 = adiv5_ap_reg(0x04);
+/// AP data read/write register
+pub const ADIV5_AP_DRW: u16 = adiv5_ap_reg(0x0c);
+/// AP configuration register
+pub const ADIV5_AP_CFG: u16 = adiv5_ap_reg(0xf4);
+/// AP debug base address register
+pub const ADIV5_AP_BASE: u16 = adiv5_ap_reg(0xf8);
+/// AP identification register
+pub const ADIV5_AP_IDR: u16 = adiv5_ap_reg(0xfc);
+
+const ADIV5_DP_ABORT_STKCMPCLR: u32 = 1 << 1;
+const ADIV5_DP_ABORT_STKERRCLR: u32 = 1 << 2;
+const ADIV5_DP_ABORT_WDERRCLR: u32 = 1 << 3;
+const ADIV5_DP_ABORT_ORUNERRCLR: u32 = 1 << 4;
+
+const ADIV5_DP_CTRLSTAT_CSYSPWRUPACK: u32 = 1 << 31;
+const ADIV5_DP_CTRLSTAT_CSYSPWRUPREQ: u32 = 1 << 30;
+const ADIV5_DP_CTRLSTAT_CDBGPWRUPACK: u32 = 1 << 29;
+const ADIV5_DP_CTRLSTAT_CDBGPWRUPREQ: u32 = 1 << 28;
+
+const ADIV5_AP_CSW_SIZE_MASK: u32 = 0x7;
+const ADIV5_AP_CSW_ADDRINC_MASK: u32 = 3 << 4;
+const ADIV5_AP_CSW_DBGSWENABLE: u32 = 1 << 31;
+
+const ADIV5_AP_CFG_LARGE_ADDRESS: u32 = 1 << 1;
+
+/// AP IDR class value for a memory access port
+const ADIV5_AP_IDR_CLASS_MEM: u8 = 8;
+
+/// The AP has a system memory bus attached
+pub const ADIV5_AP_FLAGS_HAS_MEM: u8 = 1 << 0;
+/// The AP uses 64-bit addressing
+pub const ADIV5_AP_FLAGS_64BIT: u8 = 1 << 1;
+
+/// How many APs in a row have to come back invalid before we consider enumeration done
+const ADIV5_MAX_INVALID_APS: usize = 8;
 
 /// The ADIv5 debug port associated with a JTAG TAP or a SWD interface drop of an ARM debug based device
 #[allow(unused)]
@@ -74,3 +143,325 @@ pub struct AdiV6AccessPort
 	base: AdiV5AccessPort,
 	address: TargetAddr64,
 }
+
+/// Convert the JEP-106 designer code fields found in DPIDR and AP IDR values into a single
+/// value with the continuation code in the upper byte and the identity code in the lower
+pub(crate) fn designer_from_fields(continuation: u32, identity: u32) -> u16
+{
+	(((continuation & 0xf) << 8) | (identity & 0x7f)) as u16
+}
+
+impl AdiV5DebugPort
+{
+	/// Bring up the DP at `dev_index` - this reads its DPIDR, clears any sticky errors,
+	/// and requests the debug and system power domains are powered up
+	pub fn new(remote: Arc<dyn BmdAdiV5Protocol>, dev_index: u8) -> Result<Self>
+	{
+		let mut result = Self {
+			dev_index,
+			fault: 0,
+			quirks: 0,
+			version: 0,
+			targetsel: 0,
+			designer_code: 0,
+			partno: 0,
+			target_designer_code: 0,
+			target_partno: 0,
+			address_width: 32,
+			remote,
+		};
+
+		// Read the DP's identification register and decode it
+		let dpidr = result.read(ADIV5_DP_DPIDR)?;
+		if dpidr == 0 || dpidr == 0xffffffff {
+			return Err(eyre!("Invalid DPIDR {:08x} read, no ADIv5 DP found", dpidr));
+		}
+		result.version = ((dpidr >> 12) & 0xf) as u8;
+		result.designer_code = designer_from_fields(dpidr >> 8, dpidr >> 1);
+		result.partno = ((dpidr >> 20) & 0xff) as u16;
+		debug!(
+			"DP {}: DPIDR {:08x} (DPv{} designer {:03x} partno {:02x})",
+			dev_index, dpidr, result.version, result.designer_code, result.partno
+		);
+
+		// Make sure any previous sticky errors don't upset things
+		result.clear_errors()?;
+		// Now power the debug and system domains up
+		result.power_up()?;
+		Ok(result)
+	}
+
+	/// The index of this DP on the JTAG scan chain or SWD multi-drop bus
+	pub fn dev_index(&self) -> u8
+	{
+		self.dev_index
+	}
+
+	/// The DP architecture version implemented
+	pub fn version(&self) -> u8
+	{
+		self.version
+	}
+
+	/// The JEP-106 code of the DP's designer
+	pub fn designer_code(&self) -> u16
+	{
+		self.designer_code
+	}
+
+	/// The DP's part number
+	pub fn partno(&self) -> u16
+	{
+		self.partno
+	}
+
+	/// Read a DP register
+	pub fn read(&self, addr: u16) -> Result<u32>
+	{
+		self.remote.dp_read(self, addr)
+	}
+
+	/// Write a DP register
+	pub fn write(&self, addr: u16, value: u32) -> Result<()>
+	{
+		self.remote.raw_access(self, ADIV5_LOW_WRITE, addr, value)?;
+		Ok(())
+	}
+
+	/// Clear all the sticky error flags in the DP
+	pub fn clear_errors(&self) -> Result<()>
+	{
+		self.write(
+			ADIV5_DP_ABORT,
+			ADIV5_DP_ABORT_STKCMPCLR | ADIV5_DP_ABORT_STKERRCLR | ADIV5_DP_ABORT_WDERRCLR | ADIV5_DP_ABORT_ORUNERRCLR,
+		)
+	}
+
+	fn power_up(&self) -> Result<()>
+	{
+		const ACKS: u32 = ADIV5_DP_CTRLSTAT_CSYSPWRUPACK | ADIV5_DP_CTRLSTAT_CDBGPWRUPACK;
+
+		self.write(
+			ADIV5_DP_CTRLSTAT,
+			ADIV5_DP_CTRLSTAT_CSYSPWRUPREQ | ADIV5_DP_CTRLSTAT_CDBGPWRUPREQ,
+		)?;
+		// Wait up to 250ms for the power domains to acknowledge the request
+		let start = Instant::now();
+		loop {
+			let ctrlstat = self.read(ADIV5_DP_CTRLSTAT)?;
+			if ctrlstat & ACKS == ACKS {
+				trace!("DP {}: power-up acknowledged", self.dev_index);
+				return Ok(());
+			}
+			if start.elapsed() > Duration::from_millis(250) {
+				return Err(eyre!(
+					"Timeout waiting for DP {} to power up (CTRL/STAT {:08x})",
+					self.dev_index,
+					ctrlstat
+				));
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	/// Walk the possible APs on this DP, building access port objects for each valid one found
+	pub fn enumerate_aps(self: &Arc<Self>) -> Result<Vec<AdiV5AccessPort>>
+	{
+		let mut access_ports = Vec::new();
+		let mut invalid_aps = 0;
+		for index in 0..=255u8 {
+			if invalid_aps >= ADIV5_MAX_INVALID_APS {
+				break;
+			}
+			match AdiV5AccessPort::new(self.clone(), index)? {
+				Some(ap) => {
+					invalid_aps = 0;
+					access_ports.push(ap);
+				},
+				None => invalid_aps += 1,
+			}
+		}
+		debug!("DP {}: found {} APs", self.dev_index, access_ports.len());
+		Ok(access_ports)
+	}
+}
+
+impl AdiV5AccessPort
+{
+	/// Try to bring up the AP at `index` on the given DP, returning None if there is no valid AP there
+	pub fn new(dp: Arc<AdiV5DebugPort>, index: u8) -> Result<Option<Self>>
+	{
+		let mut result = Self {
+			dp,
+			index,
+			flags: 0,
+			idr: 0,
+			base: 0,
+			csw: 0,
+			cortexm_demcr: 0,
+			designer_code: 0,
+			partno: 0,
+		};
+
+		// Read the AP's identification register, and if it reads as 0 then there's no AP here
+		result.idr = match result.read(ADIV5_AP_IDR) {
+			Ok(idr) => idr,
+			Err(error) => {
+				// A fault here indicates there's no AP, so clean up and report that
+				trace!("AP {}: IDR read failed: {}", index, error);
+				result.dp.clear_errors()?;
+				return Ok(None);
+			},
+		};
+		if result.idr == 0 {
+			return Ok(None);
+		}
+		result.designer_code = designer_from_fields(result.idr >> 24, result.idr >> 17);
+
+		// If this is a MEM-AP, find out about the memory bus attached
+		if result.is_mem_ap() {
+			result.flags |= ADIV5_AP_FLAGS_HAS_MEM;
+			let cfg = result.read(ADIV5_AP_CFG)?;
+			let base_low = result.read(ADIV5_AP_BASE)?;
+			if cfg & ADIV5_AP_CFG_LARGE_ADDRESS != 0 {
+				result.flags |= ADIV5_AP_FLAGS_64BIT;
+				// The upper half of BASE lives at 0xf0 for large address APs
+				let base_high = result.read(adiv5_ap_reg(0xf0))?;
+				result.base = ((base_high as u64) << 32) | base_low as u64;
+			} else {
+				result.base = base_low as u64;
+			}
+			// Grab the CSW value to use, dropping the size and auto-increment fields as the
+			// probe fills these in for each access it does
+			let csw = result.read(ADIV5_AP_CSW)?;
+			result.csw = (csw & !(ADIV5_AP_CSW_SIZE_MASK | ADIV5_AP_CSW_ADDRINC_MASK)) | ADIV5_AP_CSW_DBGSWENABLE;
+		}
+
+		debug!(
+			"AP {}: IDR {:08x} (designer {:03x} class {:x} type {:x}) base {:08x}",
+			index,
+			result.idr,
+			result.designer_code,
+			result.class(),
+			result.ap_type(),
+			result.base
+		);
+		Ok(Some(result))
+	}
+
+	/// The debug port this access port belongs to
+	pub fn dp(&self) -> &Arc<AdiV5DebugPort>
+	{
+		&self.dp
+	}
+
+	/// The index of this AP on its DP
+	pub fn index(&self) -> u8
+	{
+		self.index
+	}
+
+	/// The raw AP identification register value
+	pub fn idr(&self) -> u32
+	{
+		self.idr
+	}
+
+	/// The JEP-106 code of the AP's designer
+	pub fn designer_code(&self) -> u16
+	{
+		self.designer_code
+	}
+
+	/// The AP class from the IDR (8 for a MEM-AP)
+	pub fn class(&self) -> u8
+	{
+		((self.idr >> 13) & 0xf) as u8
+	}
+
+	/// The AP type from the IDR (for a MEM-AP, which bus is attached)
+	pub fn ap_type(&self) -> u8
+	{
+		(self.idr & 0xf) as u8
+	}
+
+	/// Whether this AP is a memory access port
+	pub fn is_mem_ap(&self) -> bool
+	{
+		self.class() == ADIV5_AP_IDR_CLASS_MEM
+	}
+
+	/// Whether this AP has a system memory bus attached
+	pub fn has_memory(&self) -> bool
+	{
+		self.flags & ADIV5_AP_FLAGS_HAS_MEM != 0
+	}
+
+	/// The base address of the debug component (ROM table) associated with this AP
+	pub fn base(&self) -> TargetAddr64
+	{
+		self.base
+	}
+
+	/// The Control and Status Word value used for memory accesses through this AP
+	pub fn csw(&self) -> u32
+	{
+		self.csw
+	}
+
+	/// Read an AP register
+	pub fn read(&self, addr: u16) -> Result<u32>
+	{
+		self.dp.remote.ap_read(self, addr)
+	}
+
+	/// Write an AP register
+	pub fn write(&self, addr: u16, value: u32) -> Result<()>
+	{
+		self.dp.remote.ap_write(self, addr, value)
+	}
+
+	/// Read a block of target memory via this AP
+	pub fn mem_read(&self, dest: &mut [u8], src: TargetAddr64) -> Result<()>
+	{
+		self.dp.remote.mem_read(self, dest, src)
+	}
+
+	/// Write a block of target memory via this AP, using the largest access size the alignment allows
+	pub fn mem_write(&self, dest: TargetAddr64, src: &[u8]) -> Result<()>
+	{
+		self.mem_write_aligned(dest, src, Align::for_access(dest, src.len()))
+	}
+
+	/// Write a block of target memory via this AP with a specific access size
+	pub fn mem_write_aligned(&self, dest: TargetAddr64, src: &[u8], align: Align) -> Result<()>
+	{
+		self.dp.remote.mem_write(self, dest, src, align)
+	}
+
+	/// Read a single 32-bit word of target memory
+	pub fn mem_read_u32(&self, src: TargetAddr64) -> Result<u32>
+	{
+		let mut value = [0u8; 4];
+		self.mem_read(&mut value, src)?;
+		Ok(u32::from_le_bytes(value))
+	}
+
+	/// Write a single 32-bit word of target memory
+	pub fn mem_write_u32(&self, dest: TargetAddr64, value: u32) -> Result<()>
+	{
+		self.mem_write_aligned(dest, &value.to_le_bytes(), Align::As32Bit)
+	}
+
+	/// Write a single 16-bit half-word of target memory
+	pub fn mem_write_u16(&self, dest: TargetAddr64, value: u16) -> Result<()>
+	{
+		self.mem_write_aligned(dest, &value.to_le_bytes(), Align::As16Bit)
+	}
+
+	/// Write a single byte of target memory
+	pub fn mem_write_u8(&self, dest: TargetAddr64, value: u8) -> Result<()>
+	{
+		self.mem_write_aligned(dest, &[value], Align::As8Bit)
+	}
+}
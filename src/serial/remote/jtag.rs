@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use color_eyre::eyre::{Result, eyre};
+use log::debug;
+
+use crate::serial::remote::{BmdJtagProtocol, JtagDev};
+
+/// The most devices we'll look for on a scan chain before deciding something is wrong with it
+const JTAG_MAX_DEVICES: usize = 32;
+/// The longest instruction register we'll accept a device as having
+const JTAG_MAX_IR_LENGTH: u8 = 32;
+/// Instruction register value that selects BYPASS on every device (all ones)
+pub const JTAG_IR_BYPASS: u32 = 0xffffffff;
+
+// TMS sequences (sent LSb first) for moving around the TAP state machine
+/// Run-Test/Idle -> Select-DR-Scan -> Capture-DR -> Shift-DR
+const JTAG_IDLE_TO_SHIFT_DR: (u32, usize) = (0b001, 3);
+/// Run-Test/Idle -> Select-DR-Scan -> Select-IR-Scan -> Capture-IR -> Shift-IR
+const JTAG_IDLE_TO_SHIFT_IR: (u32, usize) = (0b0011, 4);
+/// Exit1-xR -> Update-xR -> Run-Test/Idle
+const JTAG_EXIT_TO_IDLE: (u32, usize) = (0b01, 2);
+
+impl JtagDev
+{
+	/// The device's IDCODE, or 0 if the device has no IDCODE register
+	pub fn idcode(&self) -> u32
+	{
+		self.idcode
+	}
+
+	/// The length of the device's instruction register
+	pub fn ir_len(&self) -> u8
+	{
+		self.ir_len
+	}
+
+	/// Load a new instruction into the device's instruction register, putting every other device on the
+	/// chain into BYPASS. The TAP must be in, and is left in, Run-Test/Idle.
+	pub fn write_ir(&self, jtag: &dyn BmdJtagProtocol, ir: u32) -> Result<()>
+	{
+		let (tms, cycles) = JTAG_IDLE_TO_SHIFT_IR;
+		jtag.tap_tms_seq(tms, cycles)?;
+		shift_ones(jtag, false, self.ir_prescan as usize)?;
+		jtag.tap_tdi_seq(self.ir_postscan == 0, &ir.to_le_bytes(), self.ir_len as usize)?;
+		shift_ones(jtag, true, self.ir_postscan as usize)?;
+		let (tms, cycles) = JTAG_EXIT_TO_IDLE;
+		jtag.tap_tms_seq(tms, cycles)
+	}
+
+	/// Shift `clock_cycles` bits of data through the device's data register, capturing the data shifted
+	/// out into `data_out` if given. The TAP must be in, and is left in, Run-Test/Idle.
+	pub fn shift_dr(
+		&self,
+		jtag: &dyn BmdJtagProtocol,
+		data_out: Option<&mut [u8]>,
+		data_in: &[u8],
+		clock_cycles: usize,
+	) -> Result<()>
+	{
+		let (tms, cycles) = JTAG_IDLE_TO_SHIFT_DR;
+		jtag.tap_tms_seq(tms, cycles)?;
+		// Every other device is in BYPASS, so has a 1-bit data register to step over
+		shift_ones(jtag, false, self.dr_prescan as usize)?;
+		jtag.tap_tdi_tdo_seq(data_out, self.dr_postscan == 0, Some(data_in), clock_cycles)?;
+		shift_ones(jtag, true, self.dr_postscan as usize)?;
+		let (tms, cycles) = JTAG_EXIT_TO_IDLE;
+		jtag.tap_tms_seq(tms, cycles)
+	}
+}
+
+/// Shift ones into the chain for `clock_cycles` cycles, for stepping over the registers of other devices
+fn shift_ones(jtag: &dyn BmdJtagProtocol, final_tms: bool, clock_cycles: usize) -> Result<()>
+{
+	if clock_cycles == 0 {
+		return Ok(());
+	}
+	jtag.tap_tdi_seq(final_tms, &vec![0xff; clock_cycles.div_ceil(8)], clock_cycles)
+}
+
+/// Shift a single bit through the chain, shifting a one in and returning the bit shifted out
+fn shift_bit(jtag: &dyn BmdJtagProtocol) -> Result<bool>
+{
+	let mut bit = [0u8];
+	jtag.tap_tdi_tdo_seq(Some(&mut bit), false, Some(&[1]), 1)?;
+	Ok(bit[0] & 1 != 0)
+}
+
+/// Scan the JTAG chain, returning the devices found on it in order from the one nearest TDO.
+///
+/// IDCODEs are read from the data registers straight out of reset, where every device either has
+/// its IDCODE (which always has bit 0 set) or BYPASS (a single 0 bit) selected. Instruction register
+/// lengths are then worked out from the capture value every device has to load into them, which
+/// starts with the bits 1, 0. This assumes the remaining capture bits are 0, which holds for the
+/// vast majority of devices.
+pub fn scan(jtag: &dyn BmdJtagProtocol) -> Result<Vec<JtagDev>>
+{
+	jtag.tap_reset()?;
+
+	// Read out the IDCODEs, stopping once we see the ones we're shifting in come back out
+	let (tms, cycles) = JTAG_IDLE_TO_SHIFT_DR;
+	jtag.tap_tms_seq(tms, cycles)?;
+	let mut idcodes = Vec::new();
+	loop {
+		if idcodes.len() == JTAG_MAX_DEVICES {
+			return Err(eyre!("Found too many devices on the JTAG chain, is it broken?"));
+		}
+		if !shift_bit(jtag)? {
+			idcodes.push(0);
+			continue;
+		}
+		let mut data = [0u8; 4];
+		jtag.tap_tdi_tdo_seq(Some(&mut data), false, Some(&[0xff; 4]), 31)?;
+		let idcode = (u32::from_le_bytes(data) << 1) | 1;
+		if idcode == JTAG_IR_BYPASS {
+			break;
+		}
+		idcodes.push(idcode);
+	}
+	jtag.tap_next(true, true)?;
+	let (tms, cycles) = JTAG_EXIT_TO_IDLE;
+	jtag.tap_tms_seq(tms, cycles)?;
+	if idcodes.is_empty() {
+		return Err(eyre!("No devices found on the JTAG chain"));
+	}
+
+	// Now work out the instruction register lengths, which also puts every device into BYPASS
+	let (tms, cycles) = JTAG_IDLE_TO_SHIFT_IR;
+	jtag.tap_tms_seq(tms, cycles)?;
+	let mut ir_lengths = Vec::with_capacity(idcodes.len());
+	// Every device's capture value starts with a 1 bit, so the first bit shifted out must be one
+	let mut bit = shift_bit(jtag)?;
+	for index in 0..idcodes.len() {
+		if !bit {
+			return Err(eyre!("Invalid instruction register capture value from JTAG device {}", index));
+		}
+		// Count bits until we find the start of the next device's register (or our ones coming back)
+		let mut length = 1;
+		loop {
+			bit = shift_bit(jtag)?;
+			if bit {
+				break;
+			}
+			length += 1;
+			if length > JTAG_MAX_IR_LENGTH {
+				return Err(eyre!("Instruction register of JTAG device {} is too long", index));
+			}
+		}
+		ir_lengths.push(length);
+	}
+	jtag.tap_next(true, true)?;
+	let (tms, cycles) = JTAG_EXIT_TO_IDLE;
+	jtag.tap_tms_seq(tms, cycles)?;
+
+	Ok(build_chain(&idcodes, &ir_lengths))
+}
+
+/// Work out the pre- and post-scan lengths for each device on the chain from the IDCODEs and IR lengths
+fn build_chain(idcodes: &[u32], ir_lengths: &[u8]) -> Vec<JtagDev>
+{
+	let total_ir_len: u8 = ir_lengths.iter().sum();
+	let mut ir_prescan = 0;
+	idcodes
+		.iter()
+		.zip(ir_lengths)
+		.enumerate()
+		.map(|(index, (&idcode, &ir_len))| {
+			let device = JtagDev {
+				idcode,
+				current_ir: JTAG_IR_BYPASS,
+				dr_prescan: index as u8,
+				dr_postscan: (idcodes.len() - index - 1) as u8,
+				ir_len,
+				ir_prescan,
+				ir_postscan: total_ir_len - ir_prescan - ir_len,
+			};
+			debug!(
+				"JTAG device {}: IDCODE 0x{:08x}, IR length {}",
+				index, device.idcode, device.ir_len
+			);
+			ir_prescan += ir_len;
+			device
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn chain_layout()
+	{
+		let devices = build_chain(&[0x4ba00477, 0, 0x1000563d], &[4, 5, 5]);
+		let layout: Vec<_> = devices
+			.iter()
+			.map(|device| {
+				(
+					device.dr_prescan,
+					device.dr_postscan,
+					device.ir_prescan,
+					device.ir_postscan,
+				)
+			})
+			.collect();
+		assert_eq!(layout, [(0, 2, 0, 10), (1, 1, 4, 5), (2, 0, 9, 0)]);
+	}
+}
@@ -6,7 +6,7 @@ use std::fmt::Display;
 use std::sync::{Arc, Mutex};
 
 use bitmask_enum::bitmask;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, eyre};
 
 use crate::serial::bmd_rsp::BmdRspInterface;
 use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
@@ -18,6 +18,7 @@ use crate::serial::remote::protocol_v4::RemoteV4;
 use crate::serial::remote::riscv_debug::RiscvDmi;
 
 pub mod adi;
+pub mod jtag;
 mod protocol_v0;
 mod protocol_v1;
 mod protocol_v2;
@@ -45,11 +46,21 @@ pub const REMOTE_RESP_ERR: u8 = b'E';
 /// Probe does not support the request made
 pub const REMOTE_RESP_NOTSUP: u8 = b'N';
 
+/// Probe did not recognise the request made
+pub const REMOTE_ERROR_UNRECOGNISED: u64 = 1;
+/// Probe received a request of the wrong length
+pub const REMOTE_ERROR_WRONGLEN: u64 = 2;
+/// Probe encountered a bus fault executing the request, with the fault code in the upper bits
+pub const REMOTE_ERROR_FAULT: u64 = 3;
+/// Probe encountered an exception executing the request, with the exception code in the upper bits
+pub const REMOTE_ERROR_EXCEPTION: u64 = 4;
+
 pub type TargetAddr32 = u32;
 pub type TargetAddr64 = u64;
 
 /// Alignments available for use by memory accesses
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Align
 {
 	As8Bit,
@@ -119,35 +130,35 @@ pub trait BmdSwdProtocol
 {
 	/// Executes a read of the SWD bus for `clock_cycles` clock cycles, for up to 32 cycles,
 	/// and returns the result as a 32-bit integer
-	fn seq_in(&self, clock_cycles: usize) -> u32;
+	fn seq_in(&self, clock_cycles: usize) -> Result<u32>;
 	/// The same as seq_in but then does one additional cycle to read a parity bit, checks
 	/// the parity bit's value, and then only returns the result if the parity check passes -
 	/// returns None otherwise
-	fn seq_in_parity(&self, clock_cycles: usize) -> Option<u32>;
+	fn seq_in_parity(&self, clock_cycles: usize) -> Result<Option<u32>>;
 	/// Executes a write to the SWD bus for `clock_cycles` clock cycles, for up to 32 cycles,
 	/// putting out the value provided to the bus
-	fn seq_out(&self, value: u32, clock_cycles: usize);
+	fn seq_out(&self, value: u32, clock_cycles: usize) -> Result<()>;
 	/// The same as seq_out but then computes the parity bit for the provided value, and
 	/// does one additional cycle to write that parity bit out to thebus
-	fn seq_out_parity(&self, value: u32, clock_cycles: usize);
+	fn seq_out_parity(&self, value: u32, clock_cycles: usize) -> Result<()>;
 }
 
 /// Types implementing this trait provide raw JTAG access to targets over the BMD remote protocol
-pub trait BmdJtagProtocol
+pub trait BmdJtagProtocol: Send + Sync
 {
 	// Note: signal names are as for the device under test.
 
 	/// Executes a state machine reset to ensure a clean, known TAP state
-	fn tap_reset(&self);
+	fn tap_reset(&self) -> Result<()>;
 	/// Executes one state transition in the JTAG TAP state machine:
 	/// - Ensure TCK is low
 	/// - Assert the values of TMS and TDI
 	/// - Assert TCK (TMS and TDO are latched on rising edge)
 	/// - Capture the value of TDO
 	/// - Release TCK
-	fn tap_next(&self, tms: bool, tdi: bool) -> bool;
+	fn tap_next(&self, tms: bool, tdi: bool) -> Result<bool>;
 	/// Performs a sequence of cycles with the provided bitstring of TMS states
-	fn tap_tms_seq(&self, tms_states: u32, clock_cycles: usize);
+	fn tap_tms_seq(&self, tms_states: u32, clock_cycles: usize) -> Result<()>;
 	/// Shift out a sequence on TDI, capture data from TDO. Holds TMS low till the final cycle,
 	/// then uses the value of final_tms to determine what state to put TMS into.
 	/// - This is not endian safe: The first byte will always be shifted out first.
@@ -160,41 +171,45 @@ pub trait BmdJtagProtocol
 		final_tms: bool,
 		data_in: Option<&[u8]>,
 		clock_cycles: usize,
-	);
+	) -> Result<()>;
 	/// Shift out a sequence on TDI. Holds TMS low till the final cycle, then uses the value
 	/// of final_tms to determine what state to put tMS into.
 	/// - This is not endian safe: The first byte will always be shifted out first.
-	fn tap_tdi_seq(&self, final_tms: bool, data_in: &[u8], clock_cycles: usize);
+	fn tap_tdi_seq(&self, final_tms: bool, data_in: &[u8], clock_cycles: usize) -> Result<()>;
 	/// Perform a series of cycles on the state machine with TMS and TDI held in a set state
-	fn tap_cycle(&self, tms: bool, tdi: bool, clock_cycles: usize);
+	fn tap_cycle(&self, tms: bool, tdi: bool, clock_cycles: usize) -> Result<()>;
 }
 
 /// Types implementing this trait provide accelerated ADIv5 access to targets over the BMD remote protocol
-pub trait BmdAdiV5Protocol
+pub trait BmdAdiV5Protocol: Send + Sync
 {
 	/// Perform a raw AP or DP register access against the target, reporting the read result back
-	fn raw_access(&self, dp: AdiV5DebugPort, rnw: u8, addr: u16, value: u32) -> u32;
+	fn raw_access(&self, dp: &AdiV5DebugPort, rnw: u8, addr: u16, value: u32) -> Result<u32>;
 	/// Read a DP (or AP*) register from the target
-	fn dp_read(&self, dp: AdiV5DebugPort, addr: u16) -> u32;
+	fn dp_read(&self, dp: &AdiV5DebugPort, addr: u16) -> Result<u32>;
 	/// Read an AP register from the target
-	fn ap_read(&self, ap: AdiV5AccessPort, addr: u16) -> u32;
+	fn ap_read(&self, ap: &AdiV5AccessPort, addr: u16) -> Result<u32>;
 	/// Write an AP register on the target
-	fn ap_write(&self, ap: AdiV5AccessPort, addr: u16, value: u32);
+	fn ap_write(&self, ap: &AdiV5AccessPort, addr: u16, value: u32) -> Result<()>;
 	/// Read memory associated with an AP from the target into the buffer passed to dest
-	fn mem_read(&self, ap: AdiV5AccessPort, dest: &mut [u8], src: TargetAddr64);
+	fn mem_read(&self, ap: &AdiV5AccessPort, dest: &mut [u8], src: TargetAddr64) -> Result<()>;
 	/// Write memory associated with an AP to the target from the buffer passed in src and with the
 	/// access alignment given by align
-	fn mem_write(&self, ap: AdiV5AccessPort, dest: TargetAddr64, src: &[u8], align: Align);
+	fn mem_write(&self, ap: &AdiV5AccessPort, dest: TargetAddr64, src: &[u8], align: Align) -> Result<()>;
 }
 
-pub trait BmdRiscvProtocol
+/// Types implementing this trait provide access to the Debug Module Interface of RISC-V targets
+pub trait BmdRiscvProtocol: Send + Sync
 {
-	fn dmi_read(&self, dmi: RiscvDmi, address: u32) -> Option<u32>;
-	fn dmi_write(&self, dmi: RiscvDmi, address: u32, value: u32) -> bool;
+	/// Read a DMI register from the Debug Module behind the given DMI
+	fn dmi_read(&self, dmi: &RiscvDmi, address: u32) -> Result<u32>;
+	/// Write a DMI register in the Debug Module behind the given DMI
+	fn dmi_write(&self, dmi: &RiscvDmi, address: u32, value: u32) -> Result<()>;
 }
 
 /// Structure representing a device on the JTAG scan chain
 #[allow(unused)]
+#[derive(Clone, Debug)]
 pub struct JtagDev
 {
 	idcode: u32,
@@ -239,6 +254,56 @@ pub fn decode_response(response: &str, digits: usize) -> u64
 	value
 }
 
+/// Convert a block of data into the hexadecimal string form the remote protocol uses on the wire
+pub fn hexify(data: &[u8]) -> String
+{
+	data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Convert a hexadecimal string from the remote protocol back into bytes, filling dest
+pub fn unhexify(response: &str, dest: &mut [u8]) -> Result<()>
+{
+	// Check that there's enough data in the response to fill the destination buffer
+	if response.len() < dest.len() * 2 {
+		return Err(eyre!(
+			"Response too short, expected {} bytes of data, got {:?}",
+			dest.len(),
+			response
+		));
+	}
+	// Convert each pair of hex digits into a byte, working on the raw bytes so that a response with
+	// non-ASCII characters in it can't leave us slicing part way through one of them
+	for (byte, digits) in dest.iter_mut().zip(response.as_bytes().chunks_exact(2)) {
+		*byte = std::str::from_utf8(digits)
+			.ok()
+			.and_then(|digits| u8::from_str_radix(digits, 16).ok())
+			.ok_or_else(|| eyre!("Invalid hex data in response {:?}", response))?;
+	}
+	Ok(())
+}
+
+impl Align
+{
+	/// Compute the largest alignment usable for an access of `length` bytes starting at `address`
+	pub fn for_access(address: TargetAddr64, length: usize) -> Self
+	{
+		let bits = address | (length as u64);
+		if bits & 1 != 0 {
+			Self::As8Bit
+		} else if bits & 2 != 0 {
+			Self::As16Bit
+		} else {
+			Self::As32Bit
+		}
+	}
+
+	/// The number of bytes an access of this alignment moves
+	pub fn bytes(self) -> usize
+	{
+		1 << (self as u8)
+	}
+}
+
 impl ProtocolVersion
 {
 	/// Extract an instance of the BMD remote protocol communication object for this version of the protocol
@@ -361,3 +426,44 @@ impl Display for TargetFamily
 		write!(fmt, "{}", families.join(", "))
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn access_alignment()
+	{
+		assert_eq!(Align::for_access(0x20000000, 64), Align::As32Bit);
+		assert_eq!(Align::for_access(0x20000002, 64), Align::As16Bit);
+		assert_eq!(Align::for_access(0x20000000, 6), Align::As16Bit);
+		assert_eq!(Align::for_access(0x20000001, 64), Align::As8Bit);
+		assert_eq!(Align::for_access(0x20000000, 3), Align::As8Bit);
+		assert_eq!(Align::As8Bit.bytes(), 1);
+		assert_eq!(Align::As32Bit.bytes(), 4);
+	}
+
+	#[test]
+	fn hex_round_trip()
+	{
+		assert_eq!(hexify(&[0x00, 0x7f, 0xa5, 0xff]), "007fa5ff");
+		let mut data = [0u8; 4];
+		unhexify("007fA5ff", &mut data).unwrap();
+		assert_eq!(data, [0x00, 0x7f, 0xa5, 0xff]);
+		// Trailing data beyond what's wanted is ignored
+		let mut data = [0u8; 1];
+		unhexify("12345", &mut data).unwrap();
+		assert_eq!(data, [0x12]);
+	}
+
+	#[test]
+	fn hex_invalid()
+	{
+		let mut data = [0u8; 2];
+		assert!(unhexify("123", &mut data).is_err());
+		assert!(unhexify("12zz", &mut data).is_err());
+		// Non-ASCII must be rejected rather than splitting a character
+		assert!(unhexify("1\u{e9}2", &mut data).is_err());
+	}
+}
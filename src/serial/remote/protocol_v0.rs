@@ -8,10 +8,10 @@ use color_eyre::eyre::{Result, eyre};
 use log::{debug, warn};
 
 use crate::serial::bmd_rsp::BmdRspInterface;
-use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
+use crate::serial::remote::protocol_v3::RemoteV3ADIv5;
 use crate::serial::remote::{
-	Align, BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev,
-	REMOTE_RESP_ERR, TargetAddr64, TargetArchitecture, TargetFamily,
+	BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev, REMOTE_RESP_ERR,
+	REMOTE_RESP_OK, REMOTE_RESP_PARERR, TargetArchitecture, TargetFamily, decode_response,
 };
 
 pub struct RemoteV0
@@ -23,24 +23,19 @@ pub struct RemoteV0Plus(RemoteV0);
 
 pub struct RemoteV0JTAG
 {
-	#[allow(unused)]
 	interface: Arc<Mutex<BmdRspInterface>>,
 }
 
 pub struct RemoteV0SWD
 {
-	#[allow(unused)]
-	interface: Arc<Mutex<BmdRspInterface>>,
-}
-
-pub struct RemoteV0ADIv5
-{
-	#[allow(unused)]
 	interface: Arc<Mutex<BmdRspInterface>>,
 }
 
 const REMOTE_SWD_INIT: &str = "!SS#";
 const REMOTE_JTAG_INIT: &str = "!JS#";
+const REMOTE_JTAG_RESET: &str = "!JR#";
+/// The most clock cycles a single JTAG TDI/TDO sequence request can shift
+const REMOTE_JTAG_MAX_CYCLES: usize = 64;
 
 impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV0
 {
@@ -206,7 +201,7 @@ impl BmdRemoteProtocol for RemoteV0Plus
 	fn adiv5_init(&self) -> Option<Arc<dyn BmdAdiV5Protocol>>
 	{
 		warn!("Please update your probe's firmware for improved error handling");
-		Some(Arc::new(RemoteV0ADIv5::from(self.clone_interface())))
+		Some(Arc::new(RemoteV3ADIv5::from(self.clone_interface())))
 	}
 
 	fn adiv6_init(&self) -> Option<Arc<dyn BmdAdiV5Protocol>>
@@ -265,42 +260,102 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV0JTAG
 	}
 }
 
+impl RemoteV0JTAG
+{
+	fn interface(&self) -> MutexGuard<'_, BmdRspInterface>
+	{
+		self.interface.lock().unwrap()
+	}
+
+	/// Run a JTAG request against the probe, returning the response buffer on success
+	pub(crate) fn jtag_request(&self, request: &str) -> Result<String>
+	{
+		self.interface().buffer_write(request)?;
+		let buffer = self.interface().buffer_read()?;
+		// Check for communication failures
+		if buffer.is_empty() || buffer.as_bytes()[0] != REMOTE_RESP_OK {
+			let message = if buffer.len() > 1 {
+				&buffer[1..]
+			} else {
+				"unknown"
+			};
+			Err(eyre!("Remote JTAG request failed, error {}", message))
+		} else {
+			Ok(buffer)
+		}
+	}
+}
+
 impl BmdJtagProtocol for RemoteV0JTAG
 {
-	fn tap_reset(&self)
+	fn tap_reset(&self) -> Result<()>
 	{
-		//
+		self.jtag_request(REMOTE_JTAG_RESET)?;
+		Ok(())
 	}
 
-	fn tap_next(&self, _tms: bool, _tdi: bool) -> bool
+	fn tap_next(&self, tms: bool, tdi: bool) -> Result<bool>
 	{
-		false
+		let buffer = self.jtag_request(&format!("!JN{}{}#", tms as u8, tdi as u8))?;
+		Ok(decode_response(&buffer[1..], 1) != 0)
 	}
 
-	fn tap_tms_seq(&self, _tms_states: u32, _clock_cycles: usize)
+	fn tap_tms_seq(&self, tms_states: u32, clock_cycles: usize) -> Result<()>
 	{
-		//
+		self.jtag_request(&format!("!JT{:02x}{:x}#", clock_cycles, tms_states))?;
+		Ok(())
 	}
 
 	fn tap_tdi_tdo_seq(
 		&self,
-		_data_out: Option<&mut [u8]>,
-		_final_tms: bool,
-		_data_in: Option<&[u8]>,
-		_clock_cycles: usize,
-	)
-	{
-		//
+		mut data_out: Option<&mut [u8]>,
+		final_tms: bool,
+		data_in: Option<&[u8]>,
+		clock_cycles: usize,
+	) -> Result<()>
+	{
+		// The probe can only shift up to 64 bits per request, so break the sequence up into chunks of that
+		for offset in (0..clock_cycles).step_by(REMOTE_JTAG_MAX_CYCLES) {
+			let chunk_cycles = (clock_cycles - offset).min(REMOTE_JTAG_MAX_CYCLES);
+			let chunk = (offset / 8)..((offset + chunk_cycles).div_ceil(8));
+			// Build the chunk's TDI data, first byte in the least significant bits
+			let value = data_in
+				.map(|data_in| {
+					data_in[chunk.clone()]
+						.iter()
+						.rev()
+						.fold(0u64, |value, &byte| (value << 8) | byte as u64)
+				})
+				.unwrap_or(0);
+			// Only the very last cycle of the sequence gets to use final_tms
+			let command = if final_tms && offset + chunk_cycles == clock_cycles {
+				'D'
+			} else {
+				'd'
+			};
+			let buffer = self.jtag_request(&format!("!J{}{:02x}{:x}#", command, chunk_cycles, value))?;
+			if let Some(data_out) = data_out.as_deref_mut() {
+				let value = decode_response(&buffer[1..], 16);
+				for (index, byte) in data_out[chunk].iter_mut().enumerate() {
+					*byte = (value >> (index * 8)) as u8;
+				}
+			}
+		}
+		Ok(())
 	}
 
-	fn tap_tdi_seq(&self, _final_tms: bool, _data_in: &[u8], _clock_cycles: usize)
+	fn tap_tdi_seq(&self, final_tms: bool, data_in: &[u8], clock_cycles: usize) -> Result<()>
 	{
-		//
+		self.tap_tdi_tdo_seq(None, final_tms, Some(data_in), clock_cycles)
 	}
 
-	fn tap_cycle(&self, _tms: bool, _tdi: bool, _clock_cycles: usize)
+	/// v0 has no command for this, so it has to be done one transition at a time
+	fn tap_cycle(&self, tms: bool, tdi: bool, clock_cycles: usize) -> Result<()>
 	{
-		//
+		for _ in 0..clock_cycles {
+			self.tap_next(tms, tdi)?;
+		}
+		Ok(())
 	}
 }
 
@@ -314,65 +369,61 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV0SWD
 	}
 }
 
-impl BmdSwdProtocol for RemoteV0SWD
+impl RemoteV0SWD
 {
-	fn seq_in(&self, _clock_cycles: usize) -> u32
+	fn interface(&self) -> MutexGuard<'_, BmdRspInterface>
 	{
-		0
-	}
-
-	fn seq_in_parity(&self, _clock_cycles: usize) -> Option<u32>
-	{
-		None
-	}
-
-	fn seq_out(&self, _value: u32, _clock_cycles: usize)
-	{
-		//
-	}
-
-	fn seq_out_parity(&self, _value: u32, _clock_cycles: usize)
-	{
-		//
+		self.interface.lock().unwrap()
 	}
-}
 
-impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV0ADIv5
-{
-	fn from(interface: Arc<Mutex<BmdRspInterface>>) -> Self
+	/// Run a SWD sequence request against the probe, returning the response buffer on success
+	fn sequence_request(&self, request: &str) -> Result<String>
 	{
-		Self {
-			interface,
+		self.interface().buffer_write(request)?;
+		let buffer = self.interface().buffer_read()?;
+		// Check for communication failures
+		if buffer.is_empty() || (buffer.as_bytes()[0] != REMOTE_RESP_OK && buffer.as_bytes()[0] != REMOTE_RESP_PARERR)
+		{
+			let message = if buffer.len() > 1 {
+				&buffer[1..]
+			} else {
+				"unknown"
+			};
+			Err(eyre!("Remote SWD sequence failed, error {}", message))
+		} else {
+			Ok(buffer)
 		}
 	}
 }
 
-impl BmdAdiV5Protocol for RemoteV0ADIv5
+impl BmdSwdProtocol for RemoteV0SWD
 {
-	fn raw_access(&self, _dp: AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> u32
+	fn seq_in(&self, clock_cycles: usize) -> Result<u32>
 	{
-		0
+		let buffer = self.sequence_request(&format!("!Si{:02x}#", clock_cycles))?;
+		Ok(decode_response(&buffer[1..], 8) as u32)
 	}
 
-	fn dp_read(&self, _dp: AdiV5DebugPort, _addr: u16) -> u32
+	fn seq_in_parity(&self, clock_cycles: usize) -> Result<Option<u32>>
 	{
-		0
-	}
-
-	fn ap_read(&self, _ap: AdiV5AccessPort, _addr: u16) -> u32
-	{
-		0
+		let buffer = self.sequence_request(&format!("!SI{:02x}#", clock_cycles))?;
+		// A parity error response means the data is not to be trusted
+		if buffer.as_bytes()[0] == REMOTE_RESP_PARERR {
+			Ok(None)
+		} else {
+			Ok(Some(decode_response(&buffer[1..], 8) as u32))
+		}
 	}
 
-	fn ap_write(&self, _ap: AdiV5AccessPort, _addr: u16, _value: u32) {}
-
-	fn mem_read(&self, _ap: AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64)
+	fn seq_out(&self, value: u32, clock_cycles: usize) -> Result<()>
 	{
-		//
+		self.sequence_request(&format!("!So{:02x}{:x}#", clock_cycles, value))?;
+		Ok(())
 	}
 
-	fn mem_write(&self, _ap: AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align)
+	fn seq_out_parity(&self, value: u32, clock_cycles: usize) -> Result<()>
 	{
-		//
+		self.sequence_request(&format!("!SO{:02x}{:x}#", clock_cycles, value))?;
+		Ok(())
 	}
 }
@@ -8,21 +8,15 @@ use color_eyre::eyre::Result;
 use log::warn;
 
 use crate::serial::bmd_rsp::BmdRspInterface;
-use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
 use crate::serial::remote::protocol_v0::RemoteV0;
+use crate::serial::remote::protocol_v3::RemoteV3ADIv5;
 use crate::serial::remote::{
-	Align, BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev,
-	TargetAddr64, TargetArchitecture, TargetFamily,
+	BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev, REMOTE_RESP_OK,
+	TargetArchitecture, TargetFamily,
 };
 
 pub struct RemoteV1(RemoteV0);
 
-pub struct RemoteV1ADIv5
-{
-	#[allow(unused)]
-	interface: Arc<Mutex<BmdRspInterface>>,
-}
-
 impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV1
 {
 	fn from(interface: Arc<Mutex<BmdRspInterface>>) -> Self
@@ -68,7 +62,7 @@ impl BmdRemoteProtocol for RemoteV1
 	fn adiv5_init(&self) -> Option<Arc<dyn BmdAdiV5Protocol>>
 	{
 		warn!("Please update your probe's firmware for improved error handling");
-		Some(Arc::new(RemoteV1ADIv5::from(self.clone_interface())))
+		Some(Arc::new(RemoteV3ADIv5::from(self.clone_interface())))
 	}
 
 	fn adiv6_init(&self) -> Option<Arc<dyn BmdAdiV5Protocol>>
@@ -81,9 +75,28 @@ impl BmdRemoteProtocol for RemoteV1
 		self.0.riscv_jtag_init()
 	}
 
-	fn add_jtag_dev(&self, _dev_index: u32, _jtag_dev: &JtagDev)
+	fn add_jtag_dev(&self, dev_index: u32, jtag_dev: &JtagDev)
 	{
-		//
+		// Tell the probe about the device so the accelerated protocols can address it on the scan chain
+		let request = format!(
+			"!HJ{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}{:08x}#",
+			dev_index,
+			jtag_dev.dr_prescan,
+			jtag_dev.dr_postscan,
+			jtag_dev.ir_len,
+			jtag_dev.ir_prescan,
+			jtag_dev.ir_postscan,
+			jtag_dev.current_ir
+		);
+		let result = self
+			.interface()
+			.buffer_write(&request)
+			.and_then(|_| self.interface().buffer_read());
+		// There's nothing useful we can do if this fails, besides let the user know
+		match result {
+			Ok(buffer) if !buffer.is_empty() && buffer.as_bytes()[0] == REMOTE_RESP_OK => (),
+			_ => warn!("Failed to tell the probe about JTAG device {}", dev_index),
+		}
 	}
 
 	fn get_comms_frequency(&self) -> u32
@@ -116,43 +129,3 @@ impl BmdRemoteProtocol for RemoteV1
 		self.0.get_target_power_state()
 	}
 }
-
-impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV1ADIv5
-{
-	fn from(interface: Arc<Mutex<BmdRspInterface>>) -> Self
-	{
-		Self {
-			interface,
-		}
-	}
-}
-
-impl BmdAdiV5Protocol for RemoteV1ADIv5
-{
-	fn raw_access(&self, _dp: AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> u32
-	{
-		0
-	}
-
-	fn dp_read(&self, _dp: AdiV5DebugPort, _addr: u16) -> u32
-	{
-		0
-	}
-
-	fn ap_read(&self, _ap: AdiV5AccessPort, _addr: u16) -> u32
-	{
-		0
-	}
-
-	fn ap_write(&self, _ap: AdiV5AccessPort, _addr: u16, _value: u32) {}
-
-	fn mem_read(&self, _ap: AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64)
-	{
-		//
-	}
-
-	fn mem_write(&self, _ap: AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align)
-	{
-		//
-	}
-}
@@ -155,33 +155,40 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV2JTAG
 /// overhead penalty thanks to USB turnaround times.
 impl BmdJtagProtocol for RemoteV2JTAG
 {
-	fn tap_reset(&self)
+	fn tap_reset(&self) -> Result<()>
 	{
-		self.0.tap_reset();
+		self.0.tap_reset()
 	}
 
-	fn tap_next(&self, tms: bool, tdi: bool) -> bool
+	fn tap_next(&self, tms: bool, tdi: bool) -> Result<bool>
 	{
 		self.0.tap_next(tms, tdi)
 	}
 
-	fn tap_tms_seq(&self, tms_states: u32, clock_cycles: usize)
+	fn tap_tms_seq(&self, tms_states: u32, clock_cycles: usize) -> Result<()>
 	{
-		self.0.tap_tms_seq(tms_states, clock_cycles);
+		self.0.tap_tms_seq(tms_states, clock_cycles)
 	}
 
-	fn tap_tdi_tdo_seq(&self, data_out: Option<&mut [u8]>, final_tms: bool, data_in: Option<&[u8]>, clock_cycles: usize)
+	fn tap_tdi_tdo_seq(
+		&self,
+		data_out: Option<&mut [u8]>,
+		final_tms: bool,
+		data_in: Option<&[u8]>,
+		clock_cycles: usize,
+	) -> Result<()>
 	{
-		self.0.tap_tdi_tdo_seq(data_out, final_tms, data_in, clock_cycles);
+		self.0.tap_tdi_tdo_seq(data_out, final_tms, data_in, clock_cycles)
 	}
 
-	fn tap_tdi_seq(&self, final_tms: bool, data_in: &[u8], clock_cycles: usize)
+	fn tap_tdi_seq(&self, final_tms: bool, data_in: &[u8], clock_cycles: usize) -> Result<()>
 	{
-		self.0.tap_tdi_seq(final_tms, data_in, clock_cycles);
+		self.0.tap_tdi_seq(final_tms, data_in, clock_cycles)
 	}
 
-	fn tap_cycle(&self, _tms: bool, _tdi: bool, _clock_cycles: usize)
+	fn tap_cycle(&self, tms: bool, tdi: bool, clock_cycles: usize) -> Result<()>
 	{
-		//
+		self.0.jtag_request(&format!("!JC{}{}{:08x}#", tms as u8, tdi as u8, clock_cycles))?;
+		Ok(())
 	}
 }
@@ -4,7 +4,7 @@
 
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, eyre};
 use log::warn;
 
 use crate::serial::bmd_rsp::BmdRspInterface;
@@ -12,17 +12,26 @@ use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
 use crate::serial::remote::protocol_v2::RemoteV2;
 use crate::serial::remote::{
 	Align, BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev,
-	TargetAddr64, TargetArchitecture, TargetFamily,
+	REMOTE_ERROR_EXCEPTION, REMOTE_ERROR_FAULT, REMOTE_MAX_MSG_SIZE, REMOTE_RESP_ERR, REMOTE_RESP_OK, TargetAddr64,
+	TargetArchitecture, TargetFamily, decode_response, hexify, unhexify,
 };
 
 pub struct RemoteV3(RemoteV2);
 
+/// ADIv5 acceleration over the remote protocol - the requests have not changed shape from v0+ up to v3, only
+/// how much detail error responses carry, so this also serves probes speaking v0+, v1 and v2
 pub struct RemoteV3ADIv5
 {
-	#[allow(unused)]
 	interface: Arc<Mutex<BmdRspInterface>>,
+	/// How many hex digits the protocol uses to encode target addresses (v4 extends this to 64-bit)
+	address_digits: usize,
 }
 
+/// The maximum number of bytes of memory a single read request can return given the hex encoding
+const ADIV5_MEM_READ_BLOCK_SIZE: usize = (REMOTE_MAX_MSG_SIZE - 8) / 2;
+/// The number of bytes of overhead a memory write request has before any data is included
+const ADIV5_MEM_WRITE_OVERHEAD: usize = 48;
+
 impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV3
 {
 	fn from(interface: Arc<Mutex<BmdRspInterface>>) -> Self
@@ -119,39 +128,141 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV3ADIv5
 	{
 		Self {
 			interface,
+			address_digits: 8,
+		}
+	}
+}
+
+impl RemoteV3ADIv5
+{
+	/// Construct an instance of the ADIv5 acceleration that talks 64-bit target addresses (v4+)
+	pub(crate) fn with_wide_addresses(interface: Arc<Mutex<BmdRspInterface>>) -> Self
+	{
+		Self {
+			interface,
+			address_digits: 16,
+		}
+	}
+
+	fn interface(&self) -> MutexGuard<'_, BmdRspInterface>
+	{
+		self.interface.lock().unwrap()
+	}
+
+	/// Send a request to the probe and check the response for errors, returning the response's data portion
+	fn request(&self, operation: &str, request: &str) -> Result<String>
+	{
+		self.interface().buffer_write(request)?;
+		let buffer = self.interface().buffer_read()?;
+		// Check the response length for errors
+		if buffer.is_empty() {
+			return Err(eyre!("{} comms error: empty response", operation));
+		}
+		match buffer.as_bytes()[0] {
+			REMOTE_RESP_OK => Ok(buffer[1..].to_string()),
+			// The remote reported an error, so decode it
+			REMOTE_RESP_ERR => {
+				let response_code = decode_response(&buffer[1..], 16);
+				let error = response_code & 0xff;
+				match error {
+					REMOTE_ERROR_FAULT => Err(eyre!("{} failed, target reported fault {}", operation, response_code >> 8)),
+					REMOTE_ERROR_EXCEPTION => {
+						Err(eyre!("{} failed, remote raised exception {}", operation, response_code >> 8))
+					},
+					_ => Err(eyre!("{} failed, unexpected error {}", operation, error)),
+				}
+			},
+			_ => Err(eyre!("{} failed, firmware reported unexpected error: {}", operation, buffer)),
 		}
 	}
+
+	/// Decode a 32-bit register value response (which is sent as little endian bytes)
+	fn decode_u32(response: &str) -> Result<u32>
+	{
+		let mut value = [0u8; 4];
+		unhexify(response, &mut value)?;
+		Ok(u32::from_le_bytes(value))
+	}
 }
 
 impl BmdAdiV5Protocol for RemoteV3ADIv5
 {
-	fn raw_access(&self, _dp: AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> u32
+	fn raw_access(&self, dp: &AdiV5DebugPort, rnw: u8, addr: u16, value: u32) -> Result<u32>
 	{
-		0
+		let response = self.request(
+			"ADIv5 raw access",
+			&format!("!AR{:02x}{:02x}{:04x}{:08x}#", dp.dev_index(), rnw, addr, value),
+		)?;
+		Self::decode_u32(&response)
 	}
 
-	fn dp_read(&self, _dp: AdiV5DebugPort, _addr: u16) -> u32
+	fn dp_read(&self, dp: &AdiV5DebugPort, addr: u16) -> Result<u32>
 	{
-		0
+		let response = self.request("ADIv5 DP read", &format!("!Ad{:02x}ff{:04x}#", dp.dev_index(), addr))?;
+		Self::decode_u32(&response)
 	}
 
-	fn ap_read(&self, _ap: AdiV5AccessPort, _addr: u16) -> u32
+	fn ap_read(&self, ap: &AdiV5AccessPort, addr: u16) -> Result<u32>
 	{
-		0
+		let response = self.request(
+			"ADIv5 AP read",
+			&format!("!Aa{:02x}{:02x}{:04x}#", ap.dp().dev_index(), ap.index(), addr),
+		)?;
+		Self::decode_u32(&response)
 	}
 
-	fn ap_write(&self, _ap: AdiV5AccessPort, _addr: u16, _value: u32)
+	fn ap_write(&self, ap: &AdiV5AccessPort, addr: u16, value: u32) -> Result<()>
 	{
-		//
+		self.request(
+			"ADIv5 AP write",
+			&format!("!AA{:02x}{:02x}{:04x}{:08x}#", ap.dp().dev_index(), ap.index(), addr, value),
+		)?;
+		Ok(())
 	}
 
-	fn mem_read(&self, _ap: AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64)
+	fn mem_read(&self, ap: &AdiV5AccessPort, dest: &mut [u8], src: TargetAddr64) -> Result<()>
 	{
-		//
+		// Split the read up into blocks that will fit into a single response from the probe
+		for (index, block) in dest.chunks_mut(ADIV5_MEM_READ_BLOCK_SIZE).enumerate() {
+			let address = src + (index * ADIV5_MEM_READ_BLOCK_SIZE) as u64;
+			let response = self.request(
+				"ADIv5 memory read",
+				&format!(
+					"!Am{:02x}{:02x}{:08x}{:0width$x}{:08x}#",
+					ap.dp().dev_index(),
+					ap.index(),
+					ap.csw(),
+					address,
+					block.len(),
+					width = self.address_digits,
+				),
+			)?;
+			unhexify(&response, block)?;
+		}
+		Ok(())
 	}
 
-	fn mem_write(&self, _ap: AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align)
+	fn mem_write(&self, ap: &AdiV5AccessPort, dest: TargetAddr64, src: &[u8], align: Align) -> Result<()>
 	{
-		//
+		// Figure out how much data we can send per request, keeping each block a multiple of the alignment
+		let block_size = ((REMOTE_MAX_MSG_SIZE - ADIV5_MEM_WRITE_OVERHEAD) / 2) & !(align.bytes() - 1);
+		for (index, block) in src.chunks(block_size).enumerate() {
+			let address = dest + (index * block_size) as u64;
+			self.request(
+				"ADIv5 memory write",
+				&format!(
+					"!AM{:02x}{:02x}{:08x}{:02x}{:0width$x}{:08x}{}#",
+					ap.dp().dev_index(),
+					ap.index(),
+					ap.csw(),
+					align as u8,
+					address,
+					block.len(),
+					hexify(block),
+					width = self.address_digits,
+				),
+			)?;
+		}
+		Ok(())
 	}
 }
@@ -11,7 +11,7 @@ use log::{debug, warn};
 
 use crate::serial::bmd_rsp::BmdRspInterface;
 use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
-use crate::serial::remote::protocol_v3::RemoteV3;
+use crate::serial::remote::protocol_v3::{RemoteV3, RemoteV3ADIv5};
 use crate::serial::remote::riscv_debug::RiscvDmi;
 use crate::serial::remote::{
 	Align, BmdAdiV5Protocol, BmdJtagProtocol, BmdRemoteProtocol, BmdRiscvProtocol, BmdSwdProtocol, JtagDev,
@@ -29,11 +29,8 @@ pub struct RemoteV4
 	accelerations: Acceleration,
 }
 
-pub struct RemoteV4ADIv5
-{
-	#[allow(unused)]
-	interface: Arc<Mutex<BmdRspInterface>>,
-}
+/// v4 ADIv5 acceleration is the v3 acceleration but with 64-bit target addresses in memory accesses
+pub struct RemoteV4ADIv5(RemoteV3ADIv5);
 
 pub struct RemoteV4ADIv6
 {
@@ -43,7 +40,6 @@ pub struct RemoteV4ADIv6
 
 pub struct RemoteV4RiscvJtag
 {
-	#[allow(unused)]
 	interface: Arc<Mutex<BmdRspInterface>>,
 }
 
@@ -234,42 +230,40 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV4ADIv5
 {
 	fn from(interface: Arc<Mutex<BmdRspInterface>>) -> Self
 	{
-		Self {
-			interface,
-		}
+		Self(RemoteV3ADIv5::with_wide_addresses(interface))
 	}
 }
 
 impl BmdAdiV5Protocol for RemoteV4ADIv5
 {
-	fn raw_access(&self, _dp: AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> u32
+	fn raw_access(&self, dp: &AdiV5DebugPort, rnw: u8, addr: u16, value: u32) -> Result<u32>
 	{
-		0
+		self.0.raw_access(dp, rnw, addr, value)
 	}
 
-	fn dp_read(&self, _dp: AdiV5DebugPort, _addr: u16) -> u32
+	fn dp_read(&self, dp: &AdiV5DebugPort, addr: u16) -> Result<u32>
 	{
-		0
+		self.0.dp_read(dp, addr)
 	}
 
-	fn ap_read(&self, _ap: AdiV5AccessPort, _addr: u16) -> u32
+	fn ap_read(&self, ap: &AdiV5AccessPort, addr: u16) -> Result<u32>
 	{
-		0
+		self.0.ap_read(ap, addr)
 	}
 
-	fn ap_write(&self, _ap: AdiV5AccessPort, _addr: u16, _value: u32)
+	fn ap_write(&self, ap: &AdiV5AccessPort, addr: u16, value: u32) -> Result<()>
 	{
-		//
+		self.0.ap_write(ap, addr, value)
 	}
 
-	fn mem_read(&self, _ap: AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64)
+	fn mem_read(&self, ap: &AdiV5AccessPort, dest: &mut [u8], src: TargetAddr64) -> Result<()>
 	{
-		//
+		self.0.mem_read(ap, dest, src)
 	}
 
-	fn mem_write(&self, _ap: AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align)
+	fn mem_write(&self, ap: &AdiV5AccessPort, dest: TargetAddr64, src: &[u8], align: Align) -> Result<()>
 	{
-		//
+		self.0.mem_write(ap, dest, src, align)
 	}
 }
 
@@ -285,34 +279,34 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV4ADIv6
 
 impl BmdAdiV5Protocol for RemoteV4ADIv6
 {
-	fn raw_access(&self, _dp: AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> u32
+	fn raw_access(&self, _dp: &AdiV5DebugPort, _rnw: u8, _addr: u16, _value: u32) -> Result<u32>
 	{
-		0
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 
-	fn dp_read(&self, _dp: AdiV5DebugPort, _addr: u16) -> u32
+	fn dp_read(&self, _dp: &AdiV5DebugPort, _addr: u16) -> Result<u32>
 	{
-		0
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 
-	fn ap_read(&self, _ap: AdiV5AccessPort, _addr: u16) -> u32
+	fn ap_read(&self, _ap: &AdiV5AccessPort, _addr: u16) -> Result<u32>
 	{
-		0
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 
-	fn ap_write(&self, _ap: AdiV5AccessPort, _addr: u16, _value: u32)
+	fn ap_write(&self, _ap: &AdiV5AccessPort, _addr: u16, _value: u32) -> Result<()>
 	{
-		//
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 
-	fn mem_read(&self, _ap: AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64)
+	fn mem_read(&self, _ap: &AdiV5AccessPort, _dest: &mut [u8], _src: TargetAddr64) -> Result<()>
 	{
-		//
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 
-	fn mem_write(&self, _ap: AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align)
+	fn mem_write(&self, _ap: &AdiV5AccessPort, _dest: TargetAddr64, _src: &[u8], _align: Align) -> Result<()>
 	{
-		//
+		Err(eyre!("ADIv6 acceleration is not yet implemented"))
 	}
 }
 
@@ -326,16 +320,58 @@ impl From<Arc<Mutex<BmdRspInterface>>> for RemoteV4RiscvJtag
 	}
 }
 
-impl BmdRiscvProtocol for RemoteV4RiscvJtag
+impl RemoteV4RiscvJtag
 {
-	fn dmi_read(&self, _dmi: RiscvDmi, _address: u32) -> Option<u32>
+	fn interface(&self) -> MutexGuard<'_, BmdRspInterface>
 	{
-		None
+		self.interface.lock().unwrap()
 	}
 
-	fn dmi_write(&self, _dmi: RiscvDmi, _address: u32, _value: u32) -> bool
+	/// Run a DMI request against the probe, returning the response buffer on success
+	fn dmi_request(&self, request: &str, address: u32) -> Result<String>
 	{
-		false
+		self.interface().buffer_write(request)?;
+		let buffer = self.interface().buffer_read()?;
+		if buffer.is_empty() || buffer.as_bytes()[0] != REMOTE_RESP_OK {
+			let fault = if buffer.len() > 1 {
+				decode_response(&buffer[1..], 2)
+			} else {
+				0
+			};
+			Err(eyre!("DMI access to 0x{:x} failed, fault {}", address, fault))
+		} else {
+			Ok(buffer)
+		}
+	}
+}
+
+impl BmdRiscvProtocol for RemoteV4RiscvJtag
+{
+	fn dmi_read(&self, dmi: &RiscvDmi, address: u32) -> Result<u32>
+	{
+		let request = format!(
+			"!Rd{:02x}{:02x}{:02x}{:08x}#",
+			dmi.dev_index(),
+			dmi.idle_cycles(),
+			dmi.address_width(),
+			address
+		);
+		let buffer = self.dmi_request(&request, address)?;
+		Ok(decode_response(&buffer[1..], 8) as u32)
+	}
+
+	fn dmi_write(&self, dmi: &RiscvDmi, address: u32, value: u32) -> Result<()>
+	{
+		let request = format!(
+			"!RD{:02x}{:02x}{:02x}{:08x}{:08x}#",
+			dmi.dev_index(),
+			dmi.idle_cycles(),
+			dmi.address_width(),
+			address,
+			value
+		);
+		self.dmi_request(&request, address)?;
+		Ok(())
 	}
 }
 
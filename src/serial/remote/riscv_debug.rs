@@ -2,8 +2,57 @@
 // SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
 // SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
 
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, warn};
+
+use crate::serial::remote::{BmdJtagProtocol, BmdRiscvProtocol, JtagDev};
+
+/// JTAG instruction selecting the DTM control and status register
+const RISCV_IR_DTMCS: u32 = 0x10;
+/// JTAG instruction selecting the DMI access register
+const RISCV_IR_DMI: u32 = 0x11;
+/// The DTM instructions need an IR of at least 5 bits, so TAPs with shorter ones can't be DTMs
+const RISCV_MIN_IR_LEN: u8 = 5;
+
+const RISCV_DTMCS_VERSION_MASK: u32 = 0x0000000f;
+const RISCV_DTMCS_ABITS_SHIFT: u32 = 4;
+const RISCV_DTMCS_ABITS_MASK: u32 = 0x000003f0;
+const RISCV_DTMCS_IDLE_SHIFT: u32 = 12;
+const RISCV_DTMCS_IDLE_MASK: u32 = 0x00007000;
+const RISCV_DTMCS_DMI_RESET: u32 = 1 << 16;
+
+const RISCV_DMI_OP_NOP: u128 = 0;
+const RISCV_DMI_OP_READ: u128 = 1;
+const RISCV_DMI_OP_WRITE: u128 = 2;
+/// Number of bits in a DMI access, not counting the address
+const RISCV_DMI_ACCESS_BITS: usize = 34;
+
+const RISCV_DMI_STATUS_SUCCESS: u8 = 0;
+const RISCV_DMI_STATUS_FAILED: u8 = 2;
+const RISCV_DMI_STATUS_BUSY: u8 = 3;
+/// How many times to retry a DMI access the DM reports as busy before giving up
+const RISCV_DMI_BUSY_RETRIES: usize = 8;
+
+/// DMI address of the Debug Module's status register
+pub const RISCV_DM_STATUS: u32 = 0x11;
+const RISCV_DM_STATUS_VERSION_MASK: u32 = 0x0000000f;
+
+/// JTAG IDCODEs (and the bits of them that matter) of RISC-V parts we know the names of
+const RISCV_JTAG_PARTS: &[(u32, u32, &str)] = &[
+	(0x1000563d, 0x0fffffff, "GD32VF103"),
+	(0x00005c25, 0x0fffffff, "ESP32-C3"),
+	(0x0000dc25, 0x0fffffff, "ESP32-C6"),
+	(0x04e4796b, 0x0fffffff, "Kendryte K210"),
+	// Anything from SiFive, going by the designer code alone
+	(0x00000913, 0x00000fff, "SiFive"),
+];
+
 /// A version-agnostic Debug Module Interface on a RISC-V device
 #[allow(unused)]
+#[derive(Clone, Debug)]
 pub struct RiscvDmi
 {
 	/// DMI designer code
@@ -22,6 +71,7 @@ pub struct RiscvDmi
 }
 
 /// RISC-V Debug spec versions that we know about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RiscvDebugVersion
 {
 	Unknown,
@@ -30,3 +80,255 @@ pub enum RiscvDebugVersion
 	V0_13,
 	V1_0,
 }
+
+/// Bit-banged DMI access over raw JTAG, for probes without RISC-V acceleration
+pub struct RiscvJtagDmi
+{
+	jtag: Box<dyn BmdJtagProtocol>,
+	devices: Vec<JtagDev>,
+	/// Additional idle cycles found to be needed by DMs that reported themselves busy
+	extra_idle_cycles: AtomicUsize,
+}
+
+/// Look up whether an IDCODE belongs to a RISC-V part we know of, returning the part's name if so - this is
+/// only for naming parts, whether a TAP is a DTM is found out by [`RiscvDmi::from_jtag`]
+pub fn riscv_jtag_part(idcode: u32) -> Option<&'static str>
+{
+	RISCV_JTAG_PARTS
+		.iter()
+		.find(|&&(part_idcode, mask, _)| idcode & mask == part_idcode & mask)
+		.map(|(_, _, description)| *description)
+}
+
+impl RiscvDmi
+{
+	/// Probe a device on the JTAG chain for a RISC-V DTM by reading its `dtmcs` register, failing if the
+	/// device does not have one
+	pub fn from_jtag(jtag: &dyn BmdJtagProtocol, device: &JtagDev, dev_index: u8) -> Result<Self>
+	{
+		if device.ir_len() < RISCV_MIN_IR_LEN {
+			return Err(eyre!("IR length {} is too short for a RISC-V DTM", device.ir_len()));
+		}
+		device.write_ir(jtag, RISCV_IR_DTMCS)?;
+		let mut dtmcs = [0u8; 4];
+		device.shift_dr(jtag, Some(&mut dtmcs), &[0; 4], 32)?;
+		let dtmcs = u32::from_le_bytes(dtmcs);
+		debug!("DTM {} dtmcs = 0x{:08x}", dev_index, dtmcs);
+		let (version, address_width, idle_cycles) = validate_dtmcs(dtmcs)?;
+
+		Ok(Self {
+			// The designer code is JEP-106 encoded into the IDCODE just above the fixed bit 0
+			designer_code: ((device.idcode() >> 1) & 0x7ff) as u16,
+			version,
+			dev_index,
+			idle_cycles,
+			address_width,
+			fault: 0,
+		})
+	}
+
+	/// Version 0.13 and 1.0 DTMs are indistinguishable, so ask the Debug Module behind this DMI which
+	/// version of the spec it actually implements
+	pub fn refine_version(&mut self, access: &dyn BmdRiscvProtocol) -> Result<()>
+	{
+		if self.version != RiscvDebugVersion::V0_13 {
+			return Ok(());
+		}
+		let status = access.dmi_read(self, RISCV_DM_STATUS)?;
+		self.version = match status & RISCV_DM_STATUS_VERSION_MASK {
+			0 => RiscvDebugVersion::Unimplemented,
+			2 => RiscvDebugVersion::V0_13,
+			3 => RiscvDebugVersion::V1_0,
+			_ => RiscvDebugVersion::Unknown,
+		};
+		Ok(())
+	}
+
+	/// The JEP-106 code of the device's designer
+	pub fn designer_code(&self) -> u16
+	{
+		self.designer_code
+	}
+
+	/// Which version of the debug spec the DMI implements
+	pub fn version(&self) -> RiscvDebugVersion
+	{
+		self.version
+	}
+
+	/// The index of the DMI's device on the JTAG chain
+	pub fn dev_index(&self) -> u8
+	{
+		self.dev_index
+	}
+
+	/// How many Run-Test/Idle cycles the DTM needs after each access
+	pub fn idle_cycles(&self) -> u8
+	{
+		self.idle_cycles
+	}
+
+	/// The width of addresses on the DMI bus
+	pub fn address_width(&self) -> u8
+	{
+		self.address_width
+	}
+}
+
+/// Pull the version, DMI address width and idle cycle count out of a `dtmcs` register value
+fn decode_dtmcs(dtmcs: u32) -> Result<(RiscvDebugVersion, u8, u8)>
+{
+	let version = match dtmcs & RISCV_DTMCS_VERSION_MASK {
+		0 => RiscvDebugVersion::V0_11,
+		// 0.13 and 1.0 share a DTM version, the DM has to be asked to tell them apart
+		1 => RiscvDebugVersion::V0_13,
+		15 => RiscvDebugVersion::Unimplemented,
+		_ => RiscvDebugVersion::Unknown,
+	};
+	let address_width = ((dtmcs & RISCV_DTMCS_ABITS_MASK) >> RISCV_DTMCS_ABITS_SHIFT) as u8;
+	let idle_cycles = ((dtmcs & RISCV_DTMCS_IDLE_MASK) >> RISCV_DTMCS_IDLE_SHIFT) as u8;
+	// The DMI needs at least 7 address bits to reach all the registers the spec requires
+	if address_width < 7 {
+		return Err(eyre!("Invalid DMI address width {} in dtmcs 0x{:08x}", address_width, dtmcs));
+	}
+	Ok((version, address_width, idle_cycles))
+}
+
+/// Check a `dtmcs` value read from a TAP actually came from a DTM, which has to implement version 0.11 or
+/// 0.13 (and 1.0) of the spec with a usable DMI, then decode it
+fn validate_dtmcs(dtmcs: u32) -> Result<(RiscvDebugVersion, u8, u8)>
+{
+	let (version, address_width, idle_cycles) = decode_dtmcs(dtmcs)?;
+	match version {
+		RiscvDebugVersion::V0_11 | RiscvDebugVersion::V0_13 => Ok((version, address_width, idle_cycles)),
+		_ => Err(eyre!("dtmcs 0x{:08x} does not describe a RISC-V DTM", dtmcs)),
+	}
+}
+
+impl RiscvJtagDmi
+{
+	/// Take over the JTAG chain for bit-banged DMI access to the DTMs on it
+	pub fn new(jtag: Box<dyn BmdJtagProtocol>, devices: Vec<JtagDev>) -> Self
+	{
+		warn!("Falling back to bit-banged RISC-V DMI access, this will be slow");
+		Self {
+			jtag,
+			devices,
+			extra_idle_cycles: AtomicUsize::new(0),
+		}
+	}
+
+	fn device(&self, dmi: &RiscvDmi) -> Result<&JtagDev>
+	{
+		self.devices
+			.get(dmi.dev_index as usize)
+			.ok_or_else(|| eyre!("No JTAG device {} for DMI access", dmi.dev_index))
+	}
+
+	/// Perform a single DMI scan, returning the status and data of the previous access
+	fn dmi_scan(&self, dmi: &RiscvDmi, op: u128, address: u32, value: u32) -> Result<(u8, u32)>
+	{
+		let device = self.device(dmi)?;
+		let clock_cycles = dmi.address_width as usize + RISCV_DMI_ACCESS_BITS;
+		let length = clock_cycles.div_ceil(8);
+		let request = ((address as u128) << RISCV_DMI_ACCESS_BITS) | ((value as u128) << 2) | op;
+		let mut response = [0u8; 16];
+		device.shift_dr(
+			self.jtag.as_ref(),
+			Some(&mut response[..length]),
+			&request.to_le_bytes()[..length],
+			clock_cycles,
+		)?;
+		// Give the DM the time it needs to complete the access
+		let idle_cycles = dmi.idle_cycles as usize + self.extra_idle_cycles.load(Ordering::Relaxed);
+		if idle_cycles != 0 {
+			self.jtag.tap_cycle(false, false, idle_cycles)?;
+		}
+		let response = u128::from_le_bytes(response);
+		Ok(((response & 3) as u8, (response >> 2) as u32))
+	}
+
+	/// Clear a sticky error or busy condition in the DTM
+	fn dmi_reset(&self, dmi: &RiscvDmi) -> Result<()>
+	{
+		let device = self.device(dmi)?;
+		device.write_ir(self.jtag.as_ref(), RISCV_IR_DTMCS)?;
+		device.shift_dr(self.jtag.as_ref(), None, &RISCV_DTMCS_DMI_RESET.to_le_bytes(), 32)
+	}
+
+	/// Run a DMI access to completion, retrying with more idle cycles while the DM reports itself busy
+	fn dmi_access(&self, dmi: &RiscvDmi, op: u128, address: u32, value: u32) -> Result<u32>
+	{
+		for _ in 0..RISCV_DMI_BUSY_RETRIES {
+			self.device(dmi)?.write_ir(self.jtag.as_ref(), RISCV_IR_DMI)?;
+			self.dmi_scan(dmi, op, address, value)?;
+			// The result of an access comes back in the scan after it
+			let (status, data) = self.dmi_scan(dmi, RISCV_DMI_OP_NOP, 0, 0)?;
+			match status {
+				RISCV_DMI_STATUS_SUCCESS => return Ok(data),
+				RISCV_DMI_STATUS_BUSY => {
+					self.dmi_reset(dmi)?;
+					let extra_idle_cycles = self.extra_idle_cycles.fetch_add(1, Ordering::Relaxed) + 1;
+					debug!("DMI busy, increasing idle cycles by {}", extra_idle_cycles);
+				},
+				RISCV_DMI_STATUS_FAILED => {
+					self.dmi_reset(dmi)?;
+					return Err(eyre!("DMI access to 0x{:x} failed", address));
+				},
+				_ => return Err(eyre!("Invalid DMI status {} accessing 0x{:x}", status, address)),
+			}
+		}
+		Err(eyre!("DMI access to 0x{:x} timed out, DM stayed busy", address))
+	}
+}
+
+impl BmdRiscvProtocol for RiscvJtagDmi
+{
+	fn dmi_read(&self, dmi: &RiscvDmi, address: u32) -> Result<u32>
+	{
+		self.dmi_access(dmi, RISCV_DMI_OP_READ, address, 0)
+	}
+
+	fn dmi_write(&self, dmi: &RiscvDmi, address: u32, value: u32) -> Result<()>
+	{
+		self.dmi_access(dmi, RISCV_DMI_OP_WRITE, address, value)?;
+		Ok(())
+	}
+}
+
+impl Display for RiscvDebugVersion
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		match self {
+			Self::Unknown => write!(fmt, "<unknown>"),
+			Self::Unimplemented => write!(fmt, "<unimplemented>"),
+			Self::V0_11 => write!(fmt, "v0.11"),
+			Self::V0_13 => write!(fmt, "v0.13"),
+			Self::V1_0 => write!(fmt, "v1.0"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn dtmcs_decoding()
+	{
+		// GD32VF103: v0.13 DTM with 7 address bits and 5 idle cycles
+		assert_eq!(decode_dtmcs(0x00005071).unwrap(), (RiscvDebugVersion::V0_13, 7, 5));
+		assert_eq!(decode_dtmcs(0x00000070).unwrap(), (RiscvDebugVersion::V0_11, 7, 0));
+		assert_eq!(decode_dtmcs(0x0000007f).unwrap().0, RiscvDebugVersion::Unimplemented);
+		assert!(decode_dtmcs(0x00000001).is_err());
+		// Only version 0.11 and 0.13 DTMs count when probing unknown TAPs
+		assert_eq!(validate_dtmcs(0x00005071).unwrap(), (RiscvDebugVersion::V0_13, 7, 5));
+		assert!(validate_dtmcs(0x0000007f).is_err());
+		assert!(validate_dtmcs(0xffffffff).is_err());
+		assert!(validate_dtmcs(0x00000000).is_err());
+		assert_eq!(riscv_jtag_part(0x1000563d), Some("GD32VF103"));
+		assert_eq!(riscv_jtag_part(0x4ba00477), None);
+	}
+}
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! The connections the BMD remote protocol can be carried over
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, trace};
+
+use crate::serial::port;
+
+/// How long to wait on the probe to send something when talking over the network
+const TCP_READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A connection to a probe's remote protocol interface
+pub trait BmdTransport: Send
+{
+	/// Wait a while for data from the probe, reading as much as is available into `buffer` and
+	/// returning how much that was - or 0 if nothing arrived in time
+	fn read_available(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+	fn write_all(&mut self, data: &[u8]) -> Result<()>;
+
+	/// Make another handle to the same connection, such as for reading and writing from different threads
+	fn try_clone(&self) -> Result<Box<dyn BmdTransport>>;
+}
+
+/// The remote protocol interface of a locally connected probe, as a serial port
+pub struct SerialTransport
+{
+	handle: File,
+}
+
+/// A probe's remote protocol interface reached over the network, such as ctxLink's WiFi remote port
+/// or a bmputil remote protocol server
+pub struct TcpTransport
+{
+	stream: TcpStream,
+}
+
+impl SerialTransport
+{
+	pub fn open(serial_port: &Path) -> Result<Self>
+	{
+		debug!("Opening probe interface at {:?}", serial_port);
+		let handle = File::options().read(true).write(true).open(serial_port)?;
+		// Ready the handle for use with the remote serial protocol
+		port::configure(&handle)?;
+		Ok(Self {
+			handle,
+		})
+	}
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+impl BmdTransport for SerialTransport
+{
+	fn read_available(&mut self, buffer: &mut [u8]) -> Result<usize>
+	{
+		use std::mem::MaybeUninit;
+		use std::os::fd::AsRawFd;
+		use std::ptr::null_mut;
+
+		use libc::{FD_SET, FD_SETSIZE, FD_ZERO, c_int, fd_set, select, timeval};
+
+		// Set up a FD set that describes our handle's FD
+		let mut select_set = MaybeUninit::<fd_set>::uninit();
+		unsafe {
+			FD_ZERO(select_set.as_mut_ptr());
+			FD_SET(self.handle.as_raw_fd(), select_set.as_mut_ptr());
+		}
+		let mut select_set = unsafe { select_set.assume_init() };
+
+		// Wait for more data from the probe for up to 2 seconds
+		let mut timeout = timeval {
+			tv_sec: 2,
+			tv_usec: 0,
+		};
+		let result = unsafe { select(FD_SETSIZE as c_int, &mut select_set, null_mut(), null_mut(), &mut timeout) };
+
+		if result < 0 {
+			// If the select call failed, bail
+			Err(eyre!("Failed on select"))
+		} else if result == 0 {
+			// If we timed out, there's nothing to read
+			Ok(0)
+		} else {
+			// Otherwise we now know there's data, so try to fill the buffer
+			let bytes_received = self.handle.read(buffer)?;
+			trace!("Read {} bytes from probe", bytes_received);
+			Ok(bytes_received)
+		}
+	}
+
+	fn write_all(&mut self, data: &[u8]) -> Result<()>
+	{
+		Ok(self.handle.write_all(data)?)
+	}
+
+	fn try_clone(&self) -> Result<Box<dyn BmdTransport>>
+	{
+		Ok(Box::new(Self {
+			handle: self.handle.try_clone()?,
+		}))
+	}
+}
+
+#[cfg(target_os = "windows")]
+impl BmdTransport for SerialTransport
+{
+	fn read_available(&mut self, buffer: &mut [u8]) -> Result<usize>
+	{
+		use std::os::windows::io::AsRawHandle;
+
+		use windows::Win32::Foundation::{HANDLE, WAIT_OBJECT_0};
+		use windows::Win32::System::Threading::WaitForSingleObject;
+
+		// Try to wait for up to 100ms for data to become available
+		let handle = HANDLE(self.handle.as_raw_handle());
+		if unsafe { WaitForSingleObject(handle, 100) } != WAIT_OBJECT_0 {
+			return Ok(0);
+		}
+
+		// Now we know there's data, so try to fill the buffer
+		let bytes_received = self.handle.read(buffer)?;
+		trace!("Read {} bytes from probe", bytes_received);
+		Ok(bytes_received)
+	}
+
+	fn write_all(&mut self, data: &[u8]) -> Result<()>
+	{
+		Ok(self.handle.write_all(data)?)
+	}
+
+	fn try_clone(&self) -> Result<Box<dyn BmdTransport>>
+	{
+		Ok(Box::new(Self {
+			handle: self.handle.try_clone()?,
+		}))
+	}
+}
+
+impl TcpTransport
+{
+	/// Connect to a probe at the given host and port
+	pub fn connect(address: &str) -> Result<Self>
+	{
+		debug!("Connecting to probe at {}", address);
+		let stream = TcpStream::connect(address)?;
+		// Every request waits on a response, so don't let them sit in buffers
+		stream.set_nodelay(true)?;
+		stream.set_read_timeout(Some(TCP_READ_TIMEOUT))?;
+		Ok(Self {
+			stream,
+		})
+	}
+}
+
+impl BmdTransport for TcpTransport
+{
+	fn read_available(&mut self, buffer: &mut [u8]) -> Result<usize>
+	{
+		match self.stream.read(buffer) {
+			Ok(0) => Err(eyre!("Connection to probe closed")),
+			Ok(bytes_received) => {
+				trace!("Read {} bytes from probe", bytes_received);
+				Ok(bytes_received)
+			},
+			// Which of these a read timeout gives depends on the platform
+			Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(0),
+			Err(error) => Err(error.into()),
+		}
+	}
+
+	fn write_all(&mut self, data: &[u8]) -> Result<()>
+	{
+		Ok(self.stream.write_all(data)?)
+	}
+
+	fn try_clone(&self) -> Result<Box<dyn BmdTransport>>
+	{
+		Ok(Box::new(Self {
+			stream: self.stream.try_clone()?,
+		}))
+	}
+}
+
+/// Where a remote protocol URL says to find the probe
+#[derive(Debug, PartialEq, Eq)]
+enum RemoteLocation<'a>
+{
+	Tcp(&'a str),
+	Serial(&'a Path),
+}
+
+/// Open the transport a remote protocol URL refers to - either tcp://host:port for a probe on the
+/// network, or a path to a local serial port (optionally as serial://path)
+pub fn open_url(url: &str) -> Result<Box<dyn BmdTransport>>
+{
+	match parse_url(url)? {
+		RemoteLocation::Tcp(address) => Ok(Box::new(TcpTransport::connect(address)?)),
+		RemoteLocation::Serial(path) => Ok(Box::new(SerialTransport::open(path)?)),
+	}
+}
+
+/// Work out where a remote protocol URL points, without connecting to anything
+fn parse_url(url: &str) -> Result<RemoteLocation<'_>>
+{
+	let (scheme, location) = url.split_once("://").unwrap_or(("serial", url));
+	match scheme {
+		"tcp" => Ok(RemoteLocation::Tcp(location)),
+		"serial" => Ok(RemoteLocation::Serial(Path::new(location))),
+		_ => Err(eyre!(
+			"Unsupported remote protocol URL scheme '{}', expected tcp://host:port",
+			scheme
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn url_parsing()
+	{
+		assert_eq!(
+			parse_url("tcp://192.168.1.2:2000").unwrap(),
+			RemoteLocation::Tcp("192.168.1.2:2000")
+		);
+		assert_eq!(parse_url("tcp://[::1]:2000").unwrap(), RemoteLocation::Tcp("[::1]:2000"));
+		assert_eq!(
+			parse_url("serial:///dev/ttyACM0").unwrap(),
+			RemoteLocation::Serial(Path::new("/dev/ttyACM0"))
+		);
+		// Bare paths are taken to be serial ports
+		assert_eq!(
+			parse_url("/dev/ttyACM0").unwrap(),
+			RemoteLocation::Serial(Path::new("/dev/ttyACM0"))
+		);
+		assert_eq!(parse_url("COM3").unwrap(), RemoteLocation::Serial(Path::new("COM3")));
+		assert!(parse_url("udp://192.168.1.2:2000").is_err());
+	}
+}
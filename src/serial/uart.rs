@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! The auxiliary UART probes expose as their second CDC-ACM interface, wired to the target's own serial port
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use log::debug;
+
+use crate::serial::port;
+
+/// The probe's auxiliary UART interface
+pub struct UartInterface
+{
+	handle: File,
+}
+
+/// Puts a timestamp (the time since it was created) at the start of each line of UART output
+pub struct LineTimestamper
+{
+	start: Instant,
+	at_line_start: bool,
+}
+
+impl UartInterface
+{
+	/// Open the UART interface at the given path, setting the probe's UART to run at `baud_rate`
+	pub fn from_path(serial_port: &Path, baud_rate: u32) -> Result<Self>
+	{
+		debug!("Opening probe UART interface at {:?}", serial_port);
+		let handle = File::options().read(true).write(true).open(serial_port)?;
+		port::configure(&handle)?;
+		// The probe sets its UART up from the line coding requested on the interface
+		port::set_baud_rate(&handle, baud_rate)?;
+		Ok(Self {
+			handle,
+		})
+	}
+
+	/// Make another handle to the UART, such as for writing to it from another thread
+	pub fn try_clone(&self) -> Result<Self>
+	{
+		Ok(Self {
+			handle: self.handle.try_clone()?,
+		})
+	}
+
+	/// Read whatever the target has sent, returning 0 if nothing arrived for a while
+	pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize>
+	{
+		let bytes_received = self.handle.read(buffer)?;
+		// The port gives up waiting for data after a while (straight away on Windows), so don't spin on it
+		if bytes_received == 0 {
+			thread::sleep(Duration::from_millis(1));
+		}
+		Ok(bytes_received)
+	}
+
+	pub fn write_all(&mut self, data: &[u8]) -> Result<()>
+	{
+		Ok(self.handle.write_all(data)?)
+	}
+}
+
+impl LineTimestamper
+{
+	pub fn new() -> Self
+	{
+		Self {
+			start: Instant::now(),
+			at_line_start: true,
+		}
+	}
+
+	/// Timestamp the next piece of output, which may start or end part way through a line
+	pub fn process(&mut self, data: &[u8]) -> Vec<u8>
+	{
+		self.process_at(data, self.start.elapsed())
+	}
+
+	fn process_at(&mut self, data: &[u8], elapsed: Duration) -> Vec<u8>
+	{
+		let mut output = Vec::with_capacity(data.len());
+		for &byte in data {
+			if self.at_line_start {
+				output.extend(format!("[{:5}.{:03}] ", elapsed.as_secs(), elapsed.subsec_millis()).into_bytes());
+			}
+			output.push(byte);
+			self.at_line_start = byte == b'\n';
+		}
+		output
+	}
+}
+
+impl Default for LineTimestamper
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn line_timestamps()
+	{
+		let mut timestamper = LineTimestamper::new();
+		let output = timestamper.process_at(b"boot\nhel", Duration::from_millis(1500));
+		assert_eq!(output, b"[    1.500] boot\n[    1.500] hel");
+		// The rest of a line carries on without a new timestamp
+		let output = timestamper.process_at(b"lo\n", Duration::from_millis(62_004));
+		assert_eq!(output, b"lo\n");
+		let output = timestamper.process_at(b"\n", Duration::from_millis(62_004));
+		assert_eq!(output, b"[   62.004] \n");
+	}
+}
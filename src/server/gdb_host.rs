@@ -0,0 +1,873 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! A GDB server run by bmputil itself (as BMDA does), driving a Cortex-M target through the probe's
+//! remote protocol rather than relying on the GDB server built into the probe firmware
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use log::{debug, info, trace, warn};
+
+use crate::serial::gdb_rsp::{GDB_ACK, GDB_NACK, compute_checksum, escape, frame_packet, unescape};
+use crate::serial::gdb_target::{MemoryKind, MemoryMap, MemoryRegion};
+use crate::serial::remote::{hexify, unhexify};
+use crate::server::gdb_proxy::{RspItem, RspScanner, packet_payload};
+use crate::target::Target;
+use crate::target::breakpoints::{Fpb, HaltReason};
+use crate::target::coresight::discover_components;
+use crate::target::cortexm::{
+	CORTEXM_CORE_REGISTER_NAMES, CORTEXM_REG_MSP, CORTEXM_REG_PC, CORTEXM_REG_SPECIAL, CortexM,
+};
+use crate::target::flash::{FlashDriver, detect_flash};
+use crate::target::verify::crc32_update;
+
+/// Largest packet we accept from GDB, as advertised in our qSupported reply
+const GDB_HOST_PACKET_SIZE: usize = 0x1000;
+/// How often to check on the target and for interrupt requests from the client while the target is running
+const GDB_HOST_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// GDB register number of the first of PRIMASK, BASEPRI, FAULTMASK and CONTROL, which are packed into
+/// a single DCRSR register and so are presented as 8-bit registers of their own following the others
+const GDB_HOST_SPECIAL_REGISTERS: usize = 19;
+/// How many registers GDB sees in total
+const GDB_HOST_REGISTERS: usize = GDB_HOST_SPECIAL_REGISTERS + 4;
+
+const GDB_SIGINT: u8 = 2;
+const GDB_SIGTRAP: u8 = 5;
+
+/// Serves GDB clients one at a time, implementing the remote serial protocol on top of Cortex-M run control
+pub struct GdbHostServer
+{
+	target: Target,
+}
+
+/// A connected GDB client, and the state of the target it's debugging
+struct GdbSession<'a>
+{
+	stream: TcpStream,
+	scanner: RspScanner,
+	/// Traffic from the client that has been read but not acted on yet
+	pending: VecDeque<RspItem>,
+	no_ack: bool,
+	/// The last packet sent, in case the client asks for it to be sent again
+	last_reply: Vec<u8>,
+	/// Whether the client has detached from (or killed) the target, ending the session
+	finished: bool,
+	core: CortexM<'a>,
+	fpb: Option<Fpb<'a>>,
+	flash: Option<Box<dyn FlashDriver>>,
+}
+
+/// Build the target description given to GDB, which numbers the registers in the order they're listed
+fn target_description() -> String
+{
+	let mut xml = String::from(concat!(
+		"<?xml version=\"1.0\"?>",
+		"<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+		"<target>",
+		"<architecture>arm</architecture>",
+		"<feature name=\"org.gnu.gdb.arm.m-profile\">",
+	));
+	for (number, name) in CORTEXM_CORE_REGISTER_NAMES[..GDB_HOST_SPECIAL_REGISTERS]
+		.iter()
+		.enumerate()
+	{
+		if number == CORTEXM_REG_MSP as usize {
+			xml.push_str("</feature><feature name=\"org.gnu.gdb.arm.m-system\">");
+		}
+		let kind = match *name {
+			"sp" | "msp" | "psp" => "data_ptr",
+			"pc" => "code_ptr",
+			_ => "int",
+		};
+		xml.push_str(&format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\"/>", name, kind));
+	}
+	for name in ["primask", "basepri", "faultmask", "control"] {
+		xml.push_str(&format!("<reg name=\"{}\" bitsize=\"8\" type=\"int\" group=\"system\"/>", name));
+	}
+	xml.push_str("</feature></target>");
+	xml
+}
+
+/// Build the memory map given to GDB, which tells it where Flash is so it uses the vFlash packets to load there
+fn memory_map(flash: &MemoryRegion) -> MemoryMap
+{
+	let ram = |start, end| MemoryRegion {
+		kind: MemoryKind::Ram,
+		start,
+		length: end - start,
+	};
+	// GDB refuses to touch memory not in the map, so everything else is treated as RAM
+	MemoryMap {
+		regions: vec![ram(0, flash.start), flash.clone(), ram(flash.end(), 0x1_0000_0000)],
+	}
+}
+
+fn parse_hex(value: &str) -> Result<u64>
+{
+	u64::from_str_radix(value, 16).map_err(|_| eyre!("'{}' is not a valid hex number", value))
+}
+
+/// Parse the `address,length` arguments that many packets take
+fn parse_address_length(arguments: &str) -> Result<(u64, usize)>
+{
+	let (address, length) = arguments
+		.split_once(',')
+		.ok_or_else(|| eyre!("Expected address,length but got '{}'", arguments))?;
+	Ok((parse_hex(address)?, parse_hex(length)? as usize))
+}
+
+/// Split a packet carrying binary data into its textual header and the data after the first ':'
+fn split_binary(packet: &[u8]) -> Result<(&str, &[u8])>
+{
+	let separator = packet
+		.iter()
+		.position(|&byte| byte == b':')
+		.ok_or_eyre("Packet is missing its data")?;
+	Ok((std::str::from_utf8(&packet[..separator])?, &packet[separator + 1..]))
+}
+
+/// Answer a qXfer read of `document` with the part of it requested by the `offset,length` arguments
+fn xfer_reply(document: &str, arguments: &str) -> Result<Vec<u8>>
+{
+	let (offset, length) = parse_address_length(arguments)?;
+	let document = document.as_bytes();
+	let start = (offset as usize).min(document.len());
+	let end = (start + length).min(document.len());
+	// 'l' marks the last part of the document, 'm' that there's more to come
+	let mut reply = if end == document.len() {
+		vec![b'l']
+	} else {
+		vec![b'm']
+	};
+	reply.extend(escape(&document[start..end]));
+	Ok(reply)
+}
+
+impl GdbHostServer
+{
+	/// Set up a GDB server for the attached target
+	pub fn new(target: Target) -> Self
+	{
+		Self {
+			target,
+		}
+	}
+
+	/// Accept GDB clients from `listener`, serving each in turn until it goes away. This only returns if
+	/// listening for clients fails.
+	pub fn serve(&self, listener: TcpListener) -> Result<()>
+	{
+		for stream in listener.incoming() {
+			let stream = stream?;
+			let peer = stream
+				.peer_addr()
+				.map_or_else(|_| "<unknown>".into(), |address| address.to_string());
+			info!("GDB client {} connected", peer);
+			if let Err(error) = self.serve_client(stream) {
+				warn!("Lost connection to GDB client {}: {}", peer, error);
+			}
+			info!("GDB client {} disconnected", peer);
+		}
+		Ok(())
+	}
+
+	fn serve_client(&self, stream: TcpStream) -> Result<()>
+	{
+		// RSP traffic is lots of small request/response exchanges, so don't let them sit in buffers
+		stream.set_nodelay(true)?;
+
+		// Halt the core for the client to start looking at, and find out what it has to offer
+		let core = self.target.cortexm()?;
+		core.halt()?;
+		let components = discover_components(core.ap())?;
+		let fpb = match Fpb::new(core.ap(), &components) {
+			Ok(fpb) => Some(fpb),
+			Err(error) => {
+				warn!("Breakpoints will not be available: {}", error);
+				None
+			},
+		};
+		let flash = match detect_flash(core.ap()) {
+			Ok(flash) => flash,
+			Err(error) => {
+				debug!("Failed to identify the target's Flash: {}", error);
+				None
+			},
+		};
+		if flash.is_none() {
+			warn!("Target Flash is not supported, only RAM can be loaded");
+		}
+
+		let mut session = GdbSession {
+			stream,
+			scanner: RspScanner::default(),
+			pending: VecDeque::new(),
+			no_ack: false,
+			last_reply: Vec::new(),
+			finished: false,
+			core,
+			fpb,
+			flash,
+		};
+		let result = session.process_packets();
+		session.end_session();
+		result
+	}
+}
+
+impl GdbSession<'_>
+{
+	/// Act on traffic from the client until it detaches or goes away
+	fn process_packets(&mut self) -> Result<()>
+	{
+		loop {
+			while let Some(item) = self.pending.pop_front() {
+				self.handle_item(item)?;
+				if self.finished {
+					return Ok(());
+				}
+			}
+			if !self.read_items()? {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Read whatever the client has sent, returning false if it has gone away. How long this waits for data
+	/// depends on the stream's read timeout.
+	fn read_items(&mut self) -> Result<bool>
+	{
+		let mut buffer = [0u8; 1024];
+		let bytes_received = match self.stream.read(&mut buffer) {
+			Ok(0) => return Ok(false),
+			Ok(bytes_received) => bytes_received,
+			// Which of these a read timeout gives depends on the platform
+			Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(true),
+			Err(error) => return Err(error.into()),
+		};
+		for &byte in &buffer[..bytes_received] {
+			if let Some(item) = self.scanner.push(byte) {
+				self.pending.push_back(item);
+			}
+		}
+		Ok(true)
+	}
+
+	fn send_reply(&mut self, payload: &[u8]) -> Result<()>
+	{
+		trace!("GDB host -> client: {}", String::from_utf8_lossy(payload));
+		self.last_reply = frame_packet(payload);
+		Ok(self.stream.write_all(&self.last_reply)?)
+	}
+
+	fn handle_item(&mut self, item: RspItem) -> Result<()>
+	{
+		match item {
+			RspItem::Packet(packet) => {
+				let payload = packet_payload(&packet);
+				let valid = std::str::from_utf8(&packet[packet.len() - 2..])
+					.ok()
+					.and_then(|checksum| u8::from_str_radix(checksum, 16).ok()) ==
+					Some(compute_checksum(payload));
+				if !valid {
+					warn!("Checksum mismatch on packet from GDB client");
+					if !self.no_ack {
+						self.stream.write_all(&[GDB_NACK])?;
+					}
+					return Ok(());
+				}
+				if !self.no_ack {
+					self.stream.write_all(&[GDB_ACK])?;
+				}
+
+				let payload = unescape(payload);
+				trace!("GDB client -> host: {}", String::from_utf8_lossy(&payload));
+				let reply = match self.handle_packet(&payload) {
+					Ok(reply) => reply,
+					Err(error) => {
+						warn!("Failed to handle {:?}: {}", String::from_utf8_lossy(&payload), error);
+						Some(b"E01".to_vec())
+					},
+				};
+				if let Some(reply) = reply {
+					self.send_reply(&reply)?;
+				}
+				// Acks stop as soon as our reply to the request for no-ack mode has been sent
+				if payload == b"QStartNoAckMode" {
+					self.no_ack = true;
+				}
+			},
+			RspItem::Ack(GDB_NACK) if !self.no_ack => {
+				debug!("GDB client requested retransmission");
+				self.stream.write_all(&self.last_reply)?;
+			},
+			// The target is already halted whenever we're waiting on packets, so interrupts have nothing to do
+			_ => (),
+		}
+		Ok(())
+	}
+
+	/// Act on a packet from the client, returning the reply to send (if any)
+	fn handle_packet(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>>
+	{
+		// Deal with the packets carrying binary data first, so the rest can be handled as text
+		if let Some(arguments) = packet.strip_prefix(b"X") {
+			let (arguments, data) = split_binary(arguments)?;
+			let (address, _) = parse_address_length(arguments)?;
+			if !data.is_empty() {
+				self.core.ap().mem_write(address, data)?;
+			}
+			return Ok(Some(b"OK".to_vec()));
+		}
+		if let Some(arguments) = packet.strip_prefix(b"vFlashWrite:") {
+			let (address, data) = split_binary(arguments)?;
+			self.flash()?.write(self.core.ap(), parse_hex(address)?, data)?;
+			return Ok(Some(b"OK".to_vec()));
+		}
+
+		let packet = std::str::from_utf8(packet)?;
+		let reply = match packet {
+			"?" => format!("S{:02x}", GDB_SIGTRAP).into_bytes(),
+			"g" => self.read_registers()?,
+			"c" => return self.resume(),
+			"s" => self.step()?,
+			"D" => {
+				self.finished = true;
+				b"OK".to_vec()
+			},
+			"k" => {
+				self.finished = true;
+				return Ok(None);
+			},
+			"QStartNoAckMode" => b"OK".to_vec(),
+			"vFlashDone" => {
+				self.flash()?.done(self.core.ap())?;
+				b"OK".to_vec()
+			},
+			"qAttached" => b"1".to_vec(),
+			_ if packet.starts_with("qSupported") => {
+				let mut features = format!(
+					"PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;hwbreak+",
+					GDB_HOST_PACKET_SIZE
+				);
+				if self.flash.is_some() {
+					features.push_str(";qXfer:memory-map:read+");
+				}
+				features.into_bytes()
+			},
+			_ if packet.starts_with('H') => b"OK".to_vec(),
+			_ if packet.starts_with("D;") => {
+				self.finished = true;
+				b"OK".to_vec()
+			},
+			_ => return self.handle_command(packet),
+		};
+		Ok(Some(reply))
+	}
+
+	/// Act on a packet that takes arguments, returning the reply to send (if any)
+	fn handle_command(&mut self, packet: &str) -> Result<Option<Vec<u8>>>
+	{
+		let mut chars = packet.chars();
+		let Some(command) = chars.next() else {
+			// An empty packet has nothing for us to do, so it gets the same reply as one we don't support
+			return Ok(Some(Vec::new()));
+		};
+		let arguments = chars.as_str();
+		let reply = match command {
+			'm' => {
+				let (address, length) = parse_address_length(arguments)?;
+				let mut data = vec![0; length.min(GDB_HOST_PACKET_SIZE / 2)];
+				self.core.ap().mem_read(&mut data, address)?;
+				hexify(&data).into_bytes()
+			},
+			'M' => {
+				let (arguments, data) = arguments
+					.split_once(':')
+					.ok_or_eyre("Memory write is missing its data")?;
+				let (address, length) = parse_address_length(arguments)?;
+				// Don't trust the length given, the data has to be exactly that long and fit in a packet
+				if length > GDB_HOST_PACKET_SIZE / 2 || data.len() != length * 2 {
+					return Err(eyre!(
+						"Memory write of {} bytes carries {} characters of data",
+						length,
+						data.len()
+					));
+				}
+				let mut bytes = vec![0; length];
+				unhexify(data, &mut bytes)?;
+				self.core.ap().mem_write(address, &bytes)?;
+				b"OK".to_vec()
+			},
+			'p' => hexify(&self.read_register(parse_hex(arguments)? as usize)?).into_bytes(),
+			'P' => {
+				let (number, value) = arguments
+					.split_once('=')
+					.ok_or_eyre("Register write is missing its value")?;
+				let mut bytes = vec![0; value.len() / 2];
+				unhexify(value, &mut bytes)?;
+				self.write_register(parse_hex(number)? as usize, &bytes)?;
+				b"OK".to_vec()
+			},
+			'G' => {
+				self.write_registers(arguments)?;
+				b"OK".to_vec()
+			},
+			'c' | 's' => {
+				// Resuming from a new address
+				self.core.write_register(CORTEXM_REG_PC, parse_hex(arguments)? as u32)?;
+				if command == 'c' {
+					return self.resume();
+				}
+				self.step()?
+			},
+			'Z' | 'z' => {
+				if self.handle_breakpoint(command == 'Z', arguments)? {
+					b"OK".to_vec()
+				} else {
+					Vec::new()
+				}
+			},
+			_ => return self.handle_query(packet),
+		};
+		Ok(Some(reply))
+	}
+
+	/// Act on a query or other multi-letter packet, returning the reply to send
+	fn handle_query(&mut self, packet: &str) -> Result<Option<Vec<u8>>>
+	{
+		let reply = if let Some(arguments) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+			xfer_reply(&target_description(), arguments)?
+		} else if let Some(arguments) = packet.strip_prefix("qXfer:memory-map:read::") {
+			xfer_reply(&memory_map(&self.flash()?.region()).to_xml(), arguments)?
+		} else if let Some(arguments) = packet.strip_prefix("vFlashErase:") {
+			let (address, length) = parse_address_length(arguments)?;
+			self.flash()?.erase(self.core.ap(), address, length as u64)?;
+			b"OK".to_vec()
+		} else if let Some(arguments) = packet.strip_prefix("qCRC:") {
+			let (address, length) = parse_address_length(arguments)?;
+			format!("C{:08x}", self.crc(address, length)?).into_bytes()
+		} else {
+			// An empty reply tells the client we don't support the packet
+			debug!("Unsupported packet {:?}", packet);
+			Vec::new()
+		};
+		Ok(Some(reply))
+	}
+
+	fn flash(&self) -> Result<&dyn FlashDriver>
+	{
+		self.flash.as_deref().ok_or_eyre("Target Flash is not supported")
+	}
+
+	fn read_registers(&self) -> Result<Vec<u8>>
+	{
+		// The special registers are packed into the last value in the order GDB numbers them, so this is
+		// exactly the layout GDB expects
+		let registers = self.core.read_core_registers()?;
+		let data: Vec<u8> = registers.iter().flat_map(|register| register.to_le_bytes()).collect();
+		Ok(hexify(&data).into_bytes())
+	}
+
+	fn write_registers(&self, hex: &str) -> Result<()>
+	{
+		let mut data = [0u8; (GDB_HOST_SPECIAL_REGISTERS + 1) * 4];
+		unhexify(hex, &mut data)?;
+		for (number, value) in data.chunks_exact(4).enumerate() {
+			let selector = match number {
+				GDB_HOST_SPECIAL_REGISTERS => CORTEXM_REG_SPECIAL,
+				number => number as u8,
+			};
+			self.core
+				.write_register(selector, u32::from_le_bytes(value.try_into()?))?;
+		}
+		Ok(())
+	}
+
+	fn read_register(&self, number: usize) -> Result<Vec<u8>>
+	{
+		match number {
+			0..GDB_HOST_SPECIAL_REGISTERS => Ok(self.core.read_register(number as u8)?.to_le_bytes().to_vec()),
+			GDB_HOST_SPECIAL_REGISTERS..GDB_HOST_REGISTERS => {
+				let special = self.core.read_register(CORTEXM_REG_SPECIAL)?.to_le_bytes();
+				Ok(vec![special[number - GDB_HOST_SPECIAL_REGISTERS]])
+			},
+			_ => Err(eyre!("No such register {}", number)),
+		}
+	}
+
+	fn write_register(&self, number: usize, value: &[u8]) -> Result<()>
+	{
+		match number {
+			0..GDB_HOST_SPECIAL_REGISTERS => self
+				.core
+				.write_register(number as u8, u32::from_le_bytes(value.try_into()?)),
+			GDB_HOST_SPECIAL_REGISTERS..GDB_HOST_REGISTERS => {
+				let mut special = self.core.read_register(CORTEXM_REG_SPECIAL)?.to_le_bytes();
+				special[number - GDB_HOST_SPECIAL_REGISTERS] = *value.first().ok_or_eyre("Register value is empty")?;
+				self.core
+					.write_register(CORTEXM_REG_SPECIAL, u32::from_le_bytes(special))
+			},
+			_ => Err(eyre!("No such register {}", number)),
+		}
+	}
+
+	/// Set (or clear) a breakpoint from a `Z` (or `z`) packet, returning false if it's a kind we don't support
+	fn handle_breakpoint(&self, set: bool, arguments: &str) -> Result<bool>
+	{
+		let mut fields = arguments.split(',');
+		let kind = fields.next().unwrap_or_default();
+		let address = parse_hex(fields.next().ok_or_eyre("Breakpoint is missing its address")?)? as u32;
+		// Software breakpoints would mean patching Flash, so they're done with the FPB as well
+		if kind != "0" && kind != "1" {
+			return Ok(false);
+		}
+		let fpb = self
+			.fpb
+			.as_ref()
+			.ok_or_eyre("Target has no Flash Patch and Breakpoint unit")?;
+		if set {
+			fpb.set_breakpoint(address)?;
+		} else {
+			fpb.clear_breakpoint(address)?;
+		}
+		Ok(true)
+	}
+
+	/// Let the core run until it halts or the client asks for it to be stopped, returning the stop reply
+	fn resume(&mut self) -> Result<Option<Vec<u8>>>
+	{
+		// Clear out the reason for the last halt so we only see why this run stops
+		HaltReason::read(self.core.ap())?;
+		self.core.resume()?;
+		self.stream.set_read_timeout(Some(GDB_HOST_POLL_INTERVAL))?;
+		let result = self.wait_for_halt();
+		self.stream.set_read_timeout(None)?;
+		result
+	}
+
+	fn wait_for_halt(&mut self) -> Result<Option<Vec<u8>>>
+	{
+		loop {
+			if self.core.is_halted()? {
+				let reason = HaltReason::read(self.core.ap())?;
+				debug!("Target halted due to {}", reason);
+				let reply = if reason.is_breakpoint() {
+					format!("T{:02x}hwbreak:;", GDB_SIGTRAP)
+				} else {
+					format!("T{:02x}", GDB_SIGTRAP)
+				};
+				return Ok(Some(reply.into_bytes()));
+			}
+			if !self.read_items()? {
+				// The client went away with the target running, which ending the session deals with
+				self.finished = true;
+				return Ok(None);
+			}
+			if let Some(index) = self.pending.iter().position(|item| *item == RspItem::Interrupt) {
+				self.pending.remove(index);
+				self.core.halt()?;
+				HaltReason::read(self.core.ap())?;
+				return Ok(Some(format!("T{:02x}", GDB_SIGINT).into_bytes()));
+			}
+		}
+	}
+
+	fn step(&self) -> Result<Vec<u8>>
+	{
+		HaltReason::read(self.core.ap())?;
+		self.core.step()?;
+		Ok(format!("T{:02x}", GDB_SIGTRAP).into_bytes())
+	}
+
+	/// Compute the CRC32 of a block of target memory as the qCRC packet specifies
+	fn crc(&self, address: u64, length: usize) -> Result<u32>
+	{
+		let mut crc = 0xffffffff;
+		let mut buffer = vec![0; GDB_HOST_PACKET_SIZE];
+		for offset in (0..length).step_by(buffer.len()) {
+			let chunk = &mut buffer[..(length - offset).min(GDB_HOST_PACKET_SIZE)];
+			self.core.ap().mem_read(chunk, address + offset as u64)?;
+			crc = crc32_update(crc, chunk);
+		}
+		Ok(crc)
+	}
+
+	/// Leave the target running without any of the client's breakpoints once the client is done with it
+	fn end_session(&mut self)
+	{
+		let result = self
+			.fpb
+			.as_ref()
+			.map_or(Ok(()), |fpb| fpb.clear())
+			.and_then(|_| self.core.resume());
+		if let Err(error) = result {
+			warn!("Failed to release the target: {}", error);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::collections::HashMap;
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::serial::gdb_target::TargetDescription;
+	use crate::serial::remote::adi::{ADIV5_AP_IDR, ADIV5_DP_DPIDR, AdiV5AccessPort, AdiV5DebugPort};
+	use crate::serial::remote::{Align, BmdAdiV5Protocol, TargetAddr64};
+	use crate::target::coresight::{Component, ComponentKind};
+	use crate::target::cortexm::{CORTEXM_CPUID, CORTEXM_DCRDR, CORTEXM_DCRSR, CORTEXM_DHCSR};
+
+	const FPB_BASE: u64 = 0xe0002000;
+
+	/// A simulated Cortex-M target that is permanently halted, with plain memory everywhere except the
+	/// core register transfer registers
+	#[derive(Default)]
+	struct MockTarget
+	{
+		memory: Mutex<HashMap<u64, u8>>,
+		registers: Mutex<[u32; 32]>,
+	}
+
+	fn load(memory: &HashMap<u64, u8>, address: u64) -> u32
+	{
+		let bytes = [0, 1, 2, 3].map(|offset| memory.get(&(address + offset)).copied().unwrap_or(0));
+		u32::from_le_bytes(bytes)
+	}
+
+	fn store(memory: &mut HashMap<u64, u8>, address: u64, value: u32)
+	{
+		for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+			memory.insert(address + offset as u64, byte);
+		}
+	}
+
+	impl MockTarget
+	{
+		fn new() -> Self
+		{
+			let target = Self::default();
+			let mut memory = target.memory.lock().unwrap();
+			store(&mut memory, CORTEXM_CPUID, 0x410fc241);
+			// S_HALT and S_REGRDY, so the core is always halted and register transfers complete straight away
+			store(&mut memory, CORTEXM_DHCSR, 0x00030000);
+			// An FPBv1 with 6 code comparators
+			store(&mut memory, FPB_BASE, 0x00000060);
+			drop(memory);
+			target
+		}
+	}
+
+	impl BmdAdiV5Protocol for MockTarget
+	{
+		fn raw_access(&self, _: &AdiV5DebugPort, _: u8, _: u16, _: u32) -> Result<u32>
+		{
+			Ok(0)
+		}
+
+		fn dp_read(&self, _: &AdiV5DebugPort, addr: u16) -> Result<u32>
+		{
+			// A valid DPIDR, and the power domains are always up
+			Ok(if addr == ADIV5_DP_DPIDR {
+				0x2ba01477
+			} else {
+				0xf0000000
+			})
+		}
+
+		fn ap_read(&self, _: &AdiV5AccessPort, addr: u16) -> Result<u32>
+		{
+			// An AHB-AP, with everything else reading as 0
+			Ok(if addr == ADIV5_AP_IDR {
+				0x24770011
+			} else {
+				0
+			})
+		}
+
+		fn ap_write(&self, _: &AdiV5AccessPort, _: u16, _: u32) -> Result<()>
+		{
+			Ok(())
+		}
+
+		fn mem_read(&self, _: &AdiV5AccessPort, dest: &mut [u8], src: TargetAddr64) -> Result<()>
+		{
+			let memory = self.memory.lock().unwrap();
+			for (address, byte) in (src..).zip(dest.iter_mut()) {
+				*byte = memory.get(&address).copied().unwrap_or(0);
+			}
+			Ok(())
+		}
+
+		fn mem_write(&self, _: &AdiV5AccessPort, dest: TargetAddr64, src: &[u8], _: Align) -> Result<()>
+		{
+			let mut memory = self.memory.lock().unwrap();
+			match dest {
+				// Run control requests don't change anything
+				CORTEXM_DHCSR => (),
+				CORTEXM_DCRSR => {
+					let selector = u32::from_le_bytes(src.try_into()?);
+					let register = &mut self.registers.lock().unwrap()[(selector & 0x7f) as usize];
+					// REGWNR says which way the transfer goes
+					if selector & (1 << 16) != 0 {
+						*register = load(&memory, CORTEXM_DCRDR);
+					} else {
+						store(&mut memory, CORTEXM_DCRDR, *register);
+					}
+				},
+				_ => {
+					for (address, &byte) in (dest..).zip(src) {
+						memory.insert(address, byte);
+					}
+				},
+			}
+			Ok(())
+		}
+	}
+
+	fn mock_ap() -> AdiV5AccessPort
+	{
+		let dp = AdiV5DebugPort::new(Arc::new(MockTarget::new()), 0).unwrap();
+		AdiV5AccessPort::new(Arc::new(dp), 0).unwrap().unwrap()
+	}
+
+	fn session(ap: &AdiV5AccessPort) -> GdbSession<'_>
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let fpb_component = Component {
+			address: FPB_BASE,
+			kind: ComponentKind::Fpb,
+			class: 9,
+			designer_code: 0x43b,
+			partno: 0x003,
+			devarch: None,
+		};
+		GdbSession {
+			stream,
+			scanner: RspScanner::default(),
+			pending: VecDeque::new(),
+			no_ack: false,
+			last_reply: Vec::new(),
+			finished: false,
+			core: CortexM::new(ap).unwrap(),
+			fpb: Some(Fpb::new(ap, &[fpb_component]).unwrap()),
+			flash: None,
+		}
+	}
+
+	fn reply(session: &mut GdbSession, packet: &[u8]) -> Vec<u8>
+	{
+		session.handle_packet(packet).unwrap().unwrap()
+	}
+
+	#[test]
+	fn register_description()
+	{
+		let description = TargetDescription::parse(&target_description(), |_| Err(eyre!("No includes"))).unwrap();
+		let names: Vec<_> = description
+			.registers
+			.iter()
+			.map(|register| (register.name.as_str(), register.number))
+			.collect();
+		assert_eq!(names.len(), GDB_HOST_REGISTERS);
+		assert_eq!(names[15], ("pc", 15));
+		assert_eq!(names[18], ("psp", 18));
+		assert_eq!(names[GDB_HOST_SPECIAL_REGISTERS], ("primask", GDB_HOST_SPECIAL_REGISTERS));
+		assert_eq!(names[GDB_HOST_REGISTERS - 1], ("control", GDB_HOST_REGISTERS - 1));
+	}
+
+	#[test]
+	fn empty_and_unknown_packets()
+	{
+		let ap = mock_ap();
+		let mut session = session(&ap);
+		assert_eq!(reply(&mut session, b""), b"");
+		assert_eq!(reply(&mut session, "\u{e9}".as_bytes()), b"");
+		assert_eq!(reply(&mut session, b"qUnknown"), b"");
+		assert!(session.handle_packet(b"\xff").is_err());
+	}
+
+	#[test]
+	fn memory_access()
+	{
+		let ap = mock_ap();
+		let mut session = session(&ap);
+		assert_eq!(reply(&mut session, b"M20000000,4:01020304"), b"OK");
+		assert_eq!(reply(&mut session, b"m20000000,4"), b"01020304");
+		// Binary writes carry their data as-is once unescaped, including bytes that are special on the wire
+		assert_eq!(reply(&mut session, b"X20000002,3:#}*"), b"OK");
+		// GDB probes for binary write support with an empty write
+		assert_eq!(reply(&mut session, b"X20000000,0:"), b"OK");
+		assert_eq!(reply(&mut session, b"m20000000,5"), b"0102237d2a");
+		assert!(session.handle_packet(b"m20000000").is_err());
+		assert!(session.handle_packet(b"M20000000,4:0102").is_err());
+		assert!(session.handle_packet(b"M20000000,2:01020304").is_err());
+		assert!(session.handle_packet(b"M20000000,ffffffff:00").is_err());
+		assert!(session.handle_packet(b"X20000000,4").is_err());
+	}
+
+	#[test]
+	fn register_access()
+	{
+		let ap = mock_ap();
+		let mut session = session(&ap);
+		assert_eq!(reply(&mut session, b"Pf=78563412"), b"OK");
+		assert_eq!(reply(&mut session, b"pf"), b"78563412");
+		assert_eq!(session.core.read_register(CORTEXM_REG_PC).unwrap(), 0x12345678);
+		// PRIMASK and CONTROL are bytes of the packed special register
+		assert_eq!(reply(&mut session, b"P13=01"), b"OK");
+		assert_eq!(reply(&mut session, b"P16=02"), b"OK");
+		assert_eq!(reply(&mut session, b"p13"), b"01");
+		assert_eq!(reply(&mut session, b"p16"), b"02");
+		assert_eq!(session.core.read_register(CORTEXM_REG_SPECIAL).unwrap(), 0x02000001);
+		assert!(session.handle_packet(b"p17").is_err());
+		assert!(session.handle_packet(b"Pf").is_err());
+	}
+
+	#[test]
+	fn breakpoints()
+	{
+		let ap = mock_ap();
+		let mut session = session(&ap);
+		assert_eq!(reply(&mut session, b"Z1,8000100,2"), b"OK");
+		assert_eq!(reply(&mut session, b"Z0,8000106,2"), b"OK");
+		assert_eq!(ap.mem_read_u32(FPB_BASE + 0x8).unwrap(), 0x48000101);
+		assert_eq!(ap.mem_read_u32(FPB_BASE + 0xc).unwrap(), 0x88000105);
+		assert_eq!(reply(&mut session, b"z1,8000100,2"), b"OK");
+		assert_eq!(ap.mem_read_u32(FPB_BASE + 0x8).unwrap(), 0);
+		assert_eq!(ap.mem_read_u32(FPB_BASE + 0xc).unwrap(), 0x88000105);
+		// Watchpoints are not supported, which an empty reply says
+		assert_eq!(reply(&mut session, b"Z2,20000000,4"), b"");
+		assert!(session.handle_packet(b"Z1").is_err());
+	}
+
+	#[test]
+	fn xfer_chunking()
+	{
+		assert_eq!(xfer_reply("abcdef", "0,4").unwrap(), b"mabcd");
+		assert_eq!(xfer_reply("abcdef", "4,4").unwrap(), b"lef");
+		assert_eq!(xfer_reply("abcdef", "6,4").unwrap(), b"l");
+		assert_eq!(xfer_reply("abcdef", "10,4").unwrap(), b"l");
+		assert_eq!(xfer_reply("a}b", "0,10").unwrap(), b"la}]b");
+		assert!(xfer_reply("abcdef", "4").is_err());
+
+		let ap = mock_ap();
+		let mut session = session(&ap);
+		let description = target_description();
+		let first = reply(&mut session, b"qXfer:features:read:target.xml:0,20");
+		assert_eq!(first, format!("m{}", &description[..0x20]).into_bytes());
+		let packet = format!("qXfer:features:read:target.xml:20,{:x}", description.len());
+		let rest = reply(&mut session, packet.as_bytes());
+		assert_eq!(rest, format!("l{}", &description[0x20..]).into_bytes());
+		// There's no Flash for a memory map to describe
+		assert!(session.handle_packet(b"qXfer:memory-map:read::0,100").is_err());
+	}
+}
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Relaying GDB clients connecting over TCP to the GDB server built into the probe firmware
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use log::{debug, info, trace, warn};
+
+use crate::serial::gdb_rsp::{GDB_ACK, GDB_INTERRUPT, GDB_NACK, GDB_PACKET_END, GDB_PACKET_START, frame_packet};
+
+/// A piece of RSP traffic picked out of the stream of bytes going one way through the proxy
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum RspItem
+{
+	/// A packet acknowledgement (+) or request for retransmission (-)
+	Ack(u8),
+	/// An out-of-band interrupt request
+	Interrupt,
+	/// A whole packet, including its framing and checksum
+	Packet(Vec<u8>),
+	/// Anything else, such as line noise between packets
+	Other(u8),
+}
+
+/// Splits a stream of RSP traffic up into acks, interrupts and whole packets
+#[derive(Default)]
+pub(super) struct RspScanner
+{
+	packet: Vec<u8>,
+	/// How many checksum digits are still to come, once the end of a packet's payload has been seen
+	checksum_remaining: Option<usize>,
+}
+
+/// State of the proxy shared between the threads relaying traffic in each direction
+#[derive(Default)]
+struct ProxyState
+{
+	/// The connection to the current client, if there is one
+	client: Option<TcpStream>,
+	/// Whether the probe has been put in no-ack mode, which it stays in across clients
+	probe_no_ack: bool,
+	/// Whether the current client has switched to no-ack mode
+	client_no_ack: bool,
+	/// Whether the client has asked the probe for no-ack mode and is waiting on its reply
+	no_ack_pending: bool,
+	/// Whether the client's acknowledgement of the reply switching to no-ack mode is still to come
+	final_ack_pending: bool,
+	/// Whether the current client has detached from (or killed) the target itself
+	client_detached: bool,
+}
+
+/// Relays the traffic of one GDB client at a time between TCP and the probe's GDB serial interface.
+///
+/// Traffic is passed through as-is, other than where the client and probe disagree about ack mode -
+/// which happens when a client connects after a previous one put the probe in no-ack mode, as there's
+/// no way to turn acks back on. The proxy then acknowledges the client's packets on the probe's behalf,
+/// swallows the client's acknowledgements, and answers its request for no-ack mode itself.
+pub struct GdbProxy
+{
+	probe: Mutex<File>,
+	state: Mutex<ProxyState>,
+}
+
+pub(super) fn packet_payload(packet: &[u8]) -> &[u8]
+{
+	// Strip off the start marker, and the end marker and checksum
+	&packet[1..packet.len() - 3]
+}
+
+impl RspScanner
+{
+	/// Feed the next byte of traffic in, getting back an item if that byte completes one
+	pub(super) fn push(&mut self, byte: u8) -> Option<RspItem>
+	{
+		if self.packet.is_empty() {
+			return match byte {
+				GDB_PACKET_START => {
+					self.packet.push(byte);
+					None
+				},
+				GDB_ACK | GDB_NACK => Some(RspItem::Ack(byte)),
+				GDB_INTERRUPT => Some(RspItem::Interrupt),
+				_ => Some(RspItem::Other(byte)),
+			};
+		}
+
+		self.packet.push(byte);
+		match self.checksum_remaining {
+			// Escaping guarantees the end marker can't appear in the payload
+			None if byte == GDB_PACKET_END => self.checksum_remaining = Some(2),
+			None => (),
+			Some(1) => {
+				self.checksum_remaining = None;
+				return Some(RspItem::Packet(std::mem::take(&mut self.packet)));
+			},
+			Some(remaining) => self.checksum_remaining = Some(remaining - 1),
+		}
+		None
+	}
+}
+
+impl ProxyState
+{
+	fn send_to_client(&mut self, data: &[u8]) -> Result<()>
+	{
+		if let Some(client) = &mut self.client {
+			client.write_all(data)?;
+		}
+		Ok(())
+	}
+}
+
+impl GdbProxy
+{
+	/// Set up a proxy for the probe's GDB serial interface, which must not have been used yet
+	pub fn new(probe: File) -> Self
+	{
+		Self {
+			probe: Mutex::new(probe),
+			state: Mutex::new(ProxyState::default()),
+		}
+	}
+
+	/// Accept GDB clients from `listener`, relaying their traffic to and from the probe. This only
+	/// returns if talking to the probe fails.
+	pub fn serve(self, listener: TcpListener) -> Result<()>
+	{
+		let probe = self.probe.lock().unwrap().try_clone()?;
+		let proxy = Arc::new(self);
+		let acceptor = proxy.clone();
+		thread::spawn(move || acceptor.accept_clients(listener));
+		proxy.relay_probe(probe)
+	}
+
+	fn accept_clients(self: Arc<Self>, listener: TcpListener)
+	{
+		for stream in listener.incoming() {
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(error) => {
+					warn!("Failed to accept GDB client: {}", error);
+					continue;
+				},
+			};
+			let peer = stream
+				.peer_addr()
+				.map_or_else(|_| "<unknown>".into(), |address| address.to_string());
+
+			let mut state = self.state.lock().unwrap();
+			if state.client.is_some() {
+				warn!("Refusing GDB client {}, another client is already connected", peer);
+				let _ = stream.shutdown(Shutdown::Both);
+				continue;
+			}
+			let client = match stream.try_clone() {
+				Ok(client) => client,
+				Err(error) => {
+					warn!("Failed to set up connection to GDB client {}: {}", peer, error);
+					continue;
+				},
+			};
+			// RSP traffic is lots of small request/response exchanges, so don't let them sit in buffers
+			let _ = stream.set_nodelay(true);
+			info!("GDB client {} connected", peer);
+			state.client = Some(stream);
+			state.client_no_ack = false;
+			state.no_ack_pending = false;
+			state.final_ack_pending = false;
+			state.client_detached = false;
+			drop(state);
+
+			let proxy = self.clone();
+			thread::spawn(move || {
+				if let Err(error) = proxy.relay_client(client) {
+					warn!("Lost connection to GDB client {}: {}", peer, error);
+				}
+				proxy.end_session();
+				info!("GDB client {} disconnected", peer);
+			});
+		}
+	}
+
+	/// Relay traffic from the client to the probe until the client goes away
+	fn relay_client(&self, mut client: TcpStream) -> Result<()>
+	{
+		let mut scanner = RspScanner::default();
+		let mut buffer = [0u8; 1024];
+		loop {
+			let bytes_received = client.read(&mut buffer)?;
+			if bytes_received == 0 {
+				return Ok(());
+			}
+			for &byte in &buffer[..bytes_received] {
+				if let Some(item) = scanner.push(byte) {
+					self.handle_client_item(item)?;
+				}
+			}
+		}
+	}
+
+	fn handle_client_item(&self, item: RspItem) -> Result<()>
+	{
+		let mut state = self.state.lock().unwrap();
+		let mut probe = self.probe.lock().unwrap();
+		match item {
+			RspItem::Ack(byte) => {
+				// The client still acknowledges the reply switching to no-ack mode, which the probe may wait on
+				if !state.probe_no_ack || std::mem::take(&mut state.final_ack_pending) {
+					probe.write_all(&[byte])?;
+				}
+			},
+			RspItem::Other(byte) => probe.write_all(&[byte])?,
+			RspItem::Interrupt => {
+				debug!("Relaying interrupt request to probe");
+				probe.write_all(&[GDB_INTERRUPT])?;
+			},
+			RspItem::Packet(packet) => {
+				let payload = packet_payload(&packet);
+				trace!("GDB client -> probe: {}", String::from_utf8_lossy(payload));
+				// The client expects its packets to be acknowledged, but the probe won't do that any more
+				if state.probe_no_ack && !state.client_no_ack {
+					state.send_to_client(&[GDB_ACK])?;
+				}
+				match payload {
+					b"QStartNoAckMode" if state.probe_no_ack => {
+						// The probe's still in no-ack mode from a previous client, so answer on its behalf
+						state.client_no_ack = true;
+						return state.send_to_client(&frame_packet(b"OK"));
+					},
+					b"QStartNoAckMode" => state.no_ack_pending = true,
+					b"D" | b"k" => state.client_detached = true,
+					_ if payload.starts_with(b"D;") => state.client_detached = true,
+					_ => (),
+				}
+				probe.write_all(&packet)?;
+			},
+		}
+		Ok(())
+	}
+
+	/// Relay traffic from the probe to whichever client is connected
+	fn relay_probe(&self, mut probe: File) -> Result<()>
+	{
+		let mut scanner = RspScanner::default();
+		let mut buffer = [0u8; 1024];
+		loop {
+			let bytes_received = probe.read(&mut buffer)?;
+			if bytes_received == 0 {
+				// The port gives up waiting for data after a while, so just go round again
+				thread::sleep(Duration::from_millis(1));
+				continue;
+			}
+			for &byte in &buffer[..bytes_received] {
+				if let Some(item) = scanner.push(byte) {
+					self.handle_probe_item(item)?;
+				}
+			}
+		}
+	}
+
+	fn handle_probe_item(&self, item: RspItem) -> Result<()>
+	{
+		let mut state = self.state.lock().unwrap();
+		let data = match item {
+			RspItem::Packet(packet) => {
+				let payload = packet_payload(&packet);
+				trace!("GDB probe -> client: {}", String::from_utf8_lossy(payload));
+				if std::mem::take(&mut state.no_ack_pending) && payload == b"OK" {
+					state.probe_no_ack = true;
+					state.client_no_ack = true;
+					state.final_ack_pending = true;
+				}
+				if state.client.is_none() {
+					// Nobody's listening (such as to the reply to our detach), but the probe may want an ack
+					if !state.probe_no_ack {
+						self.probe.lock().unwrap().write_all(&[GDB_ACK])?;
+					}
+					return Ok(());
+				}
+				packet
+			},
+			RspItem::Ack(byte) | RspItem::Other(byte) => vec![byte],
+			RspItem::Interrupt => vec![GDB_INTERRUPT],
+		};
+		// If the client's gone bad, hang up on it - which ends its session
+		if let Err(error) = state.send_to_client(&data) {
+			warn!("Failed to send to GDB client: {}", error);
+			if let Some(client) = &state.client {
+				let _ = client.shutdown(Shutdown::Both);
+			}
+		}
+		Ok(())
+	}
+
+	/// Tidy up after a client goes away, ready for the next one
+	fn end_session(&self)
+	{
+		let mut state = self.state.lock().unwrap();
+		if let Some(client) = state.client.take() {
+			let _ = client.shutdown(Shutdown::Both);
+		}
+		if !state.client_detached {
+			// Don't leave the target halted just because the client went away without detaching
+			debug!("GDB client went away without detaching, detaching from the target");
+			if let Err(error) = self.probe.lock().unwrap().write_all(&frame_packet(b"D")) {
+				warn!("Failed to detach from the target: {}", error);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn traffic_scanning()
+	{
+		let mut scanner = RspScanner::default();
+		let items: Vec<_> = b"+$m0,4#fd\x03-$}]#a0"
+			.iter()
+			.filter_map(|&byte| scanner.push(byte))
+			.collect();
+		assert_eq!(
+			items,
+			[
+				RspItem::Ack(b'+'),
+				RspItem::Packet(b"$m0,4#fd".to_vec()),
+				RspItem::Interrupt,
+				RspItem::Ack(b'-'),
+				RspItem::Packet(b"$}]#a0".to_vec()),
+			]
+		);
+	}
+}
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Bridging the BMD remote protocol between TCP and a probe, so BMDA or bmputil on another machine
+//! can use the probe as if it were plugged in locally
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use color_eyre::eyre::Result;
+use log::{debug, info, warn};
+
+use crate::serial::remote::{REMOTE_EOM, REMOTE_RESP, REMOTE_SOM};
+use crate::serial::transport::BmdTransport;
+
+/// Picks the remote protocol packets (`!...#` requests and `&...#` responses) out of a stream of
+/// traffic so they can be logged
+#[derive(Default)]
+struct PacketLogger
+{
+	packet: Vec<u8>,
+}
+
+/// The client currently using the bridge
+struct RemoteClient
+{
+	stream: TcpStream,
+	peer: String,
+	/// How many packets have gone each way over the connection
+	requests: usize,
+	responses: usize,
+}
+
+/// Relays the BMD remote protocol traffic of one client at a time between TCP and the probe
+pub struct RemoteBridge
+{
+	/// The serial number of the probe, for telling the logs of multiple bridges apart
+	name: String,
+	probe: Mutex<Box<dyn BmdTransport>>,
+	client: Mutex<Option<RemoteClient>>,
+}
+
+impl PacketLogger
+{
+	/// Feed the next byte of traffic in, getting back the packet if that byte completes one
+	fn push(&mut self, byte: u8) -> Option<String>
+	{
+		if self.packet.is_empty() && byte != REMOTE_SOM && byte != REMOTE_RESP {
+			return None;
+		}
+		self.packet.push(byte);
+		if byte == REMOTE_EOM {
+			let packet = String::from_utf8_lossy(&self.packet).into_owned();
+			self.packet.clear();
+			Some(packet)
+		} else {
+			None
+		}
+	}
+}
+
+impl RemoteBridge
+{
+	/// Set up a bridge for the probe's remote protocol interface, named for the logs by `name`
+	pub fn new(name: String, probe: Box<dyn BmdTransport>) -> Self
+	{
+		Self {
+			name,
+			probe: Mutex::new(probe),
+			client: Mutex::new(None),
+		}
+	}
+
+	/// Accept clients from `listener`, relaying their traffic to and from the probe. This only
+	/// returns if talking to the probe fails.
+	pub fn serve(self, listener: TcpListener) -> Result<()>
+	{
+		let probe = self.probe.lock().unwrap().try_clone()?;
+		let bridge = Arc::new(self);
+		let acceptor = bridge.clone();
+		thread::spawn(move || acceptor.accept_clients(listener));
+		bridge.relay_probe(probe)
+	}
+
+	fn accept_clients(self: Arc<Self>, listener: TcpListener)
+	{
+		for stream in listener.incoming() {
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(error) => {
+					warn!("[{}] Failed to accept remote protocol client: {}", self.name, error);
+					continue;
+				},
+			};
+			let peer = stream
+				.peer_addr()
+				.map_or_else(|_| "<unknown>".into(), |address| address.to_string());
+
+			let mut client = self.client.lock().unwrap();
+			if client.is_some() {
+				warn!("[{}] Refusing client {}, another client is already connected", self.name, peer);
+				let _ = stream.shutdown(Shutdown::Both);
+				continue;
+			}
+			let reader = match stream.try_clone() {
+				Ok(reader) => reader,
+				Err(error) => {
+					warn!("[{}] Failed to set up connection to client {}: {}", self.name, peer, error);
+					continue;
+				},
+			};
+			// Every request waits on a response, so don't let them sit in buffers
+			let _ = stream.set_nodelay(true);
+			info!("[{}] Client {} connected", self.name, peer);
+			*client = Some(RemoteClient {
+				stream,
+				peer: peer.clone(),
+				requests: 0,
+				responses: 0,
+			});
+			drop(client);
+
+			let bridge = self.clone();
+			thread::spawn(move || {
+				if let Err(error) = bridge.relay_client(reader) {
+					warn!("[{}] Lost connection to client {}: {}", bridge.name, peer, error);
+				}
+				bridge.end_session();
+			});
+		}
+	}
+
+	/// Relay traffic from the client to the probe until the client goes away
+	fn relay_client(&self, mut reader: TcpStream) -> Result<()>
+	{
+		let mut logger = PacketLogger::default();
+		let mut buffer = [0u8; 1024];
+		loop {
+			let bytes_received = reader.read(&mut buffer)?;
+			if bytes_received == 0 {
+				return Ok(());
+			}
+			let data = &buffer[..bytes_received];
+			self.probe.lock().unwrap().write_all(data)?;
+
+			let mut client = self.client.lock().unwrap();
+			if let Some(client) = client.as_mut() {
+				for packet in data.iter().filter_map(|&byte| logger.push(byte)) {
+					debug!("[{}] {} -> probe: {}", self.name, client.peer, packet);
+					client.requests += 1;
+				}
+			}
+		}
+	}
+
+	/// Relay traffic from the probe to whichever client is connected
+	fn relay_probe(&self, mut probe: Box<dyn BmdTransport>) -> Result<()>
+	{
+		let mut logger = PacketLogger::default();
+		let mut buffer = [0u8; 1024];
+		loop {
+			// The transport gives up waiting for data after a while, so just go round again if that happens
+			let bytes_received = probe.read_available(&mut buffer)?;
+			if bytes_received == 0 {
+				continue;
+			}
+			let data = &buffer[..bytes_received];
+
+			let mut client = self.client.lock().unwrap();
+			let Some(client) = client.as_mut() else {
+				debug!("[{}] Discarding {} bytes from probe with no client connected", self.name, data.len());
+				continue;
+			};
+			for packet in data.iter().filter_map(|&byte| logger.push(byte)) {
+				debug!("[{}] probe -> {}: {}", self.name, client.peer, packet);
+				client.responses += 1;
+			}
+			// If the client's gone bad, hang up on it - which ends its session
+			if let Err(error) = client.stream.write_all(data) {
+				warn!("[{}] Failed to send to client {}: {}", self.name, client.peer, error);
+				let _ = client.stream.shutdown(Shutdown::Both);
+			}
+		}
+	}
+
+	/// Tidy up after a client goes away, ready for the next one
+	fn end_session(&self)
+	{
+		if let Some(client) = self.client.lock().unwrap().take() {
+			let _ = client.stream.shutdown(Shutdown::Both);
+			info!(
+				"[{}] Client {} disconnected after {} requests and {} responses",
+				self.name, client.peer, client.requests, client.responses
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn packet_logging()
+	{
+		let mut logger = PacketLogger::default();
+		let packets: Vec<_> = b"+#!GA#&Kv2.0.0#".iter().filter_map(|&byte| logger.push(byte)).collect();
+		assert_eq!(packets, ["!GA#", "&Kv2.0.0#"]);
+	}
+}
@@ -0,0 +1,493 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use log::debug;
+
+use crate::serial::remote::TargetAddr64;
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::coresight::{Component, ComponentKind, find_component};
+use crate::target::cortexm::{CORTEXM_DEMCR, CORTEXM_DEMCR_TRCENA};
+
+/// Debug Fault Status Register, records why the core last halted
+pub const CORTEXM_DFSR: u64 = 0xe000ed30;
+
+const FPB_CTRL: u64 = 0x000;
+const FPB_COMP_BASE: u64 = 0x008;
+const FPB_CTRL_ENABLE: u32 = 1 << 0;
+const FPB_CTRL_KEY: u32 = 1 << 1;
+const FPB_COMP_ENABLE: u32 = 1 << 0;
+/// FPBv1 comparators can only match in the code region, and say which halfword(s) to break on
+const FPB_V1_ADDRESS_MASK: u32 = 0x1ffffffc;
+const FPB_V1_REPLACE_LOWER: u32 = 1 << 30;
+const FPB_V1_REPLACE_UPPER: u32 = 2 << 30;
+
+const DWT_CTRL: u64 = 0x000;
+const DWT_PCSR: u64 = 0x01c;
+/// Value DWT_PCSR reads as when the core is halted or otherwise can't be sampled
+const DWT_PCSR_INVALID: u32 = 0xffffffff;
+const DWT_COMP_BASE: u64 = 0x020;
+const DWT_COMP_STRIDE: u64 = 0x010;
+const DWT_COMP: u64 = 0x0;
+const DWT_MASK: u64 = 0x4;
+const DWT_FUNCTION: u64 = 0x8;
+const DWT_FUNCTION_MASK: u32 = 0xf;
+/// ARMv7-M DWT function values for data address watchpoints
+const DWT_V7_FUNCTION_READ: u32 = 0x5;
+const DWT_V7_FUNCTION_WRITE: u32 = 0x6;
+const DWT_V7_FUNCTION_ACCESS: u32 = 0x7;
+/// ARMv8-M DWT match values for data address watchpoints, and the action to generate a debug event
+const DWT_V8_MATCH_ACCESS: u32 = 0x4;
+const DWT_V8_MATCH_WRITE: u32 = 0x5;
+const DWT_V8_MATCH_READ: u32 = 0x6;
+const DWT_V8_ACTION_DEBUG_EVENT: u32 = 1 << 4;
+const DWT_V8_DATAVSIZE_SHIFT: u32 = 10;
+
+const DFSR_HALTED: u32 = 1 << 0;
+const DFSR_BKPT: u32 = 1 << 1;
+const DFSR_DWTTRAP: u32 = 1 << 2;
+const DFSR_VCATCH: u32 = 1 << 3;
+const DFSR_EXTERNAL: u32 = 1 << 4;
+
+/// Flash Patch and Breakpoint unit, used for hardware breakpoints
+pub struct Fpb<'a>
+{
+	ap: &'a AdiV5AccessPort,
+	base: TargetAddr64,
+	revision: u8,
+	comparators: usize,
+}
+
+/// What accesses a watchpoint should trigger on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind
+{
+	Read,
+	Write,
+	Access,
+}
+
+/// Data Watchpoint and Trace unit, used for hardware watchpoints
+pub struct Dwt<'a>
+{
+	ap: &'a AdiV5AccessPort,
+	base: TargetAddr64,
+	/// ARMv8-M DWTs have a different comparator programming model to ARMv7-M ones
+	armv8m: bool,
+	comparators: usize,
+}
+
+/// An active watchpoint as read back from a DWT comparator
+pub struct Watchpoint
+{
+	pub comparator: usize,
+	pub address: u32,
+	pub size: u32,
+	pub kind: WatchKind,
+}
+
+/// Why the core halted, decoded from DFSR
+pub struct HaltReason(u32);
+
+/// Build the value for an FPB comparator (of an FPB with the given revision) to break on an instruction address
+fn encode_fpb_comparator(revision: u8, address: u32) -> Result<u32>
+{
+	if revision == 0 {
+		// The bottom bit is the Thumb bit of the address, which is ignored like it is for FPBv2
+		if address & !(FPB_V1_ADDRESS_MASK | 3) != 0 {
+			return Err(eyre!("FPBv1 can only break on addresses in the code region (below 0x20000000)"));
+		}
+		let replace = if address & 2 != 0 {
+			FPB_V1_REPLACE_UPPER
+		} else {
+			FPB_V1_REPLACE_LOWER
+		};
+		Ok((address & FPB_V1_ADDRESS_MASK) | replace | FPB_COMP_ENABLE)
+	} else {
+		Ok((address & !1) | FPB_COMP_ENABLE)
+	}
+}
+
+/// Turn a comparator value back into the address it breaks on, if it's enabled
+fn decode_fpb_comparator(revision: u8, value: u32) -> Option<u32>
+{
+	if value & FPB_COMP_ENABLE == 0 {
+		return None;
+	}
+	if revision == 0 {
+		let address = value & FPB_V1_ADDRESS_MASK;
+		// If only the upper halfword is being matched, the breakpoint is on that halfword
+		Some(
+			if value & 0xc0000000 == FPB_V1_REPLACE_UPPER {
+				address | 2
+			} else {
+				address
+			},
+		)
+	} else {
+		Some(value & !FPB_COMP_ENABLE)
+	}
+}
+
+/// Build the DWT_FUNCTION value that makes a comparator watch for `kind` accesses of `1 << size_log2` bytes
+fn encode_dwt_function(armv8m: bool, kind: WatchKind, size_log2: u32) -> u32
+{
+	if armv8m {
+		let matching = match kind {
+			WatchKind::Read => DWT_V8_MATCH_READ,
+			WatchKind::Write => DWT_V8_MATCH_WRITE,
+			WatchKind::Access => DWT_V8_MATCH_ACCESS,
+		};
+		matching | DWT_V8_ACTION_DEBUG_EVENT | (size_log2 << DWT_V8_DATAVSIZE_SHIFT)
+	} else {
+		match kind {
+			WatchKind::Read => DWT_V7_FUNCTION_READ,
+			WatchKind::Write => DWT_V7_FUNCTION_WRITE,
+			WatchKind::Access => DWT_V7_FUNCTION_ACCESS,
+		}
+	}
+}
+
+impl<'a> Fpb<'a>
+{
+	/// Find and set up the FPB from the discovered debug components
+	pub fn new(ap: &'a AdiV5AccessPort, components: &[Component]) -> Result<Self>
+	{
+		let base = find_component(components, ComponentKind::Fpb)
+			.ok_or_eyre("Could not find a Flash Patch and Breakpoint unit on the target")?
+			.address;
+		let ctrl = ap.mem_read_u32(base + FPB_CTRL)?;
+		// NUM_CODE is split into two fields, [7:4] and [14:12]
+		let comparators = (((ctrl >> 4) & 0xf) | ((ctrl >> 8) & 0x70)) as usize;
+		let revision = (ctrl >> 28) as u8;
+		debug!("FPB revision {} with {} code comparators", revision + 1, comparators);
+		Ok(Self {
+			ap,
+			base,
+			revision,
+			comparators,
+		})
+	}
+
+	/// How many breakpoints this FPB can hold
+	pub fn comparators(&self) -> usize
+	{
+		self.comparators
+	}
+
+	fn comparator_address(&self, index: usize) -> TargetAddr64
+	{
+		self.base + FPB_COMP_BASE + (index as u64 * 4)
+	}
+
+	/// List the addresses of all active breakpoints, with which comparator holds them
+	pub fn breakpoints(&self) -> Result<Vec<(usize, u32)>>
+	{
+		let mut breakpoints = Vec::new();
+		for index in 0..self.comparators {
+			let value = self.ap.mem_read_u32(self.comparator_address(index))?;
+			if let Some(address) = decode_fpb_comparator(self.revision, value) {
+				breakpoints.push((index, address));
+			}
+		}
+		Ok(breakpoints)
+	}
+
+	/// Set a breakpoint on the given instruction address, returning the comparator used
+	pub fn set_breakpoint(&self, address: u32) -> Result<usize>
+	{
+		let value = encode_fpb_comparator(self.revision, address)?;
+
+		// Find a free comparator to use
+		let mut free = None;
+		for index in 0..self.comparators {
+			let comparator = self.ap.mem_read_u32(self.comparator_address(index))?;
+			match decode_fpb_comparator(self.revision, comparator) {
+				Some(existing) if existing == address & !1 => return Ok(index),
+				None if free.is_none() => free = Some(index),
+				_ => (),
+			}
+		}
+		let index = free.ok_or_eyre("All FPB comparators are in use")?;
+
+		self.ap.mem_write_u32(self.comparator_address(index), value)?;
+		self.ap
+			.mem_write_u32(self.base + FPB_CTRL, FPB_CTRL_KEY | FPB_CTRL_ENABLE)?;
+		Ok(index)
+	}
+
+	/// Remove the breakpoint on the given instruction address, returning whether there was one
+	pub fn clear_breakpoint(&self, address: u32) -> Result<bool>
+	{
+		for index in 0..self.comparators {
+			let comparator = self.ap.mem_read_u32(self.comparator_address(index))?;
+			if decode_fpb_comparator(self.revision, comparator) == Some(address & !1) {
+				self.ap.mem_write_u32(self.comparator_address(index), 0)?;
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+
+	/// Remove all breakpoints
+	pub fn clear(&self) -> Result<()>
+	{
+		for index in 0..self.comparators {
+			self.ap.mem_write_u32(self.comparator_address(index), 0)?;
+		}
+		Ok(())
+	}
+}
+
+impl Display for WatchKind
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		match self {
+			Self::Read => write!(fmt, "read"),
+			Self::Write => write!(fmt, "write"),
+			Self::Access => write!(fmt, "access"),
+		}
+	}
+}
+
+impl<'a> Dwt<'a>
+{
+	/// Find and set up the DWT from the discovered debug components
+	pub fn new(ap: &'a AdiV5AccessPort, components: &[Component]) -> Result<Self>
+	{
+		let component = find_component(components, ComponentKind::Dwt)
+			.ok_or_eyre("Could not find a Data Watchpoint and Trace unit on the target")?;
+		let base = component.address;
+		// The DWT is only accessible once trace is enabled in DEMCR
+		let demcr = ap.mem_read_u32(CORTEXM_DEMCR)?;
+		ap.mem_write_u32(CORTEXM_DEMCR, demcr | CORTEXM_DEMCR_TRCENA)?;
+
+		let ctrl = ap.mem_read_u32(base + DWT_CTRL)?;
+		let comparators = (ctrl >> 28) as usize;
+		let armv8m = component.devarch.is_some();
+		debug!("DWT with {} comparators (ARMv8-M: {})", comparators, armv8m);
+		Ok(Self {
+			ap,
+			base,
+			armv8m,
+			comparators,
+		})
+	}
+
+	/// How many watchpoints this DWT can hold
+	pub fn comparators(&self) -> usize
+	{
+		self.comparators
+	}
+
+	/// Sample the core's program counter without disturbing it, returning None if the core could not be sampled
+	pub fn sample_pc(&self) -> Result<Option<u32>>
+	{
+		let pc = self.ap.mem_read_u32(self.base + DWT_PCSR)?;
+		Ok((pc != DWT_PCSR_INVALID).then_some(pc))
+	}
+
+	fn comparator_address(&self, index: usize, register: u64) -> TargetAddr64
+	{
+		self.base + DWT_COMP_BASE + (index as u64 * DWT_COMP_STRIDE) + register
+	}
+
+	/// Read back comparator `index`, returning the watchpoint it holds if it's an active data watchpoint
+	fn read_watchpoint(&self, index: usize) -> Result<Option<Watchpoint>>
+	{
+		let function = self.ap.mem_read_u32(self.comparator_address(index, DWT_FUNCTION))?;
+		let (kind, size) = if self.armv8m {
+			// Only consider comparators set to generate debug events
+			if function & 0x30 != DWT_V8_ACTION_DEBUG_EVENT {
+				return Ok(None);
+			}
+			let kind = match function & DWT_FUNCTION_MASK {
+				DWT_V8_MATCH_ACCESS => WatchKind::Access,
+				DWT_V8_MATCH_WRITE => WatchKind::Write,
+				DWT_V8_MATCH_READ => WatchKind::Read,
+				_ => return Ok(None),
+			};
+			(kind, 1 << ((function >> DWT_V8_DATAVSIZE_SHIFT) & 3))
+		} else {
+			let kind = match function & DWT_FUNCTION_MASK {
+				DWT_V7_FUNCTION_ACCESS => WatchKind::Access,
+				DWT_V7_FUNCTION_WRITE => WatchKind::Write,
+				DWT_V7_FUNCTION_READ => WatchKind::Read,
+				_ => return Ok(None),
+			};
+			let mask = self.ap.mem_read_u32(self.comparator_address(index, DWT_MASK))?;
+			(kind, 1 << mask)
+		};
+		let address = self.ap.mem_read_u32(self.comparator_address(index, DWT_COMP))?;
+		Ok(Some(Watchpoint {
+			comparator: index,
+			address,
+			size,
+			kind,
+		}))
+	}
+
+	/// List all active watchpoints
+	pub fn watchpoints(&self) -> Result<Vec<Watchpoint>>
+	{
+		let mut watchpoints = Vec::new();
+		for index in 0..self.comparators {
+			if let Some(watchpoint) = self.read_watchpoint(index)? {
+				watchpoints.push(watchpoint);
+			}
+		}
+		Ok(watchpoints)
+	}
+
+	/// Set a watchpoint on `size` bytes (1, 2 or 4) at the given address, returning the comparator used
+	pub fn set_watchpoint(&self, address: u32, size: u32, kind: WatchKind) -> Result<usize>
+	{
+		if !matches!(size, 1 | 2 | 4) {
+			return Err(eyre!("Watchpoint size must be 1, 2 or 4 bytes"));
+		}
+		if address & (size - 1) != 0 {
+			return Err(eyre!("Watchpoint address must be aligned to its size"));
+		}
+		let size_log2 = size.trailing_zeros();
+
+		// Find a free comparator to use - any comparator with a zero function is disabled
+		let mut index = None;
+		for comparator in 0..self.comparators {
+			let function = self
+				.ap
+				.mem_read_u32(self.comparator_address(comparator, DWT_FUNCTION))?;
+			if function & DWT_FUNCTION_MASK == 0 {
+				index = Some(comparator);
+				break;
+			}
+		}
+		let index = index.ok_or_eyre("All DWT comparators are in use")?;
+
+		self.ap
+			.mem_write_u32(self.comparator_address(index, DWT_COMP), address)?;
+		// ARMv7-M DWTs take the size as a mask of address bits to ignore, rather than as part of the function
+		if !self.armv8m {
+			self.ap
+				.mem_write_u32(self.comparator_address(index, DWT_MASK), size_log2)?;
+		}
+		let function = encode_dwt_function(self.armv8m, kind, size_log2);
+		self.ap
+			.mem_write_u32(self.comparator_address(index, DWT_FUNCTION), function)?;
+		Ok(index)
+	}
+
+	/// Remove all watchpoints
+	pub fn clear(&self) -> Result<()>
+	{
+		for index in 0..self.comparators {
+			self.ap.mem_write_u32(self.comparator_address(index, DWT_FUNCTION), 0)?;
+		}
+		Ok(())
+	}
+}
+
+impl Display for Watchpoint
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(
+			fmt,
+			"{}: {} watchpoint on {} byte(s) at 0x{:08x}",
+			self.comparator, self.kind, self.size, self.address
+		)
+	}
+}
+
+impl HaltReason
+{
+	/// Read (and then clear) the reason for the core's last halt from DFSR
+	pub fn read(ap: &AdiV5AccessPort) -> Result<Self>
+	{
+		let dfsr = ap.mem_read_u32(CORTEXM_DFSR)?;
+		// DFSR bits are write-one-to-clear
+		ap.mem_write_u32(CORTEXM_DFSR, dfsr)?;
+		Ok(Self(dfsr))
+	}
+
+	/// The raw DFSR value
+	pub fn dfsr(&self) -> u32
+	{
+		self.0
+	}
+
+	/// Whether the core halted because it executed a BKPT instruction or hit an FPB breakpoint
+	pub fn is_breakpoint(&self) -> bool
+	{
+		self.0 & DFSR_BKPT != 0
+	}
+}
+
+impl Display for HaltReason
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		let reasons = [
+			(DFSR_BKPT, "breakpoint"),
+			(DFSR_DWTTRAP, "watchpoint"),
+			(DFSR_VCATCH, "vector catch"),
+			(DFSR_EXTERNAL, "external debug request"),
+			(DFSR_HALTED, "halt request or step"),
+		]
+		.into_iter()
+		.filter(|(bit, _)| self.0 & bit != 0)
+		.map(|(_, reason)| reason)
+		.collect::<Vec<_>>();
+
+		if reasons.is_empty() {
+			write!(fmt, "unknown (DFSR 0x{:08x})", self.0)
+		} else {
+			write!(fmt, "{}", reasons.join(", "))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn fpb_v1_comparators()
+	{
+		// FPBv1 matches a word address, replacing the halfword the breakpoint is in
+		assert_eq!(encode_fpb_comparator(0, 0x08000100).unwrap(), 0x48000101);
+		assert_eq!(encode_fpb_comparator(0, 0x08000102).unwrap(), 0x88000101);
+		assert_eq!(encode_fpb_comparator(0, 0x08000103).unwrap(), 0x88000101);
+		assert!(encode_fpb_comparator(0, 0x20000000).is_err());
+		assert_eq!(decode_fpb_comparator(0, 0x48000101), Some(0x08000100));
+		assert_eq!(decode_fpb_comparator(0, 0x88000101), Some(0x08000102));
+		assert_eq!(decode_fpb_comparator(0, 0x48000100), None);
+	}
+
+	#[test]
+	fn fpb_v2_comparators()
+	{
+		// FPBv2 takes the instruction address as-is, and can break anywhere
+		assert_eq!(encode_fpb_comparator(1, 0x08000102).unwrap(), 0x08000103);
+		assert_eq!(encode_fpb_comparator(1, 0x08000103).unwrap(), 0x08000103);
+		assert_eq!(encode_fpb_comparator(1, 0x20000400).unwrap(), 0x20000401);
+		assert_eq!(decode_fpb_comparator(1, 0x20000401), Some(0x20000400));
+		assert_eq!(decode_fpb_comparator(1, 0x20000400), None);
+	}
+
+	#[test]
+	fn dwt_functions()
+	{
+		assert_eq!(encode_dwt_function(false, WatchKind::Read, 2), 0x5);
+		assert_eq!(encode_dwt_function(false, WatchKind::Write, 0), 0x6);
+		assert_eq!(encode_dwt_function(false, WatchKind::Access, 1), 0x7);
+		// ARMv8-M carries the access size and the debug event action in the function too
+		assert_eq!(encode_dwt_function(true, WatchKind::Read, 2), 0x816);
+		assert_eq!(encode_dwt_function(true, WatchKind::Write, 0), 0x015);
+		assert_eq!(encode_dwt_function(true, WatchKind::Access, 1), 0x414);
+	}
+}
@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use color_eyre::eyre::{Context, Result, eyre};
+use log::{debug, info};
+
+use crate::target::cortexm::CortexM;
+
+const ELF_HEADER_SIZE: usize = 52;
+const ELF_PROGRAM_HEADER_SIZE: usize = 32;
+const ET_CORE: u16 = 4;
+const EM_ARM: u16 = 40;
+/// EABI version 5, which is what arm-none-eabi toolchains emit
+const EF_ARM_EABI_VER5: u32 = 0x05000000;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// Size of the ARM `struct elf_prstatus`, which the NT_PRSTATUS note holds
+const PRSTATUS_SIZE: usize = 148;
+/// Offset of `pr_reg` in the ARM `struct elf_prstatus`
+const PRSTATUS_REG_OFFSET: usize = 72;
+/// SIGTRAP, reported as the signal that stopped the "process"
+const SIGTRAP: u16 = 5;
+
+/// A snapshot of a halted Cortex-M core's registers and selected regions of its memory
+pub struct CoreDump
+{
+	/// r0-r15 followed by xPSR
+	registers: [u32; 17],
+	regions: Vec<(u32, Vec<u8>)>,
+}
+
+impl CoreDump
+{
+	/// Halt the core (if it is not already) and capture its registers and the given memory regions. If the core
+	/// was running before the capture, it is let run again afterwards.
+	pub fn capture(core: &CortexM, regions: &[Range<u32>]) -> Result<Self>
+	{
+		let was_halted = core.is_halted()?;
+		if !was_halted {
+			debug!("Halting core for capture");
+			core.halt()?;
+		}
+
+		let dump = Self::capture_halted(core, regions);
+
+		if !was_halted {
+			core.resume()?;
+		}
+		dump
+	}
+
+	fn capture_halted(core: &CortexM, regions: &[Range<u32>]) -> Result<Self>
+	{
+		let core_registers = core.read_core_registers()?;
+		let mut registers = [0u32; 17];
+		registers.copy_from_slice(&core_registers[..17]);
+
+		let regions = regions
+			.iter()
+			.map(|region| {
+				info!("Reading {} bytes of memory from 0x{:08x}", region.len(), region.start);
+				let mut data = vec![0u8; region.len()];
+				core.ap()
+					.mem_read(&mut data, region.start as u64)
+					.wrap_err_with(|| eyre!("Failed to read memory region at 0x{:08x}", region.start))?;
+				Ok((region.start, data))
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		Ok(Self {
+			registers,
+			regions,
+		})
+	}
+
+	/// Write the capture out as an ELF core file to the path given
+	pub fn write_to(&self, path: &Path) -> Result<()>
+	{
+		let mut file = File::create(path).wrap_err_with(|| eyre!("Failed to create {}", path.display()))?;
+		file.write_all(&self.to_elf())?;
+		Ok(())
+	}
+
+	/// Turn the capture into an ELF core file, with a single NT_PRSTATUS note holding the registers
+	/// and a PT_LOAD segment for each memory region captured
+	pub fn to_elf(&self) -> Vec<u8>
+	{
+		let program_headers = 1 + self.regions.len();
+		let note = self.prstatus_note();
+
+		// Lay out the file - headers first, then the note, then each of the memory regions
+		let note_offset = ELF_HEADER_SIZE + (program_headers * ELF_PROGRAM_HEADER_SIZE);
+		let mut data_offset = note_offset + note.len();
+
+		let mut elf = Vec::new();
+		// Build the ELF header
+		elf.extend_from_slice(b"\x7fELF");
+		// 32-bit, little endian, ELF version 1, System V ABI, padded out to 16 bytes
+		elf.extend_from_slice(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		push_u16(&mut elf, ET_CORE);
+		push_u16(&mut elf, EM_ARM);
+		push_u32(&mut elf, 1);
+		// Entry point, program header offset, section header offset
+		push_u32(&mut elf, 0);
+		push_u32(&mut elf, ELF_HEADER_SIZE as u32);
+		push_u32(&mut elf, 0);
+		push_u32(&mut elf, EF_ARM_EABI_VER5);
+		push_u16(&mut elf, ELF_HEADER_SIZE as u16);
+		push_u16(&mut elf, ELF_PROGRAM_HEADER_SIZE as u16);
+		push_u16(&mut elf, program_headers as u16);
+		// No section headers
+		push_u16(&mut elf, 0);
+		push_u16(&mut elf, 0);
+		push_u16(&mut elf, 0);
+
+		// Now the program header for the note
+		push_program_header(&mut elf, PT_NOTE, note_offset, 0, note.len(), 0, 4);
+		// And the program headers for each memory region
+		for (address, data) in &self.regions {
+			push_program_header(&mut elf, PT_LOAD, data_offset, *address, data.len(), PF_R | PF_W | PF_X, 1);
+			data_offset += data.len();
+		}
+
+		// Finally, the note and region data itself
+		elf.extend_from_slice(&note);
+		for (_, data) in &self.regions {
+			elf.extend_from_slice(data);
+		}
+		elf
+	}
+
+	/// Build the NT_PRSTATUS note, which is how GDB finds the registers for the core file's thread
+	fn prstatus_note(&self) -> Vec<u8>
+	{
+		let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+		// pr_cursig
+		prstatus[12..14].copy_from_slice(&SIGTRAP.to_le_bytes());
+		// pr_pid
+		prstatus[24..28].copy_from_slice(&1u32.to_le_bytes());
+		// pr_reg is r0-r15 followed by cpsr (which we fill with xPSR) and orig_r0
+		for (index, register) in self.registers.iter().enumerate() {
+			let offset = PRSTATUS_REG_OFFSET + (index * 4);
+			prstatus[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+		}
+
+		let mut note = Vec::new();
+		// Name size (including the NUL), descriptor size and note type
+		push_u32(&mut note, 5);
+		push_u32(&mut note, PRSTATUS_SIZE as u32);
+		push_u32(&mut note, NT_PRSTATUS);
+		// Name, padded to a multiple of 4 bytes
+		note.extend_from_slice(b"CORE\0\0\0\0");
+		note.extend_from_slice(&prstatus);
+		note
+	}
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16)
+{
+	buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32)
+{
+	buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_program_header(
+	buffer: &mut Vec<u8>,
+	kind: u32,
+	offset: usize,
+	address: u32,
+	length: usize,
+	flags: u32,
+	align: u32,
+)
+{
+	push_u32(buffer, kind);
+	push_u32(buffer, offset as u32);
+	// Virtual and physical address
+	push_u32(buffer, address);
+	push_u32(buffer, address);
+	// File and memory size
+	push_u32(buffer, length as u32);
+	push_u32(buffer, length as u32);
+	push_u32(buffer, flags);
+	push_u32(buffer, align);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use goblin::elf::Elf;
+	use goblin::elf::program_header::{PT_LOAD, PT_NOTE};
+
+	use super::*;
+
+	#[test]
+	fn core_file_parses()
+	{
+		let mut registers = [0u32; 17];
+		registers[15] = 0x08000124;
+		let dump = CoreDump {
+			registers,
+			regions: vec![(0x20000000, vec![0xaa; 64])],
+		};
+		let data = dump.to_elf();
+		let elf = Elf::parse(&data).expect("core file should be valid ELF");
+
+		assert_eq!(elf.header.e_type, ET_CORE);
+		assert_eq!(elf.program_headers.len(), 2);
+		assert_eq!(elf.program_headers[0].p_type, PT_NOTE);
+		let load = &elf.program_headers[1];
+		assert_eq!(load.p_type, PT_LOAD);
+		assert_eq!(load.p_vaddr, 0x20000000);
+		assert_eq!(&data[load.file_range()], &[0xaa; 64]);
+
+		// Check the PC made it into the right spot in the note
+		let pc_offset = elf.program_headers[0].p_offset as usize + 20 + PRSTATUS_REG_OFFSET + (15 * 4);
+		assert_eq!(&data[pc_offset..pc_offset + 4], &0x08000124u32.to_le_bytes());
+	}
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+
+use color_eyre::eyre::Result;
+use log::{debug, trace};
+
+use crate::serial::remote::TargetAddr64;
+use crate::serial::remote::adi::{AdiV5AccessPort, designer_from_fields};
+
+/// JEP106 code for ARM, as found in component peripheral ID registers
+pub const JEP106_MANUFACTURER_ARM: u16 = 0x43b;
+
+const CORESIGHT_DEVARCH: u64 = 0xfbc;
+const CORESIGHT_PIDR4: u64 = 0xfd0;
+const CORESIGHT_PIDR0: u64 = 0xfe0;
+const CORESIGHT_CIDR0: u64 = 0xff0;
+
+const CORESIGHT_DEVARCH_PRESENT: u32 = 1 << 20;
+/// Component ID preamble bits, which are fixed for all valid components
+const CORESIGHT_CIDR_PREAMBLE: u32 = 0xb105000d;
+const CORESIGHT_CIDR_PREAMBLE_MASK: u32 = 0xffff0fff;
+const CORESIGHT_CLASS_ROM_TABLE: u8 = 0x1;
+const CORESIGHT_CLASS_CORESIGHT: u8 = 0x9;
+
+const ROM_TABLE_ENTRY_PRESENT: u32 = 1 << 0;
+/// Maximum number of entries in a class 0x1 ROM table
+const ROM_TABLE_MAX_ENTRIES: u64 = 960;
+/// How deeply nested ROM tables are allowed to be before we stop following them
+const ROM_TABLE_MAX_DEPTH: usize = 4;
+
+/// The kinds of debug component we know how to use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentKind
+{
+	RomTable,
+	/// System Control Space (Cortex-M core debug registers)
+	Scs,
+	/// Data Watchpoint and Trace unit
+	Dwt,
+	/// Flash Patch and Breakpoint unit
+	Fpb,
+	/// Instrumentation Trace Macrocell
+	Itm,
+	/// Trace Port Interface Unit
+	Tpiu,
+	Etm,
+	Other,
+}
+
+/// A debug component found by walking the ROM tables
+#[derive(Clone, Debug)]
+pub struct Component
+{
+	pub address: TargetAddr64,
+	pub kind: ComponentKind,
+	pub class: u8,
+	pub designer_code: u16,
+	pub partno: u16,
+	/// The DEVARCH register value, for components (such as all ARMv8-M ones) that have one
+	pub devarch: Option<u32>,
+}
+
+impl Component
+{
+	/// Read the identification registers of the component at `address`, returning None if there's no
+	/// valid component there
+	fn read(ap: &AdiV5AccessPort, address: TargetAddr64) -> Result<Option<Self>>
+	{
+		// Read all of the CIDR and PIDR registers in one go (PIDR4-7, PIDR0-3, CIDR0-3)
+		let mut ids = [0u8; 48];
+		ap.mem_read(&mut ids, address + CORESIGHT_PIDR4)?;
+		let id_reg = |offset: u64| {
+			let index = (offset - CORESIGHT_PIDR4) as usize;
+			// Each ID register holds only a byte of the ID in its bottom 8 bits
+			ids[index] as u32
+		};
+
+		let cidr = (0..4).fold(0, |cidr, index| cidr | (id_reg(CORESIGHT_CIDR0 + index * 4) << (index * 8)));
+		if cidr & CORESIGHT_CIDR_PREAMBLE_MASK != CORESIGHT_CIDR_PREAMBLE {
+			trace!("No valid component at 0x{:08x} (CIDR {:08x})", address, cidr);
+			return Ok(None);
+		}
+		let class = ((cidr >> 12) & 0xf) as u8;
+
+		let pidr = (0..4).fold(0, |pidr, index| pidr | (id_reg(CORESIGHT_PIDR0 + index * 4) << (index * 8)));
+		let partno = (pidr & 0xfff) as u16;
+		let designer_code = designer_from_fields(id_reg(CORESIGHT_PIDR4), pidr >> 12);
+
+		// Only CoreSight class components have the DEVARCH register
+		let devarch = if class == CORESIGHT_CLASS_CORESIGHT {
+			let devarch = ap.mem_read_u32(address + CORESIGHT_DEVARCH)?;
+			(devarch & CORESIGHT_DEVARCH_PRESENT != 0).then_some(devarch)
+		} else {
+			None
+		};
+
+		let mut component = Self {
+			address,
+			kind: ComponentKind::Other,
+			class,
+			designer_code,
+			partno,
+			devarch,
+		};
+		component.kind = component.identify();
+		Ok(Some(component))
+	}
+
+	/// Work out what kind of component this is, preferring DEVARCH where present and falling back to
+	/// known ARM part numbers
+	fn identify(&self) -> ComponentKind
+	{
+		if self.class == CORESIGHT_CLASS_ROM_TABLE {
+			return ComponentKind::RomTable;
+		}
+		if let Some(devarch) = self.devarch {
+			return match devarch & 0xffff {
+				0x0af7 => ComponentKind::RomTable,
+				0x2a04 => ComponentKind::Scs,
+				0x1a02 => ComponentKind::Dwt,
+				0x1a03 => ComponentKind::Fpb,
+				0x1a01 => ComponentKind::Itm,
+				0x4a13 => ComponentKind::Etm,
+				_ => ComponentKind::Other,
+			};
+		}
+		if self.designer_code != JEP106_MANUFACTURER_ARM {
+			return ComponentKind::Other;
+		}
+		match self.partno {
+			0x000 | 0x008 | 0x00c => ComponentKind::Scs,
+			0x002 | 0x00a => ComponentKind::Dwt,
+			0x003 | 0x00b | 0x00e => ComponentKind::Fpb,
+			0x001 => ComponentKind::Itm,
+			0x923 | 0x9a1 | 0x9a9 => ComponentKind::Tpiu,
+			0x924 | 0x925 | 0x975 => ComponentKind::Etm,
+			_ => ComponentKind::Other,
+		}
+	}
+}
+
+impl Display for Component
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(
+			fmt,
+			"0x{:08x}: {:?} (class {:x}, designer {:03x}, part {:03x})",
+			self.address, self.kind, self.class, self.designer_code, self.partno
+		)
+	}
+}
+
+/// Walk the ROM table(s) hanging off the given memory AP, returning every component found
+pub fn discover_components(ap: &AdiV5AccessPort) -> Result<Vec<Component>>
+{
+	let mut components = Vec::new();
+	// The bottom 12 bits of BASE hold format and presence flags rather than address
+	walk_rom_table(ap, ap.base() & !0xfff, 0, &mut components)?;
+	debug!("Discovered {} debug components", components.len());
+	Ok(components)
+}
+
+fn walk_rom_table(
+	ap: &AdiV5AccessPort,
+	address: TargetAddr64,
+	depth: usize,
+	components: &mut Vec<Component>,
+) -> Result<()>
+{
+	let Some(component) = Component::read(ap, address)? else {
+		return Ok(());
+	};
+	trace!("Found component {}", component);
+	let is_rom_table = component.kind == ComponentKind::RomTable;
+	components.push(component);
+	if !is_rom_table || depth >= ROM_TABLE_MAX_DEPTH {
+		return Ok(());
+	}
+
+	// Walk the entries of this ROM table, each of which gives an offset to another component
+	for index in 0..ROM_TABLE_MAX_ENTRIES {
+		let entry = ap.mem_read_u32(address + (index * 4))?;
+		// A zero entry marks the end of the table
+		if entry == 0 {
+			break;
+		}
+		if entry & ROM_TABLE_ENTRY_PRESENT == 0 {
+			continue;
+		}
+		// The offset is signed and in the top 20 bits of the entry
+		let offset = (entry & 0xfffff000) as i32 as i64;
+		let child = address.wrapping_add_signed(offset);
+		walk_rom_table(ap, child, depth + 1, components)?;
+	}
+	Ok(())
+}
+
+/// Find the first component of the given kind
+pub fn find_component(components: &[Component], kind: ComponentKind) -> Option<&Component>
+{
+	components.iter().find(|component| component.kind == kind)
+}
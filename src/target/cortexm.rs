@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::ops::Range;
+use std::time::Duration;
+
+use color_eyre::eyre::{Result, eyre};
+use log::debug;
+
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::wait_for;
+
+/// CPUID Base Register, identifies the core implementation
+pub const CORTEXM_CPUID: u64 = 0xe000ed00;
+/// Debug Halting Control and Status Register
+pub const CORTEXM_DHCSR: u64 = 0xe000edf0;
+/// Debug Core Register Selector Register
+pub const CORTEXM_DCRSR: u64 = 0xe000edf4;
+/// Debug Core Register Data Register
+pub const CORTEXM_DCRDR: u64 = 0xe000edf8;
+/// Debug Exception and Monitor Control Register
+pub const CORTEXM_DEMCR: u64 = 0xe000edfc;
+/// Application Interrupt and Reset Control Register
+pub const CORTEXM_AIRCR: u64 = 0xe000ed0c;
+/// Vector Table Offset Register
+pub const CORTEXM_VTOR: u64 = 0xe000ed08;
+
+/// Key that must be written to the top half of DHCSR for a write to take effect
+const CORTEXM_DHCSR_DBGKEY: u32 = 0xa05f0000;
+const CORTEXM_DHCSR_C_DEBUGEN: u32 = 1 << 0;
+const CORTEXM_DHCSR_C_HALT: u32 = 1 << 1;
+const CORTEXM_DHCSR_C_STEP: u32 = 1 << 2;
+const CORTEXM_DHCSR_C_MASKINTS: u32 = 1 << 3;
+const CORTEXM_DHCSR_S_REGRDY: u32 = 1 << 16;
+const CORTEXM_DHCSR_S_HALT: u32 = 1 << 17;
+const CORTEXM_DCRSR_REGWNR: u32 = 1 << 16;
+/// Global enable for the DWT, ITM and other trace components
+pub const CORTEXM_DEMCR_TRCENA: u32 = 1 << 24;
+const CORTEXM_DEMCR_VC_CORERESET: u32 = 1 << 0;
+const CORTEXM_DEMCR_VC_HARDERR: u32 = 1 << 10;
+/// Key that must be written to the top half of AIRCR for a write to take effect
+const CORTEXM_AIRCR_VECTKEY: u32 = 0x05fa0000;
+const CORTEXM_AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// DCRSR register selector values for the stack pointer, link register and program counter
+pub const CORTEXM_REG_SP: u8 = 13;
+pub const CORTEXM_REG_LR: u8 = 14;
+pub const CORTEXM_REG_PC: u8 = 15;
+
+/// The architecturally defined SRAM region of the Cortex-M memory map
+pub const CORTEXM_SRAM_REGION: Range<u32> = 0x20000000..0x40000000;
+/// xPSR Thumb state bit, which must always be set for the core to execute
+pub const CORTEXM_XPSR_THUMB: u32 = 1 << 24;
+
+/// DCRSR register selector values for the special registers
+pub const CORTEXM_REG_XPSR: u8 = 16;
+pub const CORTEXM_REG_MSP: u8 = 17;
+pub const CORTEXM_REG_PSP: u8 = 18;
+/// CONTROL, FAULTMASK, BASEPRI and PRIMASK, packed into a single 32-bit value
+pub const CORTEXM_REG_SPECIAL: u8 = 20;
+
+/// Names of all the core registers read by [`CortexM::read_core_registers`], in order
+pub const CORTEXM_CORE_REGISTER_NAMES: [&str; 20] = [
+	"r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc", "xpsr", "msp",
+	"psp", "special",
+];
+/// DCRSR register selector values for each of the registers in [`CORTEXM_CORE_REGISTER_NAMES`]
+const CORTEXM_CORE_REGISTER_SELECTORS: [u8; 20] = [
+	0,
+	1,
+	2,
+	3,
+	4,
+	5,
+	6,
+	7,
+	8,
+	9,
+	10,
+	11,
+	12,
+	CORTEXM_REG_SP,
+	CORTEXM_REG_LR,
+	CORTEXM_REG_PC,
+	CORTEXM_REG_XPSR,
+	CORTEXM_REG_MSP,
+	CORTEXM_REG_PSP,
+	CORTEXM_REG_SPECIAL,
+];
+
+/// How long to wait on the core to halt or for a register transfer to complete
+const CORTEXM_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Run control for an ARM Cortex-M core reached through a memory access port
+pub struct CortexM<'a>
+{
+	ap: &'a AdiV5AccessPort,
+	cpuid: u32,
+}
+
+impl<'a> CortexM<'a>
+{
+	/// Check that the memory AP given has a Cortex-M core behind it, and set up to control it
+	pub fn new(ap: &'a AdiV5AccessPort) -> Result<Self>
+	{
+		let cpuid = ap.mem_read_u32(CORTEXM_CPUID)?;
+		// The implementer code lives in the top byte, and must be ARM (0x41) for this to be a Cortex-M
+		if cpuid >> 24 != 0x41 {
+			return Err(eyre!("Target does not appear to be an ARM Cortex-M core (CPUID {:08x})", cpuid));
+		}
+		debug!("Found Cortex-M core with CPUID {:08x}", cpuid);
+		Ok(Self {
+			ap,
+			cpuid,
+		})
+	}
+
+	/// The access port this core is reached through
+	pub fn ap(&self) -> &'a AdiV5AccessPort
+	{
+		self.ap
+	}
+
+	/// The core's CPUID register value
+	pub fn cpuid(&self) -> u32
+	{
+		self.cpuid
+	}
+
+	/// Read the current value of the Debug Halting Control and Status Register
+	pub fn dhcsr(&self) -> Result<u32>
+	{
+		self.ap.mem_read_u32(CORTEXM_DHCSR)
+	}
+
+	/// Whether the core is currently halted in debug state
+	pub fn is_halted(&self) -> Result<bool>
+	{
+		Ok(self.dhcsr()? & CORTEXM_DHCSR_S_HALT != 0)
+	}
+
+	/// Request the core halt, and wait for it to do so
+	pub fn halt(&self) -> Result<()>
+	{
+		self.ap.mem_write_u32(
+			CORTEXM_DHCSR,
+			CORTEXM_DHCSR_DBGKEY | CORTEXM_DHCSR_C_DEBUGEN | CORTEXM_DHCSR_C_HALT | CORTEXM_DHCSR_C_MASKINTS,
+		)?;
+		wait_for(CORTEXM_TIMEOUT, "the core to halt", || self.is_halted())
+	}
+
+	/// Wait for the core to halt of its own accord (eg, on a breakpoint), for at most `timeout`
+	pub fn wait_for_halt(&self, timeout: Duration) -> Result<()>
+	{
+		wait_for(timeout, "the core to halt", || self.is_halted())
+	}
+
+	/// Turn halting debug on without otherwise disturbing the core, so that it halts on breakpoints and
+	/// watchpoints rather than taking a HardFault
+	pub fn enable_debug(&self) -> Result<()>
+	{
+		// The core can't be halted with debug off, so there is no other state in DHCSR to preserve
+		if self.dhcsr()? & CORTEXM_DHCSR_C_DEBUGEN == 0 {
+			self.ap
+				.mem_write_u32(CORTEXM_DHCSR, CORTEXM_DHCSR_DBGKEY | CORTEXM_DHCSR_C_DEBUGEN)?;
+		}
+		Ok(())
+	}
+
+	/// Let the core run again, leaving debug enabled so it can be halted again later
+	pub fn resume(&self) -> Result<()>
+	{
+		self.ap.mem_write_u32(CORTEXM_DHCSR, CORTEXM_DHCSR_DBGKEY | CORTEXM_DHCSR_C_DEBUGEN)
+	}
+
+	/// Have the halted core execute a single instruction with interrupts masked, and wait for it to halt again
+	pub fn step(&self) -> Result<()>
+	{
+		self.ap.mem_write_u32(
+			CORTEXM_DHCSR,
+			CORTEXM_DHCSR_DBGKEY | CORTEXM_DHCSR_C_DEBUGEN | CORTEXM_DHCSR_C_STEP | CORTEXM_DHCSR_C_MASKINTS,
+		)?;
+		wait_for(CORTEXM_TIMEOUT, "the core to step", || self.is_halted())
+	}
+
+	/// Reset the whole system, catching the core on the reset vector before it executes any instructions
+	pub fn reset_halt(&self) -> Result<()>
+	{
+		let demcr = self.ap.mem_read_u32(CORTEXM_DEMCR)?;
+		self.ap
+			.mem_write_u32(CORTEXM_DEMCR, demcr | CORTEXM_DEMCR_VC_CORERESET)?;
+		self.ap
+			.mem_write_u32(CORTEXM_DHCSR, CORTEXM_DHCSR_DBGKEY | CORTEXM_DHCSR_C_DEBUGEN)?;
+		self.ap
+			.mem_write_u32(CORTEXM_AIRCR, CORTEXM_AIRCR_VECTKEY | CORTEXM_AIRCR_SYSRESETREQ)?;
+		// The debug registers may not be accessible for a moment while the system is in reset, so accesses
+		// faulting is expected - clear the fault out and try again, reporting it if the core never halts
+		let mut fault = None;
+		let result = wait_for(CORTEXM_TIMEOUT, "the core to halt after reset", || match self.is_halted() {
+			Ok(halted) => Ok(halted),
+			Err(error) => {
+				self.ap.dp().clear_errors()?;
+				fault = Some(error);
+				Ok(false)
+			},
+		});
+		if let Err(error) = result {
+			return Err(match fault {
+				Some(fault) => fault.wrap_err(error.to_string()),
+				None => error,
+			});
+		}
+		self.ap.mem_write_u32(CORTEXM_DEMCR, demcr)
+	}
+
+	/// Have the core halt rather than take a HardFault, so that faults can be caught by the debugger
+	pub fn catch_hard_faults(&self) -> Result<()>
+	{
+		let demcr = self.ap.mem_read_u32(CORTEXM_DEMCR)?;
+		self.ap.mem_write_u32(CORTEXM_DEMCR, demcr | CORTEXM_DEMCR_VC_HARDERR)
+	}
+
+	/// Read one of the core's registers by its DCRSR selector value. The core must be halted.
+	pub fn read_register(&self, selector: u8) -> Result<u32>
+	{
+		self.ap.mem_write_u32(CORTEXM_DCRSR, selector as u32)?;
+		wait_for(CORTEXM_TIMEOUT, "a core register read", || {
+			Ok(self.dhcsr()? & CORTEXM_DHCSR_S_REGRDY != 0)
+		})?;
+		self.ap.mem_read_u32(CORTEXM_DCRDR)
+	}
+
+	/// Write one of the core's registers by its DCRSR selector value. The core must be halted.
+	pub fn write_register(&self, selector: u8, value: u32) -> Result<()>
+	{
+		self.ap.mem_write_u32(CORTEXM_DCRDR, value)?;
+		self.ap.mem_write_u32(CORTEXM_DCRSR, CORTEXM_DCRSR_REGWNR | selector as u32)?;
+		wait_for(CORTEXM_TIMEOUT, "a core register write", || {
+			Ok(self.dhcsr()? & CORTEXM_DHCSR_S_REGRDY != 0)
+		})
+	}
+
+	/// Read all the core registers named in [`CORTEXM_CORE_REGISTER_NAMES`]. The core must be halted.
+	pub fn read_core_registers(&self) -> Result<[u32; 20]>
+	{
+		let mut registers = [0u32; 20];
+		for (value, &selector) in registers.iter_mut().zip(&CORTEXM_CORE_REGISTER_SELECTORS) {
+			*value = self.read_register(selector)?;
+		}
+		Ok(registers)
+	}
+}
@@ -0,0 +1,635 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use defmt_parser::{DisplayHint, Fragment, Level, Parameter, ParserMode, TimePrecision, Type};
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::firmware_file::FirmwareFile;
+
+/// The defmt wire format version this decoder understands
+const DEFMT_WIRE_VERSION: &str = "4";
+/// How deeply nested `{=?}` formatting is allowed to get before we give up on a frame
+const DEFMT_MAX_NESTING: usize = 16;
+
+/// The JSON description of a format string, as found in the defmt symbol names
+#[derive(Deserialize)]
+struct DefmtSymbol
+{
+	tag: String,
+	data: String,
+}
+
+/// A single entry in the defmt format string table
+#[derive(Clone, Debug)]
+struct DefmtEntry
+{
+	tag: String,
+	format: String,
+}
+
+/// The table of format strings needed to decode defmt frames, extracted from the firmware ELF
+pub struct DefmtTable
+{
+	entries: BTreeMap<u16, DefmtEntry>,
+	timestamp: Option<String>,
+}
+
+/// A decoded defmt log frame
+pub struct DefmtFrame
+{
+	pub level: Option<Level>,
+	pub timestamp: Option<String>,
+	pub message: String,
+}
+
+/// Turns a stream of rzCOBS-encoded defmt data (as sent over RTT) into decoded frames
+pub struct DefmtDecoder
+{
+	table: DefmtTable,
+	buffer: Vec<u8>,
+}
+
+/// A decoded argument, ready to be displayed
+enum Value
+{
+	Unsigned(u128),
+	Signed(i128),
+	F32(f32),
+	F64(f64),
+	Bool(bool),
+	Char(char),
+	Bytes(Vec<u8>),
+	/// Strings and the results of nested formatting
+	Text(String),
+}
+
+/// Cursor over the bytes of a frame
+struct Reader<'a>
+{
+	data: &'a [u8],
+}
+
+impl DefmtTable
+{
+	/// Extract the format string table from a firmware file built with defmt
+	pub fn from_firmware(firmware: &FirmwareFile) -> Result<Self>
+	{
+		let mut table = Self {
+			entries: BTreeMap::new(),
+			timestamp: None,
+		};
+		for symbol in firmware.defmt_symbols() {
+			// The version marker is the only symbol with a name that isn't JSON
+			if let Some(version) = symbol.name.strip_prefix("_defmt_version_ = ") {
+				if version != DEFMT_WIRE_VERSION {
+					warn!("Firmware uses defmt wire format {}, decoding may not work correctly", version);
+				}
+				continue;
+			}
+			table.insert(symbol.address as u16, &symbol.name);
+		}
+		if table.entries.is_empty() {
+			return Err(eyre!("Firmware does not contain a defmt format string table"));
+		}
+		debug!("Loaded {} defmt format strings", table.entries.len());
+		Ok(table)
+	}
+
+	/// Add the symbol at `index` to the table, if it is a defmt format string
+	fn insert(&mut self, index: u16, name: &str)
+	{
+		let Ok(symbol) = serde_json::from_str::<DefmtSymbol>(name) else {
+			return;
+		};
+		if symbol.tag == "defmt_timestamp" {
+			self.timestamp = Some(symbol.data);
+		} else {
+			self.entries.insert(index, DefmtEntry {
+				tag: symbol.tag,
+				format: symbol.data,
+			});
+		}
+	}
+
+	fn entry(&self, index: u16) -> Result<&DefmtEntry>
+	{
+		self.entries
+			.get(&index)
+			.ok_or_else(|| eyre!("defmt frame refers to unknown format string {}", index))
+	}
+
+	/// Decode a single (already rzCOBS decoded) frame
+	pub fn decode(&self, frame: &[u8]) -> Result<DefmtFrame>
+	{
+		let mut reader = Reader {
+			data: frame,
+		};
+		let entry = self.entry(reader.u16()?)?;
+		let level = match entry.tag.as_str() {
+			"defmt_trace" => Some(Level::Trace),
+			"defmt_debug" => Some(Level::Debug),
+			"defmt_info" => Some(Level::Info),
+			"defmt_warn" => Some(Level::Warn),
+			"defmt_error" => Some(Level::Error),
+			"defmt_println" => None,
+			tag => return Err(eyre!("defmt frame starts with a non-log format string ({})", tag)),
+		};
+		// The timestamp comes first, then the message's own arguments
+		let timestamp = self
+			.timestamp
+			.as_ref()
+			.map(|format| self.format(format, &mut reader, 0))
+			.transpose()?;
+		let message = self.format(&entry.format, &mut reader, 0)?;
+		Ok(DefmtFrame {
+			level,
+			timestamp,
+			message,
+		})
+	}
+
+	/// Decode the arguments for a format string from the frame, and render the result
+	fn format(&self, format: &str, reader: &mut Reader, depth: usize) -> Result<String>
+	{
+		if depth > DEFMT_MAX_NESTING {
+			return Err(eyre!("defmt frame is nested too deeply"));
+		}
+		let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+			.map_err(|error| eyre!("Invalid defmt format string '{}': {}", format, error))?;
+		let parameters = fragments
+			.iter()
+			.filter_map(|fragment| match fragment {
+				Fragment::Parameter(parameter) => Some(parameter),
+				Fragment::Literal(_) => None,
+			})
+			.collect::<Vec<_>>();
+
+		// Arguments are sent once each, in index order, regardless of how often they are used
+		let argument_count = parameters
+			.iter()
+			.map(|parameter| parameter.index + 1)
+			.max()
+			.unwrap_or(0);
+		let mut arguments = Vec::with_capacity(argument_count);
+		for index in 0..argument_count {
+			let uses = parameters
+				.iter()
+				.filter(|parameter| parameter.index == index)
+				.copied()
+				.collect::<Vec<_>>();
+			let parameter = uses.first().ok_or_eyre("defmt format string skips an argument")?;
+			arguments.push(self.read_value(parameter, &uses, reader, depth)?);
+		}
+
+		let mut result = String::new();
+		for fragment in &fragments {
+			match fragment {
+				Fragment::Literal(literal) => result.push_str(literal),
+				Fragment::Parameter(parameter) => {
+					render(&mut result, &arguments[parameter.index], parameter);
+				},
+			}
+		}
+		Ok(result)
+	}
+
+	/// Read the value of one argument from the frame
+	fn read_value(&self, parameter: &Parameter, uses: &[&Parameter], reader: &mut Reader, depth: usize)
+	-> Result<Value>
+	{
+		Ok(match &parameter.ty {
+			Type::U8 => Value::Unsigned(reader.u8()? as u128),
+			Type::U16 => Value::Unsigned(reader.u16()? as u128),
+			Type::U32 | Type::Usize => Value::Unsigned(reader.u32()? as u128),
+			Type::U64 => Value::Unsigned(reader.u64()? as u128),
+			Type::U128 => Value::Unsigned(reader.u128()?),
+			Type::I8 => Value::Signed(reader.u8()? as i8 as i128),
+			Type::I16 => Value::Signed(reader.u16()? as i16 as i128),
+			Type::I32 | Type::Isize => Value::Signed(reader.u32()? as i32 as i128),
+			Type::I64 => Value::Signed(reader.u64()? as i64 as i128),
+			Type::I128 => Value::Signed(reader.u128()? as i128),
+			Type::F32 => Value::F32(f32::from_bits(reader.u32()?)),
+			Type::F64 => Value::F64(f64::from_bits(reader.u64()?)),
+			Type::Bool => Value::Bool(reader.u8()? != 0),
+			Type::Char => Value::Char(char::from_u32(reader.u32()?).unwrap_or(char::REPLACEMENT_CHARACTER)),
+			Type::BitField(_) => {
+				// All uses of a bitfield argument share one value, truncated to just the bytes covering them
+				let bitfields = uses
+					.iter()
+					.filter_map(|parameter| match &parameter.ty {
+						Type::BitField(range) => Some(range.clone()),
+						_ => None,
+					})
+					.collect::<Vec<_>>();
+				let lowest = bitfields.iter().map(|range| range.start).min().unwrap_or(0) / 8;
+				let highest = (bitfields.iter().map(|range| range.end).max().unwrap_or(1) - 1) / 8;
+				let bytes = reader.bytes((highest - lowest + 1) as usize)?;
+				let value = bytes
+					.iter()
+					.enumerate()
+					.fold(0u128, |value, (index, &byte)| value | ((byte as u128) << (index * 8)));
+				Value::Unsigned(value << (lowest * 8))
+			},
+			Type::Str => {
+				let length = reader.leb128()?;
+				Value::Text(String::from_utf8_lossy(reader.bytes(length)?).into_owned())
+			},
+			Type::IStr => Value::Text(self.entry(reader.u16()?)?.format.clone()),
+			Type::Debug | Type::Display => {
+				// These are sent as a string terminated by 0xff
+				let length = reader
+					.data
+					.iter()
+					.position(|&byte| byte == 0xff)
+					.ok_or_eyre("defmt frame truncated")?;
+				let text = String::from_utf8_lossy(reader.bytes(length)?).into_owned();
+				reader.bytes(1)?;
+				Value::Text(text)
+			},
+			Type::U8Slice => {
+				let length = reader.leb128()?;
+				Value::Bytes(reader.bytes(length)?.to_vec())
+			},
+			Type::U8Array(length) => Value::Bytes(reader.bytes(*length)?.to_vec()),
+			Type::Format => Value::Text(self.format_nested(reader, depth)?),
+			Type::FormatArray(length) => Value::Text(self.format_list(*length, reader, depth)?),
+			Type::FormatSlice => {
+				let length = reader.leb128()?;
+				Value::Text(self.format_list(length, reader, depth)?)
+			},
+			Type::FormatSequence => {
+				// A sequence of formatted values terminated by a zero format string index
+				let mut result = String::new();
+				loop {
+					let index = reader.u16()?;
+					if index == 0 {
+						break;
+					}
+					result.push_str(&self.format_entry(index, reader, depth)?);
+				}
+				Value::Text(result)
+			},
+		})
+	}
+
+	/// Decode a value formatted with `{=?}`, which is sent as its format string index followed by its arguments
+	fn format_nested(&self, reader: &mut Reader, depth: usize) -> Result<String>
+	{
+		let index = reader.u16()?;
+		self.format_entry(index, reader, depth)
+	}
+
+	fn format_entry(&self, index: u16, reader: &mut Reader, depth: usize) -> Result<String>
+	{
+		let entry = self.entry(index)?;
+		// Derived enums have one format string per variant separated by '|', and send which variant it is
+		if entry.tag == "defmt_derived" && entry.format.contains('|') {
+			let variants = entry.format.split('|').collect::<Vec<_>>();
+			let variant = match variants.len() {
+				0..=0x100 => reader.u8()? as usize,
+				0x101..=0x10000 => reader.u16()? as usize,
+				_ => reader.u32()? as usize,
+			};
+			let format = variants
+				.get(variant)
+				.ok_or_else(|| eyre!("defmt frame has invalid enum variant {}", variant))?;
+			self.format(format, reader, depth + 1)
+		} else {
+			self.format(&entry.format, reader, depth + 1)
+		}
+	}
+
+	/// Decode a list of `{=[?]}` values, which all share the one format string index sent before the first
+	fn format_list(&self, length: usize, reader: &mut Reader, depth: usize) -> Result<String>
+	{
+		if length == 0 {
+			return Ok("[]".into());
+		}
+		let index = reader.u16()?;
+		let items = (0..length)
+			.map(|_| self.format_entry(index, reader, depth))
+			.collect::<Result<Vec<_>>>()?;
+		Ok(format!("[{}]", items.join(", ")))
+	}
+}
+
+impl Reader<'_>
+{
+	fn bytes(&mut self, length: usize) -> Result<&[u8]>
+	{
+		if self.data.len() < length {
+			return Err(eyre!("defmt frame truncated"));
+		}
+		let (bytes, remainder) = self.data.split_at(length);
+		self.data = remainder;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8>
+	{
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16>
+	{
+		Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+	}
+
+	fn u32(&mut self) -> Result<u32>
+	{
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+	}
+
+	fn u64(&mut self) -> Result<u64>
+	{
+		Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+	}
+
+	fn u128(&mut self) -> Result<u128>
+	{
+		Ok(u128::from_le_bytes(self.bytes(16)?.try_into()?))
+	}
+
+	/// Read an unsigned LEB128 encoded length
+	fn leb128(&mut self) -> Result<usize>
+	{
+		let mut value = 0usize;
+		for shift in (0..usize::BITS).step_by(7) {
+			let byte = self.u8()?;
+			value |= ((byte & 0x7f) as usize) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		Err(eyre!("defmt frame contains an invalid length"))
+	}
+}
+
+/// Render a single argument according to its parameter's display hint
+fn render(result: &mut String, value: &Value, parameter: &Parameter)
+{
+	// Bitfields display just their slice of the value
+	let value = match (&parameter.ty, value) {
+		(Type::BitField(range), Value::Unsigned(value)) => {
+			let width = range.end - range.start;
+			&Value::Unsigned((value >> range.start) & ((1u128 << width) - 1))
+		},
+		_ => value,
+	};
+
+	let _ = match (value, &parameter.hint) {
+		(
+			Value::Unsigned(value),
+			Some(DisplayHint::Hexadecimal {
+				alternate,
+				uppercase,
+				zero_pad,
+			}),
+		) => match (alternate, uppercase) {
+			(false, false) => write!(result, "{:01$x}", value, zero_pad),
+			(false, true) => write!(result, "{:01$X}", value, zero_pad),
+			(true, false) => write!(result, "{:#01$x}", value, zero_pad),
+			(true, true) => write!(result, "{:#01$X}", value, zero_pad),
+		},
+		(
+			Value::Unsigned(value),
+			Some(DisplayHint::Binary {
+				alternate,
+				zero_pad,
+			}),
+		) => match alternate {
+			false => write!(result, "{:01$b}", value, zero_pad),
+			true => write!(result, "{:#01$b}", value, zero_pad),
+		},
+		(
+			Value::Unsigned(value),
+			Some(DisplayHint::Octal {
+				alternate,
+				zero_pad,
+			}),
+		) => match alternate {
+			false => write!(result, "{:01$o}", value, zero_pad),
+			true => write!(result, "{:#01$o}", value, zero_pad),
+		},
+		(Value::Unsigned(value), Some(DisplayHint::Seconds(precision))) => {
+			write!(result, "{}", format_seconds(*value, precision))
+		},
+		(Value::Unsigned(value), Some(DisplayHint::Time(precision))) => {
+			write!(result, "{}", format_time(*value, precision))
+		},
+		(
+			Value::Unsigned(value),
+			Some(DisplayHint::NoHint {
+				zero_pad,
+			}),
+		) => write!(result, "{:01$}", value, zero_pad),
+		(Value::Unsigned(value), _) => write!(result, "{}", value),
+		(
+			Value::Signed(value),
+			Some(DisplayHint::NoHint {
+				zero_pad,
+			}),
+		) => write!(result, "{:01$}", value, zero_pad),
+		(Value::Signed(value), _) => write!(result, "{}", value),
+		(Value::F32(value), _) => write!(result, "{}", value),
+		(Value::F64(value), _) => write!(result, "{}", value),
+		(Value::Bool(value), _) => write!(result, "{}", value),
+		(Value::Char(value), Some(DisplayHint::Debug)) => write!(result, "{:?}", value),
+		(Value::Char(value), _) => write!(result, "{}", value),
+		(Value::Bytes(bytes), Some(DisplayHint::Ascii)) => {
+			write!(result, "b\"{}\"", bytes.escape_ascii())
+		},
+		(
+			Value::Bytes(bytes),
+			Some(DisplayHint::Hexadecimal {
+				..
+			}),
+		) => {
+			let bytes = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect::<Vec<_>>();
+			write!(result, "[{}]", bytes.join(", "))
+		},
+		(Value::Bytes(bytes), _) => write!(result, "{:?}", bytes),
+		(Value::Text(text), Some(DisplayHint::Debug)) if parameter.ty == Type::Str => write!(result, "{:?}", text),
+		(Value::Text(text), _) => write!(result, "{}", text),
+	};
+}
+
+/// Render a timestamp as seconds with a fractional part, eg 1.000250
+fn format_seconds(value: u128, precision: &TimePrecision) -> String
+{
+	match precision {
+		TimePrecision::Micros => format!("{}.{:06}", value / 1_000_000, value % 1_000_000),
+		TimePrecision::Millis => format!("{}.{:03}", value / 1_000, value % 1_000),
+		TimePrecision::Seconds => format!("{}", value),
+	}
+}
+
+/// Render a timestamp as hours, minutes and seconds, eg 00:01:02.250
+fn format_time(value: u128, precision: &TimePrecision) -> String
+{
+	let (seconds, fraction) = match precision {
+		TimePrecision::Micros => (value / 1_000_000, format!(".{:06}", value % 1_000_000)),
+		TimePrecision::Millis => (value / 1_000, format!(".{:03}", value % 1_000)),
+		TimePrecision::Seconds => (value, String::new()),
+	};
+	format!(
+		"{:02}:{:02}:{:02}{}",
+		seconds / 3600,
+		(seconds / 60) % 60,
+		seconds % 60,
+		fraction
+	)
+}
+
+impl Display for DefmtFrame
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		if let Some(timestamp) = &self.timestamp {
+			write!(fmt, "{} ", timestamp)?;
+		}
+		if let Some(level) = self.level {
+			write!(fmt, "{:<5} ", level.as_str().to_uppercase())?;
+		}
+		write!(fmt, "{}", self.message)
+	}
+}
+
+/// Decode a single rzCOBS (reverse zero-compressing COBS) frame, not including its 0x00 terminator.
+/// The encoding works backwards from the end of the frame, so this does too.
+pub fn rzcobs_decode(data: &[u8]) -> Result<Vec<u8>>
+{
+	let mut result = Vec::new();
+	let mut data = data.iter().rev().copied();
+	while let Some(code) = data.next() {
+		let mut next = || data.next().ok_or_eyre("Malformed rzCOBS frame");
+		match code {
+			0x00 => return Err(eyre!("Malformed rzCOBS frame")),
+			// A bitmap of which of the next 7 bytes are zeros
+			0x01..=0x7f => {
+				for bit in (0..7).rev() {
+					if code & (1 << bit) == 0 {
+						result.push(next()?);
+					} else {
+						result.push(0);
+					}
+				}
+			},
+			// A run of (code - 0x80 + 7) non-zero bytes followed by a zero
+			0x80..=0xfe => {
+				result.push(0);
+				for _ in 0..(code & 0x7f) + 7 {
+					result.push(next()?);
+				}
+			},
+			// A run of 134 non-zero bytes
+			0xff => {
+				for _ in 0..134 {
+					result.push(next()?);
+				}
+			},
+		}
+	}
+	result.reverse();
+	Ok(result)
+}
+
+impl DefmtDecoder
+{
+	pub fn new(table: DefmtTable) -> Self
+	{
+		Self {
+			table,
+			buffer: Vec::new(),
+		}
+	}
+
+	/// Feed in more data from the target, returning all the frames it completed
+	pub fn feed(&mut self, data: &[u8]) -> Vec<Result<DefmtFrame>>
+	{
+		self.buffer.extend_from_slice(data);
+		let mut frames = Vec::new();
+		// Frames are terminated by 0x00 bytes, which can't appear in the encoded frame data
+		while let Some(end) = self.buffer.iter().position(|&byte| byte == 0) {
+			let frame = self.buffer.drain(..=end).collect::<Vec<_>>();
+			let frame = &frame[..end];
+			if frame.is_empty() {
+				continue;
+			}
+			frames.push(rzcobs_decode(frame).and_then(|frame| self.table.decode(&frame)));
+		}
+		frames
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn table() -> DefmtTable
+	{
+		let mut table = DefmtTable {
+			entries: BTreeMap::new(),
+			timestamp: None,
+		};
+		table.insert(
+			0,
+			r#"{"package":"","tag":"defmt_timestamp","data":"{=u32:us}","disambiguator":"1"}"#,
+		);
+		table.insert(
+			1,
+			r#"{"package":"app","tag":"defmt_info","data":"x={=u8} y={=u16:#x}","disambiguator":"2"}"#,
+		);
+		table.insert(
+			2,
+			r#"{"package":"app","tag":"defmt_warn","data":"state {}","disambiguator":"3"}"#,
+		);
+		table.insert(
+			3,
+			r#"{"package":"app","tag":"defmt_derived","data":"Idle|Busy({=u8})","disambiguator":"4"}"#,
+		);
+		table.insert(
+			4,
+			r#"{"package":"app","tag":"defmt_debug","data":"states {=[?]}","disambiguator":"5"}"#,
+		);
+		table
+	}
+
+	#[test]
+	fn rzcobs_decodes()
+	{
+		// The bitmap byte 0x7c marks the last 5 of the 7 bytes as zero (trailing padding)
+		assert_eq!(rzcobs_decode(&[0x01, 0x02, 0x7c]).unwrap(), vec![0x01, 0x02, 0, 0, 0, 0, 0]);
+		// A run code of 0x80 carries 7 non-zero bytes followed by a zero
+		assert_eq!(rzcobs_decode(&[1, 2, 3, 4, 5, 6, 7, 0x80]).unwrap(), vec![
+			1, 2, 3, 4, 5, 6, 7, 0
+		]);
+		assert!(rzcobs_decode(&[0x00]).is_err());
+	}
+
+	#[test]
+	fn frames_decode()
+	{
+		let table = table();
+		// Format string 1, timestamp 1500us, x = 42, y = 0xfffe
+		let frame = table.decode(&[1, 0, 0xdc, 0x05, 0, 0, 42, 0xfe, 0xff]).unwrap();
+		assert_eq!(frame.to_string(), "0.001500 INFO  x=42 y=0xfffe");
+
+		// Format string 2, timestamp 0, nested derived enum variant 1 with payload 7
+		let frame = table.decode(&[2, 0, 0, 0, 0, 0, 3, 0, 1, 7]).unwrap();
+		assert_eq!(frame.to_string(), "0.000000 WARN  state Busy(7)");
+
+		// Format string 4, timestamp 0, a slice of 3 derived enums sharing one format string index
+		let frame = table.decode(&[4, 0, 0, 0, 0, 0, 3, 3, 0, 1, 7, 0, 1, 9]).unwrap();
+		assert_eq!(frame.to_string(), "0.000000 DEBUG states [Busy(7), Idle, Busy(9)]");
+		let frame = table.decode(&[4, 0, 0, 0, 0, 0, 0]).unwrap();
+		assert_eq!(frame.to_string(), "0.000000 DEBUG states []");
+	}
+}
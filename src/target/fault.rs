@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+
+use color_eyre::eyre::Result;
+use log::debug;
+
+use crate::firmware_file::FirmwareFile;
+use crate::target::cortexm::{CORTEXM_REG_MSP, CORTEXM_REG_PSP, CORTEXM_REG_XPSR, CortexM};
+
+/// Configurable Fault Status Register (MMFSR, BFSR and UFSR combined)
+pub const CORTEXM_CFSR: u64 = 0xe000ed28;
+/// HardFault Status Register
+pub const CORTEXM_HFSR: u64 = 0xe000ed2c;
+/// MemManage Fault Address Register
+pub const CORTEXM_MMFAR: u64 = 0xe000ed34;
+/// BusFault Address Register
+pub const CORTEXM_BFAR: u64 = 0xe000ed38;
+/// Secure Fault Status Register (ARMv8-M Mainline only)
+pub const CORTEXM_SFSR: u64 = 0xe000ede4;
+/// Secure Fault Address Register (ARMv8-M Mainline only)
+pub const CORTEXM_SFAR: u64 = 0xe000ede8;
+
+const CFSR_DACCVIOL: u32 = 1 << 1;
+const CFSR_MMARVALID: u32 = 1 << 7;
+const CFSR_PRECISERR: u32 = 1 << 9;
+const CFSR_BFARVALID: u32 = 1 << 15;
+const SFSR_SFARVALID: u32 = 1 << 6;
+
+/// Bits of CFSR and what they mean
+const CFSR_BITS: [(u32, &str); 18] = [
+	(1 << 0, "instruction access violation (MPU)"),
+	(CFSR_DACCVIOL, "data access violation (MPU)"),
+	(1 << 3, "MemManage fault on exception return unstacking"),
+	(1 << 4, "MemManage fault on exception entry stacking"),
+	(1 << 5, "MemManage fault during lazy FP state preservation"),
+	(1 << 8, "instruction bus error"),
+	(CFSR_PRECISERR, "precise data bus error"),
+	(1 << 10, "imprecise data bus error"),
+	(1 << 11, "bus fault on exception return unstacking"),
+	(1 << 12, "bus fault on exception entry stacking"),
+	(1 << 13, "bus fault during lazy FP state preservation"),
+	(1 << 16, "undefined instruction"),
+	(1 << 17, "invalid state (attempted to execute in ARM state, check the Thumb bit)"),
+	(1 << 18, "invalid PC load on exception return"),
+	(1 << 19, "coprocessor access with the coprocessor disabled or absent"),
+	(1 << 20, "stack overflow (stack limit register violation)"),
+	(1 << 24, "unaligned access"),
+	(1 << 25, "divide by zero"),
+];
+
+/// Bits of HFSR and what they mean
+const HFSR_BITS: [(u32, &str); 3] = [
+	(1 << 1, "bus fault on vector table read during exception processing"),
+	(1 << 30, "forced (escalated from a configurable fault that could not be taken)"),
+	(1 << 31, "debug event"),
+];
+
+/// Bits of SFSR and what they mean
+const SFSR_BITS: [(u32, &str); 7] = [
+	(1 << 0, "invalid Secure state entry point"),
+	(1 << 1, "invalid integrity signature in exception stack frame"),
+	(1 << 2, "invalid exception return"),
+	(1 << 3, "attribution unit violation"),
+	(1 << 4, "invalid transition from Secure to Non-secure state"),
+	(1 << 5, "lazy FP state preservation error"),
+	(1 << 7, "lazy FP state activation error"),
+];
+
+/// Cortex-M part numbers (from CPUID) that implement ARMv8-M Mainline and so may have SFSR
+const CORTEXM_ARMV8M_MAINLINE_PARTNOS: [u32; 4] = [0xd21, 0xd22, 0xd23, 0xd31];
+
+/// The exception stack frame pushed by the core on exception entry
+pub struct StackFrame
+{
+	/// Which stack pointer the frame was found on
+	pub stack: &'static str,
+	pub address: u32,
+	pub r0: u32,
+	pub r1: u32,
+	pub r2: u32,
+	pub r3: u32,
+	pub r12: u32,
+	pub lr: u32,
+	pub pc: u32,
+	pub xpsr: u32,
+}
+
+/// Everything read from the core to explain a fault
+pub struct FaultReport
+{
+	pub exception: u32,
+	pub cfsr: u32,
+	pub hfsr: u32,
+	pub mmfar: u32,
+	pub bfar: u32,
+	pub sfsr: Option<u32>,
+	pub sfar: Option<u32>,
+	pub frame: Option<StackFrame>,
+	symbols: Vec<(u32, String)>,
+}
+
+impl FaultReport
+{
+	/// Halt the core and read back all its fault state
+	pub fn capture(core: &CortexM) -> Result<Self>
+	{
+		if !core.is_halted()? {
+			debug!("Halting core to read fault state");
+			core.halt()?;
+		}
+		let ap = core.ap();
+
+		let cfsr = ap.mem_read_u32(CORTEXM_CFSR)?;
+		let hfsr = ap.mem_read_u32(CORTEXM_HFSR)?;
+		let mmfar = ap.mem_read_u32(CORTEXM_MMFAR)?;
+		let bfar = ap.mem_read_u32(CORTEXM_BFAR)?;
+		// Only ARMv8-M Mainline parts have the Security Extension's fault registers
+		let (sfsr, sfar) = if CORTEXM_ARMV8M_MAINLINE_PARTNOS.contains(&((core.cpuid() >> 4) & 0xfff)) {
+			(Some(ap.mem_read_u32(CORTEXM_SFSR)?), Some(ap.mem_read_u32(CORTEXM_SFAR)?))
+		} else {
+			(None, None)
+		};
+
+		// The active exception number lives in the bottom 9 bits of xPSR (IPSR)
+		let exception = core.read_register(CORTEXM_REG_XPSR)? & 0x1ff;
+		// If we're in a handler, LR holds EXC_RETURN which tells us which stack the frame is on
+		let lr = core.read_register(14)?;
+		let frame = if exception != 0 && lr >> 24 == 0xff {
+			let (stack, selector) = if lr & (1 << 2) != 0 {
+				("PSP", CORTEXM_REG_PSP)
+			} else {
+				("MSP", CORTEXM_REG_MSP)
+			};
+			let address = core.read_register(selector)?;
+			let mut words = [0u8; 32];
+			ap.mem_read(&mut words, address as u64)?;
+			let word = |index: usize| u32::from_le_bytes(words[index * 4..(index + 1) * 4].try_into().unwrap());
+			Some(StackFrame {
+				stack,
+				address,
+				r0: word(0),
+				r1: word(1),
+				r2: word(2),
+				r3: word(3),
+				r12: word(4),
+				lr: word(5),
+				pc: word(6),
+				xpsr: word(7),
+			})
+		} else {
+			debug!("Core is not in an exception handler (or LR is not EXC_RETURN), not decoding a stack frame");
+			None
+		};
+
+		Ok(Self {
+			exception,
+			cfsr,
+			hfsr,
+			mmfar,
+			bfar,
+			sfsr,
+			sfar,
+			frame,
+			symbols: Vec::new(),
+		})
+	}
+
+	/// Use the symbol table of the given firmware to name the stacked PC and LR
+	pub fn symbolise(&mut self, firmware: &FirmwareFile)
+	{
+		let Some(frame) = &self.frame else {
+			return;
+		};
+		self.symbols = [frame.pc, frame.lr & !1]
+			.into_iter()
+			.flat_map(|address| {
+				firmware
+					.symbolise(address)
+					.map(|(symbol, offset)| (address, format!("{}+0x{:x}", symbol.name, offset)))
+			})
+			.collect();
+	}
+
+	fn symbol_for(&self, address: u32) -> String
+	{
+		self.symbols
+			.iter()
+			.find(|(symbol_address, _)| *symbol_address == address)
+			.map(|(_, name)| format!(" <{}>", name))
+			.unwrap_or_default()
+	}
+
+	/// Build the list of human readable explanations for the fault
+	pub fn explanations(&self) -> Vec<String>
+	{
+		let mut explanations = Vec::new();
+		let stacked_pc = self
+			.frame
+			.as_ref()
+			.map(|frame| format!(", stacked PC {}{}", format_address(frame.pc), self.symbol_for(frame.pc)))
+			.unwrap_or_default();
+
+		for (bit, description) in HFSR_BITS {
+			if self.hfsr & bit != 0 {
+				explanations.push(format!("HardFault: {}", description));
+			}
+		}
+		for (bit, description) in CFSR_BITS {
+			if self.cfsr & bit == 0 {
+				continue;
+			}
+			// Attach the fault address to the faults that can capture one
+			let address = match bit {
+				CFSR_DACCVIOL if self.cfsr & CFSR_MMARVALID != 0 => format!(" at {}", format_address(self.mmfar)),
+				CFSR_PRECISERR if self.cfsr & CFSR_BFARVALID != 0 => format!(" at {}", format_address(self.bfar)),
+				_ => String::new(),
+			};
+			explanations.push(format!("{}{}{}", description, address, stacked_pc));
+		}
+		if let Some(sfsr) = self.sfsr {
+			for (bit, description) in SFSR_BITS {
+				if sfsr & bit == 0 {
+					continue;
+				}
+				let address = match self.sfar {
+					Some(sfar) if sfsr & SFSR_SFARVALID != 0 => format!(" at {}", format_address(sfar)),
+					_ => String::new(),
+				};
+				explanations.push(format!("SecureFault: {}{}{}", description, address, stacked_pc));
+			}
+		}
+		explanations
+	}
+}
+
+/// Format an address with the halves separated to make it easier to read, eg 0x2001_0000
+fn format_address(address: u32) -> String
+{
+	format!("0x{:04x}_{:04x}", address >> 16, address & 0xffff)
+}
+
+fn exception_name(exception: u32) -> String
+{
+	match exception {
+		0 => "Thread mode (no active exception)".into(),
+		1 => "Reset".into(),
+		2 => "NMI".into(),
+		3 => "HardFault".into(),
+		4 => "MemManage".into(),
+		5 => "BusFault".into(),
+		6 => "UsageFault".into(),
+		7 => "SecureFault".into(),
+		11 => "SVCall".into(),
+		12 => "DebugMonitor".into(),
+		14 => "PendSV".into(),
+		15 => "SysTick".into(),
+		_ if exception >= 16 => format!("IRQ {}", exception - 16),
+		_ => format!("reserved exception {}", exception),
+	}
+}
+
+impl Display for FaultReport
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		writeln!(fmt, "Active exception: {}", exception_name(self.exception))?;
+		writeln!(fmt, "CFSR:  0x{:08x}  HFSR: 0x{:08x}", self.cfsr, self.hfsr)?;
+		writeln!(fmt, "MMFAR: 0x{:08x}  BFAR: 0x{:08x}", self.mmfar, self.bfar)?;
+		if let (Some(sfsr), Some(sfar)) = (self.sfsr, self.sfar) {
+			writeln!(fmt, "SFSR:  0x{:08x}  SFAR: 0x{:08x}", sfsr, sfar)?;
+		}
+
+		if let Some(frame) = &self.frame {
+			writeln!(fmt, "Exception stack frame on {} at 0x{:08x}:", frame.stack, frame.address)?;
+			writeln!(
+				fmt,
+				"  r0:  0x{:08x}  r1: 0x{:08x}  r2:  0x{:08x}  r3:   0x{:08x}",
+				frame.r0, frame.r1, frame.r2, frame.r3
+			)?;
+			writeln!(fmt, "  r12: 0x{:08x}  lr: 0x{:08x}{}", frame.r12, frame.lr, self.symbol_for(frame.lr & !1))?;
+			writeln!(fmt, "  pc:  0x{:08x}{}  xpsr: 0x{:08x}", frame.pc, self.symbol_for(frame.pc), frame.xpsr)?;
+		}
+
+		let explanations = self.explanations();
+		if explanations.is_empty() {
+			writeln!(fmt, "No fault status bits are set")?;
+		} else {
+			writeln!(fmt, "Fault explanation:")?;
+			for explanation in explanations {
+				writeln!(fmt, "  {}", explanation)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn precise_bus_error_explained()
+	{
+		let report = FaultReport {
+			exception: 3,
+			cfsr: CFSR_PRECISERR | CFSR_BFARVALID,
+			hfsr: 1 << 30,
+			mmfar: 0,
+			bfar: 0x20010000,
+			sfsr: None,
+			sfar: None,
+			frame: Some(StackFrame {
+				stack: "MSP",
+				address: 0x2000ffe0,
+				r0: 0,
+				r1: 0,
+				r2: 0,
+				r3: 0,
+				r12: 0,
+				lr: 0x08000fff,
+				pc: 0x08001234,
+				xpsr: 0x01000000,
+			}),
+			symbols: Vec::new(),
+		};
+		assert_eq!(report.explanations(), vec![
+			"HardFault: forced (escalated from a configurable fault that could not be taken)".to_string(),
+			"precise data bus error at 0x2001_0000, stacked PC 0x0800_1234".to_string(),
+		]);
+	}
+}
@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+//! Programming target Flash directly through the memory AP, for when bmputil is driving the target itself
+//! rather than leaving it to the probe firmware
+
+use std::time::Duration;
+
+use color_eyre::eyre::{Result, eyre};
+use log::debug;
+
+use crate::serial::gdb_target::{MemoryKind, MemoryRegion};
+use crate::serial::remote::Align;
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::mass_erase::{
+	STM32_DBGMCU_IDCODE, STM32_FLASH_KEY1, STM32_FLASH_KEY2, STM32F1_FLASH_CR, STM32F1_FLASH_CR_LOCK,
+	STM32F1_FLASH_CR_STRT, STM32F1_FLASH_KEYR, STM32F1_FLASH_SR, STM32F1_FLASH_SR_BSY,
+};
+use crate::target::verify::progress_bar;
+use crate::target::wait_for;
+
+/// Where the Flash starts in the address space of STM32 parts
+const STM32_FLASH_BASE: u64 = 0x08000000;
+/// Locations of the register giving the size of the Flash in KiB on STM32F1 and STM32F3 parts
+const STM32F1_FLASH_SIZE: u64 = 0x1ffff7e0;
+const STM32F3_FLASH_SIZE: u64 = 0x1ffff7cc;
+const STM32F1_FLASH_AR: u64 = 0x40022014;
+const STM32F1_FLASH_SR_PGERR: u32 = 1 << 2;
+const STM32F1_FLASH_SR_WRPRTERR: u32 = 1 << 4;
+const STM32F1_FLASH_CR_PG: u32 = 1 << 0;
+const STM32F1_FLASH_CR_PER: u32 = 1 << 1;
+
+/// How long we are prepared to wait for a page erase or a write to complete
+const STM32_FLASH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A target Flash controller that can be driven through the memory AP
+pub trait FlashDriver
+{
+	/// The region of the address space the Flash occupies, and the size of the blocks it erases in
+	fn region(&self) -> MemoryRegion;
+
+	/// Erase `length` bytes of Flash starting at `address`, both of which must be a whole number of blocks
+	fn erase(&self, ap: &AdiV5AccessPort, address: u64, length: u64) -> Result<()>;
+
+	/// Program data into Flash at `address`, which must already have been erased
+	fn write(&self, ap: &AdiV5AccessPort, address: u64, data: &[u8]) -> Result<()>;
+
+	/// Tidy up after a sequence of erases and writes, such as locking the controller again
+	fn done(&self, ap: &AdiV5AccessPort) -> Result<()>;
+}
+
+/// The Flash controller of STM32F1 and STM32F3 parts
+struct Stm32F1Flash
+{
+	length: u64,
+	page_size: u64,
+}
+
+/// Work out which Flash controller the target has, if it's one we know how to drive
+pub fn detect_flash(ap: &AdiV5AccessPort) -> Result<Option<Box<dyn FlashDriver>>>
+{
+	let idcode = ap.mem_read_u32(STM32_DBGMCU_IDCODE)?;
+	let (size_register, page_size) = match idcode & 0xfff {
+		// Low and medium density STM32F1 parts use 1KiB pages
+		0x410 | 0x412 | 0x420 => (STM32F1_FLASH_SIZE, 0x400),
+		// High density and connectivity line STM32F1 parts use 2KiB pages (XL density parts have a second
+		// bank, which is not supported)
+		0x414 | 0x418 | 0x428 => (STM32F1_FLASH_SIZE, 0x800),
+		0x422 | 0x432 | 0x438 | 0x439 | 0x446 => (STM32F3_FLASH_SIZE, 0x800),
+		_ => return Ok(None),
+	};
+	let length = (ap.mem_read_u32(size_register)? & 0xffff) as u64 * 1024;
+	debug!("STM32 device ID {:03x} has {} KiB of Flash", idcode & 0xfff, length / 1024);
+	Ok(Some(Box::new(Stm32F1Flash {
+		length,
+		page_size,
+	})))
+}
+
+/// Erase the Flash blocks that `image` will occupy at `address`, then program it in
+pub fn program_image(flash: &dyn FlashDriver, ap: &AdiV5AccessPort, image: &[u8], address: u64) -> Result<()>
+{
+	let region = flash.region();
+	let MemoryKind::Flash {
+		block_size,
+	} = region.kind
+	else {
+		return Err(eyre!("Flash driver describes a region that is not Flash"));
+	};
+	let end = address + image.len() as u64;
+	if address < region.start || end > region.end() {
+		return Err(eyre!(
+			"Image at 0x{:08x}..0x{:08x} does not fit in the target's Flash at 0x{:08x}..0x{:08x}",
+			address,
+			end,
+			region.start,
+			region.end()
+		));
+	}
+
+	// Erasing has to be done in whole blocks, so round out to the blocks the image touches
+	let erase_start = address - (address - region.start) % block_size;
+	let erase_end = region.start + (end - region.start).div_ceil(block_size) * block_size;
+	flash.erase(ap, erase_start, erase_end - erase_start)?;
+
+	let progress = progress_bar(image.len() as u64);
+	for (index, chunk) in image.chunks(block_size as usize).enumerate() {
+		flash.write(ap, address + index as u64 * block_size, chunk)?;
+		progress.inc(chunk.len() as u64);
+	}
+	progress.finish();
+	flash.done(ap)
+}
+
+impl Stm32F1Flash
+{
+	fn unlock(ap: &AdiV5AccessPort) -> Result<()>
+	{
+		if ap.mem_read_u32(STM32F1_FLASH_CR)? & STM32F1_FLASH_CR_LOCK != 0 {
+			ap.mem_write_u32(STM32F1_FLASH_KEYR, STM32_FLASH_KEY1)?;
+			ap.mem_write_u32(STM32F1_FLASH_KEYR, STM32_FLASH_KEY2)?;
+		}
+		Ok(())
+	}
+
+	/// Wait for the current operation to complete, then check and clear its status
+	fn wait_complete(ap: &AdiV5AccessPort, operation: &str) -> Result<()>
+	{
+		wait_for(STM32_FLASH_TIMEOUT, operation, || {
+			Ok(ap.mem_read_u32(STM32F1_FLASH_SR)? & STM32F1_FLASH_SR_BSY == 0)
+		})?;
+		let status = ap.mem_read_u32(STM32F1_FLASH_SR)?;
+		// The status flags are write-one-to-clear
+		ap.mem_write_u32(STM32F1_FLASH_SR, status)?;
+		if status & (STM32F1_FLASH_SR_PGERR | STM32F1_FLASH_SR_WRPRTERR) != 0 {
+			return Err(eyre!("STM32 {} failed (FLASH_SR {:08x})", operation, status));
+		}
+		Ok(())
+	}
+}
+
+impl FlashDriver for Stm32F1Flash
+{
+	fn region(&self) -> MemoryRegion
+	{
+		MemoryRegion {
+			kind: MemoryKind::Flash {
+				block_size: self.page_size,
+			},
+			start: STM32_FLASH_BASE,
+			length: self.length,
+		}
+	}
+
+	fn erase(&self, ap: &AdiV5AccessPort, address: u64, length: u64) -> Result<()>
+	{
+		Self::unlock(ap)?;
+		for page in (address..address + length).step_by(self.page_size as usize) {
+			debug!("Erasing STM32 Flash page at 0x{:08x}", page);
+			ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_PER)?;
+			ap.mem_write_u32(STM32F1_FLASH_AR, page as u32)?;
+			ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_PER | STM32F1_FLASH_CR_STRT)?;
+			Self::wait_complete(ap, "Flash page erase")?;
+		}
+		ap.mem_write_u32(STM32F1_FLASH_CR, 0)
+	}
+
+	fn write(&self, ap: &AdiV5AccessPort, address: u64, data: &[u8]) -> Result<()>
+	{
+		// The controller can only program whole half-words, so pad out any odd byte at the end
+		let mut data = data.to_vec();
+		if !data.len().is_multiple_of(2) {
+			data.push(0xff);
+		}
+		Self::unlock(ap)?;
+		// The bus is stalled while each half-word is programmed, so they can all be written in one go
+		ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_PG)?;
+		ap.mem_write_aligned(address, &data, Align::As16Bit)?;
+		Self::wait_complete(ap, "Flash write")?;
+		ap.mem_write_u32(STM32F1_FLASH_CR, 0)
+	}
+
+	fn done(&self, ap: &AdiV5AccessPort) -> Result<()>
+	{
+		ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_LOCK)
+	}
+}
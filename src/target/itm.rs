@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+
+/// Number of zero bytes that must precede the 0x80 of a synchronisation packet
+/// (the architecture requires at least 47 zero bits)
+const ITM_SYNC_ZEROS: usize = 5;
+const ITM_OVERFLOW: u8 = 0x70;
+const ITM_GLOBAL_TIMESTAMP_1: u8 = 0x94;
+const ITM_GLOBAL_TIMESTAMP_2: u8 = 0xb4;
+/// Continuation bit used by the multi-byte protocol packets
+const ITM_CONTINUATION: u8 = 0x80;
+/// Most payload bytes any continuation-encoded packet can have
+const ITM_MAX_CONTINUATION_BYTES: usize = 7;
+
+/// DWT hardware source packet discriminators
+const DWT_EVENT_COUNTER: u8 = 0;
+const DWT_EXCEPTION_TRACE: u8 = 1;
+const DWT_PC_SAMPLE: u8 = 2;
+
+/// What the core did with an exception, as reported by an exception trace packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionAction
+{
+	Entered,
+	Exited,
+	Returned,
+	Unknown,
+}
+
+/// A single packet decoded from an ITM/DWT trace stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ItmPacket
+{
+	/// Synchronisation packet, marking a packet boundary
+	Sync,
+	/// The ITM's FIFO overflowed and some packets were lost
+	Overflow,
+	/// Data written by software to one of the ITM stimulus ports
+	Instrumentation
+	{
+		port: u8,
+		data: Vec<u8>,
+	},
+	/// One or more of the DWT's event counters wrapped, as a bitmask of CPI, exception, sleep, LSU,
+	/// fold and cycle counters (bits 0 through 5 respectively)
+	EventCounter(u8),
+	/// The core entered, exited or returned to an exception
+	ExceptionTrace
+	{
+		exception: u16,
+		action: ExceptionAction,
+	},
+	/// Periodic PC sample, or None if the core was asleep when sampled
+	PcSample(Option<u32>),
+	/// PC value of an instruction that triggered a DWT data trace comparator
+	DataTracePc
+	{
+		comparator: u8,
+		pc: u32,
+	},
+	/// Low bits of the data address that triggered a DWT data trace comparator
+	DataTraceAddress
+	{
+		comparator: u8,
+		address: u32,
+	},
+	/// Data value read or written that triggered a DWT data trace comparator
+	DataTraceValue
+	{
+		comparator: u8,
+		write: bool,
+		value: u32,
+	},
+	/// Time since the last local timestamp, along with how it relates to the packet it goes with
+	LocalTimestamp
+	{
+		delta: u32,
+		relation: u8,
+	},
+	/// Low bits of the global timestamp, and whether it wrapped or the clock changed
+	GlobalTimestamp1
+	{
+		value: u32,
+		wrapped: bool,
+		clock_change: bool,
+	},
+	/// High bits of the global timestamp
+	GlobalTimestamp2(u64),
+	/// Extension packet, used to select the stimulus port page
+	Extension
+	{
+		value: u32,
+		hardware: bool,
+	},
+	/// A hardware source packet from a DWT source we don't know how to decode
+	Hardware
+	{
+		discriminator: u8,
+		data: Vec<u8>,
+	},
+	/// A header byte that doesn't match any known packet
+	Reserved(u8),
+}
+
+/// Streaming decoder for ITM/DWT trace data, as captured from SWO. Data can be fed in in arbitrarily
+/// sized chunks, with any incomplete packet at the end held over until the next chunk arrives.
+#[derive(Default)]
+pub struct ItmDecoder
+{
+	pending: Vec<u8>,
+}
+
+impl ItmDecoder
+{
+	pub fn new() -> Self
+	{
+		Self::default()
+	}
+
+	/// Feed more captured trace data into the decoder, returning all the packets it completes
+	pub fn feed(&mut self, data: &[u8]) -> Vec<ItmPacket>
+	{
+		self.pending.extend_from_slice(data);
+		let mut packets = Vec::new();
+		let mut offset = 0;
+		while let Some((packet, length)) = decode_packet(&self.pending[offset..]) {
+			if let Some(packet) = packet {
+				packets.push(packet);
+			}
+			offset += length;
+		}
+		self.pending.drain(..offset);
+		packets
+	}
+}
+
+/// Decode a complete capture (eg, from a file) in one go
+pub fn decode(data: &[u8]) -> Vec<ItmPacket>
+{
+	ItmDecoder::new().feed(data)
+}
+
+/// Try to decode the packet at the start of `data`, returning it and how many bytes it took up.
+/// Returns None if more data is needed to complete the packet, and a None packet for bytes that
+/// should just be skipped over.
+fn decode_packet(data: &[u8]) -> Option<(Option<ItmPacket>, usize)>
+{
+	let header = *data.first()?;
+	match header {
+		0x00 => {
+			// Zeros are only valid as the lead-in to a synchronisation packet
+			let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+			let next = *data.get(zeros)?;
+			if next == ITM_CONTINUATION && zeros >= ITM_SYNC_ZEROS {
+				Some((Some(ItmPacket::Sync), zeros + 1))
+			} else {
+				Some((None, zeros))
+			}
+		},
+		ITM_OVERFLOW => Some((Some(ItmPacket::Overflow), 1)),
+		ITM_GLOBAL_TIMESTAMP_1 => {
+			let (payload, length) = continuation_payload(&data[1..], header, 4)?;
+			let packet = if length == 4 {
+				// The final byte carries only 5 bits of timestamp, plus the wrap and clock change flags
+				let last = data[4];
+				ItmPacket::GlobalTimestamp1 {
+					value: (payload as u32) & 0x03ffffff,
+					wrapped: last & 0x40 != 0,
+					clock_change: last & 0x20 != 0,
+				}
+			} else {
+				ItmPacket::GlobalTimestamp1 {
+					value: payload as u32,
+					wrapped: false,
+					clock_change: false,
+				}
+			};
+			Some((Some(packet), length + 1))
+		},
+		ITM_GLOBAL_TIMESTAMP_2 => {
+			let (payload, length) = continuation_payload(&data[1..], header, ITM_MAX_CONTINUATION_BYTES)?;
+			Some((Some(ItmPacket::GlobalTimestamp2(payload << 26)), length + 1))
+		},
+		// Local timestamp format 2, where the header holds a small timestamp value and there's no payload
+		_ if header & 0x8f == 0x00 => Some((
+			Some(ItmPacket::LocalTimestamp {
+				delta: ((header >> 4) & 0x7) as u32,
+				relation: 0,
+			}),
+			1,
+		)),
+		// Local timestamp format 1, with the timestamp value in continuation bytes
+		_ if header & 0xcf == 0xc0 => {
+			let (payload, length) = continuation_payload(&data[1..], header, 4)?;
+			Some((
+				Some(ItmPacket::LocalTimestamp {
+					delta: payload as u32,
+					relation: (header >> 4) & 0x3,
+				}),
+				length + 1,
+			))
+		},
+		// Extension packets have the bottom bits 0b1x00, and their first 3 bits of payload in the header
+		_ if header & 0x0b == 0x08 => {
+			let (payload, length) = continuation_payload(&data[1..], header, 4)?;
+			Some((
+				Some(ItmPacket::Extension {
+					value: ((header >> 4) & 0x7) as u32 | ((payload as u32) << 3),
+					hardware: header & 0x04 != 0,
+				}),
+				length + 1,
+			))
+		},
+		_ if header & 0x03 == 0 => Some((Some(ItmPacket::Reserved(header)), 1)),
+		// Everything else is a source packet, with the payload size encoded in the bottom 2 bits
+		_ => {
+			let size = match header & 0x03 {
+				1 => 1,
+				2 => 2,
+				_ => 4,
+			};
+			let payload = data.get(1..=size)?;
+			Some((Some(decode_source(header, payload)), size + 1))
+		},
+	}
+}
+
+/// Collect the payload of a continuation-encoded packet (7 bits per byte, least significant first),
+/// returning the value and how many payload bytes there were
+fn continuation_payload(data: &[u8], header: u8, max_length: usize) -> Option<(u64, usize)>
+{
+	if header & ITM_CONTINUATION == 0 {
+		return Some((0, 0));
+	}
+	let mut value = 0u64;
+	for (index, &byte) in data.iter().take(max_length).enumerate() {
+		value |= ((byte & 0x7f) as u64) << (index * 7);
+		if byte & ITM_CONTINUATION == 0 || index + 1 == max_length {
+			return Some((value, index + 1));
+		}
+	}
+	None
+}
+
+fn decode_source(header: u8, payload: &[u8]) -> ItmPacket
+{
+	let address = header >> 3;
+	let value = payload
+		.iter()
+		.rev()
+		.fold(0u32, |value, &byte| (value << 8) | byte as u32);
+	// Bit 2 of the header says whether this is a software (ITM stimulus port) or hardware (DWT) source
+	if header & 0x04 == 0 {
+		return ItmPacket::Instrumentation {
+			port: address,
+			data: payload.to_vec(),
+		};
+	}
+
+	match address {
+		DWT_EVENT_COUNTER => ItmPacket::EventCounter(value as u8),
+		DWT_EXCEPTION_TRACE => ItmPacket::ExceptionTrace {
+			exception: (value & 0x1ff) as u16,
+			action: match (value >> 12) & 0x3 {
+				1 => ExceptionAction::Entered,
+				2 => ExceptionAction::Exited,
+				3 => ExceptionAction::Returned,
+				_ => ExceptionAction::Unknown,
+			},
+		},
+		// A single byte sample means the core was sleeping
+		DWT_PC_SAMPLE => ItmPacket::PcSample((payload.len() == 4).then_some(value)),
+		// Data trace packets encode the comparator number and packet type in the discriminator
+		8..=15 => {
+			let comparator = (address >> 1) & 0x3;
+			if address & 1 == 0 {
+				ItmPacket::DataTracePc {
+					comparator,
+					pc: value,
+				}
+			} else {
+				ItmPacket::DataTraceAddress {
+					comparator,
+					address: value,
+				}
+			}
+		},
+		16..=23 => ItmPacket::DataTraceValue {
+			comparator: (address >> 1) & 0x3,
+			write: address & 1 != 0,
+			value,
+		},
+		_ => ItmPacket::Hardware {
+			discriminator: address,
+			data: payload.to_vec(),
+		},
+	}
+}
+
+impl Display for ExceptionAction
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		let action = match self {
+			Self::Entered => "entered",
+			Self::Exited => "exited",
+			Self::Returned => "returned to",
+			Self::Unknown => "unknown action on",
+		};
+		write!(fmt, "{}", action)
+	}
+}
+
+impl Display for ItmPacket
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		match self {
+			Self::Sync => write!(fmt, "sync"),
+			Self::Overflow => write!(fmt, "overflow, trace data lost"),
+			Self::Instrumentation {
+				port,
+				data,
+			} => write!(fmt, "port {}: {:02x?}", port, data),
+			Self::EventCounter(counters) => {
+				let names = ["CPI", "exception", "sleep", "LSU", "fold", "cycle"]
+					.into_iter()
+					.enumerate()
+					.filter(|(bit, _)| counters & (1 << bit) != 0)
+					.map(|(_, name)| name)
+					.collect::<Vec<_>>();
+				write!(fmt, "event counter wrap: {}", names.join(", "))
+			},
+			Self::ExceptionTrace {
+				exception,
+				action,
+			} => write!(fmt, "exception {} {}", action, exception),
+			Self::PcSample(Some(pc)) => write!(fmt, "PC sample 0x{:08x}", pc),
+			Self::PcSample(None) => write!(fmt, "PC sample: core sleeping"),
+			Self::DataTracePc {
+				comparator,
+				pc,
+			} => write!(fmt, "comparator {} hit by PC 0x{:08x}", comparator, pc),
+			Self::DataTraceAddress {
+				comparator,
+				address,
+			} => write!(fmt, "comparator {} hit at address offset 0x{:04x}", comparator, address),
+			Self::DataTraceValue {
+				comparator,
+				write,
+				value,
+			} => write!(
+				fmt,
+				"comparator {} {} value 0x{:08x}",
+				comparator,
+				if *write { "write" } else { "read" },
+				value
+			),
+			Self::LocalTimestamp {
+				delta,
+				..
+			} => write!(fmt, "timestamp +{}", delta),
+			Self::GlobalTimestamp1 {
+				value,
+				..
+			} => write!(fmt, "global timestamp (low) {}", value),
+			Self::GlobalTimestamp2(value) => write!(fmt, "global timestamp (high) {}", value),
+			Self::Extension {
+				value,
+				hardware,
+			} => write!(fmt, "extension {} ({})", value, if *hardware { "hardware" } else { "stimulus page" }),
+			Self::Hardware {
+				discriminator,
+				data,
+			} => write!(fmt, "hardware source {}: {:02x?}", discriminator, data),
+			Self::Reserved(header) => write!(fmt, "reserved header 0x{:02x}", header),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn instrumentation_and_sync()
+	{
+		let packets = decode(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, b'h', 0x0b, 0x78, 0x56, 0x34, 0x12]);
+		assert_eq!(
+			packets,
+			[
+				ItmPacket::Sync,
+				ItmPacket::Instrumentation {
+					port: 0,
+					data: vec![b'h']
+				},
+				ItmPacket::Instrumentation {
+					port: 1,
+					data: vec![0x78, 0x56, 0x34, 0x12]
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn hardware_packets()
+	{
+		// Exception 15 (SysTick) entered, a PC sample, a sleeping PC sample, then a local timestamp of 300
+		let packets = decode(&[0x0e, 0x0f, 0x10, 0x17, 0x34, 0x12, 0x00, 0x08, 0x15, 0x00, 0xc0, 0xac, 0x02]);
+		assert_eq!(
+			packets,
+			[
+				ItmPacket::ExceptionTrace {
+					exception: 15,
+					action: ExceptionAction::Entered
+				},
+				ItmPacket::PcSample(Some(0x08001234)),
+				ItmPacket::PcSample(None),
+				ItmPacket::LocalTimestamp {
+					delta: 300,
+					relation: 0
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn split_packets()
+	{
+		let mut decoder = ItmDecoder::new();
+		assert_eq!(decoder.feed(&[0x03, 0x01, 0x02]), []);
+		assert_eq!(
+			decoder.feed(&[0x03, 0x04, 0x70]),
+			[
+				ItmPacket::Instrumentation {
+					port: 0,
+					data: vec![1, 2, 3, 4]
+				},
+				ItmPacket::Overflow,
+			]
+		);
+	}
+}
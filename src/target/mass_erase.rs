@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use clap::builder::PossibleValue;
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use log::{debug, info, warn};
+
+use crate::serial::remote::adi::{AdiV5AccessPort, adiv5_ap_reg};
+use crate::target::{Target, wait_for};
+
+/// IDR value of the Nordic nRF52 CTRL-AP
+const NRF52_CTRL_AP_IDR: u32 = 0x02880000;
+/// CTRL-AP register that soft-resets the device while held at 1
+const NRF52_CTRL_AP_RESET: u16 = adiv5_ap_reg(0x000);
+/// CTRL-AP register that starts an erase of all Flash, UICR and RAM when written to 1
+const NRF52_CTRL_AP_ERASEALL: u16 = adiv5_ap_reg(0x004);
+/// CTRL-AP register that reads as 1 while the ERASEALL operation is running
+const NRF52_CTRL_AP_ERASEALLSTATUS: u16 = adiv5_ap_reg(0x008);
+/// CTRL-AP register that reads as 0 while access port protection is enabled
+const NRF52_CTRL_AP_APPROTECTSTATUS: u16 = adiv5_ap_reg(0x00c);
+
+/// IDR values of the NXP/Freescale Kinetis MDM-AP (K-series and KL/KE/KV-series respectively)
+const KINETIS_MDM_AP_IDRS: [u32; 2] = [0x001c0000, 0x001c0020];
+const KINETIS_MDM_AP_STATUS: u16 = adiv5_ap_reg(0x00);
+const KINETIS_MDM_AP_CONTROL: u16 = adiv5_ap_reg(0x04);
+const KINETIS_MDM_STATUS_MASS_ERASE_ACK: u32 = 1 << 0;
+const KINETIS_MDM_STATUS_FLASH_READY: u32 = 1 << 1;
+const KINETIS_MDM_STATUS_SYSTEM_SECURITY: u32 = 1 << 2;
+const KINETIS_MDM_STATUS_MASS_ERASE_ENABLED: u32 = 1 << 5;
+const KINETIS_MDM_CONTROL_MASS_ERASE: u32 = 1 << 0;
+
+/// Location of the DBGMCU IDCODE register on STM32F1/F2/F3/F4/F7 parts
+pub(crate) const STM32_DBGMCU_IDCODE: u64 = 0xe0042000;
+pub(crate) const STM32_FLASH_KEY1: u32 = 0x45670123;
+pub(crate) const STM32_FLASH_KEY2: u32 = 0xcdef89ab;
+const STM32_OPTION_KEY1: u32 = 0x08192a3b;
+const STM32_OPTION_KEY2: u32 = 0x4c5d6e7f;
+
+/// Flash controller registers for STM32F1/F3 style devices
+pub(crate) const STM32F1_FLASH_KEYR: u64 = 0x40022004;
+const STM32F1_FLASH_OPTKEYR: u64 = 0x40022008;
+pub(crate) const STM32F1_FLASH_SR: u64 = 0x4002200c;
+pub(crate) const STM32F1_FLASH_CR: u64 = 0x40022010;
+pub(crate) const STM32F1_FLASH_SR_BSY: u32 = 1 << 0;
+const STM32F1_FLASH_CR_OPTPG: u32 = 1 << 4;
+const STM32F1_FLASH_CR_OPTER: u32 = 1 << 5;
+pub(crate) const STM32F1_FLASH_CR_STRT: u32 = 1 << 6;
+pub(crate) const STM32F1_FLASH_CR_LOCK: u32 = 1 << 7;
+/// Set by unlocking the option bytes, and cleared by writing it as 0
+const STM32F1_FLASH_CR_OPTWRE: u32 = 1 << 9;
+const STM32F1_OPTION_RDP: u64 = 0x1ffff800;
+
+/// Flash controller registers for STM32F2/F4/F7 style devices
+const STM32F4_FLASH_OPTKEYR: u64 = 0x40023c08;
+const STM32F4_FLASH_SR: u64 = 0x40023c0c;
+const STM32F4_FLASH_OPTCR: u64 = 0x40023c14;
+const STM32F4_FLASH_SR_BSY: u32 = 1 << 16;
+const STM32F4_FLASH_OPTCR_OPTLOCK: u32 = 1 << 0;
+const STM32F4_FLASH_OPTCR_OPTSTRT: u32 = 1 << 1;
+const STM32F4_FLASH_OPTCR_RDP_MASK: u32 = 0xff << 8;
+const STM32F4_RDP_LEVEL_0: u32 = 0xaa << 8;
+
+/// Location of the external (debugger) access view of the SAM D/L/C Device Service Unit
+const SAMD_DSU_EXT_ACCESS: u64 = 0x41002100;
+const SAMD_DSU_CTRL: u64 = SAMD_DSU_EXT_ACCESS;
+const SAMD_DSU_STATUSA: u64 = SAMD_DSU_EXT_ACCESS + 0x001;
+const SAMD_DSU_DID: u64 = SAMD_DSU_EXT_ACCESS + 0x018;
+const SAMD_DID_MASK: u32 = 0xff380000;
+const SAMD_DID_CONST_VALUE: u32 = 0x10000000;
+const SAMD_CTRL_CHIP_ERASE: u8 = 1 << 4;
+const SAMD_STATUSA_DONE: u32 = 1 << 0;
+const SAMD_STATUSA_PERR: u32 = 1 << 4;
+const SAMD_STATUSA_FAIL: u32 = 1 << 3;
+
+/// How long we are prepared to wait for a whole-device erase to complete
+const MASS_ERASE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The vendor-specific recovery sequences we know how to use to mass erase a target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MassEraseVendor
+{
+	/// Nordic nRF52 - CTRL-AP ERASEALL
+	Nrf52,
+	/// NXP/Freescale Kinetis - MDM-AP mass erase
+	Kinetis,
+	/// ST STM32 - regress read-out protection from level 1 to level 0 via the option bytes
+	Stm32,
+	/// Microchip/Atmel SAM D/L/C - chip erase via the Device Service Unit
+	Sam,
+}
+
+/// The kinds of STM32 flash controller we know how to regress read-out protection on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stm32FlashController
+{
+	/// STM32F1 and F3 style controller, holding the RDP option byte value for level 0
+	F1(u16),
+	/// STM32F2, F4 and F7 style controller
+	F4,
+}
+
+impl MassEraseVendor
+{
+	/// Try to work out which vendor's mass erase sequence applies to the attached target
+	pub fn detect(target: &Target) -> Option<Self>
+	{
+		// Start with the vendors that have dedicated APs as these don't need memory access to work
+		for ap in target.access_ports() {
+			if ap.idr() == NRF52_CTRL_AP_IDR {
+				return Some(Self::Nrf52);
+			}
+			if KINETIS_MDM_AP_IDRS.contains(&ap.idr()) {
+				return Some(Self::Kinetis);
+			}
+		}
+
+		// Now try the vendors that need us to look at memory to identify them
+		let ap = target.memory_ap()?;
+		if let Ok(idcode) = ap.mem_read_u32(STM32_DBGMCU_IDCODE) &&
+			stm32_flash_controller(idcode).is_some()
+		{
+			return Some(Self::Stm32);
+		}
+		if let Ok(did) = ap.mem_read_u32(SAMD_DSU_DID) &&
+			did & SAMD_DID_MASK == SAMD_DID_CONST_VALUE
+		{
+			return Some(Self::Sam);
+		}
+		None
+	}
+}
+
+/// Mass erase the attached target using the given vendor's sequence, or the one detected as
+/// appropriate if no vendor is given
+pub fn mass_erase(target: &Target, vendor: Option<MassEraseVendor>) -> Result<()>
+{
+	let vendor = match vendor {
+		Some(vendor) => vendor,
+		None => MassEraseVendor::detect(target)
+			.ok_or_eyre("Could not determine how to mass erase the attached target, please specify a vendor")?,
+	};
+	info!("Mass erasing target using the {} sequence", vendor);
+
+	match vendor {
+		MassEraseVendor::Nrf52 => nrf52_mass_erase(find_ap(target, &[NRF52_CTRL_AP_IDR], "nRF52 CTRL-AP")?),
+		MassEraseVendor::Kinetis => kinetis_mass_erase(find_ap(target, &KINETIS_MDM_AP_IDRS, "Kinetis MDM-AP")?),
+		MassEraseVendor::Stm32 => stm32_mass_erase(memory_ap(target)?),
+		MassEraseVendor::Sam => sam_mass_erase(memory_ap(target)?),
+	}
+}
+
+fn find_ap<'a>(target: &'a Target, idrs: &[u32], name: &str) -> Result<&'a AdiV5AccessPort>
+{
+	target
+		.access_ports()
+		.iter()
+		.find(|ap| idrs.contains(&ap.idr()))
+		.ok_or_else(|| eyre!("Could not find the {} on the target", name))
+}
+
+fn memory_ap(target: &Target) -> Result<&AdiV5AccessPort>
+{
+	target
+		.memory_ap()
+		.ok_or_eyre("Could not find a memory access port on the target")
+}
+
+fn nrf52_mass_erase(ctrl_ap: &AdiV5AccessPort) -> Result<()>
+{
+	if ctrl_ap.read(NRF52_CTRL_AP_APPROTECTSTATUS)? == 0 {
+		info!("Access port protection is enabled, erasing will remove it");
+	}
+	// Kick off the erase and wait for it to complete
+	ctrl_ap.write(NRF52_CTRL_AP_ERASEALL, 1)?;
+	wait_for(MASS_ERASE_TIMEOUT, "nRF52 ERASEALL to complete", || {
+		Ok(ctrl_ap.read(NRF52_CTRL_AP_ERASEALLSTATUS)? == 0)
+	})?;
+	// Now pulse the soft reset so the device comes back up unprotected, and tidy up
+	ctrl_ap.write(NRF52_CTRL_AP_RESET, 1)?;
+	ctrl_ap.write(NRF52_CTRL_AP_RESET, 0)?;
+	ctrl_ap.write(NRF52_CTRL_AP_ERASEALL, 0)?;
+	info!("nRF52 mass erase complete");
+	Ok(())
+}
+
+fn kinetis_mass_erase(mdm_ap: &AdiV5AccessPort) -> Result<()>
+{
+	let status = mdm_ap.read(KINETIS_MDM_AP_STATUS)?;
+	debug!("Kinetis MDM-AP status: {:08x}", status);
+	if status & KINETIS_MDM_STATUS_FLASH_READY == 0 {
+		return Err(eyre!(
+			"Kinetis Flash controller is not ready, try again with the target held in reset"
+		));
+	}
+	if status & KINETIS_MDM_STATUS_MASS_ERASE_ENABLED == 0 {
+		return Err(eyre!("Kinetis mass erase has been disabled by the Flash security settings"));
+	}
+	if status & KINETIS_MDM_STATUS_SYSTEM_SECURITY != 0 {
+		info!("System security is enabled, erasing will remove it");
+	}
+
+	// Request the erase, wait for the device to acknowledge it, and then wait for it to complete
+	mdm_ap.write(KINETIS_MDM_AP_CONTROL, KINETIS_MDM_CONTROL_MASS_ERASE)?;
+	wait_for(MASS_ERASE_TIMEOUT, "Kinetis to acknowledge mass erase", || {
+		Ok(mdm_ap.read(KINETIS_MDM_AP_STATUS)? & KINETIS_MDM_STATUS_MASS_ERASE_ACK != 0)
+	})?;
+	wait_for(MASS_ERASE_TIMEOUT, "Kinetis mass erase to complete", || {
+		Ok(mdm_ap.read(KINETIS_MDM_AP_CONTROL)? & KINETIS_MDM_CONTROL_MASS_ERASE == 0)
+	})?;
+	info!("Kinetis mass erase complete");
+	Ok(())
+}
+
+/// Map a DBGMCU IDCODE value to the kind of flash controller that device has
+fn stm32_flash_controller(idcode: u32) -> Option<Stm32FlashController>
+{
+	match idcode & 0xfff {
+		// STM32F1 parts
+		0x410 | 0x412 | 0x414 | 0x418 | 0x420 | 0x428 | 0x430 => Some(Stm32FlashController::F1(0x00a5)),
+		// STM32F3 parts
+		0x422 | 0x432 | 0x438 | 0x439 | 0x446 => Some(Stm32FlashController::F1(0x00aa)),
+		// STM32F2, F4 and F7 parts
+		0x411 | 0x413 | 0x419 | 0x421 | 0x423 | 0x431 | 0x433 | 0x434 | 0x441 | 0x449 | 0x451 | 0x452 | 0x458 |
+		0x463 => Some(Stm32FlashController::F4),
+		_ => None,
+	}
+}
+
+fn stm32_mass_erase(ap: &AdiV5AccessPort) -> Result<()>
+{
+	let idcode = ap.mem_read_u32(STM32_DBGMCU_IDCODE)?;
+	let controller = stm32_flash_controller(idcode)
+		.ok_or_else(|| eyre!("Unsupported STM32 device (DBGMCU IDCODE {:08x})", idcode))?;
+	debug!("STM32 device ID {:03x} uses {:?} flash controller", idcode & 0xfff, controller);
+
+	match controller {
+		Stm32FlashController::F1(rdp_key) => {
+			// Unlock the Flash controller and then the option bytes
+			ap.mem_write_u32(STM32F1_FLASH_KEYR, STM32_FLASH_KEY1)?;
+			ap.mem_write_u32(STM32F1_FLASH_KEYR, STM32_FLASH_KEY2)?;
+			ap.mem_write_u32(STM32F1_FLASH_OPTKEYR, STM32_FLASH_KEY1)?;
+			ap.mem_write_u32(STM32F1_FLASH_OPTKEYR, STM32_FLASH_KEY2)?;
+			let wait_not_busy = || Ok(ap.mem_read_u32(STM32F1_FLASH_SR)? & STM32F1_FLASH_SR_BSY == 0);
+
+			// Erase the option bytes, which triggers the mass erase if RDP is active. Every write to CR has to
+			// keep OPTWRE set, as writing it as 0 locks the option bytes again
+			ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_OPTWRE | STM32F1_FLASH_CR_OPTER)?;
+			ap.mem_write_u32(
+				STM32F1_FLASH_CR,
+				STM32F1_FLASH_CR_OPTWRE | STM32F1_FLASH_CR_OPTER | STM32F1_FLASH_CR_STRT,
+			)?;
+			wait_for(MASS_ERASE_TIMEOUT, "STM32 option byte erase", wait_not_busy)?;
+
+			// Program the RDP option byte back to level 0 and lock everything back up
+			ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_OPTWRE | STM32F1_FLASH_CR_OPTPG)?;
+			ap.mem_write_u16(STM32F1_OPTION_RDP, rdp_key)?;
+			wait_for(MASS_ERASE_TIMEOUT, "STM32 option byte programming", wait_not_busy)?;
+			ap.mem_write_u32(STM32F1_FLASH_CR, STM32F1_FLASH_CR_LOCK)?;
+		},
+		Stm32FlashController::F4 => {
+			// Unlock the option bytes
+			ap.mem_write_u32(STM32F4_FLASH_OPTKEYR, STM32_OPTION_KEY1)?;
+			ap.mem_write_u32(STM32F4_FLASH_OPTKEYR, STM32_OPTION_KEY2)?;
+			// Set RDP back to level 0 and start the option byte programming, which mass erases the Flash
+			let optcr = ap.mem_read_u32(STM32F4_FLASH_OPTCR)?;
+			let optcr = (optcr & !(STM32F4_FLASH_OPTCR_RDP_MASK | STM32F4_FLASH_OPTCR_OPTLOCK)) | STM32F4_RDP_LEVEL_0;
+			ap.mem_write_u32(STM32F4_FLASH_OPTCR, optcr)?;
+			ap.mem_write_u32(STM32F4_FLASH_OPTCR, optcr | STM32F4_FLASH_OPTCR_OPTSTRT)?;
+			wait_for(MASS_ERASE_TIMEOUT, "STM32 option byte programming", || {
+				Ok(ap.mem_read_u32(STM32F4_FLASH_SR)? & STM32F4_FLASH_SR_BSY == 0)
+			})?;
+			ap.mem_write_u32(STM32F4_FLASH_OPTCR, optcr | STM32F4_FLASH_OPTCR_OPTLOCK)?;
+		},
+	}
+	info!("STM32 read-out protection regressed to level 0");
+	warn!("Power cycle the target for the new option byte values to take effect");
+	Ok(())
+}
+
+fn sam_mass_erase(ap: &AdiV5AccessPort) -> Result<()>
+{
+	let did = ap.mem_read_u32(SAMD_DSU_DID)?;
+	if did & SAMD_DID_MASK != SAMD_DID_CONST_VALUE {
+		return Err(eyre!("Unsupported SAM device (DSU DID {:08x})", did));
+	}
+
+	// Ask the DSU to do a chip erase and wait for it to complete
+	ap.mem_write_u8(SAMD_DSU_CTRL, SAMD_CTRL_CHIP_ERASE)?;
+	let mut status = 0;
+	wait_for(MASS_ERASE_TIMEOUT, "SAM chip erase", || {
+		status = ap.mem_read_u32(SAMD_DSU_STATUSA)? & 0xff;
+		Ok(status & SAMD_STATUSA_DONE != 0)
+	})?;
+	if status & (SAMD_STATUSA_FAIL | SAMD_STATUSA_PERR) != 0 {
+		return Err(eyre!("SAM chip erase failed (DSU STATUSA {:02x})", status));
+	}
+	info!("SAM chip erase complete");
+	Ok(())
+}
+
+impl Display for MassEraseVendor
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		match self {
+			Self::Nrf52 => write!(fmt, "nRF52 CTRL-AP ERASEALL"),
+			Self::Kinetis => write!(fmt, "Kinetis MDM-AP"),
+			Self::Stm32 => write!(fmt, "STM32 RDP regression"),
+			Self::Sam => write!(fmt, "SAM DSU chip-erase"),
+		}
+	}
+}
+
+impl ValueEnum for MassEraseVendor
+{
+	fn value_variants<'a>() -> &'a [Self]
+	{
+		&[Self::Nrf52, Self::Kinetis, Self::Stm32, Self::Sam]
+	}
+
+	fn to_possible_value(&self) -> Option<PossibleValue>
+	{
+		match self {
+			Self::Nrf52 => Some("nrf52".into()),
+			Self::Kinetis => Some("kinetis".into()),
+			Self::Stm32 => Some("stm32".into()),
+			Self::Sam => Some("sam".into()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn stm32_controllers()
+	{
+		// The revision ID in the top half of IDCODE should make no difference
+		assert_eq!(stm32_flash_controller(0x20036410), Some(Stm32FlashController::F1(0x00a5)));
+		assert_eq!(stm32_flash_controller(0x10016430), Some(Stm32FlashController::F1(0x00a5)));
+		assert_eq!(stm32_flash_controller(0x10016422), Some(Stm32FlashController::F1(0x00aa)));
+		assert_eq!(stm32_flash_controller(0x10076413), Some(Stm32FlashController::F4));
+		assert_eq!(stm32_flash_controller(0x10016463), Some(Stm32FlashController::F4));
+		// STM32L4 and STM32G0 parts have other Flash controllers
+		assert_eq!(stm32_flash_controller(0x10006415), None);
+		assert_eq!(stm32_flash_controller(0x10006460), None);
+	}
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use log::debug;
+
+use crate::bmp::BmpDevice;
+use crate::serial::remote::adi::{AdiV5AccessPort, AdiV5DebugPort};
+use crate::serial::remote::{BmdRemoteProtocol, BmdSwdProtocol};
+use crate::target::cortexm::CortexM;
+
+pub mod breakpoints;
+pub mod coredump;
+pub mod coresight;
+pub mod cortexm;
+pub mod defmt;
+pub mod fault;
+pub mod flash;
+pub mod itm;
+pub mod mass_erase;
+pub mod profile;
+pub mod riscv;
+pub mod rtt;
+pub mod semihosting;
+pub mod svd;
+pub mod swo;
+pub mod variables;
+pub mod verify;
+
+/// Sequence that, when sent LSb first, switches a SWJ-DP from JTAG to SWD mode
+const ADIV5_JTAG_TO_SWD_SELECT_SEQUENCE: u32 = 0xe79e;
+
+/// A target attached to a probe over SWD, with its debug port and access ports brought up ready for use
+pub struct Target
+{
+	#[allow(unused)]
+	remote: Box<dyn BmdRemoteProtocol>,
+	debug_port: Arc<AdiV5DebugPort>,
+	access_ports: Vec<AdiV5AccessPort>,
+}
+
+impl Target
+{
+	/// Scan for and attach to a target connected to the given probe
+	pub fn attach(probe: &BmpDevice) -> Result<Self>
+	{
+		Self::attach_remote(probe.bmd_serial_interface()?.remote()?)
+	}
+
+	/// Scan for and attach to a target using an already open remote protocol session
+	pub fn attach_remote(remote: Box<dyn BmdRemoteProtocol>) -> Result<Self>
+	{
+		// Start by getting the probe's SWD interface initialised and switching the target DP over to SWD
+		let swd = remote.swd_init()?;
+		Self::swd_line_reset(swd.as_ref())?;
+		swd.seq_out(ADIV5_JTAG_TO_SWD_SELECT_SEQUENCE, 16)?;
+		Self::swd_line_reset(swd.as_ref())?;
+
+		// Now we need the ADIv5 acceleration to talk with the DP and its APs
+		let adiv5 = remote
+			.adiv5_init()
+			.ok_or_eyre("Probe firmware does not support ADIv5 acceleration, please update it")?;
+		let debug_port = Arc::new(AdiV5DebugPort::new(adiv5, 0)?);
+		let access_ports = debug_port.enumerate_aps()?;
+		debug!("Attached to target with {} access ports", access_ports.len());
+
+		Ok(Self {
+			remote,
+			debug_port,
+			access_ports,
+		})
+	}
+
+	/// A line reset is achieved by holding SWDIO high for at least 50 cycles, followed by 2 idle cycles
+	fn swd_line_reset(swd: &dyn BmdSwdProtocol) -> Result<()>
+	{
+		swd.seq_out(0xffffffff, 32)?;
+		swd.seq_out(0x0fffffff, 32)
+	}
+
+	/// The debug port the target was found on
+	pub fn debug_port(&self) -> &Arc<AdiV5DebugPort>
+	{
+		&self.debug_port
+	}
+
+	/// All the valid access ports found on the target's debug port
+	pub fn access_ports(&self) -> &[AdiV5AccessPort]
+	{
+		&self.access_ports
+	}
+
+	/// The first access port with a system memory bus attached, if any
+	pub fn memory_ap(&self) -> Option<&AdiV5AccessPort>
+	{
+		self.access_ports.iter().find(|ap| ap.has_memory())
+	}
+
+	/// Run control for the Cortex-M core behind the target's memory access port
+	pub fn cortexm(&self) -> Result<CortexM<'_>>
+	{
+		let ap = self
+			.memory_ap()
+			.ok_or_eyre("Could not find a memory access port on the target")?;
+		CortexM::new(ap)
+	}
+}
+
+/// Poll `condition` until it returns true, or until `timeout` has elapsed in which case
+/// an error about `operation` timing out is returned
+pub(crate) fn wait_for<F>(timeout: Duration, operation: &str, mut condition: F) -> Result<()>
+where
+	F: FnMut() -> Result<bool>,
+{
+	let start = Instant::now();
+	while !condition()? {
+		if start.elapsed() > timeout {
+			return Err(eyre!("Timeout while waiting for {}", operation));
+		}
+		thread::sleep(Duration::from_millis(10));
+	}
+	Ok(())
+}
@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use log::info;
+
+use crate::firmware_file::FirmwareFile;
+use crate::target::breakpoints::Dwt;
+
+/// Name used for samples taken while the core couldn't be sampled (halted, or in a sleep state on some parts)
+const PROFILE_NOT_RUNNING: &str = "[not running]";
+
+/// PC samples collected from a running core
+pub struct Profile
+{
+	/// How many times each PC value was seen
+	samples: BTreeMap<u32, u64>,
+	/// How many samples came back invalid
+	invalid: u64,
+	elapsed: Duration,
+}
+
+/// How many samples landed in a given function
+#[derive(Debug, PartialEq, Eq)]
+pub struct FunctionSamples
+{
+	pub name: String,
+	pub samples: u64,
+}
+
+impl Profile
+{
+	/// Repeatedly sample the core's PC through the DWT for `duration`
+	pub fn capture(dwt: &Dwt, duration: Duration) -> Result<Self>
+	{
+		info!("Sampling the core's PC for {:.1}s", duration.as_secs_f32());
+		let mut profile = Self {
+			samples: BTreeMap::new(),
+			invalid: 0,
+			elapsed: Duration::ZERO,
+		};
+		let start = Instant::now();
+		while start.elapsed() < duration {
+			match dwt.sample_pc()? {
+				Some(pc) => *profile.samples.entry(pc).or_default() += 1,
+				None => profile.invalid += 1,
+			}
+		}
+		profile.elapsed = start.elapsed();
+		Ok(profile)
+	}
+
+	/// Total number of samples taken
+	pub fn total(&self) -> u64
+	{
+		self.samples.values().sum::<u64>() + self.invalid
+	}
+
+	/// How quickly samples were taken, in samples per second
+	pub fn sample_rate(&self) -> f64
+	{
+		self.total() as f64 / self.elapsed.as_secs_f64()
+	}
+
+	/// Group the samples by the function they landed in, most sampled first. PC values that don't land
+	/// in any known function are reported by address.
+	pub fn by_function(&self, firmware: Option<&FirmwareFile>) -> Vec<FunctionSamples>
+	{
+		aggregate(&self.samples, self.invalid, |pc| {
+			firmware
+				.and_then(|firmware| firmware.symbolise(pc))
+				.map(|(symbol, _)| symbol.name.clone())
+		})
+	}
+
+	/// Write the samples out in the "folded stacks" format understood by flamegraph tools. Only the
+	/// sampled function is known, so each stack is a single frame deep.
+	pub fn write_folded(&self, firmware: Option<&FirmwareFile>, writer: &mut impl Write) -> Result<()>
+	{
+		for function in self.by_function(firmware) {
+			// Spaces and semicolons are the format's separators, so must not appear in frame names
+			let name = function.name.replace([' ', ';'], "_");
+			writeln!(writer, "{} {}", name, function.samples)?;
+		}
+		Ok(())
+	}
+}
+
+fn aggregate<F>(samples: &BTreeMap<u32, u64>, invalid: u64, mut lookup: F) -> Vec<FunctionSamples>
+where
+	F: FnMut(u32) -> Option<String>,
+{
+	let mut functions = BTreeMap::<String, u64>::new();
+	for (&pc, &count) in samples {
+		let name = lookup(pc).unwrap_or_else(|| format!("0x{:08x}", pc));
+		*functions.entry(name).or_default() += count;
+	}
+	if invalid != 0 {
+		functions.insert(PROFILE_NOT_RUNNING.into(), invalid);
+	}
+
+	let mut functions: Vec<_> = functions
+		.into_iter()
+		.map(|(name, samples)| FunctionSamples {
+			name,
+			samples,
+		})
+		.collect();
+	functions.sort_by(|a, b| b.samples.cmp(&a.samples).then_with(|| a.name.cmp(&b.name)));
+	functions
+}
+
+impl Display for Profile
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(
+			fmt,
+			"{} samples over {:.1}s ({:.0} samples/s)",
+			self.total(),
+			self.elapsed.as_secs_f32(),
+			self.sample_rate()
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn aggregation()
+	{
+		let samples = BTreeMap::from([(0x100, 5), (0x104, 3), (0x200, 4), (0x300, 1)]);
+		let functions = aggregate(&samples, 2, |pc| match pc {
+			0x100..0x200 => Some("main".into()),
+			0x200..0x300 => Some("delay".into()),
+			_ => None,
+		});
+		let functions: Vec<_> = functions
+			.iter()
+			.map(|function| (function.name.as_str(), function.samples))
+			.collect();
+		assert_eq!(
+			functions,
+			[("main", 8), ("delay", 4), (PROFILE_NOT_RUNNING, 2), ("0x00000300", 1)]
+		);
+	}
+}
@@ -0,0 +1,591 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, warn};
+
+use crate::bmp::BmpDevice;
+use crate::serial::remote::riscv_debug::{
+	RISCV_DM_STATUS, RiscvDebugVersion, RiscvDmi, RiscvJtagDmi, riscv_jtag_part,
+};
+use crate::serial::remote::{BmdRemoteProtocol, BmdRiscvProtocol, JtagDev, jtag};
+use crate::target::wait_for;
+
+// Debug Module registers, by DMI address
+const RISCV_DM_DATA0: u32 = 0x04;
+const RISCV_DM_DATA1: u32 = 0x05;
+const RISCV_DM_CONTROL: u32 = 0x10;
+const RISCV_DM_ABSTRACT_CS: u32 = 0x16;
+const RISCV_DM_COMMAND: u32 = 0x17;
+const RISCV_DM_PROGBUF0: u32 = 0x20;
+const RISCV_DM_SB_CS: u32 = 0x38;
+const RISCV_DM_SB_ADDRESS0: u32 = 0x39;
+const RISCV_DM_SB_DATA0: u32 = 0x3c;
+
+const RISCV_DM_CONTROL_ACTIVE: u32 = 1 << 0;
+const RISCV_DM_CONTROL_HARTSEL_MASK: u32 = 0x03ffffc0;
+const RISCV_DM_CONTROL_RESUMEREQ: u32 = 1 << 30;
+const RISCV_DM_CONTROL_HALTREQ: u32 = 1 << 31;
+
+const RISCV_DM_STATUS_IMPEBREAK: u32 = 1 << 22;
+const RISCV_DM_STATUS_ALLRESUMEACK: u32 = 1 << 17;
+const RISCV_DM_STATUS_ANYNONEXISTENT: u32 = 1 << 14;
+const RISCV_DM_STATUS_ALLHALTED: u32 = 1 << 9;
+const RISCV_DM_STATUS_AUTHENTICATED: u32 = 1 << 7;
+
+const RISCV_DM_ABSTRACT_CS_DATACOUNT_MASK: u32 = 0x0000000f;
+const RISCV_DM_ABSTRACT_CS_CMDERR_SHIFT: u32 = 8;
+const RISCV_DM_ABSTRACT_CS_CMDERR_MASK: u32 = 0x00000700;
+const RISCV_DM_ABSTRACT_CS_BUSY: u32 = 1 << 12;
+const RISCV_DM_ABSTRACT_CS_PROGBUFSIZE_SHIFT: u32 = 24;
+const RISCV_DM_ABSTRACT_CS_PROGBUFSIZE_MASK: u32 = 0x1f000000;
+/// Abstract command error meaning the command is not supported
+const RISCV_DM_CMDERR_NOT_SUPPORTED: u32 = 2;
+
+// Access Register abstract command fields
+const RISCV_DM_COMMAND_AARSIZE_32: u32 = 2 << 20;
+const RISCV_DM_COMMAND_AARSIZE_64: u32 = 3 << 20;
+const RISCV_DM_COMMAND_POSTEXEC: u32 = 1 << 18;
+const RISCV_DM_COMMAND_TRANSFER: u32 = 1 << 17;
+const RISCV_DM_COMMAND_WRITE: u32 = 1 << 16;
+
+const RISCV_DM_SB_CS_VERSION_SHIFT: u32 = 29;
+const RISCV_DM_SB_CS_BUSYERROR: u32 = 1 << 22;
+const RISCV_DM_SB_CS_READONADDR: u32 = 1 << 20;
+const RISCV_DM_SB_CS_ACCESS_32: u32 = 2 << 17;
+const RISCV_DM_SB_CS_AUTOINCREMENT: u32 = 1 << 16;
+const RISCV_DM_SB_CS_READONDATA: u32 = 1 << 15;
+const RISCV_DM_SB_CS_ERROR_MASK: u32 = 0x00007000;
+const RISCV_DM_SB_CS_ACCESS32_SUPPORTED: u32 = 1 << 2;
+
+/// Abstract register number of GPR x0, the rest follow on from it
+const RISCV_REGNO_GPR0: u16 = 0x1000;
+/// Debug Control and Status CSR
+const RISCV_CSR_DCSR: u16 = 0x7b0;
+/// Debug PC CSR, holding the PC the hart halted at
+pub const RISCV_CSR_DPC: u16 = 0x7b1;
+const RISCV_DCSR_STEP: u64 = 1 << 2;
+
+/// GPRs used as scratch registers by program buffer sequences
+const RISCV_REG_S0: u8 = 8;
+const RISCV_REG_S1: u8 = 9;
+/// ebreak, to hand control back to the debugger at the end of a program buffer sequence
+const RISCV_EBREAK: u32 = 0x00100073;
+
+/// How long to wait on a hart to halt or resume, or on an abstract command to complete
+const RISCV_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// ABI names of the GPRs, in register number order
+pub const RISCV_GPR_NAMES: [&str; 32] = [
+	"zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+	"s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// The devices on a probe's JTAG scan chain, with the DMIs of any RISC-V Debug Transport Modules
+/// among them found and ready for use
+pub struct RiscvJtagTarget
+{
+	#[allow(unused)]
+	remote: Box<dyn BmdRemoteProtocol>,
+	devices: Vec<JtagDev>,
+	dmis: Vec<RiscvDmi>,
+	access: Arc<dyn BmdRiscvProtocol>,
+}
+
+impl RiscvJtagTarget
+{
+	/// Scan the JTAG chain of the given probe for RISC-V targets
+	pub fn attach(probe: &BmpDevice) -> Result<Self>
+	{
+		Self::attach_remote(probe.bmd_serial_interface()?.remote()?)
+	}
+
+	/// Scan the JTAG chain for RISC-V targets using an already open remote protocol session
+	pub fn attach_remote(remote: Box<dyn BmdRemoteProtocol>) -> Result<Self>
+	{
+		let jtag = remote.jtag_init()?;
+		let devices = jtag::scan(jtag.as_ref())?;
+		for (index, device) in devices.iter().enumerate() {
+			remote.add_jtag_dev(index as u32, device);
+		}
+
+		// Read the dtmcs of every device to find which are RISC-V DTMs and how to talk to their DMIs - the
+		// IDCODE is only used to name the parts we know of, as plenty of RISC-V parts aren't in our table
+		let mut dmis = Vec::new();
+		for (index, device) in devices.iter().enumerate() {
+			let part = riscv_jtag_part(device.idcode());
+			match RiscvDmi::from_jtag(jtag.as_ref(), device, index as u8) {
+				Ok(dmi) => {
+					debug!(
+						"Found {} DTM on JTAG device {}: {} with {} address bits and {} idle cycles",
+						part.unwrap_or("RISC-V"),
+						index,
+						dmi.version(),
+						dmi.address_width(),
+						dmi.idle_cycles()
+					);
+					dmis.push(dmi);
+				},
+				Err(error) => match part {
+					Some(part) => warn!("Could not read the DTM of {} JTAG device {}: {}", part, index, error),
+					None => debug!("JTAG device {} is not a RISC-V DTM: {}", index, error),
+				},
+			}
+		}
+
+		// Use the probe's RISC-V acceleration if it has it, otherwise drive the DMIs over raw JTAG
+		let access = remote
+			.riscv_jtag_init()
+			.unwrap_or_else(|| Arc::new(RiscvJtagDmi::new(jtag, devices.clone())));
+		for dmi in &mut dmis {
+			if dmi.version() == RiscvDebugVersion::V0_11 {
+				warn!("RISC-V debug v0.11 is not supported, ignoring JTAG device {}", dmi.dev_index());
+				continue;
+			}
+			if let Err(error) = dmi.refine_version(access.as_ref()) {
+				warn!("Could not read the DM status for JTAG device {}: {}", dmi.dev_index(), error);
+			}
+		}
+
+		Ok(Self {
+			remote,
+			devices,
+			dmis,
+			access,
+		})
+	}
+
+	/// All the devices found on the JTAG scan chain
+	pub fn devices(&self) -> &[JtagDev]
+	{
+		&self.devices
+	}
+
+	/// The DMIs of the RISC-V DTMs found on the chain
+	pub fn dmis(&self) -> &[RiscvDmi]
+	{
+		&self.dmis
+	}
+
+	/// The means by which the DMIs are accessed - accelerated by the probe, or bit-banged
+	pub fn dmi_access(&self) -> &Arc<dyn BmdRiscvProtocol>
+	{
+		&self.access
+	}
+
+	/// Bring up the Debug Module behind the first usable DMI found
+	pub fn debug_module(&self) -> Result<RiscvDebugModule<'_>>
+	{
+		let dmi = self
+			.dmis
+			.iter()
+			.find(|dmi| matches!(dmi.version(), RiscvDebugVersion::V0_13 | RiscvDebugVersion::V1_0))
+			.ok_or_else(|| eyre!("Could not find a supported RISC-V debug module on the JTAG chain"))?;
+		RiscvDebugModule::new(self.access.as_ref(), dmi)
+	}
+}
+
+/// Run control, register and memory access for the harts behind a RISC-V Debug Module
+pub struct RiscvDebugModule<'a>
+{
+	access: &'a dyn BmdRiscvProtocol,
+	dmi: &'a RiscvDmi,
+	hart_count: u32,
+	/// How many abstract command data registers the DM has
+	data_count: u8,
+	/// How many instructions fit in the DM's program buffer
+	progbuf_size: u8,
+	/// Whether the DM puts an implicit ebreak at the end of the program buffer
+	impebreak: bool,
+	/// Whether the DM provides 32-bit system bus access for reading memory
+	system_bus: bool,
+	/// Register width of the harts, worked out on first register access
+	xlen: Cell<Option<u8>>,
+}
+
+impl<'a> RiscvDebugModule<'a>
+{
+	/// Activate the Debug Module behind the given DMI, and find out what harts and features it has
+	pub fn new(access: &'a dyn BmdRiscvProtocol, dmi: &'a RiscvDmi) -> Result<Self>
+	{
+		match dmi.version() {
+			RiscvDebugVersion::V0_13 | RiscvDebugVersion::V1_0 => (),
+			version => return Err(eyre!("RISC-V debug spec version {} is not supported", version)),
+		}
+
+		access.dmi_write(dmi, RISCV_DM_CONTROL, RISCV_DM_CONTROL_ACTIVE)?;
+		wait_for(RISCV_TIMEOUT, "the debug module to activate", || {
+			Ok(access.dmi_read(dmi, RISCV_DM_CONTROL)? & RISCV_DM_CONTROL_ACTIVE != 0)
+		})?;
+		let status = access.dmi_read(dmi, RISCV_DM_STATUS)?;
+		if status & RISCV_DM_STATUS_AUTHENTICATED == 0 {
+			return Err(eyre!("RISC-V debug module requires authentication, which is not supported"));
+		}
+
+		// Find out how many hartsel bits are implemented by writing all ones and seeing what sticks
+		access.dmi_write(dmi, RISCV_DM_CONTROL, RISCV_DM_CONTROL_ACTIVE | RISCV_DM_CONTROL_HARTSEL_MASK)?;
+		let max_hart = decode_hartsel(access.dmi_read(dmi, RISCV_DM_CONTROL)?);
+		// Then walk through the harts until we find one that doesn't exist
+		let mut hart_count = 0;
+		while hart_count <= max_hart {
+			access.dmi_write(dmi, RISCV_DM_CONTROL, RISCV_DM_CONTROL_ACTIVE | encode_hartsel(hart_count))?;
+			if access.dmi_read(dmi, RISCV_DM_STATUS)? & RISCV_DM_STATUS_ANYNONEXISTENT != 0 {
+				break;
+			}
+			hart_count += 1;
+		}
+		if hart_count == 0 {
+			return Err(eyre!("RISC-V debug module has no harts"));
+		}
+
+		let abstract_cs = access.dmi_read(dmi, RISCV_DM_ABSTRACT_CS)?;
+		let system_bus_cs = access.dmi_read(dmi, RISCV_DM_SB_CS)?;
+		let debug_module = Self {
+			access,
+			dmi,
+			hart_count,
+			data_count: (abstract_cs & RISCV_DM_ABSTRACT_CS_DATACOUNT_MASK) as u8,
+			progbuf_size: ((abstract_cs & RISCV_DM_ABSTRACT_CS_PROGBUFSIZE_MASK) >>
+				RISCV_DM_ABSTRACT_CS_PROGBUFSIZE_SHIFT) as u8,
+			impebreak: status & RISCV_DM_STATUS_IMPEBREAK != 0,
+			system_bus: system_bus_cs >> RISCV_DM_SB_CS_VERSION_SHIFT == 1 &&
+				system_bus_cs & RISCV_DM_SB_CS_ACCESS32_SUPPORTED != 0,
+			xlen: Cell::new(None),
+		};
+		debug!(
+			"RISC-V debug module with {} harts, {} data registers, {} program buffer slots{}",
+			debug_module.hart_count,
+			debug_module.data_count,
+			debug_module.progbuf_size,
+			if debug_module.system_bus {
+				" and system bus access"
+			} else {
+				""
+			}
+		);
+		Ok(debug_module)
+	}
+
+	/// How many harts the Debug Module controls
+	pub fn hart_count(&self) -> u32
+	{
+		self.hart_count
+	}
+
+	fn read(&self, address: u32) -> Result<u32>
+	{
+		self.access.dmi_read(self.dmi, address)
+	}
+
+	fn write(&self, address: u32, value: u32) -> Result<()>
+	{
+		self.access.dmi_write(self.dmi, address, value)
+	}
+
+	/// Point the Debug Module at a hart, optionally requesting it halt or resume at the same time
+	fn select(&self, hart: u32, request: u32) -> Result<()>
+	{
+		if hart >= self.hart_count {
+			return Err(eyre!("Hart {} does not exist, there are only {}", hart, self.hart_count));
+		}
+		self.write(RISCV_DM_CONTROL, RISCV_DM_CONTROL_ACTIVE | encode_hartsel(hart) | request)
+	}
+
+	/// Whether the hart is currently halted
+	pub fn is_halted(&self, hart: u32) -> Result<bool>
+	{
+		self.select(hart, 0)?;
+		Ok(self.read(RISCV_DM_STATUS)? & RISCV_DM_STATUS_ALLHALTED != 0)
+	}
+
+	/// Request the hart halt, and wait for it to do so
+	pub fn halt(&self, hart: u32) -> Result<()>
+	{
+		self.select(hart, RISCV_DM_CONTROL_HALTREQ)?;
+		wait_for(RISCV_TIMEOUT, "the hart to halt", || {
+			Ok(self.read(RISCV_DM_STATUS)? & RISCV_DM_STATUS_ALLHALTED != 0)
+		})?;
+		self.select(hart, 0)
+	}
+
+	/// Let the hart run again
+	pub fn resume(&self, hart: u32) -> Result<()>
+	{
+		self.select(hart, RISCV_DM_CONTROL_RESUMEREQ)?;
+		wait_for(RISCV_TIMEOUT, "the hart to resume", || {
+			Ok(self.read(RISCV_DM_STATUS)? & RISCV_DM_STATUS_ALLRESUMEACK != 0)
+		})?;
+		self.select(hart, 0)
+	}
+
+	/// Have the halted hart execute a single instruction, then halt again
+	pub fn step(&self, hart: u32) -> Result<()>
+	{
+		let dcsr = self.read_csr(hart, RISCV_CSR_DCSR)?;
+		self.write_csr(hart, RISCV_CSR_DCSR, dcsr | RISCV_DCSR_STEP)?;
+		self.resume(hart)?;
+		wait_for(RISCV_TIMEOUT, "the hart to step", || self.is_halted(hart))?;
+		self.write_csr(hart, RISCV_CSR_DCSR, dcsr)
+	}
+
+	/// Run an abstract command, returning the command error code (0 on success)
+	fn execute(&self, command: u32) -> Result<u32>
+	{
+		self.write(RISCV_DM_COMMAND, command)?;
+		let mut abstract_cs = 0;
+		wait_for(RISCV_TIMEOUT, "an abstract command to complete", || {
+			abstract_cs = self.read(RISCV_DM_ABSTRACT_CS)?;
+			Ok(abstract_cs & RISCV_DM_ABSTRACT_CS_BUSY == 0)
+		})?;
+		let error = (abstract_cs & RISCV_DM_ABSTRACT_CS_CMDERR_MASK) >> RISCV_DM_ABSTRACT_CS_CMDERR_SHIFT;
+		if error != 0 {
+			// The error is sticky, and must be cleared by writing ones to it before the next command
+			self.write(RISCV_DM_ABSTRACT_CS, RISCV_DM_ABSTRACT_CS_CMDERR_MASK)?;
+		}
+		Ok(error)
+	}
+
+	/// Run an abstract command that is expected to succeed
+	fn execute_checked(&self, command: u32, operation: &str) -> Result<()>
+	{
+		match self.execute(command)? {
+			0 => Ok(()),
+			error => Err(eyre!("Abstract command to {} failed with error {}", operation, error)),
+		}
+	}
+
+	fn access_register_command(regno: u16, xlen: u8) -> u32
+	{
+		let size = if xlen == 64 {
+			RISCV_DM_COMMAND_AARSIZE_64
+		} else {
+			RISCV_DM_COMMAND_AARSIZE_32
+		};
+		size | RISCV_DM_COMMAND_TRANSFER | regno as u32
+	}
+
+	/// Read a register with the Access Register abstract command, returning None if the DM can't do that
+	fn abstract_read(&self, regno: u16, xlen: u8) -> Result<Option<u64>>
+	{
+		match self.execute(Self::access_register_command(regno, xlen))? {
+			0 => (),
+			RISCV_DM_CMDERR_NOT_SUPPORTED => return Ok(None),
+			error => return Err(eyre!("Failed to read register 0x{:04x}, error {}", regno, error)),
+		}
+		let low = self.read(RISCV_DM_DATA0)? as u64;
+		if xlen == 64 {
+			Ok(Some(((self.read(RISCV_DM_DATA1)? as u64) << 32) | low))
+		} else {
+			Ok(Some(low))
+		}
+	}
+
+	/// Write a register with the Access Register abstract command, returning None if the DM can't do that
+	fn abstract_write(&self, regno: u16, xlen: u8, value: u64, postexec: bool) -> Result<Option<()>>
+	{
+		self.write(RISCV_DM_DATA0, value as u32)?;
+		if xlen == 64 {
+			self.write(RISCV_DM_DATA1, (value >> 32) as u32)?;
+		}
+		let mut command = Self::access_register_command(regno, xlen) | RISCV_DM_COMMAND_WRITE;
+		if postexec {
+			command |= RISCV_DM_COMMAND_POSTEXEC;
+		}
+		match self.execute(command)? {
+			0 => Ok(Some(())),
+			RISCV_DM_CMDERR_NOT_SUPPORTED => Ok(None),
+			error => Err(eyre!("Failed to write register 0x{:04x}, error {}", regno, error)),
+		}
+	}
+
+	/// Work out the register width of the (halted, selected) hart, by seeing if 64-bit accesses work
+	fn xlen(&self) -> Result<u8>
+	{
+		if let Some(xlen) = self.xlen.get() {
+			return Ok(xlen);
+		}
+		let xlen = if self.abstract_read(RISCV_REGNO_GPR0 + RISCV_REG_S0 as u16, 64)?.is_some() {
+			64
+		} else {
+			32
+		};
+		debug!("Hart is RV{}", xlen);
+		self.xlen.set(Some(xlen));
+		Ok(xlen)
+	}
+
+	fn read_gpr_selected(&self, register: u8) -> Result<u64>
+	{
+		let xlen = self.xlen()?;
+		self.abstract_read(RISCV_REGNO_GPR0 + register as u16, xlen)?
+			.ok_or_else(|| eyre!("Debug module does not support reading GPRs"))
+	}
+
+	fn write_gpr_selected(&self, register: u8, value: u64, postexec: bool) -> Result<()>
+	{
+		let xlen = self.xlen()?;
+		self.abstract_write(RISCV_REGNO_GPR0 + register as u16, xlen, value, postexec)?
+			.ok_or_else(|| eyre!("Debug module does not support writing GPRs"))
+	}
+
+	/// Load a sequence of instructions into the program buffer, with an ebreak to finish
+	fn load_program(&self, program: &[u32]) -> Result<()>
+	{
+		let needed = program.len() + if self.impebreak { 0 } else { 1 };
+		if needed > self.progbuf_size as usize {
+			return Err(eyre!(
+				"Debug module program buffer is too small ({} instructions, need {})",
+				self.progbuf_size,
+				needed
+			));
+		}
+		for (index, &instruction) in program.iter().chain(&[RISCV_EBREAK]).enumerate() {
+			if index < self.progbuf_size as usize {
+				self.write(RISCV_DM_PROGBUF0 + index as u32, instruction)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Read one of the (halted) hart's GPRs
+	pub fn read_gpr(&self, hart: u32, register: u8) -> Result<u64>
+	{
+		self.select(hart, 0)?;
+		self.read_gpr_selected(register)
+	}
+
+	/// Read one of the (halted) hart's CSRs, falling back on the program buffer if the DM can't
+	/// access CSRs with abstract commands
+	pub fn read_csr(&self, hart: u32, csr: u16) -> Result<u64>
+	{
+		self.select(hart, 0)?;
+		let xlen = self.xlen()?;
+		if let Some(value) = self.abstract_read(csr, xlen)? {
+			return Ok(value);
+		}
+
+		// csrr s0, csr
+		self.load_program(&[((csr as u32) << 20) | 0x00002473])?;
+		let saved = self.read_gpr_selected(RISCV_REG_S0)?;
+		self.execute_checked(RISCV_DM_COMMAND_POSTEXEC, "read a CSR")?;
+		let value = self.read_gpr_selected(RISCV_REG_S0)?;
+		self.write_gpr_selected(RISCV_REG_S0, saved, false)?;
+		Ok(value)
+	}
+
+	/// Write one of the (halted) hart's CSRs, falling back on the program buffer if the DM can't
+	/// access CSRs with abstract commands
+	pub fn write_csr(&self, hart: u32, csr: u16, value: u64) -> Result<()>
+	{
+		self.select(hart, 0)?;
+		let xlen = self.xlen()?;
+		if self.abstract_write(csr, xlen, value, false)?.is_some() {
+			return Ok(());
+		}
+
+		// csrw csr, s0
+		self.load_program(&[((csr as u32) << 20) | 0x00041073])?;
+		let saved = self.read_gpr_selected(RISCV_REG_S0)?;
+		self.write_gpr_selected(RISCV_REG_S0, value, true)?;
+		self.write_gpr_selected(RISCV_REG_S0, saved, false)
+	}
+
+	/// Read all the GPRs named in [`RISCV_GPR_NAMES`] followed by the PC. The hart must be halted.
+	pub fn read_registers(&self, hart: u32) -> Result<Vec<u64>>
+	{
+		let mut registers = (0..RISCV_GPR_NAMES.len() as u8)
+			.map(|register| self.read_gpr(hart, register))
+			.collect::<Result<Vec<_>>>()?;
+		registers.push(self.read_csr(hart, RISCV_CSR_DPC)?);
+		Ok(registers)
+	}
+
+	/// Read memory as seen by the hart, using system bus access if the DM has it. Otherwise the hart
+	/// must be halted so the memory can be read with load instructions run from the program buffer.
+	pub fn read_memory(&self, hart: u32, dest: &mut [u8], address: u64) -> Result<()>
+	{
+		let start = address & !3;
+		let words = (address + dest.len() as u64 - start).div_ceil(4) as usize;
+		let data = if self.system_bus {
+			self.system_bus_read(start, words)?
+		} else {
+			self.program_buffer_read(hart, start, words)?
+		};
+		let offset = (address - start) as usize;
+		let data: Vec<u8> = data.iter().flat_map(|word| word.to_le_bytes()).collect();
+		dest.copy_from_slice(&data[offset..offset + dest.len()]);
+		Ok(())
+	}
+
+	fn system_bus_read(&self, address: u64, words: usize) -> Result<Vec<u32>>
+	{
+		let address = u32::try_from(address).map_err(|_| eyre!("Address 0x{:x} out of system bus range", address))?;
+		let control = RISCV_DM_SB_CS_ACCESS_32 | RISCV_DM_SB_CS_AUTOINCREMENT | RISCV_DM_SB_CS_READONADDR;
+		// Have each read of the data register kick off the next bus read, until the last
+		self.write(RISCV_DM_SB_CS, control | RISCV_DM_SB_CS_READONDATA)?;
+		self.write(RISCV_DM_SB_ADDRESS0, address)?;
+		let mut data = Vec::with_capacity(words);
+		for word in 0..words {
+			if word + 1 == words {
+				self.write(RISCV_DM_SB_CS, control)?;
+			}
+			data.push(self.read(RISCV_DM_SB_DATA0)?);
+		}
+		let status = self.read(RISCV_DM_SB_CS)?;
+		if status & (RISCV_DM_SB_CS_ERROR_MASK | RISCV_DM_SB_CS_BUSYERROR) != 0 {
+			// Errors are sticky, and must be cleared by writing ones to them
+			self.write(RISCV_DM_SB_CS, RISCV_DM_SB_CS_ERROR_MASK | RISCV_DM_SB_CS_BUSYERROR)?;
+			return Err(eyre!("System bus read at 0x{:08x} failed (sbcs 0x{:08x})", address, status));
+		}
+		Ok(data)
+	}
+
+	fn program_buffer_read(&self, hart: u32, address: u64, words: usize) -> Result<Vec<u32>>
+	{
+		self.select(hart, 0)?;
+		// lw s1, 0(s0)
+		self.load_program(&[0x00042483])?;
+		let saved = (self.read_gpr_selected(RISCV_REG_S0)?, self.read_gpr_selected(RISCV_REG_S1)?);
+		let mut data = Vec::with_capacity(words);
+		for word in 0..words as u64 {
+			// Writing the address into s0 runs the load straight after
+			self.write_gpr_selected(RISCV_REG_S0, address + word * 4, true)?;
+			data.push(self.read_gpr_selected(RISCV_REG_S1)? as u32);
+		}
+		self.write_gpr_selected(RISCV_REG_S0, saved.0, false)?;
+		self.write_gpr_selected(RISCV_REG_S1, saved.1, false)?;
+		Ok(data)
+	}
+}
+
+/// Encode a hart index into the split hartsello/hartselhi fields of dmcontrol
+fn encode_hartsel(hart: u32) -> u32
+{
+	((hart & 0x3ff) << 16) | (((hart >> 10) & 0x3ff) << 6)
+}
+
+/// Decode the hart index from the hartsello/hartselhi fields of dmcontrol
+fn decode_hartsel(control: u32) -> u32
+{
+	(((control >> 6) & 0x3ff) << 10) | ((control >> 16) & 0x3ff)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn hartsel()
+	{
+		assert_eq!(encode_hartsel(1), 0x00010000);
+		assert_eq!(encode_hartsel(0x401), 0x00010040);
+		assert_eq!(decode_hartsel(RISCV_DM_CONTROL_HARTSEL_MASK), 0xfffff);
+		assert_eq!(decode_hartsel(encode_hartsel(0x12345)), 0x12345);
+	}
+}
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::ops::Range;
+
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, info};
+
+use crate::serial::remote::REMOTE_MAX_MSG_SIZE;
+use crate::serial::remote::adi::AdiV5AccessPort;
+
+/// The ID string found at the start of the RTT control block
+const RTT_CONTROL_BLOCK_ID: &[u8] = b"SEGGER RTT\0";
+/// Size of the ID field at the start of the control block
+const RTT_CONTROL_BLOCK_ID_SIZE: u32 = 16;
+/// Size of the control block header (ID + up and down buffer counts)
+const RTT_CONTROL_BLOCK_HEADER_SIZE: u32 = RTT_CONTROL_BLOCK_ID_SIZE + 8;
+/// Size of each buffer descriptor (name, buffer, size, write offset, read offset, flags)
+const RTT_BUFFER_DESCRIPTOR_SIZE: u32 = 24;
+const RTT_BUFFER_WRITE_OFFSET: u32 = 12;
+const RTT_BUFFER_READ_OFFSET: u32 = 16;
+/// Sanity limit on how many buffers a control block may claim to have
+const RTT_MAX_BUFFERS: u32 = 32;
+/// Largest read to do in one go so that it fits within a single remote protocol response
+/// (each byte is sent as two hex characters, plus the response framing)
+const RTT_READ_BLOCK_SIZE: usize = (REMOTE_MAX_MSG_SIZE - 8) / 2;
+/// How much RAM to read at a time when scanning for the control block
+const RTT_SCAN_BLOCK_SIZE: usize = 4096;
+
+/// A single RTT ring buffer, either up (target to host) or down (host to target)
+#[derive(Clone, Debug)]
+pub struct RttChannel
+{
+	pub index: usize,
+	pub name: Option<String>,
+	/// Address of this channel's buffer descriptor in the control block
+	descriptor: u32,
+	/// Address and size of the ring buffer itself
+	buffer: u32,
+	size: u32,
+}
+
+/// A SEGGER RTT control block found in target RAM
+pub struct Rtt<'a>
+{
+	ap: &'a AdiV5AccessPort,
+	address: u32,
+	up: Vec<RttChannel>,
+	down: Vec<RttChannel>,
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> u32
+{
+	u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Rtt<'a>
+{
+	/// Scan the given range of target RAM for the RTT control block's ID string,
+	/// returning the address of the control block if found
+	pub fn scan(ap: &AdiV5AccessPort, range: Range<u32>) -> Result<Option<u32>>
+	{
+		info!("Scanning 0x{:08x}..0x{:08x} for the RTT control block", range.start, range.end);
+		let mut data = vec![0u8; RTT_SCAN_BLOCK_SIZE];
+		let mut address = range.start;
+		while address < range.end {
+			let length = RTT_SCAN_BLOCK_SIZE.min((range.end - address) as usize);
+			let data = &mut data[..length];
+			ap.mem_read(data, address as u64)?;
+			// The control block is always at least 4-byte aligned
+			if let Some(offset) = data
+				.windows(RTT_CONTROL_BLOCK_ID.len())
+				.step_by(4)
+				.position(|window| window == RTT_CONTROL_BLOCK_ID)
+			{
+				return Ok(Some(address + (offset * 4) as u32));
+			}
+			if address as usize + length >= range.end as usize {
+				break;
+			}
+			// Overlap the next block with the tail of this one so we don't miss an ID spanning the two
+			address += (length - RTT_CONTROL_BLOCK_ID_SIZE as usize) as u32;
+		}
+		Ok(None)
+	}
+
+	/// Read the control block at `address` and all of its buffer descriptors
+	pub fn attach(ap: &'a AdiV5AccessPort, address: u32) -> Result<Self>
+	{
+		let mut header = [0u8; RTT_CONTROL_BLOCK_HEADER_SIZE as usize];
+		ap.mem_read(&mut header, address as u64)?;
+		if !header.starts_with(RTT_CONTROL_BLOCK_ID) {
+			return Err(eyre!("No RTT control block found at 0x{:08x}", address));
+		}
+		let up_count = read_u32_le(&header, 16);
+		let down_count = read_u32_le(&header, 20);
+		if up_count > RTT_MAX_BUFFERS || down_count > RTT_MAX_BUFFERS {
+			return Err(eyre!(
+				"RTT control block at 0x{:08x} is corrupt ({} up, {} down buffers)",
+				address,
+				up_count,
+				down_count
+			));
+		}
+		debug!(
+			"RTT control block at 0x{:08x} with {} up and {} down buffers",
+			address, up_count, down_count
+		);
+
+		// Grab all the buffer descriptors in one go
+		let mut descriptors = vec![0u8; ((up_count + down_count) * RTT_BUFFER_DESCRIPTOR_SIZE) as usize];
+		let descriptors_address = address + RTT_CONTROL_BLOCK_HEADER_SIZE;
+		ap.mem_read(&mut descriptors, descriptors_address as u64)?;
+
+		let mut up = Vec::new();
+		let mut down = Vec::new();
+		for (index, descriptor) in descriptors.chunks(RTT_BUFFER_DESCRIPTOR_SIZE as usize).enumerate() {
+			// The up buffer descriptors come first, followed by the down buffer ones
+			let (channels, channel_index) = if index < up_count as usize {
+				(&mut up, index)
+			} else {
+				(&mut down, index - up_count as usize)
+			};
+			channels.push(RttChannel {
+				index: channel_index,
+				name: Self::read_name(ap, read_u32_le(descriptor, 0))?,
+				descriptor: descriptors_address + (index as u32 * RTT_BUFFER_DESCRIPTOR_SIZE),
+				buffer: read_u32_le(descriptor, 4),
+				size: read_u32_le(descriptor, 8),
+			});
+		}
+
+		Ok(Self {
+			ap,
+			address,
+			up,
+			down,
+		})
+	}
+
+	/// Read a channel's name string from target memory
+	fn read_name(ap: &AdiV5AccessPort, address: u32) -> Result<Option<String>>
+	{
+		if address == 0 {
+			return Ok(None);
+		}
+		let mut name = [0u8; 32];
+		ap.mem_read(&mut name, address as u64)?;
+		let length = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+		Ok(Some(String::from_utf8_lossy(&name[..length]).into_owned()))
+	}
+
+	/// Address of the control block in target memory
+	pub fn address(&self) -> u32
+	{
+		self.address
+	}
+
+	/// The target to host channels
+	pub fn up_channels(&self) -> &[RttChannel]
+	{
+		&self.up
+	}
+
+	/// The host to target channels
+	pub fn down_channels(&self) -> &[RttChannel]
+	{
+		&self.down
+	}
+
+	/// Read the write and read offsets of a channel from its descriptor
+	fn offsets(&self, channel: &RttChannel) -> Result<(u32, u32)>
+	{
+		let mut offsets = [0u8; 8];
+		self.ap.mem_read(&mut offsets, (channel.descriptor + RTT_BUFFER_WRITE_OFFSET) as u64)?;
+		let write = read_u32_le(&offsets, 0);
+		let read = read_u32_le(&offsets, 4);
+		if write >= channel.size || read >= channel.size {
+			return Err(eyre!("RTT channel {} has corrupt offsets", channel.index));
+		}
+		Ok((write, read))
+	}
+
+	/// Pull any pending data out of up channel `index`, appending it to `data` and returning how much was read
+	pub fn read(&self, index: usize, data: &mut Vec<u8>) -> Result<usize>
+	{
+		let channel = self
+			.up
+			.get(index)
+			.ok_or_else(|| eyre!("RTT up channel {} does not exist", index))?;
+		let (write, read) = self.offsets(channel)?;
+		if write == read {
+			return Ok(0);
+		}
+
+		// If the data wraps around the end of the ring buffer, read up to the end first, then the start
+		let ranges = if write > read {
+			[read..write, 0..0]
+		} else {
+			[read..channel.size, 0..write]
+		};
+		let start = data.len();
+		for range in ranges {
+			// Batch reads so each fits in a single remote protocol response
+			let mut offset = range.start;
+			while offset < range.end {
+				let length = RTT_READ_BLOCK_SIZE.min((range.end - offset) as usize);
+				let position = data.len();
+				data.resize(position + length, 0);
+				self.ap.mem_read(&mut data[position..], (channel.buffer + offset) as u64)?;
+				offset += length as u32;
+			}
+		}
+
+		// Tell the target we've consumed the data
+		self.ap.mem_write_u32((channel.descriptor + RTT_BUFFER_READ_OFFSET) as u64, write)?;
+		Ok(data.len() - start)
+	}
+
+	/// Push as much of `data` as will fit into down channel `index`, returning how much was written
+	pub fn write(&self, index: usize, data: &[u8]) -> Result<usize>
+	{
+		let channel = self
+			.down
+			.get(index)
+			.ok_or_else(|| eyre!("RTT down channel {} does not exist", index))?;
+		let (write, read) = self.offsets(channel)?;
+
+		// The ring buffer always keeps one byte free to tell full apart from empty
+		let free = if read > write {
+			read - write - 1
+		} else {
+			channel.size - (write - read) - 1
+		};
+		let length = (free as usize).min(data.len());
+		if length == 0 {
+			return Ok(0);
+		}
+
+		// Write up to the end of the ring buffer, and then wrap around for any remainder
+		let first = length.min((channel.size - write) as usize);
+		self.ap.mem_write(channel.buffer as u64 + write as u64, &data[..first])?;
+		if first < length {
+			self.ap.mem_write(channel.buffer as u64, &data[first..length])?;
+		}
+		let write = (write + length as u32) % channel.size;
+		self.ap.mem_write_u32((channel.descriptor + RTT_BUFFER_WRITE_OFFSET) as u64, write)?;
+		Ok(length)
+	}
+}
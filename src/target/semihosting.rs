@@ -0,0 +1,414 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, stderr, stdin, stdout};
+use std::path::{Component, Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use log::{debug, trace, warn};
+
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::breakpoints::HaltReason;
+use crate::target::cortexm::{CORTEXM_REG_PC, CortexM};
+
+/// The Thumb instruction used by targets to make a semihosting call (BKPT 0xab)
+const SEMIHOSTING_BKPT: u16 = 0xbeab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Exit reason code used by the target to report that the application finished normally
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Special file name a target opens to get at the host's console
+const SEMIHOSTING_CONSOLE: &str = ":tt";
+/// Limit on how long a string SYS_WRITE0 will print, in case the target hands us garbage
+const SEMIHOSTING_MAX_STRING: usize = 4096;
+/// How many bytes of a NUL-terminated string to read from the target at a time
+const SEMIHOSTING_STRING_BLOCK: usize = 64;
+
+/// How a file should be opened, decoded from the fopen()-style mode number SYS_OPEN is given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OpenMode
+{
+	Read,
+	Write,
+	Append,
+}
+
+impl OpenMode
+{
+	/// Decode a SYS_OPEN mode number into the access mode and whether the file is opened for update
+	/// ("+"). Modes come in groups of four: "r", "rb", "r+", "r+b", then the same for "w" and "a".
+	fn decode(mode: u32) -> Option<(Self, bool)>
+	{
+		let access = match mode / 4 {
+			0 => Self::Read,
+			1 => Self::Write,
+			2 => Self::Append,
+			_ => return None,
+		};
+		Some((access, mode & 2 != 0))
+	}
+
+	fn options(self, update: bool) -> OpenOptions
+	{
+		let mut options = OpenOptions::new();
+		match self {
+			Self::Read => options.read(true).write(update),
+			Self::Write => options.write(true).read(update).create(true).truncate(true),
+			Self::Append => options.append(true).read(update).create(true),
+		};
+		options
+	}
+}
+
+/// Files the target has open on the host
+enum HostFile
+{
+	Stdin,
+	Stdout,
+	Stderr,
+	File(File),
+}
+
+impl HostFile
+{
+	fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize>
+	{
+		match self {
+			Self::Stdin => stdin().read(data),
+			Self::File(file) => file.read(data),
+			_ => Ok(0),
+		}
+	}
+
+	fn write(&mut self, data: &[u8]) -> std::io::Result<()>
+	{
+		match self {
+			Self::Stdout => {
+				let mut stdout = stdout();
+				stdout.write_all(data)?;
+				stdout.flush()
+			},
+			Self::Stderr => stderr().write_all(data),
+			Self::File(file) => file.write_all(data),
+			Self::Stdin => Err(std::io::ErrorKind::PermissionDenied.into()),
+		}
+	}
+}
+
+/// What the target asked of us
+enum Request
+{
+	/// The call completed and its result should be handed back in R0
+	Return(u32),
+	/// The target wants to exit with the given status
+	Exit(i32),
+}
+
+/// Why a target being serviced stopped running
+pub enum TargetStop
+{
+	/// The target asked to exit with the given status
+	Exit(i32),
+	/// The core halted for some reason other than a semihosting call
+	Halt
+	{
+		reason: HaltReason,
+		pc: u32,
+	},
+}
+
+/// Services ARM semihosting calls from a target, giving it access to the host's console
+/// and to files within a sandbox directory
+pub struct Semihosting
+{
+	root: PathBuf,
+	files: BTreeMap<u32, HostFile>,
+	next_handle: u32,
+}
+
+impl Semihosting
+{
+	/// Set up a semihosting service where any files the target opens are relative to (and confined to) `root`
+	pub fn new(root: impl Into<PathBuf>) -> Self
+	{
+		Self {
+			root: root.into(),
+			files: BTreeMap::new(),
+			// Handle 0 is avoided as some C libraries treat it as an error
+			next_handle: 1,
+		}
+	}
+
+	/// Check on the core, and if it has halted to make a semihosting call, service that call and
+	/// resume it. Returns why the target stopped if it asked to exit or halted for any other reason,
+	/// and otherwise None.
+	pub fn poll(&mut self, core: &CortexM) -> Result<Option<TargetStop>>
+	{
+		if !core.is_halted()? {
+			return Ok(None);
+		}
+
+		let ap = core.ap();
+		let reason = HaltReason::read(ap)?;
+		let pc = core.read_register(CORTEXM_REG_PC)?;
+		if !reason.is_breakpoint() || read_u16(ap, pc)? != SEMIHOSTING_BKPT {
+			return Ok(Some(TargetStop::Halt {
+				reason,
+				pc,
+			}));
+		}
+
+		let operation = core.read_register(0)?;
+		let parameter = core.read_register(1)?;
+		trace!("Semihosting call 0x{:02x} with parameter 0x{:08x}", operation, parameter);
+		match self.service(ap, operation, parameter)? {
+			Request::Return(result) => {
+				core.write_register(0, result)?;
+				// Step over the BKPT so the target carries on from after the call
+				core.write_register(CORTEXM_REG_PC, pc + 2)?;
+				core.resume()?;
+				Ok(None)
+			},
+			Request::Exit(status) => {
+				debug!("Target exited with status {}", status);
+				Ok(Some(TargetStop::Exit(status)))
+			},
+		}
+	}
+
+	fn service(&mut self, ap: &AdiV5AccessPort, operation: u32, parameter: u32) -> Result<Request>
+	{
+		let result = match operation {
+			SYS_OPEN => {
+				let [name, mode, length] = read_parameters(ap, parameter)?;
+				let mut name_data = vec![0u8; length as usize];
+				ap.mem_read(&mut name_data, name as u64)?;
+				self.open(&String::from_utf8_lossy(&name_data), mode)
+			},
+			SYS_CLOSE => {
+				let [handle] = read_parameters(ap, parameter)?;
+				match self.files.remove(&handle) {
+					Some(_) => 0,
+					None => u32::MAX,
+				}
+			},
+			SYS_WRITEC => {
+				let mut character = [0u8];
+				ap.mem_read(&mut character, parameter as u64)?;
+				HostFile::Stdout.write(&character)?;
+				0
+			},
+			SYS_WRITE0 => {
+				let string = read_string(ap, parameter)?;
+				HostFile::Stdout.write(&string)?;
+				0
+			},
+			SYS_WRITE => {
+				let [handle, buffer, length] = read_parameters(ap, parameter)?;
+				let Some(file) = self.files.get_mut(&handle) else {
+					return Ok(Request::Return(length));
+				};
+				let mut data = vec![0u8; length as usize];
+				ap.mem_read(&mut data, buffer as u64)?;
+				// SYS_WRITE returns how many bytes were *not* written
+				match file.write(&data) {
+					Ok(()) => 0,
+					Err(error) => {
+						warn!("Semihosting write to handle {} failed: {}", handle, error);
+						length
+					},
+				}
+			},
+			SYS_READ => {
+				let [handle, buffer, length] = read_parameters(ap, parameter)?;
+				let Some(file) = self.files.get_mut(&handle) else {
+					return Ok(Request::Return(u32::MAX));
+				};
+				let mut data = vec![0u8; length as usize];
+				// SYS_READ returns how many bytes were *not* read, so a full-length result means end of file
+				match file.read(&mut data) {
+					Ok(count) => {
+						ap.mem_write(buffer as u64, &data[..count])?;
+						length - count as u32
+					},
+					Err(error) => {
+						warn!("Semihosting read from handle {} failed: {}", handle, error);
+						u32::MAX
+					},
+				}
+			},
+			// On 32-bit targets the exit reason is passed directly in R1 rather than via a parameter block
+			SYS_EXIT => return Ok(Request::Exit(exit_status(parameter, 0))),
+			SYS_EXIT_EXTENDED => {
+				let [reason, subcode] = read_parameters(ap, parameter)?;
+				return Ok(Request::Exit(exit_status(reason, subcode)));
+			},
+			_ => {
+				warn!("Unsupported semihosting operation 0x{:02x}", operation);
+				u32::MAX
+			},
+		};
+		Ok(Request::Return(result))
+	}
+
+	/// Open a file on behalf of the target, returning its handle or -1 on failure
+	fn open(&mut self, name: &str, mode: u32) -> u32
+	{
+		let Some((access, update)) = OpenMode::decode(mode) else {
+			warn!("Semihosting open of '{}' with invalid mode {}", name, mode);
+			return u32::MAX;
+		};
+
+		let file = if name == SEMIHOSTING_CONSOLE {
+			match access {
+				OpenMode::Read => HostFile::Stdin,
+				OpenMode::Write => HostFile::Stdout,
+				OpenMode::Append => HostFile::Stderr,
+			}
+		} else {
+			let Some(path) = sandbox_path(&self.root, name) else {
+				warn!("Target tried to open '{}', which is outside the semihosting directory", name);
+				return u32::MAX;
+			};
+			match access.options(update).open(&path) {
+				Ok(file) => HostFile::File(file),
+				Err(error) => {
+					warn!("Semihosting open of {} failed: {}", path.display(), error);
+					return u32::MAX;
+				},
+			}
+		};
+
+		let handle = self.next_handle;
+		self.next_handle += 1;
+		debug!("Target opened '{}' as handle {}", name, handle);
+		self.files.insert(handle, file);
+		handle
+	}
+}
+
+/// Turn an exit reason and subcode into a process exit status
+fn exit_status(reason: u32, subcode: u32) -> i32
+{
+	if reason == ADP_STOPPED_APPLICATION_EXIT {
+		subcode as i32
+	} else {
+		warn!("Target exited abnormally (reason 0x{:05x})", reason);
+		1
+	}
+}
+
+/// Resolve a file name given by the target to a path within `root`, refusing anything that
+/// would escape it (absolute paths, ones that use '..', or ones that lead out through a symlink)
+fn sandbox_path(root: &Path, name: &str) -> Option<PathBuf>
+{
+	let name = Path::new(name);
+	if !name
+		.components()
+		.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+	{
+		return None;
+	}
+	let path = root.join(name);
+	// Symlinks within the root can still point outside it, so check where the path really ends up
+	let root = root.canonicalize().ok()?;
+	let resolved = match path.canonicalize() {
+		Ok(path) => path,
+		// If the file doesn't exist yet it's about to be created, so its directory is what has to be in the root
+		Err(_) if path.symlink_metadata().is_err() => path.parent()?.canonicalize().ok()?.join(path.file_name()?),
+		// A dangling symlink, which would be followed when opening the file
+		Err(_) => return None,
+	};
+	resolved.starts_with(&root).then_some(resolved)
+}
+
+/// Read a semihosting parameter block of N words from the target
+fn read_parameters<const N: usize>(ap: &AdiV5AccessPort, address: u32) -> Result<[u32; N]>
+{
+	let mut data = vec![0u8; N * 4];
+	ap.mem_read(&mut data, address as u64)?;
+	let mut parameters = [0u32; N];
+	for (parameter, word) in parameters.iter_mut().zip(data.chunks_exact(4)) {
+		*parameter = u32::from_le_bytes(word.try_into().unwrap());
+	}
+	Ok(parameters)
+}
+
+fn read_u16(ap: &AdiV5AccessPort, address: u32) -> Result<u16>
+{
+	let mut data = [0u8; 2];
+	ap.mem_read(&mut data, address as u64)?;
+	Ok(u16::from_le_bytes(data))
+}
+
+/// Read a NUL-terminated string from the target
+fn read_string(ap: &AdiV5AccessPort, address: u32) -> Result<Vec<u8>>
+{
+	let mut string = Vec::new();
+	let mut block = [0u8; SEMIHOSTING_STRING_BLOCK];
+	while string.len() < SEMIHOSTING_MAX_STRING {
+		ap.mem_read(&mut block, (address as usize + string.len()) as u64)?;
+		if let Some(length) = block.iter().position(|&byte| byte == 0) {
+			string.extend_from_slice(&block[..length]);
+			return Ok(string);
+		}
+		string.extend_from_slice(&block);
+	}
+	Ok(string)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn open_modes()
+	{
+		assert_eq!(OpenMode::decode(0), Some((OpenMode::Read, false)));
+		assert_eq!(OpenMode::decode(3), Some((OpenMode::Read, true)));
+		assert_eq!(OpenMode::decode(5), Some((OpenMode::Write, false)));
+		assert_eq!(OpenMode::decode(10), Some((OpenMode::Append, true)));
+		assert_eq!(OpenMode::decode(12), None);
+	}
+
+	#[test]
+	fn sandboxing()
+	{
+		let root = std::env::temp_dir().join(format!("bmputil-semihosting-{}", std::process::id()));
+		std::fs::create_dir_all(root.join("out")).unwrap();
+		std::fs::write(root.join("data.bin"), b"data").unwrap();
+		let canonical_root = root.canonicalize().unwrap();
+		#[cfg(unix)]
+		{
+			std::os::unix::fs::symlink("/", root.join("escape")).unwrap();
+			std::os::unix::fs::symlink("/nonexistent", root.join("dangling")).unwrap();
+		}
+
+		assert_eq!(sandbox_path(&root, "out/log.txt"), Some(canonical_root.join("out/log.txt")));
+		assert_eq!(sandbox_path(&root, "./data.bin"), Some(canonical_root.join("data.bin")));
+		assert_eq!(sandbox_path(&root, "missing/log.txt"), None);
+		assert_eq!(sandbox_path(&root, "../secret"), None);
+		assert_eq!(sandbox_path(&root, "a/../../secret"), None);
+		assert_eq!(sandbox_path(&root, "/etc/passwd"), None);
+		#[cfg(unix)]
+		{
+			assert_eq!(sandbox_path(&root, "escape/etc/passwd"), None);
+			assert_eq!(sandbox_path(&root, "escape/new.txt"), None);
+			assert_eq!(sandbox_path(&root, "dangling"), None);
+		}
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -0,0 +1,696 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+use std::path::Path;
+
+use color_eyre::eyre::{Context, OptionExt, Result, eyre};
+use log::debug;
+use roxmltree::{Document, Node};
+
+use crate::serial::remote::Align;
+use crate::serial::remote::adi::AdiV5AccessPort;
+
+/// Register size to assume when neither the register nor anything containing it says
+const SVD_DEFAULT_REGISTER_SIZE: u32 = 32;
+
+/// Whether a register can be read, written or both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access
+{
+	ReadOnly,
+	WriteOnly,
+	ReadWrite,
+}
+
+/// A peripheral device description loaded from a CMSIS-SVD file
+pub struct Svd
+{
+	pub name: String,
+	pub peripherals: Vec<Peripheral>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Peripheral
+{
+	pub name: String,
+	pub base_address: u32,
+	pub registers: Vec<Register>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Register
+{
+	/// Name of the register - registers within clusters are named CLUSTER.REGISTER
+	pub name: String,
+	pub description: Option<String>,
+	/// Offset of the register from the start of the peripheral
+	pub offset: u32,
+	/// Size of the register in bits
+	pub size: u32,
+	pub access: Access,
+	/// Value the register takes on reset
+	pub reset_value: u64,
+	/// Whether reading the register has side effects (such as clearing status flags), as marked by a
+	/// `<readAction>` on it or any of its fields
+	pub read_action: bool,
+	pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Field
+{
+	pub name: String,
+	pub description: Option<String>,
+	/// Position of the least significant bit of the field
+	pub offset: u32,
+	/// Width of the field in bits
+	pub width: u32,
+	pub values: Vec<EnumeratedValue>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EnumeratedValue
+{
+	pub name: String,
+	pub description: Option<String>,
+	pub value: u64,
+}
+
+/// Properties that are inherited from the device, peripheral and cluster levels down to registers
+#[derive(Clone, Copy)]
+struct RegisterProperties
+{
+	size: u32,
+	access: Access,
+	reset_value: u64,
+}
+
+/// The value of a register, ready to display broken down into its fields
+pub struct DecodedRegister<'a>
+{
+	peripheral: &'a Peripheral,
+	register: &'a Register,
+	value: u64,
+}
+
+/// Parse a number as written in an SVD file - decimal, hex with a 0x prefix, or binary with a # prefix
+fn parse_svd_number(value: &str) -> Result<u64>
+{
+	let value = value.trim();
+	let result = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+		u64::from_str_radix(hex, 16)
+	} else if let Some(binary) = value.strip_prefix('#') {
+		u64::from_str_radix(binary, 2)
+	} else {
+		value.parse()
+	};
+	result.wrap_err_with(|| eyre!("'{}' is not a valid SVD number", value))
+}
+
+/// Find the text of a direct child element with the given tag name
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str>
+{
+	node.children()
+		.find(|child| child.has_tag_name(name))
+		.and_then(|child| child.text())
+		.map(str::trim)
+}
+
+fn child_number(node: Node, name: &str) -> Result<Option<u64>>
+{
+	child_text(node, name).map(parse_svd_number).transpose()
+}
+
+fn required_text<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str>
+{
+	child_text(node, name).ok_or_else(|| eyre!("SVD <{}> element is missing its <{}>", node.tag_name().name(), name))
+}
+
+/// Work out how many bits wide a field given by its most and least significant bits is
+fn field_width(name: &str, msb: u64, lsb: u64) -> Result<u64>
+{
+	msb.checked_sub(lsb).map(|span| span + 1).ok_or_else(|| {
+		eyre!(
+			"Field {} has its most significant bit {} below its least significant {}",
+			name,
+			msb,
+			lsb
+		)
+	})
+}
+
+/// Work out the names and address offsets of each element of a dim array (or just the one element if
+/// the node is not an array)
+fn dim_elements(node: Node, name: &str) -> Result<Vec<(String, u32)>>
+{
+	let Some(dim) = child_number(node, "dim")? else {
+		return Ok(vec![(name.into(), 0)]);
+	};
+	let increment = child_number(node, "dimIncrement")?.unwrap_or(0) as u32;
+	let indices: Vec<String> = match child_text(node, "dimIndex") {
+		// Indices can be given either as a range ("0-3") or a list ("A,B,C")
+		Some(indices) => match indices.split_once('-') {
+			Some((start, end)) if !indices.contains(',') => (parse_svd_number(start)?..=parse_svd_number(end)?)
+				.map(|index| index.to_string())
+				.collect(),
+			_ => indices.split(',').map(|index| index.trim().into()).collect(),
+		},
+		None => (0..dim).map(|index| index.to_string()).collect(),
+	};
+	Ok(indices
+		.into_iter()
+		.enumerate()
+		.map(|(index, name_index)| (name.replace("%s", &name_index), index as u32 * increment))
+		.collect())
+}
+
+impl Access
+{
+	fn parse(value: &str) -> Self
+	{
+		match value {
+			"read-only" => Self::ReadOnly,
+			"write-only" | "writeOnce" => Self::WriteOnly,
+			_ => Self::ReadWrite,
+		}
+	}
+
+	pub fn is_readable(self) -> bool
+	{
+		self != Self::WriteOnly
+	}
+
+	pub fn is_writable(self) -> bool
+	{
+		self != Self::ReadOnly
+	}
+}
+
+impl RegisterProperties
+{
+	/// Pick up any properties the node overrides
+	fn inherit(self, node: Node) -> Result<Self>
+	{
+		Ok(Self {
+			size: child_number(node, "size")?.map_or(self.size, |size| size as u32),
+			access: child_text(node, "access").map_or(self.access, Access::parse),
+			reset_value: child_number(node, "resetValue")?.unwrap_or(self.reset_value),
+		})
+	}
+}
+
+impl Svd
+{
+	/// Load and parse an SVD file
+	pub fn from_path(path: &Path) -> Result<Self>
+	{
+		let text =
+			std::fs::read_to_string(path).wrap_err_with(|| eyre!("Failed to read SVD file {}", path.display()))?;
+		Self::parse(&text).wrap_err_with(|| eyre!("Failed to parse SVD file {}", path.display()))
+	}
+
+	pub fn parse(text: &str) -> Result<Self>
+	{
+		let document = Document::parse(text)?;
+		let device = document.root_element();
+		if !device.has_tag_name("device") {
+			return Err(eyre!("Not an SVD file, root element is <{}>", device.tag_name().name()));
+		}
+		let properties = RegisterProperties {
+			size: SVD_DEFAULT_REGISTER_SIZE,
+			access: Access::ReadWrite,
+			reset_value: 0,
+		}
+		.inherit(device)?;
+
+		let peripheral_nodes: Vec<_> = device
+			.children()
+			.find(|child| child.has_tag_name("peripherals"))
+			.ok_or_eyre("SVD file has no peripherals")?
+			.children()
+			.filter(|child| child.has_tag_name("peripheral"))
+			.collect();
+
+		let mut peripherals: Vec<Peripheral> = Vec::new();
+		for node in &peripheral_nodes {
+			let name = required_text(*node, "name")?;
+			let base_address = parse_svd_number(required_text(*node, "baseAddress")?)? as u32;
+			let properties = properties.inherit(*node)?;
+			let mut registers = Vec::new();
+			if let Some(registers_node) = node.children().find(|child| child.has_tag_name("registers")) {
+				Self::parse_registers(registers_node, "", 0, properties, &mut registers)?;
+			}
+			// Peripherals can be derived from another, taking its registers if they don't define their own
+			if registers.is_empty() &&
+				let Some(parent) = node.attribute("derivedFrom")
+			{
+				let parent = peripherals
+					.iter()
+					.find(|peripheral| peripheral.name == parent)
+					.ok_or_else(|| eyre!("Peripheral {} is derived from unknown peripheral {}", name, parent))?;
+				registers = parent.registers.clone();
+			}
+			for (name, offset) in dim_elements(*node, name)? {
+				peripherals.push(Peripheral {
+					name,
+					base_address: base_address + offset,
+					registers: registers.clone(),
+				});
+			}
+		}
+
+		let name = child_text(device, "name").unwrap_or_default().into();
+		debug!("Loaded SVD for {} with {} peripherals", name, peripherals.len());
+		Ok(Self {
+			name,
+			peripherals,
+		})
+	}
+
+	/// Parse the registers and clusters within a <registers> or <cluster> element
+	fn parse_registers(
+		node: Node,
+		prefix: &str,
+		base_offset: u32,
+		properties: RegisterProperties,
+		registers: &mut Vec<Register>,
+	) -> Result<()>
+	{
+		for child in node.children().filter(Node::is_element) {
+			let is_cluster = child.has_tag_name("cluster");
+			if !is_cluster && !child.has_tag_name("register") {
+				continue;
+			}
+			let name = required_text(child, "name")?;
+			let offset = base_offset + parse_svd_number(required_text(child, "addressOffset")?)? as u32;
+			let properties = properties.inherit(child)?;
+			for (name, element_offset) in dim_elements(child, name)? {
+				let name = format!("{}{}", prefix, name);
+				if is_cluster {
+					Self::parse_registers(
+						child,
+						&format!("{}.", name),
+						offset + element_offset,
+						properties,
+						registers,
+					)?;
+				} else {
+					let mut fields = child
+						.children()
+						.find(|fields| fields.has_tag_name("fields"))
+						.into_iter()
+						.flat_map(|fields| fields.children().filter(|field| field.has_tag_name("field")));
+					let read_action = child_text(child, "readAction").is_some() ||
+						fields.any(|field| child_text(field, "readAction").is_some());
+					registers.push(Register {
+						name,
+						description: child_text(child, "description").map(Into::into),
+						offset: offset + element_offset,
+						size: properties.size,
+						access: properties.access,
+						reset_value: properties.reset_value,
+						read_action,
+						fields: Self::parse_fields(child)?,
+					});
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn parse_fields(register: Node) -> Result<Vec<Field>>
+	{
+		let Some(fields) = register.children().find(|child| child.has_tag_name("fields")) else {
+			return Ok(Vec::new());
+		};
+		let mut result = Vec::new();
+		for field in fields.children().filter(|child| child.has_tag_name("field")) {
+			let name = required_text(field, "name")?;
+			// Field positions can be given in any one of three different ways
+			let (offset, width) = if let Some(offset) = child_number(field, "bitOffset")? {
+				(offset, child_number(field, "bitWidth")?.unwrap_or(1))
+			} else if let Some(lsb) = child_number(field, "lsb")? {
+				let msb = child_number(field, "msb")?.unwrap_or(lsb);
+				(lsb, field_width(name, msb, lsb)?)
+			} else if let Some(range) = child_text(field, "bitRange") {
+				let (msb, lsb) = range
+					.trim_matches(['[', ']'])
+					.split_once(':')
+					.ok_or_else(|| eyre!("Field {} has invalid bit range {}", name, range))?;
+				let (msb, lsb) = (parse_svd_number(msb)?, parse_svd_number(lsb)?);
+				(lsb, field_width(name, msb, lsb)?)
+			} else {
+				return Err(eyre!("Field {} does not say where it is in its register", name));
+			};
+
+			let values: Vec<_> = field
+				.children()
+				.filter(|child| child.has_tag_name("enumeratedValues"))
+				.flat_map(|values| values.children().filter(|child| child.has_tag_name("enumeratedValue")))
+				.filter_map(|value| {
+					// Values using "don't care" bits (or default values) can't be matched exactly, so skip them
+					let number = child_text(value, "value").and_then(|number| parse_svd_number(number).ok())?;
+					Some(EnumeratedValue {
+						name: child_text(value, "name")?.into(),
+						description: child_text(value, "description").map(Into::into),
+						value: number,
+					})
+				})
+				.collect();
+
+			for (name, element_offset) in dim_elements(field, name)? {
+				result.push(Field {
+					name,
+					description: child_text(field, "description").map(Into::into),
+					offset: (offset as u32) + element_offset,
+					width: width as u32,
+					values: values.clone(),
+				});
+			}
+		}
+		Ok(result)
+	}
+
+	/// Look a peripheral up by name
+	pub fn peripheral(&self, name: &str) -> Result<&Peripheral>
+	{
+		self.peripherals
+			.iter()
+			.find(|peripheral| peripheral.name.eq_ignore_ascii_case(name))
+			.ok_or_else(|| eyre!("No peripheral named {} in the SVD for {}", name, self.name))
+	}
+
+	/// Look a register up by its PERIPHERAL.REGISTER path
+	pub fn register(&self, path: &str) -> Result<(&Peripheral, &Register)>
+	{
+		let (peripheral, register) = path
+			.split_once('.')
+			.ok_or_else(|| eyre!("'{}' is not a register name, expected PERIPHERAL.REGISTER", path))?;
+		let peripheral = self.peripheral(peripheral)?;
+		let register = peripheral.register(register)?;
+		Ok((peripheral, register))
+	}
+}
+
+impl Peripheral
+{
+	pub fn register(&self, name: &str) -> Result<&Register>
+	{
+		self.registers
+			.iter()
+			.find(|register| register.name.eq_ignore_ascii_case(name))
+			.ok_or_else(|| eyre!("Peripheral {} has no register named {}", self.name, name))
+	}
+
+	/// Read the current value of one of this peripheral's registers from the target
+	pub fn read(&self, ap: &AdiV5AccessPort, register: &Register) -> Result<u64>
+	{
+		if !register.access.is_readable() {
+			return Err(eyre!("{}.{} is write-only", self.name, register.name));
+		}
+		let mut value = [0u8; 8];
+		let bytes = register.bytes();
+		ap.mem_read(&mut value[..bytes], register.address(self) as u64)?;
+		Ok(u64::from_le_bytes(value))
+	}
+
+	/// Write a new value to one of this peripheral's registers on the target
+	pub fn write(&self, ap: &AdiV5AccessPort, register: &Register, value: u64) -> Result<()>
+	{
+		if !register.access.is_writable() {
+			return Err(eyre!("{}.{} is read-only", self.name, register.name));
+		}
+		let bytes = register.bytes();
+		let align = match bytes {
+			1 => Align::As8Bit,
+			2 => Align::As16Bit,
+			_ => Align::As32Bit,
+		};
+		ap.mem_write_aligned(register.address(self) as u64, &value.to_le_bytes()[..bytes], align)
+	}
+
+	/// Read one of this peripheral's registers, ready for display
+	pub fn decode<'a>(&'a self, ap: &AdiV5AccessPort, register: &'a Register) -> Result<DecodedRegister<'a>>
+	{
+		Ok(DecodedRegister {
+			peripheral: self,
+			register,
+			value: self.read(ap, register)?,
+		})
+	}
+}
+
+impl Register
+{
+	/// Absolute address of the register within the given peripheral
+	pub fn address(&self, peripheral: &Peripheral) -> u32
+	{
+		peripheral.base_address + self.offset
+	}
+
+	fn bytes(&self) -> usize
+	{
+		(self.size as usize).div_ceil(8).clamp(1, 8)
+	}
+
+	pub fn field(&self, name: &str) -> Result<&Field>
+	{
+		self.fields
+			.iter()
+			.find(|field| field.name.eq_ignore_ascii_case(name))
+			.ok_or_else(|| eyre!("Register {} has no field named {}", self.name, name))
+	}
+
+	/// Apply a set of FIELD=VALUE assignments to a register value, where values may be numbers or the names of
+	/// one of the field's enumerated values
+	pub fn apply_fields(&self, mut value: u64, assignments: &[(String, String)]) -> Result<u64>
+	{
+		for (field, field_value) in assignments {
+			let field = self.field(field)?;
+			value = field.insert(value, field.parse_value(field_value)?)?;
+		}
+		Ok(value)
+	}
+}
+
+impl Field
+{
+	fn mask(&self) -> u64
+	{
+		if self.width >= 64 {
+			u64::MAX
+		} else {
+			(1 << self.width) - 1
+		}
+	}
+
+	/// Pull this field's value out of the register value
+	pub fn extract(&self, register: u64) -> u64
+	{
+		(register >> self.offset) & self.mask()
+	}
+
+	/// Replace this field's bits in the register value with a new value
+	pub fn insert(&self, register: u64, value: u64) -> Result<u64>
+	{
+		if value & !self.mask() != 0 {
+			return Err(eyre!(
+				"Value 0x{:x} does not fit in {}-bit field {}",
+				value,
+				self.width,
+				self.name
+			));
+		}
+		Ok((register & !(self.mask() << self.offset)) | (value << self.offset))
+	}
+
+	/// Turn a value given by the user into a number, looking it up in the enumerated values if it's not a number
+	pub fn parse_value(&self, value: &str) -> Result<u64>
+	{
+		if let Ok(number) = parse_svd_number(value) {
+			return Ok(number);
+		}
+		self.values
+			.iter()
+			.find(|enumerated| enumerated.name.eq_ignore_ascii_case(value))
+			.map(|enumerated| enumerated.value)
+			.ok_or_else(|| eyre!("'{}' is not a number or a known value for field {}", value, self.name))
+	}
+
+	/// Find the enumerated value matching the given field value
+	pub fn value_name(&self, value: u64) -> Option<&EnumeratedValue>
+	{
+		self.values.iter().find(|enumerated| enumerated.value == value)
+	}
+}
+
+impl Display for DecodedRegister<'_>
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		let digits = self.register.bytes() * 2;
+		write!(
+			fmt,
+			"{}.{} @ 0x{:08x} = 0x{:0digits$x}",
+			self.peripheral.name,
+			self.register.name,
+			self.register.address(self.peripheral),
+			self.value,
+		)?;
+		if let Some(description) = &self.register.description {
+			write!(fmt, " ({})", description)?;
+		}
+		writeln!(fmt)?;
+
+		for field in &self.register.fields {
+			let value = field.extract(self.value);
+			let bits = if field.width == 1 {
+				format!("[{}]", field.offset)
+			} else {
+				format!("[{}:{}]", field.offset + field.width - 1, field.offset)
+			};
+			write!(fmt, "  {:<16} {:<8} = 0x{:x}", field.name, bits, value)?;
+			if let Some(enumerated) = field.value_name(value) {
+				write!(fmt, " {}", enumerated.name)?;
+				if let Some(description) = &enumerated.description {
+					write!(fmt, " ({})", description)?;
+				}
+			}
+			writeln!(fmt)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const TEST_SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<device>
+	<name>TEST</name>
+	<size>32</size>
+	<peripherals>
+		<peripheral>
+			<name>GPIOA</name>
+			<baseAddress>0x40020000</baseAddress>
+			<registers>
+				<register>
+					<name>MODER</name>
+					<addressOffset>0x0</addressOffset>
+					<fields>
+						<field>
+							<name>MODER%s</name>
+							<bitOffset>0</bitOffset>
+							<bitWidth>2</bitWidth>
+							<dim>2</dim>
+							<dimIncrement>2</dimIncrement>
+							<enumeratedValues>
+								<enumeratedValue><name>Input</name><value>0</value></enumeratedValue>
+								<enumeratedValue><name>Output</name><value>#01</value></enumeratedValue>
+							</enumeratedValues>
+						</field>
+					</fields>
+				</register>
+				<register>
+					<name>IDR</name>
+					<addressOffset>0x10</addressOffset>
+					<access>read-only</access>
+					<fields>
+						<field><name>IDR0</name><bitRange>[0:0]</bitRange></field>
+					</fields>
+				</register>
+			</registers>
+		</peripheral>
+		<peripheral derivedFrom="GPIOA">
+			<name>GPIOB</name>
+			<baseAddress>0x40020400</baseAddress>
+		</peripheral>
+		<peripheral>
+			<name>USART1</name>
+			<baseAddress>0x40013800</baseAddress>
+			<resetValue>0x0</resetValue>
+			<registers>
+				<register>
+					<name>SR</name>
+					<addressOffset>0x0</addressOffset>
+					<resetValue>0xc0</resetValue>
+					<fields>
+						<field><name>ORE</name><bitOffset>3</bitOffset><readAction>clear</readAction></field>
+					</fields>
+				</register>
+				<register>
+					<name>DR</name>
+					<addressOffset>0x4</addressOffset>
+					<readAction>modify</readAction>
+				</register>
+				<register>
+					<name>BRR</name>
+					<addressOffset>0x8</addressOffset>
+				</register>
+			</registers>
+		</peripheral>
+	</peripherals>
+</device>"#;
+
+	#[test]
+	fn parsing()
+	{
+		let svd = Svd::parse(TEST_SVD).unwrap();
+		let (peripheral, register) = svd.register("GPIOB.IDR").unwrap();
+		assert_eq!(register.address(peripheral), 0x40020410);
+		assert_eq!(register.access, Access::ReadOnly);
+		let (_, moder) = svd.register("gpioa.moder").unwrap();
+		let names: Vec<_> = moder
+			.fields
+			.iter()
+			.map(|field| (field.name.as_str(), field.offset))
+			.collect();
+		assert_eq!(names, [("MODER0", 0), ("MODER1", 2)]);
+	}
+
+	#[test]
+	fn field_values()
+	{
+		let svd = Svd::parse(TEST_SVD).unwrap();
+		let (_, moder) = svd.register("GPIOA.MODER").unwrap();
+		let value = moder
+			.apply_fields(0xf0, &[("MODER1".into(), "Output".into()), ("MODER0".into(), "0x3".into())])
+			.unwrap();
+		assert_eq!(value, 0xf7);
+		let field = moder.field("MODER1").unwrap();
+		assert_eq!(field.value_name(field.extract(value)).unwrap().name, "Output");
+		assert!(moder.apply_fields(0, &[("MODER0".into(), "4".into())]).is_err());
+	}
+
+	#[test]
+	fn invalid_bit_ranges()
+	{
+		for field in [
+			"<field><name>BAD</name><bitRange>[0:3]</bitRange></field>",
+			"<field><name>BAD</name><lsb>3</lsb><msb>0</msb></field>",
+		] {
+			let svd = TEST_SVD.replace("<field><name>IDR0</name><bitRange>[0:0]</bitRange></field>", field);
+			assert!(Svd::parse(&svd).is_err());
+		}
+		assert_eq!(field_width("GOOD", 7, 4).unwrap(), 4);
+	}
+
+	#[test]
+	fn read_actions()
+	{
+		let svd = Svd::parse(TEST_SVD).unwrap();
+		let usart = svd.peripheral("USART1").unwrap();
+		let read_actions: Vec<_> = usart
+			.registers
+			.iter()
+			.map(|register| (register.name.as_str(), register.read_action, register.reset_value))
+			.collect();
+		assert_eq!(read_actions, [("SR", true, 0xc0), ("DR", true, 0), ("BRR", false, 0)]);
+		let (_, moder) = svd.register("GPIOA.MODER").unwrap();
+		assert!(!moder.read_action);
+	}
+}
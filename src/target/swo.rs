@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use clap::builder::PossibleValue;
+use color_eyre::eyre::{OptionExt, Result, eyre};
+use futures_executor::block_on;
+use log::{debug, info};
+use nusb::Interface;
+use nusb::transfer::{Direction, EndpointType, Queue, RequestBuffer};
+
+use crate::bmp::BmpDevice;
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::cortexm::{CORTEXM_DEMCR, CORTEXM_DEMCR_TRCENA};
+use crate::usb::InterfaceClass;
+
+const ITM_TER: u64 = 0xe0000e00;
+const ITM_TPR: u64 = 0xe0000e40;
+const ITM_TCR: u64 = 0xe0000e80;
+const ITM_LAR: u64 = 0xe0000fb0;
+/// Key that unlocks the ITM registers for writing
+const ITM_LAR_KEY: u32 = 0xc5acce55;
+const ITM_TCR_ITMENA: u32 = 1 << 0;
+const ITM_TCR_TSENA: u32 = 1 << 1;
+const ITM_TCR_SYNCENA: u32 = 1 << 2;
+const ITM_TCR_TXENA: u32 = 1 << 3;
+/// ATB ID the ITM uses for its trace data
+const ITM_TCR_TRACE_BUS_ID: u32 = 1 << 16;
+
+const DWT_CTRL: u64 = 0xe0001000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+/// Reload value for the cycle count based PC sampling timer, giving a sample every 16 taps
+const DWT_CTRL_POSTPRESET: u32 = 0xf << 1;
+/// Tap the PC sampling timer from bit 10 of the cycle counter rather than bit 6
+const DWT_CTRL_CYCTAP: u32 = 1 << 9;
+/// Emit a synchronisation packet every 2^24 cycles
+const DWT_CTRL_SYNCTAP: u32 = 1 << 10;
+const DWT_CTRL_PCSAMPLENA: u32 = 1 << 12;
+const DWT_CTRL_EXCTRCENA: u32 = 1 << 16;
+/// Enables for the CPI, exception overhead, sleep, LSU and fold event counters
+const DWT_CTRL_EVENT_COUNTERS: u32 = 0x1f << 17;
+
+const TPIU_CSPSR: u64 = 0xe0040004;
+const TPIU_ACPR: u64 = 0xe0040010;
+const TPIU_SPPR: u64 = 0xe00400f0;
+const TPIU_FFCR: u64 = 0xe0040304;
+/// Formatter and flush control value that bypasses the formatter so only ITM data is output
+const TPIU_FFCR_TRIGIN: u32 = 1 << 8;
+
+/// How much data to ask the probe for in each trace endpoint transfer
+const SWO_TRANSFER_SIZE: usize = 4096;
+/// How many transfers to keep queued up so we don't drop data between reads
+const SWO_TRANSFERS_IN_FLIGHT: usize = 4;
+
+/// How the target's SWO pin encodes the trace data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwoMode
+{
+	Manchester,
+	Uart,
+}
+
+/// Settings for setting a target up to emit ITM trace over SWO
+pub struct SwoConfig
+{
+	pub mode: SwoMode,
+	/// SWO bit rate in bits per second
+	pub baud_rate: u32,
+	/// Frequency of the target's trace clock (usually the core clock) in Hz
+	pub trace_clock: u32,
+	/// Bitmask of ITM stimulus ports to enable
+	pub stimulus_ports: u32,
+	pub pc_sampling: bool,
+	pub exception_trace: bool,
+	pub event_counters: bool,
+	pub timestamps: bool,
+}
+
+/// Program the target's TPIU, ITM and DWT to produce trace over SWO as described by `config`
+pub fn configure_swo(ap: &AdiV5AccessPort, config: &SwoConfig) -> Result<()>
+{
+	if config.baud_rate == 0 || config.baud_rate > config.trace_clock {
+		return Err(eyre!(
+			"Cannot generate {} baud SWO from a {}Hz trace clock",
+			config.baud_rate,
+			config.trace_clock
+		));
+	}
+	let prescaler = config.trace_clock / config.baud_rate - 1;
+	debug!("Configuring {} SWO with prescaler {}", config.mode, prescaler);
+
+	// Turn on the trace components
+	let demcr = ap.mem_read_u32(CORTEXM_DEMCR)?;
+	ap.mem_write_u32(CORTEXM_DEMCR, demcr | CORTEXM_DEMCR_TRCENA)?;
+
+	// Set the TPIU up for a 1-bit wide SWO port with the formatter bypassed
+	ap.mem_write_u32(TPIU_CSPSR, 1)?;
+	ap.mem_write_u32(TPIU_ACPR, prescaler)?;
+	ap.mem_write_u32(TPIU_SPPR, config.mode.protocol())?;
+	ap.mem_write_u32(TPIU_FFCR, TPIU_FFCR_TRIGIN)?;
+
+	// Configure which DWT packets the ITM will be forwarding on
+	let mut dwt_ctrl = ap.mem_read_u32(DWT_CTRL)? | DWT_CTRL_CYCCNTENA | DWT_CTRL_SYNCTAP;
+	if config.pc_sampling {
+		dwt_ctrl |= DWT_CTRL_POSTPRESET | DWT_CTRL_CYCTAP | DWT_CTRL_PCSAMPLENA;
+	}
+	if config.exception_trace {
+		dwt_ctrl |= DWT_CTRL_EXCTRCENA;
+	}
+	if config.event_counters {
+		dwt_ctrl |= DWT_CTRL_EVENT_COUNTERS;
+	}
+	ap.mem_write_u32(DWT_CTRL, dwt_ctrl)?;
+
+	// Finally, unlock the ITM and switch it on
+	ap.mem_write_u32(ITM_LAR, ITM_LAR_KEY)?;
+	let mut itm_tcr = ITM_TCR_ITMENA | ITM_TCR_SYNCENA | ITM_TCR_TXENA | ITM_TCR_TRACE_BUS_ID;
+	if config.timestamps {
+		itm_tcr |= ITM_TCR_TSENA;
+	}
+	ap.mem_write_u32(ITM_TCR, itm_tcr)?;
+	// Allow unprivileged code to use all the stimulus ports
+	ap.mem_write_u32(ITM_TPR, 0)?;
+	ap.mem_write_u32(ITM_TER, config.stimulus_ports)?;
+	Ok(())
+}
+
+/// Reader for the probe's trace capture USB endpoint, which streams the SWO data it receives
+pub struct SwoCapture
+{
+	#[allow(unused)]
+	interface: Interface,
+	queue: Queue<RequestBuffer>,
+}
+
+impl SwoCapture
+{
+	/// Find and claim the probe's trace capture interface, and start reading from it
+	pub fn open(probe: &BmpDevice) -> Result<Self>
+	{
+		let device = probe.device();
+		// The trace interface is the vendor-specific one with a bulk IN endpoint
+		let (interface_number, endpoint) = device
+			.active_configuration()?
+			.interface_alt_settings()
+			.filter(|alt_mode| InterfaceClass(alt_mode.class()) == InterfaceClass::VENDOR_SPECIFIC)
+			.find_map(|alt_mode| {
+				alt_mode
+					.endpoints()
+					.find(|endpoint| {
+						endpoint.direction() == Direction::In && endpoint.transfer_type() == EndpointType::Bulk
+					})
+					.map(|endpoint| (alt_mode.interface_number(), endpoint.address()))
+			})
+			.ok_or_eyre("Could not find the probe's trace capture interface")?;
+		info!("Capturing SWO from interface {} endpoint 0x{:02x}", interface_number, endpoint);
+
+		let interface = device.claim_interface(interface_number)?;
+		let mut queue = interface.bulk_in_queue(endpoint);
+		for _ in 0..SWO_TRANSFERS_IN_FLIGHT {
+			queue.submit(RequestBuffer::new(SWO_TRANSFER_SIZE));
+		}
+		Ok(Self {
+			interface,
+			queue,
+		})
+	}
+
+	/// Wait for the next block of trace data from the probe
+	pub fn read(&mut self) -> Result<Vec<u8>>
+	{
+		let completion = block_on(self.queue.next_complete());
+		completion.status?;
+		let data = completion.data;
+		// Put a new transfer in the queue to replace the one we just took off it
+		self.queue.submit(RequestBuffer::new(SWO_TRANSFER_SIZE));
+		Ok(data)
+	}
+}
+
+impl SwoMode
+{
+	/// TPIU SPPR value for this mode
+	fn protocol(self) -> u32
+	{
+		match self {
+			Self::Manchester => 1,
+			Self::Uart => 2,
+		}
+	}
+}
+
+impl Display for SwoMode
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		match self {
+			Self::Manchester => write!(fmt, "Manchester"),
+			Self::Uart => write!(fmt, "UART (NRZ)"),
+		}
+	}
+}
+
+impl ValueEnum for SwoMode
+{
+	fn value_variants<'a>() -> &'a [Self]
+	{
+		&[Self::Manchester, Self::Uart]
+	}
+
+	fn to_possible_value(&self) -> Option<PossibleValue>
+	{
+		match self {
+			Self::Manchester => Some("manchester".into()),
+			Self::Uart => Some("uart".into()),
+		}
+	}
+}
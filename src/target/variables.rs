@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::Duration;
+
+use color_eyre::eyre::{Result, eyre};
+
+use crate::firmware_file::FirmwareFile;
+use crate::serial::remote::adi::AdiV5AccessPort;
+
+/// Size to assume for symbols the ELF doesn't give a size for
+const VARIABLE_DEFAULT_SIZE: u32 = 4;
+/// Largest variable we'll read, to keep each sample to a single probe request
+const VARIABLE_MAX_SIZE: u32 = 256;
+/// How many bytes of non-scalar variables to show
+const VARIABLE_DISPLAY_BYTES: usize = 16;
+
+/// A variable in target memory being watched
+#[derive(Clone, Debug)]
+pub struct Variable
+{
+	pub name: String,
+	pub address: u32,
+	pub size: u32,
+	/// The value from the most recent sample
+	pub value: Vec<u8>,
+	/// How many times the value has been seen to change
+	pub changes: u64,
+}
+
+impl Variable
+{
+	/// Look a variable up by name in the firmware's symbol table
+	pub fn from_symbol(firmware: &FirmwareFile, name: &str) -> Result<Self>
+	{
+		let symbol = firmware
+			.symbol_by_name(name)
+			.ok_or_else(|| eyre!("Could not find symbol '{}' in the firmware", name))?;
+		let size = match symbol.size {
+			0 => VARIABLE_DEFAULT_SIZE,
+			size => size.min(VARIABLE_MAX_SIZE),
+		};
+		Ok(Self {
+			name: symbol.name.clone(),
+			address: symbol.address,
+			size,
+			value: Vec::new(),
+			changes: 0,
+		})
+	}
+
+	/// Read the variable's current value from the target (without halting it), returning whether it changed
+	pub fn sample(&mut self, ap: &AdiV5AccessPort) -> Result<bool>
+	{
+		let mut value = vec![0u8; self.size as usize];
+		ap.mem_read(&mut value, self.address as u64)?;
+		let changed = !self.value.is_empty() && value != self.value;
+		if changed {
+			self.changes += 1;
+		}
+		self.value = value;
+		Ok(changed)
+	}
+
+	/// The value as an unsigned integer, for variables of a scalar size
+	pub fn as_integer(&self) -> Option<u64>
+	{
+		match self.value.len() {
+			1 | 2 | 4 | 8 => Some(
+				self.value
+					.iter()
+					.rev()
+					.fold(0u64, |value, &byte| (value << 8) | byte as u64),
+			),
+			_ => None,
+		}
+	}
+
+	/// The value formatted in hex - scalars as a single number, anything else as a (possibly truncated) byte list
+	pub fn hex_value(&self) -> String
+	{
+		if let Some(value) = self.as_integer() {
+			return format!("0x{:0width$x}", value, width = self.value.len() * 2);
+		}
+		let mut result = String::new();
+		for byte in self.value.iter().take(VARIABLE_DISPLAY_BYTES) {
+			let _ = write!(result, "{:02x} ", byte);
+		}
+		if self.value.len() > VARIABLE_DISPLAY_BYTES {
+			result.push_str("...");
+		}
+		result.trim_end().into()
+	}
+
+	/// The value formatted as a decimal number, if it's a scalar
+	pub fn decimal_value(&self) -> String
+	{
+		self.as_integer()
+			.map(|value| value.to_string())
+			.unwrap_or_default()
+	}
+}
+
+/// Writes samples of a set of variables out as CSV, one row per sample
+pub struct CsvLog<W: Write>
+{
+	writer: W,
+}
+
+impl<W: Write> CsvLog<W>
+{
+	/// Start a new log, writing the header row naming each of the variables
+	pub fn new(mut writer: W, variables: &[Variable]) -> Result<Self>
+	{
+		write!(writer, "time_ms")?;
+		for variable in variables {
+			write!(writer, ",{}", variable.name)?;
+		}
+		writeln!(writer)?;
+		Ok(Self {
+			writer,
+		})
+	}
+
+	/// Log the current value of each of the variables, scalars in decimal and anything else in hex
+	pub fn log(&mut self, elapsed: Duration, variables: &[Variable]) -> Result<()>
+	{
+		write!(self.writer, "{}", elapsed.as_millis())?;
+		for variable in variables {
+			match variable.as_integer() {
+				Some(value) => write!(self.writer, ",{}", value)?,
+				None => write!(self.writer, ",{}", variable.hex_value())?,
+			}
+		}
+		writeln!(self.writer)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn variable(value: &[u8]) -> Variable
+	{
+		Variable {
+			name: "counter".into(),
+			address: 0x20000000,
+			size: value.len() as u32,
+			value: value.to_vec(),
+			changes: 0,
+		}
+	}
+
+	#[test]
+	fn formatting()
+	{
+		let counter = variable(&[0x34, 0x12, 0x00, 0x00]);
+		assert_eq!(counter.hex_value(), "0x00001234");
+		assert_eq!(counter.decimal_value(), "4660");
+		let buffer = variable(&[1, 2, 3]);
+		assert_eq!(buffer.hex_value(), "01 02 03");
+		assert_eq!(buffer.decimal_value(), "");
+	}
+
+	#[test]
+	fn csv()
+	{
+		let variables = [variable(&[42]), variable(&[0xaa, 0xbb, 0xcc])];
+		let mut output = Vec::new();
+		let mut log = CsvLog::new(&mut output, &variables).unwrap();
+		log.log(Duration::from_millis(100), &variables).unwrap();
+		assert_eq!(String::from_utf8(output).unwrap(), "time_ms,counter,counter\n100,42,aa bb cc\n");
+	}
+}
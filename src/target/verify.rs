@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fmt::Display;
+use std::ops::Range;
+
+use color_eyre::eyre::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
+
+use crate::serial::remote::TargetAddr64;
+use crate::serial::remote::adi::AdiV5AccessPort;
+
+/// How much memory to read back from the target per request when doing a full comparison
+const VERIFY_BLOCK_SIZE: usize = 4096;
+/// Differences closer together than this many bytes get reported as a single mismatching range
+const MISMATCH_MERGE_DISTANCE: usize = 16;
+
+/// A range of target memory that does not match the firmware image
+pub struct Mismatch
+{
+	/// Address in target memory the mismatching range starts at
+	pub address: TargetAddr64,
+	/// What the firmware image says should be in this range
+	pub expected: Vec<u8>,
+	/// What was actually read back from the target
+	pub actual: Vec<u8>,
+}
+
+/// A run of adjacent sectors of target memory whose CRCs do not match those of the firmware image
+#[derive(Debug, PartialEq, Eq)]
+pub struct SectorMismatch
+{
+	/// Address in target memory the first sector starts at
+	pub address: TargetAddr64,
+	/// How long the run of sectors is
+	pub length: usize,
+	/// How many sectors the run covers
+	pub sectors: usize,
+}
+
+/// Compute the CRC32 of a block of data in the same way as Black Magic Debug's firmware and
+/// GDB's qCRC do it (polynomial 0x04c11db7, MSb-first, initial value of 0xffffffff and no final inversion)
+pub fn crc32(data: &[u8]) -> u32
+{
+	crc32_update(0xffffffff, data)
+}
+
+/// Continue a CRC32 computation started with [`crc32`] over more data
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32
+{
+	data.iter().fold(crc, |crc, &byte| {
+		let mut crc = crc ^ ((byte as u32) << 24);
+		for _ in 0..8 {
+			crc = if crc & 0x80000000 != 0 {
+				(crc << 1) ^ 0x04c11db7
+			} else {
+				crc << 1
+			};
+		}
+		crc
+	})
+}
+
+pub(crate) fn progress_bar(length: u64) -> ProgressBar
+{
+	ProgressBar::new(length).with_style(
+		ProgressStyle::default_bar()
+			.template(" {percent:>3}% |{bar:50}| {bytes}/{total_bytes} [{binary_bytes_per_sec} {elapsed}]")
+			.unwrap(),
+	)
+}
+
+/// Read the target memory covered by `image` back (which is expected to be at `load_address`),
+/// and compare it against the image, returning all the ranges found not to match
+pub fn verify_image(ap: &AdiV5AccessPort, image: &[u8], load_address: TargetAddr64) -> Result<Vec<Mismatch>>
+{
+	let progress = progress_bar(image.len() as u64);
+	let mut mismatches = Vec::new();
+	let mut actual = vec![0u8; VERIFY_BLOCK_SIZE];
+
+	for (index, expected) in image.chunks(VERIFY_BLOCK_SIZE).enumerate() {
+		let offset = index * VERIFY_BLOCK_SIZE;
+		let actual = &mut actual[..expected.len()];
+		ap.mem_read(actual, load_address + offset as u64)?;
+		// Find all the ranges in this block that don't match and turn them into mismatch reports
+		for range in mismatching_ranges(expected, actual) {
+			mismatches.push(Mismatch {
+				address: load_address + (offset + range.start) as u64,
+				expected: expected[range.clone()].to_vec(),
+				actual: actual[range].to_vec(),
+			});
+		}
+		progress.inc(expected.len() as u64);
+	}
+	progress.finish();
+	debug!("Found {} mismatching ranges", mismatches.len());
+	Ok(mismatches)
+}
+
+/// Compare `image` against target memory a sector at a time, using `sector_crc` to find the CRC32 of
+/// the target memory in each sector (given as its address and length), returning the runs of adjacent
+/// sectors found not to match
+pub fn verify_sectors(
+	image: &[u8],
+	load_address: TargetAddr64,
+	sector_size: usize,
+	mut sector_crc: impl FnMut(TargetAddr64, usize) -> Result<u32>,
+) -> Result<Vec<SectorMismatch>>
+{
+	let progress = progress_bar(image.len() as u64);
+	let mut mismatches = Vec::new();
+
+	for (index, expected) in image.chunks(sector_size).enumerate() {
+		let address = load_address + (index * sector_size) as u64;
+		let expected_crc = crc32(expected);
+		let actual_crc = sector_crc(address, expected.len())?;
+		if expected_crc != actual_crc {
+			debug!(
+				"Sector 0x{:08x} has CRC32 {:08x}, expected {:08x}",
+				address, actual_crc, expected_crc
+			);
+			match mismatches.last_mut() {
+				// If the previous sector also mismatched, extend its run rather than starting a new one
+				Some(SectorMismatch {
+					address: run_address,
+					length,
+					sectors,
+				}) if *run_address + *length as u64 == address => {
+					*length += expected.len();
+					*sectors += 1;
+				},
+				_ => mismatches.push(SectorMismatch {
+					address,
+					length: expected.len(),
+					sectors: 1,
+				}),
+			}
+		}
+		progress.inc(expected.len() as u64);
+	}
+	progress.finish();
+	debug!("Found {} runs of mismatching sectors", mismatches.len());
+	Ok(mismatches)
+}
+
+/// Find the ranges of bytes that differ between the two blocks, merging ranges that are close together
+fn mismatching_ranges(expected: &[u8], actual: &[u8]) -> Vec<Range<usize>>
+{
+	let mut ranges: Vec<Range<usize>> = Vec::new();
+	for (offset, (lhs, rhs)) in expected.iter().zip(actual).enumerate() {
+		if lhs == rhs {
+			continue;
+		}
+		match ranges.last_mut() {
+			// If this difference is close enough to the last range, extend that range
+			Some(range) if offset - range.end < MISMATCH_MERGE_DISTANCE => range.end = offset + 1,
+			_ => ranges.push(offset..offset + 1),
+		}
+	}
+	ranges
+}
+
+fn hexdump_line(fmt: &mut std::fmt::Formatter<'_>, marker: char, address: TargetAddr64, data: &[u8])
+-> std::fmt::Result
+{
+	write!(fmt, "  {} {:08x}: ", marker, address)?;
+	for byte in data {
+		write!(fmt, "{:02x} ", byte)?;
+	}
+	// Pad short lines out so the character view lines up
+	for _ in data.len()..16 {
+		write!(fmt, "   ")?;
+	}
+	let characters: String = data
+		.iter()
+		.map(|&byte| {
+			if byte.is_ascii_graphic() || byte == b' ' {
+				byte as char
+			} else {
+				'.'
+			}
+		})
+		.collect();
+	writeln!(fmt, "|{}|", characters)
+}
+
+impl Display for Mismatch
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		writeln!(
+			fmt,
+			"Mismatch at 0x{:08x}..0x{:08x} ({} bytes)",
+			self.address,
+			self.address + self.expected.len() as u64,
+			self.expected.len()
+		)?;
+		// Display the expected data first, then the actual, a line of 16 bytes at a time
+		for (index, (expected, actual)) in self.expected.chunks(16).zip(self.actual.chunks(16)).enumerate() {
+			let address = self.address + (index * 16) as u64;
+			hexdump_line(fmt, '-', address, expected)?;
+			hexdump_line(fmt, '+', address, actual)?;
+		}
+		Ok(())
+	}
+}
+
+impl Display for SectorMismatch
+{
+	fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(
+			fmt,
+			"CRC32 mismatch in 0x{:08x}..0x{:08x} ({} {})",
+			self.address,
+			self.address + self.length as u64,
+			self.sectors,
+			if self.sectors == 1 {
+				"sector"
+			} else {
+				"sectors"
+			}
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn crc32_matches_gdb()
+	{
+		// CRC-32/MPEG-2 check value, which is the variant GDB and BMD use
+		assert_eq!(crc32(b"123456789"), 0x0376e6e7);
+		assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0x0376e6e7);
+	}
+
+	#[test]
+	fn mismatches_merge()
+	{
+		let expected = [0u8; 64];
+		let mut actual = [0u8; 64];
+		actual[2] = 1;
+		actual[10] = 1;
+		actual[40] = 1;
+		assert_eq!(mismatching_ranges(&expected, &actual), vec![2..11, 40..41]);
+		assert!(mismatching_ranges(&expected, &expected).is_empty());
+	}
+
+	#[test]
+	fn sector_mismatches_merge()
+	{
+		// Sectors 1 and 2 are corrupted, as is the short final sector 4
+		let image = [0u8; 18];
+		let mut target = image;
+		target[4] = 1;
+		target[11] = 1;
+		target[17] = 1;
+		let mismatches = verify_sectors(&image, 0x08000000, 4, |address, length| {
+			let offset = (address - 0x08000000) as usize;
+			Ok(crc32(&target[offset..offset + length]))
+		})
+		.unwrap();
+		assert_eq!(mismatches, [
+			SectorMismatch {
+				address: 0x08000004,
+				length: 8,
+				sectors: 2,
+			},
+			SectorMismatch {
+				address: 0x08000010,
+				length: 2,
+				sectors: 1,
+			},
+		]);
+	}
+}
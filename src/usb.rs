@@ -29,6 +29,8 @@ impl InterfaceClass
 	/// \[[USB DFU Device Class Spec § 4.2.1, Table 4.1](https://usb.org/sites/default/files/DFU_1.1.pdf#page=12)
 	/// and [§ 4.2.3, Table 4.4](https://usb.org/sites/default/files/DFU_1.1.pdf#page=15)\]
 	pub const APPLICATION_SPECIFIC: Self = Self(0xfe);
+	/// bInterfaceClass field for vendor-specific interfaces, such as the BMP's trace capture interface.
+	pub const VENDOR_SPECIFIC: Self = Self(0xff);
 }
 
 /// Simple newtype struct for some clarity in function arguments and whatnot.
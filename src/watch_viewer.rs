@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2025 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Rachel Mant <git@dragonmux.network>
+
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use ratatui::buffer::Buffer;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Alignment, Constraint, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Text;
+use ratatui::widgets::{Block, BorderType, Padding, Row, Table, Widget};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::serial::remote::adi::AdiV5AccessPort;
+use crate::target::variables::{CsvLog, Variable};
+
+/// Live-updating table of variables being read from a running target
+pub struct WatchViewer<'a>
+{
+	exit: bool,
+	ap: &'a AdiV5AccessPort,
+	variables: Vec<Variable>,
+	/// Which variables changed in the most recent sample, so they can be highlighted
+	changed: Vec<bool>,
+	interval: Duration,
+	start: Instant,
+	samples: u64,
+	log: Option<CsvLog<File>>,
+}
+
+impl<'a> WatchViewer<'a>
+{
+	/// Display the variables, sampling them every `interval` (and logging each sample to `log` if given)
+	/// until the user quits
+	pub fn display(
+		ap: &'a AdiV5AccessPort,
+		variables: Vec<Variable>,
+		interval: Duration,
+		log: Option<File>,
+	) -> Result<()>
+	{
+		let log = log.map(|file| CsvLog::new(file, &variables)).transpose()?;
+		let mut viewer = Self {
+			exit: false,
+			ap,
+			changed: vec![false; variables.len()],
+			variables,
+			interval,
+			start: Instant::now(),
+			samples: 0,
+			log,
+		};
+
+		// Grab the console, putting it in TUI mode
+		let mut terminal = ratatui::init();
+		// Run the viewer until the user quits or something goes wrong
+		let result = viewer.run(&mut terminal);
+		// Put the console back and propagate any errors
+		ratatui::restore();
+		result
+	}
+
+	fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()>
+	{
+		while !self.exit {
+			let next_sample = Instant::now() + self.interval;
+			self.sample()?;
+			terminal.draw(|frame| self.draw(frame))?;
+			// Handle user input until it's time for the next sample
+			while !self.exit {
+				let remaining = next_sample.saturating_duration_since(Instant::now());
+				if remaining.is_zero() || !event::poll(remaining)? {
+					break;
+				}
+				self.handle_events()?;
+			}
+		}
+		Ok(())
+	}
+
+	fn sample(&mut self) -> Result<()>
+	{
+		for (variable, changed) in self.variables.iter_mut().zip(&mut self.changed) {
+			*changed = variable.sample(self.ap)?;
+		}
+		self.samples += 1;
+		if let Some(log) = &mut self.log {
+			log.log(self.start.elapsed(), &self.variables)?;
+		}
+		Ok(())
+	}
+
+	fn draw(&mut self, frame: &mut Frame)
+	{
+		frame.render_widget(self, frame.area())
+	}
+
+	fn handle_events(&mut self) -> Result<()>
+	{
+		if let Event::Key(key) = event::read()? &&
+			key.kind == KeyEventKind::Press &&
+			matches!(key.code, KeyCode::Char('q' | 'Q') | KeyCode::Esc)
+		{
+			self.exit = true;
+		}
+		Ok(())
+	}
+}
+
+impl Widget for &mut WatchViewer<'_>
+{
+	fn render(self, area: Rect, buf: &mut Buffer)
+	where
+		Self: Sized,
+	{
+		let header = Row::new(["Variable", "Address", "Size", "Value", "Decimal", "Changes"])
+			.style(Style::new().add_modifier(Modifier::BOLD));
+		let rows = self
+			.variables
+			.iter()
+			.zip(&self.changed)
+			.map(|(variable, &changed)| {
+				let row = Row::new([
+					variable.name.clone(),
+					format!("0x{:08x}", variable.address),
+					variable.size.to_string(),
+					variable.hex_value(),
+					variable.decimal_value(),
+					variable.changes.to_string(),
+				]);
+				// Make values that just changed stand out
+				if changed {
+					row.style(Style::new().add_modifier(Modifier::REVERSED))
+				} else {
+					row
+				}
+			});
+		let title = format!(
+			"Watching {} variables every {}ms ({} samples)",
+			self.variables.len(),
+			self.interval.as_millis(),
+			self.samples
+		);
+		Table::new(
+			rows,
+			[
+				Constraint::Fill(2),
+				Constraint::Length(10),
+				Constraint::Length(5),
+				Constraint::Fill(3),
+				Constraint::Fill(1),
+				Constraint::Length(8),
+			],
+		)
+		.header(header)
+		.block(
+			Block::bordered()
+				.title(title)
+				.title_alignment(Alignment::Left)
+				.border_type(BorderType::Rounded)
+				.padding(Padding::horizontal(1)),
+		)
+		.render(area, buf);
+
+		// Render the key bindings help
+		Text::from(" q: quit ")
+			.centered()
+			.render(area.rows().next_back().unwrap(), buf);
+	}
+}